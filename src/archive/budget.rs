@@ -0,0 +1,86 @@
+//! `--target-duration`, picking the zstd level of every batch so the archive is done within a time budget.
+
+use std::{
+    ops::RangeInclusive,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tracing::info;
+
+/// The level compressing starts at when no level was set, slow enough to leave a lot of room
+pub const START_LEVEL: i8 = 19;
+
+/// How many levels it takes to compress about twice as fast, roughly true for the levels in between 1 and 19
+const LEVELS_PER_DOUBLING: f64 = 3.0;
+
+/// Shared by the workers. Starts at the highest level and lowers it whenever the batches compressed at the current
+/// level are too slow to get through the rest of the world before the deadline. Never raises it again, a level that
+/// was too slow once would most likely be too slow again.
+pub struct LevelBudget {
+    deadline: Instant,
+    total_bytes: u64,
+    workers: usize,
+    lowest_level: i8,
+    state: Mutex<BudgetState>,
+}
+
+struct BudgetState {
+    level: i8,
+    done_bytes: u64,
+    /// Bytes per second a worker compresses at the current level, `None` until a batch at that level is done
+    speed: Option<f64>,
+}
+
+impl LevelBudget {
+    pub fn new(target_duration: Duration, total_bytes: u64, workers: usize, levels: RangeInclusive<i8>, start_level: i8) -> Self {
+        LevelBudget {
+            deadline: Instant::now() + target_duration,
+            total_bytes,
+            workers: workers.max(1),
+            lowest_level: *levels.start(),
+            state: Mutex::new(BudgetState { level: start_level, done_bytes: 0, speed: None }),
+        }
+    }
+
+    /// The level to compress the next batch with.
+    pub fn next_level(&self) -> i8 {
+        let mut state = self.state.lock().unwrap();
+        let Some(speed) = state.speed else {
+            return state.level;
+        };
+        let remaining_bytes = self.total_bytes.saturating_sub(state.done_bytes) as f64;
+        let remaining_secs = self.deadline.saturating_duration_since(Instant::now()).as_secs_f64();
+        let needed_speed = remaining_bytes / remaining_secs.max(f64::EPSILON) / self.workers as f64;
+        if speed >= needed_speed || state.level == self.lowest_level {
+            return state.level;
+        }
+
+        let steps = ((needed_speed / speed).log2() * LEVELS_PER_DOUBLING).ceil().max(1.0);
+        let level = (state.level as f64 - steps).max(self.lowest_level as f64) as i8;
+        info!(
+            "Lowering the zstd level from {} to {} to finish in time, {} left",
+            state.level,
+            level,
+            humantime::format_duration(Duration::from_secs(remaining_secs as u64))
+        );
+        state.level = level;
+        state.speed = None;
+        level
+    }
+
+    /// Reports a batch of `bytes` that took `elapsed` to compress at `level`.
+    pub fn batch_done(&self, level: i8, bytes: u64, elapsed: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.done_bytes += bytes;
+        // batches of a level that was given up on already don't say anything about the current one
+        if level != state.level || elapsed.is_zero() {
+            return;
+        }
+        let speed = bytes as f64 / elapsed.as_secs_f64();
+        state.speed = Some(match state.speed {
+            Some(previous) => (previous + speed) / 2.0,
+            None => speed,
+        });
+    }
+}
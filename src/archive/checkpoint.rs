@@ -0,0 +1,123 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow};
+use serde_json::{Value, json};
+
+/// Progress of a `--resume`able parallel zstd run, kept in the temp directory until the archive is finished.
+/// The archive consists of independent zstd frames, one per batch, so after an interruption it is cut back to the
+/// length after the last recorded batch and the remaining batches are appended.
+pub struct Checkpoint {
+    dir: PathBuf,
+    /// Archive paths of the files in each batch, in batch index order
+    pub batches: Vec<Vec<String>>,
+    /// Batch indices already written to the archive
+    pub completed: Vec<usize>,
+    /// Length of the archive after the completed batches
+    pub archive_len: u64,
+}
+
+impl Checkpoint {
+    /// The checkpoint directory belonging to an archive, the same for every run writing that archive.
+    fn dir_for(archive_output_path: &Path) -> PathBuf {
+        use sha2::{Digest, Sha256};
+        let absolute = std::path::absolute(archive_output_path).unwrap_or_else(|_| archive_output_path.to_path_buf());
+        let hash = Sha256::digest(absolute.to_string_lossy().as_bytes());
+        let hex: String = hash[..8].iter().map(|byte| format!("{:02x}", byte)).collect();
+        std::env::temp_dir().join(format!("mwdh_resume_{}", hex))
+    }
+
+    /// Whether an interrupted run left a checkpoint for this archive.
+    pub fn exists(archive_output_path: &Path) -> bool {
+        Self::dir_for(archive_output_path).join("checkpoint.json").is_file()
+    }
+
+    /// Loads the checkpoint of an interrupted run. Returns `None` if there is none or if the archive
+    /// doesn't contain the recorded batches anymore.
+    pub fn load(archive_output_path: &Path) -> Result<Option<Checkpoint>> {
+        let dir = Self::dir_for(archive_output_path);
+        let Ok(contents) = std::fs::read_to_string(dir.join("checkpoint.json")) else {
+            return Ok(None);
+        };
+        let value: Value = serde_json::from_str(&contents).context("Failed to parse the resume checkpoint")?;
+        let invalid = || anyhow!("Invalid resume checkpoint in {}", dir.display());
+        let batches = value["batches"]
+            .as_array()
+            .ok_or_else(invalid)?
+            .iter()
+            .map(|batch| {
+                batch
+                    .as_array()
+                    .ok_or_else(invalid)?
+                    .iter()
+                    .map(|name| name.as_str().map(String::from).ok_or_else(invalid))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let completed = value["completed"]
+            .as_array()
+            .ok_or_else(invalid)?
+            .iter()
+            .map(|index| index.as_u64().map(|index| index as usize).ok_or_else(invalid))
+            .collect::<Result<Vec<_>>>()?;
+        let archive_len = value["archive_len"].as_u64().ok_or_else(invalid)?;
+
+        let current_len = std::fs::metadata(archive_output_path).map(|meta| meta.len()).unwrap_or(0);
+        if current_len < archive_len {
+            return Ok(None);
+        }
+        Ok(Some(Checkpoint {
+            dir,
+            batches,
+            completed,
+            archive_len,
+        }))
+    }
+
+    /// Starts a new checkpoint for the given batch plan.
+    pub fn create(archive_output_path: &Path, batches: Vec<Vec<String>>) -> Result<Checkpoint> {
+        let dir = Self::dir_for(archive_output_path);
+        std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create: {}", dir.display()))?;
+        let checkpoint = Checkpoint {
+            dir,
+            batches,
+            completed: Vec::new(),
+            archive_len: 0,
+        };
+        checkpoint.save()?;
+        Ok(checkpoint)
+    }
+
+    /// Records that a batch was written and the archive (synced to disk before) is now `archive_len` bytes long.
+    pub fn record(&mut self, batch_idx: usize, archive_len: u64) -> Result<()> {
+        self.completed.push(batch_idx);
+        self.archive_len = archive_len;
+        self.save()
+    }
+
+    /// Adds batches to the plan, returning the index of the first new one.
+    pub fn extend(&mut self, batches: Vec<Vec<String>>) -> Result<usize> {
+        let first = self.batches.len();
+        self.batches.extend(batches);
+        self.save()?;
+        Ok(first)
+    }
+
+    /// Writes the checkpoint to a temp file first, so a crash never leaves a half written one.
+    fn save(&self) -> Result<()> {
+        let value = json!({
+            "batches": self.batches,
+            "completed": self.completed,
+            "archive_len": self.archive_len,
+        });
+        let path = self.dir.join("checkpoint.json");
+        let temp_path = self.dir.join("checkpoint.json.tmp");
+        std::fs::write(&temp_path, value.to_string()).context("Failed to write the resume checkpoint")?;
+        std::fs::rename(&temp_path, &path).context("Failed to write the resume checkpoint")?;
+        Ok(())
+    }
+
+    /// Deletes the checkpoint of an archive, if there is one.
+    pub fn discard(archive_output_path: &Path) {
+        std::fs::remove_dir_all(Self::dir_for(archive_output_path)).ok();
+    }
+}
@@ -0,0 +1,116 @@
+//! `--diff-base`, archives holding only the files that are new or changed since a previous archive of the world,
+//! plus `mwdh-diff.json` listing the ones that were deleted since. `mwdh extract --apply-onto` applies them.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, anyhow};
+use serde_json::{Value, json};
+use tracing::info;
+
+use crate::{
+    CompressionFormat, FileToCompress,
+    archive::{
+        entries,
+        zip::{zip_mtime, zip_time_to_system_time},
+    },
+    manifest::MANIFEST_NAME,
+};
+
+/// Name of the list of deleted files inside a differential archive
+pub const DIFF_NAME: &str = "mwdh-diff.json";
+
+/// What a differential archive doesn't contain itself.
+pub struct DiffInfo {
+    /// File name of the archive the differential one is relative to
+    pub base: String,
+    /// Paths of the files that are in the base archive, but were deleted from the world since
+    pub deleted: Vec<String>,
+}
+
+impl DiffInfo {
+    pub fn to_json(&self) -> Value {
+        json!({"base": self.base, "deleted": self.deleted})
+    }
+
+    pub fn from_json(bytes: &[u8]) -> Result<Self> {
+        let value: Value = serde_json::from_slice(bytes).with_context(|| format!("{} isn't valid JSON", DIFF_NAME))?;
+        let deleted = value
+            .get("deleted")
+            .and_then(Value::as_array)
+            .ok_or_else(|| anyhow!("{} doesn't list the deleted files", DIFF_NAME))?
+            .iter()
+            .map(|path| path.as_str().map(str::to_string))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| anyhow!("{} has a deleted file that isn't a path", DIFF_NAME))?;
+        Ok(DiffInfo {
+            base: value.get("base").and_then(Value::as_str).unwrap_or("unknown").to_string(),
+            deleted,
+        })
+    }
+}
+
+/// Leaves out the files that have an entry with the same size and modification time in the base archive, and puts
+/// the list of files that are only in the base archive written to `dir` in front of the rest.
+pub fn diff_against(files: Vec<FileToCompress>, base: &Path, dir: &Path) -> Result<Vec<FileToCompress>> {
+    let format = CompressionFormat::from_file_extension(base.extension())
+        .ok_or_else(|| anyhow!("Unknown type of the base archive, expected a .zip or .tar.zst file"))?;
+    info!("Comparing the world with {}", base.display());
+    let mut base_entries = HashMap::new();
+    entries::for_each_entry(base, |entry, _| {
+        if entry.path != MANIFEST_NAME && entry.path != DIFF_NAME {
+            base_entries.insert(entry.path, (entry.size, entry.modified));
+        }
+        Ok(true)
+    })
+    .with_context(|| format!("Failed to read the base archive {}", base.display()))?;
+
+    let total = files.len();
+    let changed: Vec<_> = files
+        .into_iter()
+        .filter(|file| {
+            let unchanged = base_entries.remove(&file.file_name).is_some_and(|(size, modified)| {
+                let stored = file.modified.and_then(|modified| stored_time(format, modified));
+                size == file.size && stored.is_some() && stored == modified
+            });
+            !unchanged
+        })
+        .collect();
+    let mut deleted: Vec<_> = base_entries.into_keys().collect();
+    deleted.sort();
+    info!(
+        "{} of {} files are new or changed since {}, {} were deleted",
+        changed.len(),
+        total,
+        base.display(),
+        deleted.len()
+    );
+
+    let diff = DiffInfo {
+        base: base.file_name().unwrap_or_default().to_string_lossy().to_string(),
+        deleted,
+    };
+    let path = dir.join(DIFF_NAME);
+    std::fs::write(&path, serde_json::to_vec_pretty(&diff.to_json())?)
+        .with_context(|| format!("Failed to write: {}", path.display()))?;
+    let meta = std::fs::metadata(&path)?;
+
+    let mut with_diff = Vec::with_capacity(changed.len() + 1);
+    with_diff.push(FileToCompress::new(path, DIFF_NAME.to_string(), &meta));
+    with_diff.extend(changed);
+    Ok(with_diff)
+}
+
+/// The modification time like an archive of the format stores it.
+fn stored_time(format: CompressionFormat, modified: SystemTime) -> Option<SystemTime> {
+    match format {
+        CompressionFormat::TarZstd => {
+            let secs = modified.duration_since(UNIX_EPOCH).ok()?.as_secs();
+            Some(UNIX_EPOCH + Duration::from_secs(secs))
+        }
+        CompressionFormat::ZipDeflate => zip_mtime(modified).and_then(zip_time_to_system_time),
+    }
+}
@@ -0,0 +1,127 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+#[cfg(feature = "encryption")]
+use anyhow::anyhow;
+use anyhow::{Context, Result};
+
+use crate::{archive::hints, paths};
+
+/// How to encrypt the archive with [age](https://age-encryption.org).
+#[cfg(feature = "encryption")]
+#[derive(Clone)]
+pub enum AgeEncryption {
+    /// X25519 public keys (`age1...`), any of the matching private keys can decrypt
+    Recipients(Vec<age::x25519::Recipient>),
+    Passphrase(String),
+}
+
+/// Stands in for the encryption without the `encryption` feature, so archives can't be encrypted.
+#[cfg(not(feature = "encryption"))]
+#[derive(Clone)]
+pub enum AgeEncryption {}
+
+#[cfg(feature = "encryption")]
+impl AgeEncryption {
+    pub fn parse_recipients<'a>(recipients: impl Iterator<Item = &'a String>) -> Result<AgeEncryption> {
+        let recipients = recipients
+            .map(|recipient| {
+                recipient
+                    .parse::<age::x25519::Recipient>()
+                    .map_err(|err| anyhow!("Invalid age recipient {}: {}", recipient, err))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(AgeEncryption::Recipients(recipients))
+    }
+}
+
+/// The archive's output file, optionally encrypted, written through a buffer of `buffer_size` bytes.
+/// Has to be [finished](ArchiveWriter::finish) to write the end of the age stream and the rest of the buffer.
+/// The estimated size of the archive is reserved on disk when creating it.
+pub enum ArchiveWriter {
+    Plain(BufWriter<File>),
+    #[cfg(feature = "encryption")]
+    Age(age::stream::StreamWriter<BufWriter<File>>),
+}
+
+// without the `encryption` feature, every archive is a plain one
+#[cfg_attr(not(feature = "encryption"), allow(irrefutable_let_patterns, clippy::infallible_destructuring_match))]
+impl ArchiveWriter {
+    pub fn create(path: &Path, encryption: Option<&AgeEncryption>, buffer_size: usize, estimated_size: u64) -> Result<ArchiveWriter> {
+        let file = File::create(paths::long_path(path)).with_context(|| format!("Failed to create: {}", path.display()))?;
+        hints::preallocate(&file, estimated_size);
+        let file = BufWriter::with_capacity(buffer_size, file);
+        let Some(encryption) = encryption else {
+            return Ok(ArchiveWriter::Plain(file));
+        };
+        #[cfg(not(feature = "encryption"))]
+        match *encryption {}
+        #[cfg(feature = "encryption")]
+        let encryptor = match encryption {
+            AgeEncryption::Recipients(recipients) => age::Encryptor::with_recipients(
+                recipients.iter().map(|recipient| recipient as &dyn age::Recipient),
+            )
+            .context("Failed to set up age encryption")?,
+            AgeEncryption::Passphrase(passphrase) => {
+                age::Encryptor::with_user_passphrase(passphrase.clone().into())
+            }
+        };
+        #[cfg(feature = "encryption")]
+        Ok(ArchiveWriter::Age(encryptor.wrap_output(file)?))
+    }
+
+    /// Opens the unencrypted archive of an interrupted run to append to it, cutting it back to `len` bytes.
+    pub fn resume(path: &Path, len: u64, buffer_size: usize) -> Result<ArchiveWriter> {
+        let mut file = File::options()
+            .write(true)
+            .open(paths::long_path(path))
+            .with_context(|| format!("Failed to open: {}", path.display()))?;
+        file.set_len(len)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(ArchiveWriter::Plain(BufWriter::with_capacity(buffer_size, file)))
+    }
+
+    /// Makes sure everything written so far is on disk. Only possible for unencrypted archives.
+    pub fn sync(&mut self) -> Result<()> {
+        if let ArchiveWriter::Plain(file) = self {
+            file.flush()?;
+            file.get_ref().sync_data()?;
+            hints::drop_cache(file.get_ref());
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        let file = match self {
+            ArchiveWriter::Plain(file) => file,
+            #[cfg(feature = "encryption")]
+            ArchiveWriter::Age(writer) => writer.finish()?,
+        };
+        let file = file.into_inner().map_err(|err| err.into_error())?;
+        hints::release_preallocation(&file)?;
+        file.sync_all()?;
+        hints::drop_cache(&file);
+        Ok(())
+    }
+}
+
+impl Write for ArchiveWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ArchiveWriter::Plain(file) => file.write(buf),
+            #[cfg(feature = "encryption")]
+            ArchiveWriter::Age(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ArchiveWriter::Plain(file) => file.flush(),
+            #[cfg(feature = "encryption")]
+            ArchiveWriter::Age(writer) => writer.flush(),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+//! Reading the files of an existing archive, for `mwdh list`, `mwdh verify` and `--diff-base`.
+
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::{CompressionFormat, archive::zip::zip_time_to_system_time};
+
+/// A file in an archive.
+pub struct Entry {
+    /// Path inside the archive, separated by `/`
+    pub path: String,
+    pub size: u64,
+    /// Modification time as the archive stores it, whole seconds for tar and two seconds for ZIP
+    pub modified: Option<SystemTime>,
+}
+
+/// Hands every file in the archive with its contents to `visit`, in the order they're stored in. Stops early once
+/// `visit` returns `false`.
+pub fn for_each_entry(archive: &Path, mut visit: impl FnMut(Entry, &mut dyn Read) -> Result<bool>) -> Result<()> {
+    if archive.extension().is_some_and(|ext| ext == "age") {
+        return Err(anyhow!("The archive is encrypted, decrypt it first with `age -d`"));
+    }
+    let format = CompressionFormat::from_file_extension(archive.extension())
+        .ok_or_else(|| anyhow!("Unknown archive type, expected a .zip or .tar.zst file"))?;
+    let file = File::open(archive).with_context(|| format!("Failed to open: {}", archive.display()))?;
+    match format {
+        CompressionFormat::TarZstd => {
+            let mut decoder = zstd::Decoder::new(file)?;
+            decoder.window_log_max(31)?; // archives created with a large --zstd-long window
+            let mut tar = tar::Archive::new(decoder);
+            for entry in tar.entries()? {
+                let mut entry = entry?;
+                if entry.header().entry_type().is_dir() {
+                    continue;
+                }
+                let info = Entry {
+                    path: entry.path()?.to_string_lossy().to_string(),
+                    size: entry.size(),
+                    // files without a modification time get 0 in their header
+                    modified: entry
+                        .header()
+                        .mtime()
+                        .ok()
+                        .filter(|&mtime| mtime > 0)
+                        .map(|mtime| UNIX_EPOCH + Duration::from_secs(mtime)),
+                };
+                if !visit(info, &mut entry)? {
+                    break;
+                }
+            }
+        }
+        CompressionFormat::ZipDeflate => {
+            let mut zip = zip::ZipArchive::new(BufReader::new(file))?;
+            for i in 0..zip.len() {
+                let mut entry = zip.by_index(i)?;
+                if entry.is_dir() {
+                    continue;
+                }
+                let info = Entry {
+                    path: entry.name().to_string(),
+                    size: entry.size(),
+                    modified: entry.last_modified().and_then(zip_time_to_system_time),
+                };
+                if !visit(info, &mut entry)? {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,152 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        mpsc::{self, Sender},
+    },
+    time::Instant,
+};
+
+use anyhow::Result;
+use tracing::{Span, info_span};
+
+use crate::{
+    ArchiveOptions, CompressionStats, FileToCompress, ProgressMessage,
+    archive::{
+        ArchivedFiles,
+        preprocess::PreprocessDir,
+        priority,
+        progress::handle_progress,
+        scan_files,
+        zip::ZipFormat,
+        zstd::TarZstdFormat,
+    },
+};
+
+/// A kind of archive mwdh can write. [`do_compression`](crate::archive::do_compression) scans the world and hands
+/// the files to [`compress`](ArchiveFormat::compress) on a blocking thread, reporting the progress to the configured sink.
+pub trait ArchiveFormat: Send + Sync {
+    /// Short name of the format, like `zstd` or `zip`
+    fn name(&self) -> &'static str;
+
+    /// File ending of the archives without the leading dot, like `tar.zst`
+    fn extension(&self) -> &'static str;
+
+    /// Content type the server sends the archives with
+    fn mime_type(&self) -> &'static str;
+
+    /// Finds the files to archive and runs the region preprocessing. The [`PreprocessDir`] has to be kept alive
+    /// until the archive is written.
+    fn scan(
+        &self,
+        tx: &Sender<ProgressMessage>,
+        paths_to_be_archived: Vec<PathBuf>,
+        options: &ArchiveOptions,
+    ) -> Result<(Vec<FileToCompress>, PreprocessDir)> {
+        scan_files(tx, paths_to_be_archived, options)
+    }
+
+    /// Writes the files into the archive. [`ProgressMessage::Complete`] is sent once this returned successfully.
+    fn compress(
+        &self,
+        files: Vec<FileToCompress>,
+        archive_output_path: PathBuf,
+        tx: Sender<ProgressMessage>,
+        options: ArchiveOptions,
+    ) -> Result<()>;
+}
+
+/// The archive formats to choose from by name or file ending. [`Default`] has the ones built into mwdh.
+#[derive(Clone)]
+pub struct FormatRegistry {
+    formats: Vec<Arc<dyn ArchiveFormat>>,
+}
+
+impl FormatRegistry {
+    /// A registry without any formats.
+    pub fn empty() -> Self {
+        FormatRegistry { formats: Vec::new() }
+    }
+
+    /// Adds a format, replacing a registered one with the same name.
+    pub fn register(&mut self, format: Arc<dyn ArchiveFormat>) {
+        self.formats.retain(|registered| registered.name() != format.name());
+        self.formats.push(format);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn ArchiveFormat>> {
+        self.formats.iter().find(|format| format.name() == name).cloned()
+    }
+
+    /// The format of an archive file, by the longest matching file ending. Looks through an `.age` encryption suffix.
+    pub fn for_path(&self, path: &Path) -> Option<Arc<dyn ArchiveFormat>> {
+        let file_name = path.file_name()?.to_str()?;
+        let file_name = file_name.strip_suffix(".age").unwrap_or(file_name);
+        self.formats
+            .iter()
+            .filter(|format| {
+                file_name
+                    .strip_suffix(format.extension())
+                    .is_some_and(|stem| stem.ends_with('.'))
+            })
+            .max_by_key(|format| format.extension().len())
+            .cloned()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<dyn ArchiveFormat>> {
+        self.formats.iter()
+    }
+}
+
+impl Default for FormatRegistry {
+    fn default() -> Self {
+        let mut registry = FormatRegistry::empty();
+        registry.register(Arc::new(TarZstdFormat));
+        registry.register(Arc::new(ZipFormat));
+        registry
+    }
+}
+
+/// Scans and compresses on a blocking thread while the progress is handed to the sink on another one.
+pub async fn generate_with_progress(
+    format: Arc<dyn ArchiveFormat>,
+    paths_to_be_archived: Vec<PathBuf>,
+    archive_output_path: PathBuf,
+    options: ArchiveOptions,
+) -> Result<(ArchivedFiles, CompressionStats)> {
+    let (tx, rx) = mpsc::channel();
+    let progress_sink = options.progress.sink();
+
+    let span = Span::current();
+    let archive_handle = tokio::task::spawn_blocking(move || {
+        let background = options.background;
+        let archive = move || {
+            let _span = span.enter();
+            let (files, _preprocess_dir) = info_span!("scan").in_scope(|| format.scan(&tx, paths_to_be_archived, &options))?;
+            let archived = ArchivedFiles::of(&files);
+            let threads = options.threads;
+            let started = Instant::now();
+            let complete_tx = tx.clone();
+            info_span!("compress", format = format.name())
+                .in_scope(|| format.compress(files, archive_output_path.clone(), tx, options))?;
+            let stats = CompressionStats {
+                archive_size: std::fs::metadata(&archive_output_path)?.len(),
+                uncompressed_size: archived.bytes,
+                elapsed: started.elapsed(),
+                threads,
+            };
+            complete_tx.send(ProgressMessage::Complete(stats)).ok();
+            Ok::<_, anyhow::Error>((archived, stats))
+        };
+        // the blocking thread goes back to tokio afterwards, so it keeps its priority
+        if background { priority::run_in_background(archive) } else { archive() }
+    });
+
+    // Handle progress updates on main thread
+    let progress_handle = tokio::task::spawn_blocking(move || handle_progress(rx, progress_sink));
+
+    let result = archive_handle.await??;
+    progress_handle.await?;
+
+    Ok(result)
+}
@@ -0,0 +1,117 @@
+//! Hints to the kernel about how mwdh uses its files, so archiving a world bigger than the RAM neither fragments
+//! the archive nor pushes everything else out of the page cache. Only does something on Linux, failing hints are
+//! ignored.
+
+use std::{
+    fs::File,
+    io::{self, Read},
+};
+
+#[cfg(target_os = "linux")]
+use std::os::fd::AsRawFd;
+#[cfg(target_os = "linux")]
+use tracing::debug;
+
+/// Reserves `len` bytes of disk space for the archive up front, so the filesystem can keep it in one piece.
+/// The file's size doesn't change, [`release_preallocation`] gives back what wasn't used.
+pub fn preallocate(file: &File, len: u64) {
+    #[cfg(target_os = "linux")]
+    {
+        let Ok(len) = libc::off_t::try_from(len) else {
+            return;
+        };
+        if len > 0 && unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len) } != 0 {
+            debug!("Failed to preallocate the archive: {}", io::Error::last_os_error());
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (file, len);
+}
+
+/// Frees the space [`preallocate`] reserved behind the end of the finished archive.
+pub fn release_preallocation(file: &File) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    file.set_len(file.metadata()?.len())?;
+    #[cfg(not(target_os = "linux"))]
+    let _ = file;
+    Ok(())
+}
+
+/// Drops the file's pages from the page cache. Only pages that were written to disk already are dropped.
+pub fn drop_cache(file: &File) {
+    advise(file, AccessPattern::DontNeed);
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+enum AccessPattern {
+    Sequential,
+    DontNeed,
+}
+
+fn advise(file: &File, pattern: AccessPattern) {
+    #[cfg(target_os = "linux")]
+    {
+        let advice = match pattern {
+            AccessPattern::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            AccessPattern::DontNeed => libc::POSIX_FADV_DONTNEED,
+        };
+        // returns the error instead of setting errno
+        let err = unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, advice) };
+        if err != 0 {
+            debug!("Failed to advise the kernel: {}", io::Error::from_raw_os_error(err));
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (file, pattern);
+}
+
+/// A file of the world, read once from start to end. The kernel reads ahead further than usual, and the file
+/// leaves the page cache once it's archived. With the `io-uring` feature, files that take a few reads of
+/// `chunk_size` are read through io_uring.
+pub struct SourceFile(SourceReader);
+
+enum SourceReader {
+    Plain(File),
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    Uring(Box<crate::uring::UringReader>),
+}
+
+impl SourceFile {
+    pub fn new(file: File, len: u64, chunk_size: usize) -> Self {
+        advise(&file, AccessPattern::Sequential);
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if len > 2 * chunk_size as u64 && crate::uring::available() {
+            match file.try_clone().and_then(|file| crate::uring::UringReader::new(file, len, chunk_size)) {
+                Ok(reader) => return SourceFile(SourceReader::Uring(Box::new(reader))),
+                Err(err) => tracing::debug!("Failed to set up io_uring, reading the file the usual way: {}", err),
+            }
+        }
+        #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+        let _ = (len, chunk_size);
+        SourceFile(SourceReader::Plain(file))
+    }
+
+    fn file(&self) -> &File {
+        match &self.0 {
+            SourceReader::Plain(file) => file,
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            SourceReader::Uring(reader) => reader.file(),
+        }
+    }
+}
+
+impl Read for SourceFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.0 {
+            SourceReader::Plain(file) => file.read(buf),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            SourceReader::Uring(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl Drop for SourceFile {
+    fn drop(&mut self) {
+        drop_cache(self.file());
+    }
+}
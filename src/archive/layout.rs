@@ -0,0 +1,81 @@
+use anyhow::anyhow;
+
+use crate::{ArchiveOptions, FileToCompress};
+
+/// Directory layout of the worlds inside the archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// Keep the layout of the server
+    AsIs,
+    /// Single world directory with the Nether and the End in DIM-1 and DIM1, like singleplayer and vanilla servers
+    Vanilla,
+    /// Nether and End in their own `<world>_nether/DIM-1` and `<world>_the_end/DIM1` directories, like Bukkit/Spigot/Paper servers
+    Bukkit,
+}
+
+impl std::str::FromStr for Layout {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "as-is" => Ok(Layout::AsIs),
+            "vanilla" => Ok(Layout::Vanilla),
+            "bukkit" => Ok(Layout::Bukkit),
+            _ => Err(anyhow!("Unknown layout: {} (expected as-is, vanilla or bukkit)", s)),
+        }
+    }
+}
+
+/// Rewrites the paths inside the archive to the requested layout. Files that have no place in the target layout are left out.
+pub fn apply_layout(files: Vec<FileToCompress>, options: &ArchiveOptions) -> Vec<FileToCompress> {
+    match options.layout {
+        Layout::Vanilla if options.is_bukkit => files
+            .into_iter()
+            .filter_map(|mut file| {
+                file.file_name = bukkit_to_vanilla(&file.file_name, &options.world_name)?;
+                Some(file)
+            })
+            .collect(),
+        Layout::Bukkit if !options.is_bukkit => vanilla_to_bukkit(files, &options.world_name),
+        _ => files,
+    }
+}
+
+/// Moves `world/DIM-1/..` to `world_nether/DIM-1/..` and `world/DIM1/..` to `world_the_end/DIM1/..`.
+/// Bukkit expects a level.dat in every world directory, so the overworld's one gets copied into them.
+fn vanilla_to_bukkit(mut files: Vec<FileToCompress>, world_name: &str) -> Vec<FileToCompress> {
+    let mut moved_dimensions = Vec::new();
+    for file in &mut files {
+        for (suffix, dim) in [("_nether", "DIM-1"), ("_the_end", "DIM1")] {
+            if let Some(rest) = file.file_name.strip_prefix(&format!("{}/{}/", world_name, dim)) {
+                file.file_name = format!("{}{}/{}/{}", world_name, suffix, dim, rest);
+                if !moved_dimensions.contains(&suffix) {
+                    moved_dimensions.push(suffix);
+                }
+            }
+        }
+    }
+    let level_dat_name = format!("{}/level.dat", world_name);
+    if let Some(level_dat) = files.iter().find(|file| file.file_name == level_dat_name).cloned() {
+        for suffix in moved_dimensions {
+            files.push(FileToCompress {
+                file_name: format!("{}{}/level.dat", world_name, suffix),
+                ..level_dat.clone()
+            });
+        }
+    }
+    files
+}
+
+/// Moves `world_nether/DIM-1/..` to `world/DIM-1/..` and `world_the_end/DIM1/..` to `world/DIM1/..`.
+/// The rest of the nether and end directories (their own level.dat, uid.dat, ..) is only used by Bukkit and dropped.
+fn bukkit_to_vanilla(path: &str, world_name: &str) -> Option<String> {
+    for (suffix, dim) in [("_nether", "DIM-1"), ("_the_end", "DIM1")] {
+        let dimension_dir = format!("{}{}/", world_name, suffix);
+        if let Some(rest) = path.strip_prefix(&dimension_dir) {
+            return rest
+                .strip_prefix(&format!("{}/", dim))
+                .map(|inner| format!("{}/{}/{}", world_name, dim, inner));
+        }
+    }
+    Some(path.to_string())
+}
@@ -1,24 +1,147 @@
 pub mod zip;
 pub mod zstd;
 pub mod progress;
+pub mod preprocess;
+pub mod layout;
+pub mod encrypt;
+pub mod checkpoint;
+pub mod space;
+pub mod format;
+pub mod hints;
+pub mod priority;
+pub mod scanner;
+pub mod throttle;
+pub mod pool;
+pub mod budget;
+pub mod entries;
+pub mod diff;
 
-use crate::{ArchiveOptions, CompressionFormat, FileToCompress, ProgressMessage, archive, collect_files_recursive, paths_to_be_archived};
+use crate::{manifest, archive::{hints::SourceFile, preprocess::PreprocessDir, scanner::WorldScanner, throttle::ThrottledReader}, error::{MwdhError, ScanFailed}, ArchiveOptions, CompressionFormat, CompressionStats, FileToCompress, ProgressMessage, paths_to_be_archived, hooks::{self, HookFailurePolicy}, logging, paths, prune, systemd};
+#[cfg(feature = "notify")]
+use crate::{healthcheck::{self, Ping}, notify::{self, Notification, NotifyEvent}};
+#[cfg(feature = "upload")]
+use crate::upload;
 use anyhow::{Context, Result};
 use scopeguard::ScopeGuard;
-use std::{path::{Path, PathBuf}, process, sync::mpsc::Sender};
+use std::{fs::File, io::{BufRead, Read, Write}, path::{Path, PathBuf}, process, sync::mpsc::Sender, time::{Duration, Instant}};
+use tracing::{debug, info, warn};
 
-fn print_archiving_info(options: &ArchiveOptions) {
+/// What to do when a file can't be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnError {
+    /// Fail the whole archive
+    Abort,
+    /// Leave the file out and report it at the end
+    Skip,
+    /// Try again a few times with increasing pauses, then skip the file
+    Retry,
+}
+
+impl std::str::FromStr for OnError {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "abort" => Ok(OnError::Abort),
+            "skip" => Ok(OnError::Skip),
+            "retry" => Ok(OnError::Retry),
+            _ => Err(anyhow::anyhow!("Unknown error policy: {} (expected skip, abort or retry)", s)),
+        }
+    }
+}
+
+/// Pauses between the attempts of `--on-error retry`.
+const RETRY_DELAYS: [Duration; 3] = [Duration::from_secs(1), Duration::from_secs(2), Duration::from_secs(4)];
+
+/// Runs a file operation under the `--on-error` policy. Returns `None` if the file is skipped,
+/// which is reported with [`ProgressMessage::FileSkipped`].
+pub fn with_error_policy<T>(
+    file_name: &str,
+    options: &ArchiveOptions,
+    tx: &Sender<ProgressMessage>,
+    mut operation: impl FnMut() -> Result<T>,
+) -> Result<Option<T>> {
+    let mut attempt = 0;
+    loop {
+        let err = match operation() {
+            Ok(value) => return Ok(Some(value)),
+            Err(err) => err,
+        };
+        match options.on_error {
+            OnError::Abort if paths::is_locked(&err) => {
+                return Err(err.context(format!(
+                    "{} is locked by another program, like a Minecraft client or server that has the world open. \
+                    Close it first or pass --on-error skip or --on-error retry",
+                    file_name
+                )));
+            }
+            OnError::Abort => return Err(err),
+            OnError::Retry if attempt < RETRY_DELAYS.len() && !options.cancel.is_cancelled() => {
+                debug!("{}: {:#}, trying again in {}s", file_name, err, RETRY_DELAYS[attempt].as_secs());
+                std::thread::sleep(RETRY_DELAYS[attempt]);
+                attempt += 1;
+            }
+            OnError::Skip | OnError::Retry => {
+                tx.send(ProgressMessage::FileSkipped(file_name.to_string(), format!("{:#}", err))).ok();
+                return Ok(None);
+            }
+        }
+    }
+}
+
+/// Default size in bytes of the buffers the world's files are read with and the archive is written with
+pub const DEFAULT_IO_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Like [`std::io::copy`], but passes on the reader's whole buffer at a time instead of 8 KiB pieces, so the
+/// files are read and the encoders are fed in as big chunks as the buffer was created with.
+pub fn copy_buf(reader: &mut impl BufRead, writer: &mut (impl Write + ?Sized)) -> std::io::Result<u64> {
+    let mut copied = 0;
+    loop {
+        let buf = match reader.fill_buf() {
+            Ok(buf) => buf,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        if buf.is_empty() {
+            return Ok(copied);
+        }
+        writer.write_all(buf)?;
+        let len = buf.len();
+        reader.consume(len);
+        copied += len as u64;
+    }
+}
+
+/// Reads exactly `len` bytes from the file: cut off if it grew, padded with zeros if it shrank since its size
+/// went into the tar header.
+pub fn sized_reader(file: impl Read, len: u64) -> impl Read {
+    file.take(len).chain(std::io::repeat(0)).take(len)
+}
+
+/// Opens a file to be archived under the `--on-error` policy. It's read no faster than the `--io-limit`.
+/// Its size and metadata are the ones from the scan.
+pub fn open_source_file(
+    file_info: &FileToCompress,
+    options: &ArchiveOptions,
+    tx: &Sender<ProgressMessage>,
+) -> Result<Option<ThrottledReader<SourceFile>>> {
+    with_error_policy(&file_info.file_name, options, tx, || {
+        let file = File::open(&file_info.src_path)
+            .with_context(|| format!("Failed to open: {}", file_info.src_path.display()))?;
+        let file = SourceFile::new(file, file_info.size, options.io_buffer_size);
+        Ok(ThrottledReader::new(file, options.io_limit.as_ref()))
+    })
+}
+
+fn print_archiving_info(options: &ArchiveOptions) -> Result<(), MwdhError> {
     let path = Path::new(&options.world_path);
     if !path.exists() {
-        eprintln!("ERR: Given path does not exist");
-        process::exit(1);
+        return Err(MwdhError::WorldNotFound(path.to_path_buf()));
     }
     if !path.is_dir() {
-        eprintln!("ERR: Path should be a directory");
-        process::exit(1);
+        return Err(MwdhError::WorldNotADirectory(path.to_path_buf()));
     }
-    let absolute_path = std::fs::canonicalize(&path).unwrap_or(path.into());
-    println!(
+    let absolute_path = std::fs::canonicalize(path).unwrap_or(path.into());
+    info!(
         "(Server) worlds directory: {}",
         absolute_path.to_string_lossy()
     );
@@ -42,50 +165,244 @@ fn print_archiving_info(options: &ArchiveOptions) {
         }
         inclusions.push_str("The End");
     }
-    println!("{}", inclusions);
-    println!(
+    info!("{}", inclusions);
+    info!(
         "Compressing to \"{}\" using {} at level {} with {} threads",
-        format!("{}.{}", options.archive_name, options.compression_format.get_file_ending()),
+        options.archive_output_path().display(),
         options.compression_format,
         options.compression_level,
         options.threads
     );
+    Ok(())
+}
+
+/// The files that went into an archive, as found by the scan.
+#[derive(Debug, Clone, Copy)]
+pub struct ArchivedFiles {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+impl ArchivedFiles {
+    pub fn of(files: &[FileToCompress]) -> Self {
+        ArchivedFiles { count: files.len() as u64, bytes: space::total_size(files) }
+    }
+}
+
+/// What [`do_compression`] created.
+#[derive(Debug, Clone)]
+pub struct ArchiveSummary {
+    pub output_path: PathBuf,
+    /// Size of the archive file in bytes
+    pub compressed_size: u64,
+    /// Total size of the archived files in bytes
+    pub uncompressed_size: u64,
+    /// Number of files found in the world, including files skipped with [`OnError::Skip`]
+    pub file_count: u64,
+    /// How long scanning, compressing and writing took, without the hooks, upload and pruning
+    pub duration: Duration,
+    pub format: CompressionFormat,
+    /// Hex encoded SHA-256 of the archive
+    pub sha256: String,
+    /// Ratio and speed of the compression itself
+    pub compression: CompressionStats,
+}
+
+/// Archives the world as configured in the options, then uploads, prunes and runs the post-hook. Pings the health
+/// check and sends the notifications about how it went.
+#[tracing::instrument(name = "archive", skip_all, fields(world = %options.world_name))]
+pub async fn do_compression(options: ArchiveOptions) -> Result<ArchiveSummary, MwdhError> {
+    #[cfg(feature = "notify")]
+    let (targets, world_name, healthcheck_url) = (options.notify.clone(), options.world_name.clone(), options.healthcheck_url.clone());
+    #[cfg(feature = "notify")]
+    if let Some(ref url) = healthcheck_url {
+        healthcheck::ping(url, Ping::Start, String::new()).await;
+    }
+    let result = archive_world(options).await;
+    #[cfg(feature = "notify")]
+    report(&result, &world_name, healthcheck_url.as_ref(), &targets).await;
+    result
 }
 
-pub async fn do_compression(
-    options: ArchiveOptions,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    print_archiving_info(&options);
-    let archive_output_path =
-        Path::new(&options.archive_name).with_extension(options.compression_format.get_file_ending());
+/// Pings the health check and sends the notifications about how archiving `world_name` went.
+#[cfg(feature = "notify")]
+async fn report(result: &Result<ArchiveSummary, MwdhError>, world_name: &str, healthcheck_url: Option<&url::Url>, targets: &[notify::NotifyTarget]) {
+    if let Some(url) = healthcheck_url {
+        // a cancelled run didn't back anything up either
+        let (ping, body) = match result {
+            Ok(summary) => (Ping::Success, format!("Created {} ({})", summary.output_path.display(), summary.compression)),
+            Err(err) => (Ping::Fail, format!("{:#}", err)),
+        };
+        healthcheck::ping(url, ping, body).await;
+    }
+    let notification = match result {
+        Ok(summary) => Notification {
+            event: NotifyEvent::Compressed,
+            title: format!("Compressed {}", world_name),
+            message: format!("Created {} ({})", summary.output_path.display(), summary.compression),
+        },
+        // whoever cancelled it knows
+        Err(MwdhError::Cancelled) => return,
+        Err(err) => Notification {
+            event: NotifyEvent::CompressionFailed,
+            title: format!("Compressing {} failed", world_name),
+            message: format!("{:#}", err),
+        },
+    };
+    notify::send(targets, &notification).await;
+}
+
+async fn archive_world(options: ArchiveOptions) -> Result<ArchiveSummary, MwdhError> {
+    options.validate()?;
+    print_archiving_info(&options)?;
+    if let Some(ref pre_hook) = options.pre_hook {
+        let result = hooks::run_hook(
+            "pre-hook",
+            pre_hook,
+            vec![
+                ("MWDH_WORLD_PATH", options.world_path.clone()),
+                ("MWDH_WORLD_NAME", options.world_name.clone()),
+            ],
+        )
+        .await;
+        match (result, options.pre_hook_failure) {
+            (Ok(()), _) => {}
+            (Err(err), HookFailurePolicy::Continue) => warn!("{:#}, continuing anyway", err),
+            (Err(err), HookFailurePolicy::Abort) => {
+                return Err(MwdhError::Hook(err.context("Aborting because the pre-hook failed").into()));
+            }
+        }
+    }
+    let archive_output_path = options.archive_output_path();
+    // the archive only gets its real name once it's complete, so nothing picks up a half-written one
+    let part_path = partial_path(&archive_output_path);
+    let resuming = options.resume && checkpoint::Checkpoint::exists(&part_path);
+    if archive_output_path.exists() && !options.overwrite && !options.update {
+        return Err(MwdhError::ArchiveExists(archive_output_path));
+    }
+    prepare_output_dir(&archive_output_path)?;
+    if resuming {
+        info!("Continuing {}", part_path.display());
+    }
     let paths_to_be_archived = paths_to_be_archived(&options);
-    match options.compression_format {
-        CompressionFormat::ZipDeflate => {
-            archive::zip::generate_zip_with_progress(
-                paths_to_be_archived,
-                archive_output_path.clone(),
-                options.clone(),
-            )
-            .await
-            .context("Failed to generate ZIP file")?;
+    systemd::notify_status(&format!("Compressing {} into {}", options.world_name, archive_output_path.display()));
+    let started = Instant::now();
+    let format = options.archive_format();
+    let extension = format.extension();
+    let result = format::generate_with_progress(format, paths_to_be_archived, part_path.clone(), options.clone())
+        .await
+        .with_context(|| format!("Failed to generate {} file", extension));
+    let (archived, compression) = match result {
+        Ok(result) => result,
+        Err(err) => {
+            // an archive that was about to be replaced is still intact either way
+            let output_written = part_path.exists();
+            if options.resume && output_written {
+                info!("Run the same command again to continue where it stopped");
+            } else if options.cancel.is_cancelled() && options.keep_partial && output_written {
+                warn!("Kept the partial archive {}", part_path.display());
+            } else {
+                std::fs::remove_file(&part_path).ok();
+            }
+            return Err(MwdhError::from_archiving(err));
         }
-        CompressionFormat::TarZstd => {
-            archive::zstd::generate_zstd_with_progress(
-                paths_to_be_archived,
-                archive_output_path.clone(),
-                options.clone(),
-            )
+    };
+    std::fs::rename(&part_path, &archive_output_path)?;
+    let summary = ArchiveSummary {
+        compressed_size: std::fs::metadata(&archive_output_path)?.len(),
+        uncompressed_size: archived.bytes,
+        file_count: archived.count,
+        duration: started.elapsed(),
+        format: options.compression_format,
+        sha256: sha256_file(&archive_output_path).map_err(|err| MwdhError::Compression(err.into()))?,
+        output_path: archive_output_path.clone(),
+        compression,
+    };
+    info!(
+        target: logging::SUMMARY,
+        "Created {} ({})",
+        archive_output_path.display(),
+        summary.compression
+    );
+    #[cfg(feature = "upload")]
+    if let Some(ref target) = options.upload {
+        systemd::notify_status(&format!("Uploading {}", archive_output_path.display()));
+        upload::upload(&archive_output_path, target)
             .await
-            .context("Failed to generate tar.zst file")?;
-        }
+            .context("Failed to upload archive")
+            .map_err(|err| MwdhError::Upload(err.into()))?;
+    }
+    if let Some(ref policy) = options.auto_prune {
+        let backup_dir = archive_output_path.parent().unwrap_or(Path::new("."));
+        prune::prune(backup_dir, policy, false)
+            .context("Failed to prune old archives")
+            .map_err(|err| MwdhError::Prune(err.into()))?;
+    }
+    if let Some(ref post_hook) = options.post_hook {
+        let absolute_path = std::fs::canonicalize(&archive_output_path)?;
+        hooks::run_hook(
+            "post-hook",
+            post_hook,
+            vec![
+                ("MWDH_ARCHIVE_PATH", absolute_path.to_string_lossy().to_string()),
+                ("MWDH_ARCHIVE_SIZE", summary.compressed_size.to_string()),
+                ("MWDH_ARCHIVE_FORMAT", options.compression_format.to_string()),
+                ("MWDH_ARCHIVE_SHA256", summary.sha256.clone()),
+            ],
+        )
+        .await
+        .map_err(|err| MwdhError::Hook(err.into()))?;
     }
+    Ok(summary)
+}
+
+/// Creates the directory the archive goes to and makes sure it can be written to before the world gets scanned.
+fn prepare_output_dir(archive_output_path: &Path) -> std::io::Result<()> {
+    let output_dir = match archive_output_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => return Ok(()),
+    };
+    let context = |action: &str, err: std::io::Error| {
+        std::io::Error::new(err.kind(), format!("Failed to {} the output directory {}: {}", action, output_dir.display(), err))
+    };
+    std::fs::create_dir_all(output_dir).map_err(|err| context("create", err))?;
+    let probe = output_dir.join(format!(".mwdh-write-test-{}", process::id()));
+    File::create(&probe).map_err(|err| context("write to", err))?;
+    std::fs::remove_file(&probe).ok();
     Ok(())
 }
 
-#[must_use]
+/// File ending of archives that are still being written
+pub const PARTIAL_SUFFIX: &str = ".part";
+
+/// Where the archive is written to until it's complete.
+pub fn partial_path(archive_output_path: &Path) -> PathBuf {
+    let mut file_name = archive_output_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(PARTIAL_SUFFIX);
+    archive_output_path.with_file_name(file_name)
+}
+
+/// Whether the file is an archive that is still being written, or was left behind by a run that failed.
+pub fn is_partial(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name.to_string_lossy().ends_with(PARTIAL_SUFFIX))
+}
+
+/// Header the hex encoded SHA-256 of a pushed archive is sent in. The receiving side checks the upload against it.
+pub const SHA256_HEADER: &str = "x-mwdh-sha256";
+
+/// Hex encoded SHA-256 of the file's contents.
+pub fn sha256_file(path: &Path) -> Result<String> {
+    use sha2::{Digest, Sha256};
+    let mut file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to open: {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[allow(clippy::type_complexity)]
 pub fn create_temp_dir() -> Result<(PathBuf, ScopeGuard<(), impl FnOnce(())>)> {
-    let temp_dir = std::env::temp_dir().join(format!("mwdh_{}", std::process::id()));
-    std::fs::create_dir_all(&temp_dir).context("Failed to create temp directory")?;
+    let temp_dir = create_unique_temp_dir("mwdh")?;
     let temp_dir_clone = temp_dir.clone();
     let cleanup_guard = scopeguard::guard((), move |_| {
         let _ = std::fs::remove_dir_all(&temp_dir_clone);
@@ -93,34 +410,50 @@ pub fn create_temp_dir() -> Result<(PathBuf, ScopeGuard<(), impl FnOnce(())>)> {
     Ok((temp_dir, cleanup_guard))
 }
 
-pub fn scan_files(tx: &Sender<ProgressMessage>, paths_to_be_archived: Vec<PathBuf>, args: &ArchiveOptions) -> Result<Vec<FileToCompress>> {
+/// Creates a directory in the system's temp directory that belongs to this run alone. The process id isn't enough,
+/// another mwdh with a recycled pid or in a container sharing /tmp could pick the same one. So the name gets a random
+/// part too, and creating it fails instead of reusing a directory that exists already. On Unix, only the user can
+/// access it.
+pub fn create_unique_temp_dir(prefix: &str) -> Result<PathBuf> {
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    for _ in 0..16 {
+        let temp_dir = std::env::temp_dir().join(format!("{}_{}_{:016x}", prefix, process::id(), rand::random::<u64>()));
+        match builder.create(&temp_dir) {
+            Ok(()) => return Ok(temp_dir),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err).context("Failed to create temp directory"),
+        }
+    }
+    anyhow::bail!("Failed to create temp directory, all names that were tried are in use")
+}
+
+/// Scans the world and runs the region preprocessing. The returned [`PreprocessDir`] has to be kept alive until the archive is written.
+pub fn scan_files(tx: &Sender<ProgressMessage>, paths_to_be_archived: Vec<PathBuf>, args: &ArchiveOptions) -> Result<(Vec<FileToCompress>, PreprocessDir)> {
+    scan_world(tx, paths_to_be_archived, args).context(ScanFailed)
+}
+
+fn scan_world(tx: &Sender<ProgressMessage>, paths_to_be_archived: Vec<PathBuf>, args: &ArchiveOptions) -> Result<(Vec<FileToCompress>, PreprocessDir)> {
     // Scan files
     tx.send(ProgressMessage::StartScanning).ok();
-    let mut all_files = Vec::new();
-
-    for path in &paths_to_be_archived {
-        let name = path
-            .file_name()
-            .ok_or_else(|| anyhow::anyhow!("Invalid path: {}", path.display()))?
-            .to_string_lossy()
-            .to_string();
-
-        let meta = std::fs::metadata(path)
-            .with_context(|| format!("Failed to stat: {}", path.display()))?;
-
-        if meta.is_file() {
-            all_files.push(FileToCompress {
-                src_path: path.clone(),
-                file_name: name,
-            });
-            tx.send(ProgressMessage::FileFound(path.display().to_string()))
-                .ok();
-        } else {
-            collect_files_recursive(path, &name, &mut all_files, &args, &tx)?;
-        }
+    let all_files = WorldScanner::new(args)
+        .paths(paths_to_be_archived)
+        .progress(tx.clone())
+        .iter()
+        .collect::<Result<Vec<_>>>()?;
+
+    let (all_files, mut preprocess_dir, region_issues) = preprocess::preprocess_regions(all_files, args, tx)?;
+    let mut all_files = layout::apply_layout(all_files, args);
+    if let Some(ref base) = args.diff_base {
+        all_files = diff::diff_against(all_files, base, preprocess_dir.get_or_create()?)?;
+    }
+    if args.manifest {
+        all_files = manifest::add_manifest(all_files, preprocess_dir.get_or_create()?, &region_issues, args, tx)?;
     }
 
     let total_files = all_files.len() as u64;
-    tx.send(ProgressMessage::StartCompression(total_files)).ok();
-    Ok(all_files)
+    let total_bytes = space::total_size(&all_files);
+    tx.send(ProgressMessage::StartCompression(total_files, total_bytes)).ok();
+    Ok((all_files, preprocess_dir))
 }
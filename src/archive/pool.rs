@@ -0,0 +1,33 @@
+use std::sync::{Arc, Mutex};
+
+/// Buffers the workers compress into, handed back by the writer once their contents are in the archive.
+/// A recycled buffer keeps its capacity, so the next batch or file doesn't have to grow a fresh one from scratch,
+/// which saves allocating and page faulting the same memory over and over. Clones share the pool.
+#[derive(Clone)]
+pub struct BufferPool {
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    /// Keeps at most `max_buffers` buffers around, more are freed when they're given back.
+    pub fn new(max_buffers: usize) -> Self {
+        BufferPool {
+            buffers: Arc::new(Mutex::new(Vec::with_capacity(max_buffers))),
+            max_buffers,
+        }
+    }
+
+    /// An empty buffer, recycled if there is one.
+    pub fn take(&self) -> Vec<u8> {
+        self.buffers.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    pub fn give_back(&self, mut buffer: Vec<u8>) {
+        buffer.clear();
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_buffers {
+            buffers.push(buffer);
+        }
+    }
+}
@@ -0,0 +1,386 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+        mpsc::Sender,
+    },
+};
+
+use anyhow::{Context, Result};
+use tracing::{info, warn};
+
+use crate::{
+    ArchiveOptions, FileToCompress, ProgressMessage, archive, format_bytes,
+    region::{self, Region, RegionChunk},
+};
+
+/// Minecraft runs at 20 ticks per second, `InhabitedTime` is counted in ticks.
+const TICKS_PER_SECOND: u64 = 20;
+
+/// Directory holding the rewritten region files and the manifest. Removed once the archive is written.
+pub struct PreprocessDir(Option<PathBuf>);
+
+impl PreprocessDir {
+    /// The directory, created now if the preprocessing didn't need one.
+    pub fn get_or_create(&mut self) -> Result<&Path> {
+        if self.0.is_none() {
+            self.0 = Some(archive::create_unique_temp_dir("mwdh_regions")?);
+        }
+        Ok(self.0.as_deref().expect("created above"))
+    }
+}
+
+impl Drop for PreprocessDir {
+    fn drop(&mut self) {
+        if let Some(ref dir) = self.0 {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+/// Whether any of the options needs region files to be parsed before compressing.
+pub fn is_needed(options: &ArchiveOptions) -> bool {
+    rewrites_regions(options) || options.validate_regions
+}
+
+fn rewrites_regions(options: &ArchiveOptions) -> bool {
+    options.prune_inhabited_under.is_some() || options.crop.is_some() || options.raw_region_chunks
+}
+
+/// A region file found during scanning, identified by its dimension directory (the parent of `region/`, `entities/` and `poi/`) and file name.
+struct RegionFile {
+    index: usize,
+    kind: RegionKind,
+    key: (PathBuf, String),
+    coords: (i32, i32),
+}
+
+#[derive(PartialEq)]
+enum RegionKind {
+    /// Terrain, the only kind that knows about `InhabitedTime`
+    Terrain,
+    /// `entities/` and `poi/`, which follow the decisions made for the terrain chunk at the same position
+    Companion,
+}
+
+fn classify(index: usize, file: &FileToCompress) -> Option<RegionFile> {
+    let file_name = file.src_path.file_name()?.to_str()?;
+    let coords = region::region_coords(file_name)?;
+    let parent = file.src_path.parent()?;
+    let kind = match parent.file_name()?.to_str()? {
+        "region" => RegionKind::Terrain,
+        "entities" | "poi" => RegionKind::Companion,
+        _ => return None,
+    };
+    Some(RegionFile {
+        index,
+        kind,
+        key: (parent.parent()?.to_path_buf(), file_name.to_string()),
+        coords,
+    })
+}
+
+#[derive(Default)]
+struct Stats {
+    chunks_before: usize,
+    chunks_dropped: usize,
+    bytes_before: u64,
+    bytes_after: u64,
+}
+
+enum Outcome {
+    Unchanged,
+    Rewritten(PathBuf, u64),
+    Removed,
+}
+
+/// Rewrites region files according to the options into a temp directory and points the affected entries at the rewritten copies.
+/// Region files left without any chunk are dropped from the archive. Problems found in region files are reported and returned.
+pub fn preprocess_regions(
+    files: Vec<FileToCompress>,
+    options: &ArchiveOptions,
+    tx: &Sender<ProgressMessage>,
+) -> Result<(Vec<FileToCompress>, PreprocessDir, Vec<String>)> {
+    if !is_needed(options) {
+        return Ok((files, PreprocessDir(None), Vec::new()));
+    }
+    let temp_dir = archive::create_unique_temp_dir("mwdh_regions")?;
+    let preprocess_dir = PreprocessDir(Some(temp_dir.clone()));
+
+    let (terrain, companions): (Vec<_>, Vec<_>) = files
+        .iter()
+        .enumerate()
+        .filter_map(|(index, file)| classify(index, file))
+        .partition(|region_file| region_file.kind == RegionKind::Terrain);
+
+    let stats = Mutex::new(Stats::default());
+    let issues = Mutex::new(Vec::new());
+    let dropped_chunks = Mutex::new(HashMap::new());
+    // by file index, to leave out the `.mcc` files of dropped chunks
+    let dropped_by_file = Mutex::new(HashMap::new());
+    let mut outcomes = HashMap::new();
+
+    // terrain has to be done first, entities and poi reuse its decisions
+    let terrain_outcomes = for_each_parallel(&terrain, options, |region_file| {
+        let file = &files[region_file.index];
+        if is_cropped_out(region_file, options) {
+            return remove_region(file, options, tx, &stats);
+        }
+        // unreadable files that get skipped are left out of the archive
+        let Some(mut region) = read_region(region_file, file, options, tx, &issues)? else {
+            return Ok(Outcome::Removed);
+        };
+        let mut dropped = vec![false; region::CHUNKS_PER_REGION];
+        for (index, slot) in region.chunks.iter_mut().enumerate() {
+            if slot
+                .as_ref()
+                .is_some_and(|chunk| !keep_terrain_chunk(chunk, region_file.coords, index, options))
+            {
+                *slot = None;
+                dropped[index] = true;
+            }
+        }
+        let outcome = write_region(&mut region, &dropped, file, &temp_dir, options, &stats)?;
+        dropped_by_file.lock().unwrap().insert(region_file.index, dropped.clone());
+        dropped_chunks.lock().unwrap().insert(region_file.key.clone(), dropped);
+        Ok(outcome)
+    })?;
+    outcomes.extend(terrain_outcomes);
+
+    let dropped_chunks = dropped_chunks.into_inner().unwrap();
+    let companion_outcomes = for_each_parallel(&companions, options, |region_file| {
+        let file = &files[region_file.index];
+        if is_cropped_out(region_file, options) {
+            return remove_region(file, options, tx, &stats);
+        }
+        let dropped = dropped_chunks.get(&region_file.key);
+        if dropped.is_none() && options.crop.is_none() && !options.raw_region_chunks && !options.validate_regions {
+            return Ok(Outcome::Unchanged);
+        }
+        let Some(mut region) = read_region(region_file, file, options, tx, &issues)? else {
+            return Ok(Outcome::Removed);
+        };
+        let mut dropped_here = vec![false; region::CHUNKS_PER_REGION];
+        for (index, slot) in region.chunks.iter_mut().enumerate() {
+            let drop = dropped.is_some_and(|dropped| dropped[index])
+                || options
+                    .crop
+                    .is_some_and(|crop| !crop.overlaps_chunk(region_file.coords.0, region_file.coords.1, index));
+            if drop && slot.take().is_some() {
+                dropped_here[index] = true;
+            }
+        }
+        let outcome = write_region(&mut region, &dropped_here, file, &temp_dir, options, &stats)?;
+        dropped_by_file.lock().unwrap().insert(region_file.index, dropped_here);
+        Ok(outcome)
+    })?;
+    outcomes.extend(companion_outcomes);
+
+    let stats = stats.into_inner().unwrap();
+    if options.prune_inhabited_under.is_some() || options.crop.is_some() {
+        info!("Left out {} of {} chunks", stats.chunks_dropped, stats.chunks_before);
+    }
+    if rewrites_regions(options) {
+        info!(
+            "Region files: {} -> {}",
+            format_bytes(stats.bytes_before),
+            format_bytes(stats.bytes_after)
+        );
+    }
+    let issues = issues.into_inner().unwrap();
+    if options.validate_regions {
+        match issues.len() {
+            0 => info!("Validated {} region files, no problems found", terrain.len() + companions.len()),
+            count => warn!("Found {} problems in region files, see the warnings above", count),
+        }
+    }
+
+    let dropped_external = dropped_external_chunks(&files, &outcomes, &dropped_by_file.into_inner().unwrap());
+    let files = files
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| !dropped_external.contains(index))
+        .filter_map(|(index, mut file)| match outcomes.remove(&index) {
+            None | Some(Outcome::Unchanged) => Some(file),
+            // the original modification time ends up in the archive
+            Some(Outcome::Rewritten(path, size)) => {
+                file.src_path = path;
+                file.size = size;
+                Some(file)
+            }
+            Some(Outcome::Removed) => None,
+        })
+        .collect();
+    Ok((files, preprocess_dir, issues))
+}
+
+/// Indices of the `c.<x>.<z>.mcc` files whose chunk was dropped, or whose whole region file was left out.
+fn dropped_external_chunks(
+    files: &[FileToCompress],
+    outcomes: &HashMap<usize, Outcome>,
+    dropped_by_file: &HashMap<usize, Vec<bool>>,
+) -> HashSet<usize> {
+    let regions: HashMap<&Path, usize> = outcomes
+        .keys()
+        .map(|&index| (files[index].src_path.as_path(), index))
+        .collect();
+    files
+        .iter()
+        .enumerate()
+        .filter_map(|(index, file)| {
+            let (region_name, chunk_index) = region::external_chunk_region(file.src_path.file_name()?.to_str()?)?;
+            let region_path = file.src_path.with_file_name(region_name);
+            let region_index = *regions.get(region_path.as_path())?;
+            let dropped = matches!(outcomes.get(&region_index), Some(Outcome::Removed))
+                || dropped_by_file.get(&region_index).is_some_and(|dropped| dropped[chunk_index]);
+            dropped.then_some(index)
+        })
+        .collect()
+}
+
+/// Reads a region file, reporting broken chunks. With `--validate-regions` every chunk gets decompressed and parsed as well.
+fn read_region(
+    region_file: &RegionFile,
+    file: &FileToCompress,
+    options: &ArchiveOptions,
+    tx: &Sender<ProgressMessage>,
+    issues: &Mutex<Vec<String>>,
+) -> Result<Option<Region>> {
+    tx.send(ProgressMessage::Preprocessing(file.file_name.clone())).ok();
+    let Some((region, mut found)) = archive::with_error_policy(&file.file_name, options, tx, || Region::read(&file.src_path))? else {
+        return Ok(None);
+    };
+    if options.validate_regions {
+        found.extend(region.validate_chunks());
+    }
+    for issue in found {
+        let message = match issue.chunk_index {
+            Some(index) => {
+                let chunk_x = region_file.coords.0 * 32 + (index % 32) as i32;
+                let chunk_z = region_file.coords.1 * 32 + (index / 32) as i32;
+                format!("{}: chunk {} {}: {}", file.file_name, chunk_x, chunk_z, issue.message)
+            }
+            None => format!("{}: {}", file.file_name, issue.message),
+        };
+        tx.send(ProgressMessage::RegionIssue(message.clone())).ok();
+        issues.lock().unwrap().push(message);
+    }
+    Ok(Some(region))
+}
+
+fn is_cropped_out(region_file: &RegionFile, options: &ArchiveOptions) -> bool {
+    options
+        .crop
+        .is_some_and(|crop| !crop.overlaps_region(region_file.coords.0, region_file.coords.1))
+}
+
+fn keep_terrain_chunk(chunk: &RegionChunk, (region_x, region_z): (i32, i32), index: usize, options: &ArchiveOptions) -> bool {
+    if let Some(crop) = options.crop
+        && !crop.overlaps_chunk(region_x, region_z, index)
+    {
+        return false;
+    }
+    if let Some(threshold) = options.prune_inhabited_under {
+        let threshold_ticks = threshold.as_secs() * TICKS_PER_SECOND
+            + threshold.subsec_millis() as u64 * TICKS_PER_SECOND / 1000;
+        // chunks that can't be read are kept, better a few bytes too many than a hole in the world
+        let Ok(Some(nbt)) = chunk.read_nbt() else {
+            return true;
+        };
+        // 1.18+ has it in the root, older versions inside Level
+        let inhabited_time = nbt
+            .get("InhabitedTime")
+            .or_else(|| nbt.get("Level/InhabitedTime"))
+            .and_then(|tag| tag.as_i64());
+        if let Some(inhabited_time) = inhabited_time
+            && (inhabited_time.max(0) as u64) < threshold_ticks
+        {
+            return false;
+        }
+    }
+    true
+}
+
+/// Leaves out a region file without reading its chunks.
+fn remove_region(
+    file: &FileToCompress,
+    options: &ArchiveOptions,
+    tx: &Sender<ProgressMessage>,
+    stats: &Mutex<Stats>,
+) -> Result<Outcome> {
+    let bytes_before = file.size;
+    // the file is left out either way, skipping it only costs the numbers
+    let Some(chunks) = archive::with_error_policy(&file.file_name, options, tx, || region::count_chunks(&file.src_path))? else {
+        return Ok(Outcome::Removed);
+    };
+    let mut stats = stats.lock().unwrap();
+    stats.chunks_before += chunks;
+    stats.chunks_dropped += chunks;
+    stats.bytes_before += bytes_before;
+    Ok(Outcome::Removed)
+}
+
+/// Applies the per-chunk transforms and writes the region into the temp dir if it changed.
+fn write_region(
+    region: &mut Region,
+    dropped: &[bool],
+    file: &FileToCompress,
+    temp_dir: &Path,
+    options: &ArchiveOptions,
+    stats: &Mutex<Stats>,
+) -> Result<Outcome> {
+    let bytes_before = file.size;
+    let chunks_left = region.chunks.iter().filter(|chunk| chunk.is_some()).count();
+    let chunks_dropped = dropped.iter().filter(|dropped| **dropped).count();
+    let mut changed = chunks_dropped > 0;
+    if options.raw_region_chunks {
+        changed |= region.decompress_chunks();
+    }
+    let (outcome, bytes_after) = if !changed {
+        (Outcome::Unchanged, bytes_before)
+    } else if region.is_empty() {
+        (Outcome::Removed, 0)
+    } else {
+        let bytes = region.to_bytes()?;
+        let out_path = temp_dir.join(&file.file_name);
+        if let Some(parent) = out_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&out_path, &bytes)
+            .with_context(|| format!("Failed to write: {}", out_path.display()))?;
+        (Outcome::Rewritten(out_path, bytes.len() as u64), bytes.len() as u64)
+    };
+
+    let mut stats = stats.lock().unwrap();
+    stats.chunks_before += chunks_left + chunks_dropped;
+    stats.chunks_dropped += chunks_dropped;
+    stats.bytes_before += bytes_before;
+    stats.bytes_after += bytes_after;
+    Ok(outcome)
+}
+
+/// Runs `work` for every region file on `threads` threads and returns the outcomes by file index.
+fn for_each_parallel<F>(region_files: &[RegionFile], options: &ArchiveOptions, work: F) -> Result<Vec<(usize, Outcome)>>
+where
+    F: Fn(&RegionFile) -> Result<Outcome> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(region_files.len()));
+    std::thread::scope(|scope| {
+        for _ in 0..options.threads.max(1) {
+            scope.spawn(|| {
+                while let Some(region_file) = region_files.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let result = options
+                        .cancel
+                        .check()
+                        .and_then(|_| work(region_file))
+                        .map(|outcome| (region_file.index, outcome));
+                    results.lock().unwrap().push(result);
+                }
+            });
+        }
+    });
+    results.into_inner().unwrap().into_iter().collect()
+}
@@ -0,0 +1,61 @@
+//! `--background`, archiving with a lower CPU and disk priority than the game server.
+
+use tracing::debug;
+
+/// Runs `f` on a thread with a lowered priority, which the threads it starts inherit on Linux.
+/// Other threads of the process, like the server's, keep their priority.
+pub fn run_in_background<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+    std::thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            lower_thread_priority();
+            f()
+        });
+        match handle.join() {
+            Ok(result) => result,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    })
+}
+
+/// Lowers the priority of the calling thread. There's no going back without privileges, so only call it on threads
+/// that end with the archiving. Failing to lower it isn't a reason to stop archiving.
+pub fn lower_thread_priority() {
+    #[cfg(target_os = "linux")]
+    {
+        /// The `nice` command's default
+        const NICENESS: libc::c_int = 10;
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        const IOPRIO_CLASS_BE: libc::c_int = 2;
+        const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+        // like `ionice -c 2 -n 7`, the lowest best-effort priority. The idle class could stall the backup forever.
+        const IOPRIO: libc::c_int = (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | 7;
+
+        // on Linux, both are set per thread when passing 0
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, NICENESS) } != 0 {
+            debug!("Failed to lower the CPU priority: {}", std::io::Error::last_os_error());
+        }
+        if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, IOPRIO) } != 0 {
+            debug!("Failed to lower the I/O priority: {}", std::io::Error::last_os_error());
+        }
+    }
+    #[cfg(all(unix, not(target_os = "linux")))]
+    {
+        // sets the nice value of the whole process on other Unixes
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 10) } != 0 {
+            debug!("Failed to lower the CPU priority: {}", std::io::Error::last_os_error());
+        }
+    }
+    #[cfg(windows)]
+    {
+        use windows_sys::Win32::System::Threading::{GetCurrentThread, SetThreadPriority, THREAD_PRIORITY_BELOW_NORMAL};
+
+        if unsafe { SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_BELOW_NORMAL) } == 0 {
+            debug!("Failed to lower the thread priority: {}", std::io::Error::last_os_error());
+        }
+    }
+}
+
+/// Half of the CPUs, so the game server keeps the other half.
+pub fn background_threads() -> usize {
+    (num_cpus::get() / 2).max(1)
+}
@@ -1,32 +1,411 @@
-use std::{path::Path, sync::mpsc::Receiver};
+use std::{
+    io::{self, BufRead, Read, Write},
+    sync::{
+        Arc, Mutex,
+        mpsc::{Receiver, Sender},
+    },
+};
 
+#[cfg(feature = "progress-ui")]
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+#[cfg(feature = "progress-ui")]
+use std::path::Path;
+use serde_json::json;
+use tracing::warn;
 
 use crate::ProgressMessage;
 
-pub fn handle_progress(rx: Receiver<ProgressMessage>) {
-    let multi = MultiProgress::new();
+/// How the archiving progress is shown.
+#[derive(Clone)]
+pub enum ProgressMode {
+    /// Progress bars on the terminal
+    Bars,
+    /// One JSON object per line on stdout for every progress event
+    Json,
+    /// No progress output at all, only warnings
+    Hidden,
+    /// Every progress message goes to this sink, e.g. a GUI or a web panel
+    Custom(Arc<dyn ProgressSink>),
+}
 
-    let scan_bar = multi.add(ProgressBar::new_spinner());
-    scan_bar.set_style(
-        ProgressStyle::default_spinner()
-            .template("{spinner} {msg}")
-            .unwrap(),
-    );
+impl ProgressMode {
+    /// The sink showing the progress of one archiving run this way.
+    pub fn sink(&self) -> Arc<dyn ProgressSink> {
+        match self {
+            #[cfg(feature = "progress-ui")]
+            ProgressMode::Bars => Arc::new(IndicatifSink::new()),
+            // without the progress bars, only the warnings are left to show
+            #[cfg(not(feature = "progress-ui"))]
+            ProgressMode::Bars => Arc::new(LogSink::new()),
+            ProgressMode::Json => Arc::new(JsonSink::new()),
+            ProgressMode::Hidden => Arc::new(LogSink::new()),
+            ProgressMode::Custom(sink) => Arc::clone(sink),
+        }
+    }
+}
+
+impl std::str::FromStr for ProgressMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bars" => Ok(ProgressMode::Bars),
+            "json" => Ok(ProgressMode::Json),
+            "none" => Ok(ProgressMode::Hidden),
+            _ => Err(anyhow::anyhow!("Unknown progress mode: {} (expected bars, json or none)", s)),
+        }
+    }
+}
 
-    let mut worker_bars: Vec<ProgressBar> = Vec::new();
-    let mut compression_bar: Option<ProgressBar> = None;
-    let mut write_bar: Option<ProgressBar> = None;
-    let mut compressed_count = 0u64;
-    let mut written_count = 0u64;
+/// How many bytes a [`ProgressReader`] reads before reporting them.
+const REPORT_INTERVAL: u64 = 1024 * 1024;
 
+/// Passes reads through to the inner reader and reports the bytes read as [`ProgressMessage::BytesProcessed`].
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    tx: &'a Sender<ProgressMessage>,
+    unreported: u64,
+}
+
+impl<'a, R> ProgressReader<'a, R> {
+    pub fn new(inner: R, tx: &'a Sender<ProgressMessage>) -> Self {
+        ProgressReader { inner, tx, unreported: 0 }
+    }
+}
+
+impl<R> ProgressReader<'_, R> {
+    fn count(&mut self, bytes: usize) {
+        self.unreported += bytes as u64;
+        if self.unreported >= REPORT_INTERVAL {
+            self.tx.send(ProgressMessage::BytesProcessed(self.unreported)).ok();
+            self.unreported = 0;
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count(read);
+        Ok(read)
+    }
+}
+
+/// Counts the bytes once they're consumed.
+impl<R: BufRead> BufRead for ProgressReader<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.inner.consume(amount);
+        self.count(amount);
+    }
+}
+
+impl<R> Drop for ProgressReader<'_, R> {
+    fn drop(&mut self) {
+        if self.unreported > 0 {
+            self.tx.send(ProgressMessage::BytesProcessed(self.unreported)).ok();
+        }
+    }
+}
+
+/// Receives the progress of the archiving. mwdh drains its progress channel on its own thread and hands every
+/// message to the sink in order, so frontends only have to pass the messages on to their own UI.
+/// A sink may be used for several runs, each one starts with [`ProgressMessage::StartScanning`]
+/// and ends with [`ProgressMessage::Complete`] if it succeeds.
+pub trait ProgressSink: Send + Sync {
+    fn report(&self, message: ProgressMessage);
+}
+
+/// Ignores all progress.
+pub struct NoopSink;
+
+impl ProgressSink for NoopSink {
+    fn report(&self, _message: ProgressMessage) {}
+}
+
+/// Hands the messages of the archiving to the sink until it's complete or the archiving stopped.
+pub fn handle_progress(rx: Receiver<ProgressMessage>, sink: Arc<dyn ProgressSink>) {
     while let Ok(msg) = rx.recv() {
-        match msg {
+        let complete = matches!(msg, ProgressMessage::Complete(_));
+        sink.report(msg);
+        if complete {
+            break;
+        }
+    }
+}
+
+/// The final report of the files left out with `--on-error skip` or `retry`.
+fn report_skipped(skipped: &[String]) {
+    if !skipped.is_empty() {
+        warn!("Skipped {} unreadable files: {}", skipped.len(), skipped.join(", "));
+    }
+}
+
+/// Shows no progress, only the warnings about region files and skipped files.
+pub struct LogSink {
+    skipped: Mutex<Vec<String>>,
+}
+
+impl LogSink {
+    pub fn new() -> Self {
+        LogSink { skipped: Mutex::new(Vec::new()) }
+    }
+}
+
+impl Default for LogSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressSink for LogSink {
+    fn report(&self, message: ProgressMessage) {
+        let mut skipped = self.skipped.lock().unwrap();
+        match message {
+            ProgressMessage::StartScanning => skipped.clear(),
+            ProgressMessage::RegionIssue(message) => warn!("{}", message),
+            ProgressMessage::FileSkipped(file, reason) => {
+                warn!("Skipped {}: {}", file, reason);
+                skipped.push(file);
+            }
+            ProgressMessage::Complete(_) => report_skipped(&skipped),
+            _ => {}
+        }
+    }
+}
+
+/// Hands every message to all of the sinks, e.g. to show the progress on the terminal and in the browser at once.
+pub struct TeeSink(pub Vec<Arc<dyn ProgressSink>>);
+
+impl ProgressSink for TeeSink {
+    fn report(&self, message: ProgressMessage) {
+        for sink in &self.0 {
+            sink.report(message.clone());
+        }
+    }
+}
+
+/// Prints every progress message as a line of JSON with the phase it belongs to.
+/// Byte progress is only printed when the percentage changes by at least 0.1, files are always printed.
+pub struct JsonSink {
+    events: JsonEvents,
+}
+
+impl JsonSink {
+    pub fn new() -> Self {
+        JsonSink { events: JsonEvents::new() }
+    }
+}
+
+impl Default for JsonSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressSink for JsonSink {
+    fn report(&self, message: ProgressMessage) {
+        if let Some(event) = self.events.event(message) {
+            print_json_line(&event);
+        }
+    }
+}
+
+/// Turns progress messages into the JSON events of `--progress json`, keeping track of the totals they report.
+pub struct JsonEvents {
+    state: Mutex<JsonState>,
+}
+
+#[derive(Default)]
+struct JsonState {
+    total_files: u64,
+    total_bytes: u64,
+    processed_bytes: u64,
+    last_permille: Option<u64>,
+    compressed_count: u64,
+    total_writes: u64,
+    written_count: u64,
+    skipped: Vec<String>,
+}
+
+impl JsonEvents {
+    pub fn new() -> Self {
+        JsonEvents { state: Mutex::new(JsonState::default()) }
+    }
+
+    /// The event of `message`, `None` for byte progress that didn't change the percentage.
+    pub fn event(&self, message: ProgressMessage) -> Option<serde_json::Value> {
+        let mut state = self.state.lock().unwrap();
+        let permille = |done: u64, total: u64| (done * 1000).checked_div(total).unwrap_or(1000);
+        let percent = |done: u64, total: u64| permille(done, total) as f64 / 10.0;
+
+        let event = match message {
             ProgressMessage::StartScanning => {
-                scan_bar.set_message("Scanning directories...");
+                *state = JsonState::default();
+                json!({"phase": "scanning", "event": "start"})
+            }
+            ProgressMessage::FileFound(file) => json!({"phase": "scanning", "event": "file_found", "file": file}),
+            ProgressMessage::Preprocessing(file) => json!({"phase": "scanning", "event": "preprocessing", "file": file}),
+            ProgressMessage::Hashing(file) => json!({"phase": "scanning", "event": "hashing", "file": file}),
+            ProgressMessage::RegionIssue(message) => json!({"phase": "scanning", "event": "warning", "message": message}),
+            ProgressMessage::FileSkipped(file, reason) => {
+                let phase = if state.total_files > 0 { "compressing" } else { "scanning" };
+                let event = json!({"phase": phase, "event": "skipped", "file": file, "reason": reason});
+                state.skipped.push(file);
+                event
+            }
+            ProgressMessage::StartCompression(files, bytes) => {
+                state.total_files = files;
+                state.total_bytes = bytes;
+                json!({"phase": "compressing", "event": "start", "files": files, "bytes": bytes})
+            }
+            ProgressMessage::Compressing(worker, file) => {
+                json!({"phase": "compressing", "event": "file_started", "worker": worker, "file": file})
+            }
+            ProgressMessage::BytesProcessed(bytes) => {
+                state.processed_bytes += bytes;
+                let current = permille(state.processed_bytes, state.total_bytes);
+                if state.last_permille == Some(current) {
+                    return None;
+                }
+                state.last_permille = Some(current);
+                json!({
+                    "phase": "compressing",
+                    "event": "bytes",
+                    "bytes": state.processed_bytes,
+                    "total_bytes": state.total_bytes,
+                    "percent": percent(state.processed_bytes, state.total_bytes),
+                })
+            }
+            ProgressMessage::FileCompressed(worker, file) => {
+                state.compressed_count += 1;
+                json!({
+                    "phase": "compressing",
+                    "event": "file_done",
+                    "worker": worker,
+                    "file": file,
+                    "files": state.compressed_count,
+                    "total_files": state.total_files,
+                })
+            }
+            ProgressMessage::StartWriting(total) => {
+                state.total_writes = total;
+                json!({"phase": "writing", "event": "start", "total": total})
+            }
+            ProgressMessage::WritingFile(file) => {
+                state.written_count += 1;
+                json!({
+                    "phase": "writing",
+                    "event": "file",
+                    "file": file,
+                    "written": state.written_count,
+                    "total": state.total_writes,
+                    "percent": percent(state.written_count, state.total_writes),
+                })
+            }
+            ProgressMessage::Complete(stats) => {
+                json!({
+                    "phase": "complete",
+                    "event": "complete",
+                    "archive_size": stats.archive_size,
+                    "uncompressed_size": stats.uncompressed_size,
+                    "ratio": stats.ratio(),
+                    "elapsed_secs": stats.elapsed.as_secs_f64(),
+                    "threads": stats.threads,
+                    "bytes_per_second_per_worker": stats.throughput_per_worker() as u64,
+                    "skipped": state.skipped,
+                })
+            }
+        };
+        Some(event)
+    }
+}
+
+impl Default for JsonEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Ignores write errors, a script that stopped reading the progress shouldn't abort the archiving.
+fn print_json_line(event: &serde_json::Value) {
+    let _ = writeln!(io::stdout().lock(), "{}", event);
+}
+
+/// Progress bars on the terminal: a spinner while scanning, the compressed bytes, one line per worker and the
+/// entries written to the archive.
+#[cfg(feature = "progress-ui")]
+pub struct IndicatifSink {
+    state: Mutex<BarsState>,
+}
+
+#[cfg(feature = "progress-ui")]
+struct BarsState {
+    multi: MultiProgress,
+    scan_bar: ProgressBar,
+    worker_bars: Vec<ProgressBar>,
+    compression_bar: Option<ProgressBar>,
+    write_bar: Option<ProgressBar>,
+    compressed_count: u64,
+    total_files: u64,
+    written_count: u64,
+    skipped: Vec<String>,
+}
+
+#[cfg(feature = "progress-ui")]
+impl BarsState {
+    fn new() -> Self {
+        let multi = MultiProgress::new();
+        let scan_bar = multi.add(ProgressBar::new_spinner());
+        scan_bar.set_style(
+            ProgressStyle::default_spinner()
+                .template("{spinner} {msg}")
+                .unwrap(),
+        );
+        BarsState {
+            multi,
+            scan_bar,
+            worker_bars: Vec::new(),
+            compression_bar: None,
+            write_bar: None,
+            compressed_count: 0,
+            total_files: 0,
+            written_count: 0,
+            skipped: Vec::new(),
+        }
+    }
+}
+
+#[cfg(feature = "progress-ui")]
+impl IndicatifSink {
+    pub fn new() -> Self {
+        IndicatifSink { state: Mutex::new(BarsState::new()) }
+    }
+}
+
+#[cfg(feature = "progress-ui")]
+impl Default for IndicatifSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "progress-ui")]
+impl ProgressSink for IndicatifSink {
+    fn report(&self, message: ProgressMessage) {
+        let mut state = self.state.lock().unwrap();
+        match message {
+            ProgressMessage::StartScanning => {
+                // the bars of a previous run are finished already
+                if state.compression_bar.is_some() {
+                    *state = BarsState::new();
+                }
+                state.scan_bar.set_message("Scanning directories...");
             }
             ProgressMessage::FileFound(name) => {
-                scan_bar.set_message(format!(
+                state.scan_bar.set_message(format!(
                     "Found: {}",
                     Path::new(&name)
                         .file_name()
@@ -34,30 +413,61 @@ pub fn handle_progress(rx: Receiver<ProgressMessage>) {
                         .to_string_lossy()
                 ));
             }
-            ProgressMessage::StartCompression(total) => {
-                scan_bar.finish_with_message(format!("Found {} files", total));
+            ProgressMessage::Preprocessing(name) => {
+                state.scan_bar.set_message(format!(
+                    "Trimming: {}",
+                    Path::new(&name)
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                ));
+            }
+            ProgressMessage::Hashing(name) => {
+                state.scan_bar.set_message(format!(
+                    "Hashing: {}",
+                    Path::new(&name)
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                ));
+            }
+            ProgressMessage::RegionIssue(message) => {
+                state.multi.suspend(|| warn!("{}", message));
+            }
+            ProgressMessage::FileSkipped(file, reason) => {
+                state.multi.suspend(|| warn!("Skipped {}: {}", file, reason));
+                state.skipped.push(file);
+            }
+            ProgressMessage::StartCompression(files, bytes) => {
+                state.scan_bar.finish_with_message(format!("Found {} files ({})", files, crate::format_bytes(bytes)));
+                state.total_files = files;
 
-                // Create compression progress bar
-                let pg = multi.add(ProgressBar::new(total));
+                // Create compression progress bar, counting bytes because file sizes vary a lot
+                let pg = state.multi.add(ProgressBar::new(bytes));
                 pg.set_style(
                     ProgressStyle::default_bar()
-                        .template("{spinner} Compressing: [{elapsed_precise}] {wide_bar} {percent}% {pos}/{len} (ETA: {eta})")
+                        .template("{spinner} Compressing: [{elapsed_precise}] {wide_bar} {percent}% {bytes}/{total_bytes} {binary_bytes_per_sec} (ETA: {eta}) {msg}")
                         .unwrap()
                 );
-                compression_bar = Some(pg);
+                state.compression_bar = Some(pg);
+            }
+            ProgressMessage::BytesProcessed(bytes) => {
+                if let Some(ref pb) = state.compression_bar {
+                    pb.inc(bytes);
+                }
             }
             ProgressMessage::Compressing(worker_id, filename) => {
                 // Ensure we have enough worker bars with bounds checking
                 // This is where the bar is initialized for a worker_id
-                while worker_bars.len() <= worker_id {
-                    let bar_id = worker_bars.len();
-                    let pb = multi.add(ProgressBar::new_spinner());
+                while state.worker_bars.len() <= worker_id {
+                    let bar_id = state.worker_bars.len();
+                    let pb = state.multi.add(ProgressBar::new_spinner());
                     pb.set_style(
                         ProgressStyle::default_spinner()
                             .template(&format!("{{spinner}} Worker {}: {{msg}}", bar_id))
                             .unwrap(),
                     );
-                    worker_bars.push(pb);
+                    state.worker_bars.push(pb);
                 }
 
                 let short_name = Path::new(&filename)
@@ -65,44 +475,38 @@ pub fn handle_progress(rx: Receiver<ProgressMessage>) {
                     .unwrap_or_default()
                     .to_string_lossy();
 
-                if let Some(bar) = worker_bars.get(worker_id) {
+                if let Some(bar) = state.worker_bars.get(worker_id) {
                     bar.set_message(format!("{}", short_name));
                 }
             }
             ProgressMessage::FileCompressed(worker_id, _filename) => {
-                compressed_count += 1;
+                state.compressed_count += 1;
 
-                if let Some(ref pb) = compression_bar {
-                    pb.set_position(compressed_count);
+                if let Some(ref pb) = state.compression_bar {
+                    pb.set_message(format!("{}/{} files", state.compressed_count, state.total_files));
                 }
 
-                if let Some(bar) = worker_bars.get(worker_id) {
+                if let Some(bar) = state.worker_bars.get(worker_id) {
                     bar.set_message("Idle".to_string());
                 }
             }
             ProgressMessage::StartWriting(total) => {
-                // Finish compression phase
-                if let Some(ref pb) = compression_bar {
-                    pb.finish_with_message("All files compressed!");
-                }
-                for bar in &worker_bars {
-                    bar.finish_and_clear();
-                }
+                // Compression keeps going while the archive is written, its bar is finished on completion
 
                 // Create write progress bar
-                let wb = multi.add(ProgressBar::new(total));
+                let wb = state.multi.add(ProgressBar::new(total));
                 wb.set_style(
                     ProgressStyle::default_bar()
                         .template("{spinner} Writing archive: [{elapsed_precise}] {wide_bar} {percent}% {pos}/{len} - {msg}")
                         .unwrap()
                 );
-                write_bar = Some(wb);
+                state.write_bar = Some(wb);
             }
             ProgressMessage::WritingFile(filename) => {
-                written_count += 1;
+                state.written_count += 1;
 
-                if let Some(ref pb) = write_bar {
-                    pb.set_position(written_count);
+                if let Some(ref pb) = state.write_bar {
+                    pb.set_position(state.written_count);
                     let short_name = Path::new(&filename)
                         .file_name()
                         .unwrap_or_default()
@@ -110,15 +514,71 @@ pub fn handle_progress(rx: Receiver<ProgressMessage>) {
                     pb.set_message(short_name.to_string());
                 }
             }
-            ProgressMessage::Complete(file_size) => {
-                if let Some(ref pb) = write_bar {
-                    pb.finish_with_message(format!(
-                        "Archive created successfully! ({})",
-                        crate::format_bytes(file_size)
-                    ));
+            ProgressMessage::Complete(stats) => {
+                if let Some(ref pb) = state.compression_bar {
+                    pb.finish_with_message("All files compressed!");
                 }
-                break;
+                for bar in &state.worker_bars {
+                    bar.finish_and_clear();
+                }
+                if let Some(ref pb) = state.write_bar {
+                    pb.finish_with_message(format!("Archive created successfully! ({})", stats));
+                }
+                report_skipped(&state.skipped);
             }
         }
     }
 }
+
+#[cfg(all(any(feature = "upload", feature = "download"), feature = "progress-ui"))]
+pub(crate) type TransferProgress = ProgressBar;
+
+/// A progress bar for a transfer of `len` bytes, labelled with `action` like "Uploading". Hidden with --quiet, which
+/// turns off info messages.
+#[cfg(all(any(feature = "upload", feature = "download"), feature = "progress-ui"))]
+pub(crate) fn transfer_bar(len: u64, action: &str) -> TransferProgress {
+    let progress_bar = if tracing::enabled!(tracing::Level::INFO) {
+        ProgressBar::new(len)
+    } else {
+        ProgressBar::hidden()
+    };
+    progress_bar.set_style(
+        ProgressStyle::default_bar()
+            .template(&format!(
+                "{{spinner}} {}: [{{elapsed_precise}}] {{wide_bar}} {{bytes}}/{{total_bytes}} ({{bytes_per_sec}}, ETA: {{eta}})",
+                action
+            ))
+            .unwrap(),
+    );
+    progress_bar
+}
+
+#[cfg(all(any(feature = "upload", feature = "download"), not(feature = "progress-ui")))]
+pub(crate) fn transfer_bar(_len: u64, _action: &str) -> TransferProgress {
+    TransferProgress
+}
+
+/// Stands in for the transfer's progress bar without the `progress-ui` feature, only passing on its messages.
+#[cfg(all(any(feature = "upload", feature = "download"), not(feature = "progress-ui")))]
+#[derive(Clone)]
+pub(crate) struct TransferProgress;
+
+#[cfg(all(any(feature = "upload", feature = "download"), not(feature = "progress-ui")))]
+impl TransferProgress {
+    pub(crate) fn inc(&self, _delta: u64) {}
+
+    pub(crate) fn set_position(&self, _position: u64) {}
+
+    #[cfg(feature = "upload")]
+    pub(crate) fn println(&self, message: impl AsRef<str>) {
+        tracing::warn!("{}", message.as_ref());
+    }
+
+    pub(crate) fn finish_with_message(&self, message: &'static str) {
+        tracing::info!("{}", message);
+    }
+
+    pub(crate) fn abandon_with_message(&self, message: &'static str) {
+        tracing::warn!("{}", message);
+    }
+}
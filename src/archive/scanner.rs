@@ -0,0 +1,267 @@
+use std::{
+    fs::{Metadata, ReadDir},
+    path::PathBuf,
+    sync::mpsc::{self, Sender},
+};
+
+use anyhow::{Context, Result};
+use glob::{MatchOptions, Pattern};
+
+use crate::{ArchiveOptions, FileToCompress, ProgressMessage, archive, is_player_data, paths, paths_to_be_archived};
+
+/// Globs match whole archive paths, `*` doesn't cross directories but `**` does.
+const GLOB_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+type FileFilter = Box<dyn Fn(&FileToCompress, &Metadata) -> bool + Send + Sync>;
+
+/// Walks the world's directories and yields the files to archive one at a time, applying the same rules as the
+/// archiving (dimensions, player data, plugin jars, `--on-error`) plus filters of your own.
+///
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// # let options = mwdh::ArchiveOptions::builder().build()?;
+/// use mwdh::archive::scanner::WorldScanner;
+///
+/// let scanner = WorldScanner::new(&options).exclude_glob("world/region/*")?.max_file_size(64 * 1024 * 1024);
+/// for file in scanner.iter() {
+///     println!("{}", file?.file_name);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct WorldScanner {
+    options: ArchiveOptions,
+    paths: Option<Vec<PathBuf>>,
+    tx: Sender<ProgressMessage>,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    max_file_size: Option<u64>,
+    filters: Vec<FileFilter>,
+}
+
+impl WorldScanner {
+    pub fn new(options: &ArchiveOptions) -> Self {
+        WorldScanner {
+            options: options.clone(),
+            paths: None,
+            // nobody listens unless progress is asked for
+            tx: mpsc::channel().0,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            max_file_size: None,
+            filters: Vec::new(),
+        }
+    }
+
+    /// Which dimensions to scan, overriding the options.
+    pub fn dimensions(mut self, overworld: bool, nether: bool, end: bool) -> Self {
+        self.options.include_overworld = overworld;
+        self.options.include_nether = nether;
+        self.options.include_end = end;
+        self
+    }
+
+    /// Scans these files and directories instead of the ones the options select.
+    pub fn paths(mut self, paths: Vec<PathBuf>) -> Self {
+        self.paths = Some(paths);
+        self
+    }
+
+    /// Sends [`ProgressMessage::FileFound`] and [`ProgressMessage::FileSkipped`] for the scanned files.
+    pub fn progress(mut self, tx: Sender<ProgressMessage>) -> Self {
+        self.tx = tx;
+        self
+    }
+
+    /// Only yields files whose archive path (like `world/region/r.0.0.mca`) matches one of the included globs.
+    pub fn include_glob(mut self, pattern: &str) -> Result<Self> {
+        self.include.push(Pattern::new(pattern).with_context(|| format!("Invalid glob: {}", pattern))?);
+        Ok(self)
+    }
+
+    /// Leaves out files whose archive path matches the glob.
+    pub fn exclude_glob(mut self, pattern: &str) -> Result<Self> {
+        self.exclude.push(Pattern::new(pattern).with_context(|| format!("Invalid glob: {}", pattern))?);
+        Ok(self)
+    }
+
+    /// Leaves out files larger than this many bytes.
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = Some(bytes);
+        self
+    }
+
+    /// Only yields files the filter returns `true` for.
+    pub fn filter(mut self, filter: impl Fn(&FileToCompress, &Metadata) -> bool + Send + Sync + 'static) -> Self {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    /// The files to archive. Stops after the first error, which with [`OnError::Skip`](archive::OnError::Skip)
+    /// only happens for errors that can't be skipped like cancellation.
+    pub fn iter(&self) -> ScanIter<'_> {
+        let roots = self.paths.clone().unwrap_or_else(|| paths_to_be_archived(&self.options));
+        ScanIter {
+            scanner: self,
+            roots: roots.into_iter(),
+            base_dir: PathBuf::new(),
+            stack: Vec::new(),
+            current: None,
+            failed: false,
+        }
+    }
+
+    fn accepts(&self, file: &FileToCompress, meta: &Metadata) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|glob| glob.matches_with(&file.file_name, GLOB_OPTIONS)))
+            && !self.exclude.iter().any(|glob| glob.matches_with(&file.file_name, GLOB_OPTIONS))
+            && self.max_file_size.is_none_or(|max| meta.len() <= max)
+            && self.filters.iter().all(|filter| filter(file, meta))
+    }
+
+    /// Yields the file if it passes the filters, reporting it as found.
+    fn found(&self, file: FileToCompress, meta: &Metadata) -> Option<FileToCompress> {
+        if !self.accepts(&file, meta) {
+            return None;
+        }
+        self.tx.send(ProgressMessage::FileFound(file.src_path.display().to_string())).ok();
+        Some(file)
+    }
+}
+
+/// Iterator over the files of a [`WorldScanner`], reading one directory at a time.
+pub struct ScanIter<'a> {
+    scanner: &'a WorldScanner,
+    roots: std::vec::IntoIter<PathBuf>,
+    /// The root directory currently being walked
+    base_dir: PathBuf,
+    /// Directories still to read with their path in the archive
+    stack: Vec<(PathBuf, String)>,
+    /// The directory being read, its path in the archive and whether it is the root directory
+    current: Option<(ReadDir, String, bool)>,
+    failed: bool,
+}
+
+impl Iterator for ScanIter<'_> {
+    type Item = Result<FileToCompress>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.advance() {
+            Ok(file) => file.map(Ok),
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl ScanIter<'_> {
+    fn advance(&mut self) -> Result<Option<FileToCompress>> {
+        let scanner = self.scanner;
+        let args = &scanner.options;
+        let tx = &scanner.tx;
+        loop {
+            if let Some((read_dir, curr_zip_path, at_base)) = self.current.as_mut() {
+                let Some(entry) = read_dir.next() else {
+                    self.current = None;
+                    continue;
+                };
+                let entry = entry?;
+                let path = entry.path();
+                let name = paths::entry_name(&entry.file_name().to_string_lossy());
+                let child_zip_path = format!("{}/{}", curr_zip_path, name);
+
+                let meta = archive::with_error_policy(&child_zip_path, args, tx, || {
+                    entry.metadata().with_context(|| format!("Failed to stat: {}", path.display()))
+                })?;
+                let Some(meta) = meta else {
+                    continue;
+                };
+
+                // the game's lock file is locked while the world is open and useless in a backup
+                if name == "session.lock" && meta.is_file() {
+                    continue;
+                }
+
+                if args.scrub_player_data && *at_base && is_player_data(&name, meta.is_dir()) {
+                    continue;
+                }
+
+                // the plugin jars themselves can be downloaded again, their data folders can't
+                if args.exclude_plugin_jars
+                    && *at_base
+                    && self.base_dir.file_name().is_some_and(|dir| dir == "plugins")
+                    && meta.is_file()
+                    && name.ends_with(".jar")
+                {
+                    continue;
+                }
+
+                if meta.is_dir() {
+                    if !args.is_bukkit {
+                        if !args.include_end && entry.file_name() == "DIM1" {
+                            continue;
+                        }
+                        if !args.include_nether && entry.file_name() == "DIM-1" {
+                            continue;
+                        }
+                        if !args.include_overworld
+                            && path
+                                .parent()
+                                .and_then(|parent| parent.file_name())
+                                .and_then(|file_name| file_name.to_str())
+                                .is_some_and(|file_name| file_name == args.world_name) // basically checks if parent dir is the world dir that contains the overworld. just looks crazy because of all the conversions and Options.
+                            && (entry.file_name() == "region" || entry.file_name() == "entities" || entry.file_name() == "poi")
+                        {
+                            continue; // skip region, entities and poi directories in the main world directory.
+                        }
+                    }
+                    self.stack.push((path, child_zip_path));
+                } else if meta.is_file() {
+                    let file = FileToCompress::new(path, child_zip_path, &meta);
+                    if let Some(file) = scanner.found(file, &meta) {
+                        return Ok(Some(file));
+                    }
+                }
+                continue;
+            }
+
+            if let Some((curr_fs_path, curr_zip_path)) = self.stack.pop() {
+                args.cancel.check()?;
+                let read_dir = archive::with_error_policy(&curr_zip_path, args, tx, || {
+                    std::fs::read_dir(&curr_fs_path).with_context(|| format!("Failed to read: {}", curr_fs_path.display()))
+                })?;
+                if let Some(read_dir) = read_dir {
+                    self.current = Some((read_dir, curr_zip_path, curr_fs_path == self.base_dir));
+                }
+                continue;
+            }
+
+            let Some(path) = self.roots.next() else {
+                return Ok(None);
+            };
+            let name = path
+                .file_name()
+                .ok_or_else(|| anyhow::anyhow!("Invalid path: {}", path.display()))?
+                .to_string_lossy()
+                .to_string();
+            let meta = std::fs::metadata(&path).with_context(|| format!("Failed to stat: {}", path.display()))?;
+            if meta.is_file() {
+                let file = FileToCompress::new(path, name, &meta);
+                if let Some(file) = scanner.found(file, &meta) {
+                    return Ok(Some(file));
+                }
+            } else {
+                self.base_dir = path.clone();
+                self.stack.push((path, name));
+            }
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::path::Path;
+
+use anyhow::{Context, Result, anyhow};
+use tracing::debug;
+
+use crate::{ArchiveOptions, CompressionFormat, FileToCompress, archive::zip::ZipMethod, format_bytes};
+
+/// Tar headers and padding or ZIP entry headers, per file.
+const PER_FILE_OVERHEAD: u64 = 1024;
+
+/// Guesses how large the archive of `total_bytes` of world files gets. Region files are already zlib compressed
+/// by the game and barely shrink, so this errs on the large side.
+pub fn estimate_archive_size(file_count: u64, total_bytes: u64, options: &ArchiveOptions) -> u64 {
+    let stored = matches!(options.compression_format, CompressionFormat::ZipDeflate)
+        && (options.zip_method == ZipMethod::Stored || options.compression_level == 0);
+    let high_level = match options.compression_format {
+        CompressionFormat::TarZstd => options.compression_level >= 10,
+        CompressionFormat::ZipDeflate => options.compression_level >= 7,
+    };
+    let ratio = if stored {
+        1.0
+    } else if options.raw_region_chunks {
+        // uncompressed chunks shrink a lot, that's the point of the option
+        0.5
+    } else if high_level {
+        0.9
+    } else {
+        0.95
+    };
+    (total_bytes as f64 * ratio) as u64 + file_count * PER_FILE_OVERHEAD
+}
+
+/// Total size of the files to be archived.
+pub fn total_size(files: &[FileToCompress]) -> u64 {
+    files.iter().map(|file| file.size).sum()
+}
+
+/// Fails before anything is written if the output's or the temp directory's filesystem is too full for the
+/// estimated archive (`output_bytes`) and the batches spilled to the temp directory while compressing.
+pub fn check_free_space(
+    archive_output_path: &Path,
+    output_bytes: u64,
+    temp: Option<(&Path, u64)>,
+    options: &ArchiveOptions,
+) -> Result<()> {
+    if options.skip_space_check {
+        return Ok(());
+    }
+    let output_dir = match archive_output_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    // An existing archive gets replaced (or cut back and continued with --resume), its space counts as free
+    let replaced = std::fs::metadata(archive_output_path).map_or(0, |meta| meta.len());
+    let output_available = available_space(output_dir)? + replaced;
+    let (temp_dir, temp_bytes) = temp.unwrap_or((Path::new("."), 0));
+    debug!(
+        "Estimated space needed: {} for the archive, {} in the temp directory",
+        format_bytes(output_bytes),
+        format_bytes(temp_bytes)
+    );
+
+    if temp_bytes > 0 && same_filesystem(output_dir, temp_dir) {
+        let needed = output_bytes + temp_bytes;
+        if needed > output_available {
+            return Err(anyhow!(
+                "Not enough disk space: the archive and the temporary files need about {} on the filesystem of {}, but only {} are free. \
+                Free up some space, write the archive to another disk with -f, raise --memory-limit-mb to keep more in memory \
+                or pass --no-space-check if you know it fits",
+                format_bytes(needed),
+                output_dir.display(),
+                format_bytes(output_available)
+            ));
+        }
+        return Ok(());
+    }
+
+    if output_bytes > output_available {
+        return Err(anyhow!(
+            "Not enough disk space: the archive needs about {} in {}, but only {} are free. \
+            Free up some space, write the archive to another disk with -f or pass --no-space-check if you know it fits",
+            format_bytes(output_bytes),
+            output_dir.display(),
+            format_bytes(output_available)
+        ));
+    }
+    if temp_bytes > 0 {
+        let temp_available = available_space(temp_dir)?;
+        if temp_bytes > temp_available {
+            return Err(anyhow!(
+                "Not enough disk space: compressing needs about {} of temporary files in {}, but only {} are free. \
+                Point TMPDIR to a bigger disk, raise --memory-limit-mb to keep more in memory \
+                or pass --no-space-check if you know it fits",
+                format_bytes(temp_bytes),
+                temp_dir.display(),
+                format_bytes(temp_available)
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn available_space(path: &Path) -> Result<u64> {
+    fs4::available_space(path).with_context(|| format!("Failed to get the free space of {}", path.display()))
+}
+
+#[cfg(unix)]
+fn same_filesystem(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (std::fs::metadata(a), std::fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_filesystem(a: &Path, b: &Path) -> bool {
+    // different drive letters are different filesystems, the same drive letter is close enough
+    let root = |path: &Path| std::path::absolute(path).ok().and_then(|path| path.components().next().map(|c| c.as_os_str().to_owned()));
+    root(a).is_some() && root(a) == root(b)
+}
@@ -0,0 +1,82 @@
+use std::{
+    io::{self, Read},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Limit on how many bytes per second all workers together read from the world, so a live game server keeps
+/// enough disk bandwidth. Clones share the limit.
+///
+/// Same idea as the server's rate limiting: reads that take more than what's available go into debt and sleep
+/// until it's paid back. Allows bursts of up to a tenth of a second worth of data.
+#[derive(Clone)]
+pub struct IoLimit {
+    bytes_per_second: u64,
+    state: Arc<Mutex<BucketState>>,
+}
+
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl IoLimit {
+    /// Has to be greater than 0.
+    pub fn new(bytes_per_second: u64) -> Self {
+        IoLimit {
+            bytes_per_second,
+            state: Arc::new(Mutex::new(BucketState {
+                tokens: 0.0,
+                last_refill: Instant::now(),
+            })),
+        }
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second
+    }
+
+    /// Takes `bytes` out of the bucket, blocking until the bucket would have refilled enough.
+    fn consume(&self, bytes: usize) {
+        let rate = self.bytes_per_second as f64;
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * rate).min(rate / 10.0);
+            state.last_refill = now;
+            state.tokens -= bytes as f64;
+            if state.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-state.tokens / rate)
+        };
+        std::thread::sleep(wait);
+    }
+}
+
+/// Passes reads through to the inner reader, no faster than the limit allows.
+pub struct ThrottledReader<R> {
+    inner: R,
+    limit: Option<IoLimit>,
+}
+
+impl<R> ThrottledReader<R> {
+    /// Reads at full speed without a limit.
+    pub fn new(inner: R, limit: Option<&IoLimit>) -> Self {
+        ThrottledReader {
+            inner,
+            limit: limit.cloned(),
+        }
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if let Some(ref limit) = self.limit {
+            limit.consume(read);
+        }
+        Ok(read)
+    }
+}
@@ -1,51 +1,91 @@
 use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Cursor, Seek, Write},
     path::{Path, PathBuf},
     sync::mpsc::{self},
 };
 
 use crate::{
-    ArchiveOptions, FileToCompress, ProgressMessage,
-    archive::{create_temp_dir, progress::handle_progress, scan_files},
+    ArchiveOptions, FileToCompress, ProgressMessage, manifest,
+    archive::{
+        self, create_temp_dir,
+        format::ArchiveFormat,
+        hints,
+        pool::BufferPool,
+        priority,
+        progress::ProgressReader,
+        space,
+        zstd::{CompressedDataLocation, MemoryManagerMessage, spawn_memory_manager_thread},
+    },
 };
 use anyhow::{Context, Result};
 use crossbeam::channel;
+use tracing::{debug, info, info_span};
 use zip::{ZipWriter, write::SimpleFileOptions};
 
-pub async fn generate_zip_with_progress(
-    paths_to_be_archived: Vec<PathBuf>,
-    archive_output_path: PathBuf,
-    args: ArchiveOptions,
-) -> Result<()> {
-    let (tx, rx) = mpsc::channel();
+/// ZIP archives with Deflate compressed entries.
+pub struct ZipFormat;
 
-    // Spawn blocking task for ZIP creation
-    let zip_handle = tokio::task::spawn_blocking(move || {
-        generate_zip_parallel(paths_to_be_archived, archive_output_path, tx, args)
-    });
+impl ArchiveFormat for ZipFormat {
+    fn name(&self) -> &'static str {
+        "zip"
+    }
 
-    // Handle progress updates on main thread
-    let progress_handle = tokio::task::spawn_blocking(move || handle_progress(rx));
+    fn extension(&self) -> &'static str {
+        "zip"
+    }
 
-    // Wait for both tasks
-    zip_handle.await??;
-    progress_handle.await?;
+    fn mime_type(&self) -> &'static str {
+        "application/zip"
+    }
 
-    Ok(())
+    fn compress(
+        &self,
+        files: Vec<FileToCompress>,
+        archive_output_path: PathBuf,
+        tx: mpsc::Sender<ProgressMessage>,
+        options: ArchiveOptions,
+    ) -> Result<()> {
+        generate_zip_parallel(files, archive_output_path, tx, options)
+    }
 }
 
+/// Workers compress every file into a ZIP holding just that entry and hand it over a bounded channel. The entries are
+/// raw copied into the archive as they come in. They're kept in memory as long as they fit into `--memory-limit-mb`,
+/// the others go through the temp directory like the batches of the zstd archives.
 pub fn generate_zip_parallel(
-    paths_to_be_archived: Vec<PathBuf>,
+    mut all_files: Vec<FileToCompress>,
     archive_output_path: PathBuf,
     tx: mpsc::Sender<ProgressMessage>,
     args: ArchiveOptions,
 ) -> Result<()> {
-    let all_files = scan_files(&tx, paths_to_be_archived, &args)?;
+    // the entries are written as they're done, the manifest is written before all of them
+    let manifest = manifest::take_manifest(&mut all_files);
+    // with --update, the unchanged files are copied over from the existing archive, which is replaced once the
+    // new one is done. That's the one at the final path, mwdh writes to a partial file until then.
+    let previous_path = args.archive_output_path();
+    let mut previous = if args.update { open_previous(&previous_path, &mut all_files)? } else { None };
+    let unchanged_count = previous.as_ref().map_or(0, |previous| previous.unchanged.len());
 
-    // Second pass: compress files in parallel and write to individual temp ZIPs
     let (temp_dir, _cleanup_guard) = create_temp_dir()?;
-
-    let (work_tx, work_rx) = channel::unbounded::<(usize, FileToCompress)>();
-    let (result_tx, result_rx) = channel::unbounded::<Result<(usize, PathBuf)>>();
+    let global_memory_limit_bytes = args.memory_limit_mb * 1024 * 1024;
+    let (mem_tx, mem_rx) = channel::unbounded::<MemoryManagerMessage>();
+    let mem_manager_handle = spawn_memory_manager_thread(mem_rx, global_memory_limit_bytes);
+
+    // At most the biggest files are waiting for the writer or being compressed at once, they only end up in the
+    // temp directory once they don't fit into the memory limit anymore
+    let mut sizes: Vec<u64> = all_files.iter().map(|file| file.size).collect();
+    sizes.sort_unstable_by(|a, b| b.cmp(a));
+    let in_flight = space::estimate_archive_size(0, sizes.iter().take(args.threads * 3).sum(), &args);
+    let temp_bytes = if in_flight > global_memory_limit_bytes { in_flight } else { 0 };
+    let archive_size = space::estimate_archive_size(all_files.len() as u64, space::total_size(&all_files), &args);
+    space::check_free_space(&archive_output_path, archive_size, Some((&temp_dir, temp_bytes)), &args)?;
+
+    let (work_tx, work_rx) = channel::bounded::<FileToCompress>(args.threads);
+    // Bounds the number of compressed entries waiting for the writer
+    let (result_tx, result_rx) = channel::bounded::<Result<Option<(String, CompressedDataLocation)>>>(args.threads * 2);
+    // The entries waiting in the channel plus the ones being compressed
+    let buffers = BufferPool::new(args.threads * 3);
 
     // Spawn worker threads
     let workers: Vec<_> = (0..args.threads)
@@ -53,24 +93,37 @@ pub fn generate_zip_parallel(
             let work_rx = work_rx.clone();
             let result_tx = result_tx.clone();
             let tx = tx.clone();
+            let args = args.clone();
+            let buffers = buffers.clone();
+            let mem_tx = mem_tx.clone();
             let temp_dir = temp_dir.clone();
+            let span = info_span!("worker", id = worker_id);
 
             std::thread::Builder::new()
                 .name(format!("worker-{}", worker_id))
                 .spawn(move || {
-                    while let Ok((idx, file_info)) = work_rx.recv() {
+                    let _span = span.entered();
+                    // threads don't inherit the priority on Windows
+                    if args.background {
+                        priority::lower_thread_priority();
+                    }
+                    while let Ok(file_info) = work_rx.recv() {
                         tx.send(ProgressMessage::Compressing(
                             worker_id,
                             file_info.file_name.clone(),
                         ))
                         .ok();
 
-                        let result = compress_single_file_to_zip(
-                            &file_info,
-                            &temp_dir,
-                            idx,
-                            args.compression_level,
-                        );
+                        let method = compression_method_for(&file_info.file_name, args.zip_method, &args.store_extensions);
+                        let result = args.cancel.check().and_then(|_| {
+                            // None if the file couldn't be read and got skipped
+                            let Some(input_file) = archive::open_source_file(&file_info, &args, &tx)? else {
+                                return Ok(None);
+                            };
+                            let input_file = BufReader::with_capacity(args.io_buffer_size, input_file);
+                            let entry = EntryToCompress { file_info: &file_info, method, worker_id, temp_dir: &temp_dir };
+                            compress_entry(entry, input_file, &args, &buffers, &mem_tx, global_memory_limit_bytes, &tx).map(Some)
+                        });
 
                         tx.send(ProgressMessage::FileCompressed(
                             worker_id,
@@ -78,7 +131,8 @@ pub fn generate_zip_parallel(
                         ))
                         .ok();
 
-                        if result_tx.send(result.map(|path| (idx, path))).is_err() {
+                        // Fails once the writer gave up because of an error
+                        if result_tx.send(result.map(|zip| zip.map(|zip| (file_info.file_name, zip)))).is_err() {
                             break;
                         }
                     }
@@ -87,83 +141,267 @@ pub fn generate_zip_parallel(
         })
         .collect();
 
-    // Send work to workers
-    for (idx, file_info) in all_files.iter().enumerate() {
-        work_tx.send((idx, file_info.clone())).ok();
-    }
-    drop(work_tx);
+    let file_count = all_files.len() as u64 + unchanged_count as u64 + manifest.is_some() as u64;
+
+    // Feed the workers from their own thread, the work channel blocks until a worker is free
+    let feeder = std::thread::spawn(move || {
+        for file_info in all_files {
+            if work_tx.send(file_info).is_err() {
+                break;
+            }
+        }
+    });
     drop(result_tx);
 
-    // Collect results
-    let mut temp_zips = vec![None; all_files.len()];
+    // Compression and writing happen at the same time, like in the sequential zstd mode
+    tx.send(ProgressMessage::StartWriting(file_count)).ok();
+    let _write_span = info_span!("write").entered();
+
+    let file = File::create(&archive_output_path)?;
+    hints::preallocate(&file, archive_size);
+    let mut final_zip = ZipWriter::new(BufWriter::with_capacity(args.io_buffer_size, file));
+
+    if let Some(manifest) = manifest {
+        tx.send(ProgressMessage::WritingFile(manifest.file_name.clone())).ok();
+        let input = BufReader::new(File::open(&manifest.src_path)?);
+        let entry_zip = compress_single_file_to_zip(&manifest, input, args.zip_method, args.compression_level, Cursor::new(buffers.take()), &tx)?;
+        let mut entry_archive = zip::ZipArchive::new(entry_zip)?;
+        final_zip.raw_copy_file(entry_archive.by_index(0)?)?;
+    }
+
+    if let Some(ref mut previous) = previous {
+        for file_name in &previous.unchanged {
+            tx.send(ProgressMessage::WritingFile(file_name.clone())).ok();
+            let index = previous.archive.index_for_name(file_name).expect("found when opening the archive");
+            let entry = previous.archive.by_index_raw(index)?;
+            tx.send(ProgressMessage::BytesProcessed(entry.size())).ok();
+            final_zip.raw_copy_file(entry)?;
+        }
+    }
+
     for result in result_rx {
-        let (idx, temp_zip_path) = result?;
-        temp_zips[idx] = Some(temp_zip_path);
+        let Some((file_name, entry_zip)) = result? else {
+            continue;
+        };
+
+        tx.send(ProgressMessage::WritingFile(file_name)).ok();
+
+        // There is exactly one file in each entry's ZIP
+        match entry_zip {
+            CompressedDataLocation::Memory(entry_zip) => {
+                let len = entry_zip.len() as u64;
+                let mut entry_archive = zip::ZipArchive::new(Cursor::new(entry_zip))?;
+                final_zip.raw_copy_file(entry_archive.by_index(0)?)?;
+                mem_tx.send(MemoryManagerMessage::ReleaseAllocation(len)).ok();
+                buffers.give_back(entry_archive.into_inner().into_inner());
+            }
+            CompressedDataLocation::Disk(temp_file_path) => {
+                let temp_file = BufReader::with_capacity(args.io_buffer_size, File::open(&temp_file_path)?);
+                let mut entry_archive = zip::ZipArchive::new(temp_file)?;
+                final_zip.raw_copy_file(entry_archive.by_index(0)?)?;
+                // Keeps the temp directory from growing to the size of the archive
+                std::fs::remove_file(&temp_file_path).ok();
+            }
+        }
     }
 
     // Wait for workers
+    feeder.join().ok();
     for worker in workers {
         worker.join().ok();
     }
+    drop(mem_tx);
+    mem_manager_handle.join().ok();
 
-    // Third pass: merge all individual ZIPs into final ZIP
-    tx.send(ProgressMessage::StartWriting(all_files.len() as u64))
-        .ok();
+    let file = final_zip
+        .finish()
+        .and_then(|file| file.into_inner().map_err(|err| err.into_error().into()))
+        .context("Failed to finish ZIP")?;
+    hints::release_preallocation(&file)?;
+    hints::drop_cache(&file);
 
-    let file = std::fs::File::create(&archive_output_path)?;
-    let mut final_zip = ZipWriter::new(file);
+    Ok(())
+}
 
-    for (file_info, temp_zip_opt) in all_files.iter().zip(temp_zips.iter()) {
-        let temp_zip_path = temp_zip_opt
-            .as_ref()
-            .ok_or_else(|| anyhow::anyhow!("Missing temp ZIP"))?;
+/// A file a worker compresses into a ZIP of its own.
+struct EntryToCompress<'a> {
+    file_info: &'a FileToCompress,
+    method: ZipMethod,
+    worker_id: usize,
+    temp_dir: &'a Path,
+}
 
-        tx.send(ProgressMessage::WritingFile(file_info.file_name.clone()))
-            .ok();
+/// Compresses the file into a ZIP in memory, or in the temp directory when it doesn't fit into the memory limit.
+/// Files bigger than the whole limit go to the temp directory right away.
+fn compress_entry(
+    entry: EntryToCompress,
+    input_file: impl std::io::BufRead,
+    args: &ArchiveOptions,
+    buffers: &BufferPool,
+    mem_tx: &channel::Sender<MemoryManagerMessage>,
+    global_memory_limit_bytes: u64,
+    tx: &mpsc::Sender<ProgressMessage>,
+) -> Result<CompressedDataLocation> {
+    // entries of different workers can have the same name in different directories
+    let temp_file_path = entry.temp_dir.join(format!("entry_{}_{}.zip", entry.worker_id, rand::random::<u64>()));
+    if entry.file_info.size > global_memory_limit_bytes {
+        let temp_file = File::options().write(true).create_new(true).open(&temp_file_path)?;
+        let temp_file = BufWriter::with_capacity(args.io_buffer_size, temp_file);
+        let temp_file = compress_single_file_to_zip(entry.file_info, input_file, entry.method, args.compression_level, temp_file, tx)?;
+        temp_file.into_inner().map_err(|err| err.into_error())?;
+        return Ok(CompressedDataLocation::Disk(temp_file_path));
+    }
 
-        // Open temp ZIP and copy the file
-        let temp_zip_file = std::fs::File::open(temp_zip_path)?;
-        let mut temp_archive = zip::ZipArchive::new(temp_zip_file)?;
+    let entry_zip = compress_single_file_to_zip(
+        entry.file_info,
+        input_file,
+        entry.method,
+        args.compression_level,
+        Cursor::new(buffers.take()),
+        tx,
+    )?
+    .into_inner();
+    let (response_tx, response_rx) = channel::bounded(1);
+    mem_tx
+        .send(MemoryManagerMessage::RequestAllocation(entry_zip.len() as u64, response_tx))
+        .ok();
+    if response_rx.recv().unwrap_or(false) {
+        return Ok(CompressedDataLocation::Memory(entry_zip));
+    }
+    debug!("{} doesn't fit into the memory limit, moving it to the temp directory", entry.file_info.file_name);
+    std::fs::write(&temp_file_path, &entry_zip)?;
+    buffers.give_back(entry_zip);
+    Ok(CompressedDataLocation::Disk(temp_file_path))
+}
 
-        // There should be exactly one file in each temp ZIP
-        let file_in_zip = temp_archive.by_index(0)?;
+/// The archive that gets updated, with the entries that are copied over.
+struct PreviousArchive {
+    archive: zip::ZipArchive<BufReader<File>>,
+    /// Names of the entries whose files didn't change, in the order the files were found
+    unchanged: Vec<String>,
+}
 
-        // Copy using raw_copy_file
-        final_zip.raw_copy_file(file_in_zip)?;
+/// Opens the archive to update and takes the files out of `files` that have an entry with the same size and
+/// modification time. `None` if there's no archive to update yet.
+fn open_previous(archive_path: &Path, files: &mut Vec<FileToCompress>) -> Result<Option<PreviousArchive>> {
+    if !archive_path.exists() {
+        info!("{} doesn't exist yet, creating it", archive_path.display());
+        return Ok(None);
     }
+    let file = File::open(archive_path).with_context(|| format!("Failed to open: {}", archive_path.display()))?;
+    let mut archive = zip::ZipArchive::new(BufReader::new(file))
+        .with_context(|| format!("Failed to read the archive to update: {}", archive_path.display()))?;
+
+    let mut unchanged = Vec::new();
+    let mut changed = Vec::with_capacity(files.len());
+    for file_info in files.drain(..) {
+        let entry = archive
+            .index_for_name(&file_info.file_name)
+            .and_then(|index| archive.by_index_raw(index).ok());
+        // ZIP timestamps only have a precision of two seconds, like the ones of the entries written before
+        let mtime = file_info.modified.and_then(zip_mtime);
+        match entry {
+            Some(entry) if entry.size() == file_info.size && mtime.is_some() && entry.last_modified() == mtime => {
+                unchanged.push(file_info.file_name);
+            }
+            _ => changed.push(file_info),
+        }
+    }
+    *files = changed;
+    info!(
+        "Updating {}: {} files are unchanged, {} are new or changed",
+        archive_path.display(),
+        unchanged.len(),
+        files.len()
+    );
+    Ok(Some(PreviousArchive { archive, unchanged }))
+}
 
-    final_zip.finish().context("Failed to finish ZIP")?;
-
-    let final_size = std::fs::metadata(&archive_output_path)
-        .context("Failed to get ZIP file size")?
-        .len();
+/// Compression method for the entries of a ZIP archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZipMethod {
+    Stored,
+    Deflated,
+}
 
-    tx.send(ProgressMessage::Complete(final_size)).ok();
+impl std::str::FromStr for ZipMethod {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stored" => Ok(ZipMethod::Stored),
+            "deflated" => Ok(ZipMethod::Deflated),
+            _ => Err(anyhow::anyhow!("Unknown ZIP method: {} (expected stored or deflated)", s)),
+        }
+    }
+}
 
-    Ok(())
+/// Stores files with one of the given extensions as they are, everything else gets the default method.
+fn compression_method_for(file_name: &str, default: ZipMethod, store_extensions: &[String]) -> ZipMethod {
+    let extension = Path::new(file_name)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase());
+    match extension {
+        Some(extension) if store_extensions.contains(&extension) => ZipMethod::Stored,
+        _ => default,
+    }
 }
 
-pub fn compress_single_file_to_zip(
+/// Compresses a file into a ZIP written to `output` with that file as its only entry, keeping its modification
+/// time and Unix permissions from the scan.
+pub fn compress_single_file_to_zip<W: Write + Seek>(
     file_info: &FileToCompress,
-    temp_dir: &Path,
-    idx: usize,
+    input_file: impl std::io::BufRead,
+    method: ZipMethod,
     compression_level: i8,
-) -> Result<PathBuf> {
-    let temp_zip_path = temp_dir.join(format!("file_{}.zip", idx));
-    let temp_file = std::fs::File::create(&temp_zip_path)?;
-    let mut zip = ZipWriter::new(temp_file);
-
-    let options = SimpleFileOptions::default()
-        .compression_method(zip::CompressionMethod::Deflated)
-        .compression_level(Some(compression_level as i64))
-        .large_file(true);
+    output: W,
+    tx: &mpsc::Sender<ProgressMessage>,
+) -> Result<W> {
+    let mut zip = ZipWriter::new(output);
+
+    let options = match method {
+        ZipMethod::Stored => SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored),
+        ZipMethod::Deflated => SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .compression_level(Some(compression_level as i64)),
+    }
+    .large_file(true);
+    let options = match file_info.modified.and_then(zip_mtime) {
+        Some(mtime) => options.last_modified_time(mtime),
+        None => options,
+    };
+    #[cfg(unix)]
+    let options = options.unix_permissions(file_info.mode);
 
     zip.start_file(&file_info.file_name, options)?;
 
-    let mut input_file = std::fs::File::open(&file_info.src_path)?;
-    std::io::copy(&mut input_file, &mut zip)?;
+    let mut input_file = ProgressReader::new(input_file, tx);
+    archive::copy_buf(&mut input_file, &mut zip)?;
 
-    zip.finish()?;
+    Ok(zip.finish()?)
+}
+
+/// The modification time of a file as a ZIP timestamp, which is in local time with a precision of two seconds.
+/// `None` for times ZIP can't represent (before 1980 or after 2107).
+pub fn zip_mtime(modified: std::time::SystemTime) -> Option<zip::DateTime> {
+    use chrono::{Datelike, Timelike};
+    let modified: chrono::DateTime<chrono::Local> = modified.into();
+    zip::DateTime::from_date_and_time(
+        modified.year().try_into().ok()?,
+        modified.month() as u8,
+        modified.day() as u8,
+        modified.hour() as u8,
+        modified.minute() as u8,
+        modified.second() as u8,
+    )
+    .ok()
+}
 
-    Ok(temp_zip_path)
+/// ZIP timestamps are in the local time of whoever created the archive, we can only assume that's ours.
+pub fn zip_time_to_system_time(time: zip::DateTime) -> Option<std::time::SystemTime> {
+    let date = chrono::NaiveDate::from_ymd_opt(time.year().into(), time.month().into(), time.day().into())?;
+    let local = date
+        .and_hms_opt(time.hour().into(), time.minute().into(), time.second().into())?
+        .and_local_timezone(chrono::Local)
+        .earliest()?;
+    Some(local.into())
 }
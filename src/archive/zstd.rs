@@ -1,50 +1,67 @@
 use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet},
     fs::File,
-    io::Write,
+    io::{BufReader, BufWriter, Write},
     path::{Path, PathBuf},
-    sync::mpsc::{self, Sender},
+    sync::{Arc, mpsc::Sender},
     thread::JoinHandle,
+    time::Instant,
 };
 
 use crate::{
-    ArchiveOptions, FileToCompress, ProgressMessage,
-    archive::{create_temp_dir, progress::handle_progress, scan_files},
+    ArchiveOptions, FileToCompress, ProgressMessage, manifest,
+    archive::{self, budget::LevelBudget, checkpoint::Checkpoint, create_temp_dir, encrypt::ArchiveWriter, format::ArchiveFormat, pool::BufferPool, priority, progress::ProgressReader, space},
 };
 use anyhow::Result;
+use tracing::{debug, info, info_span};
+use zstd::stream::{raw, zio};
+use zstd::zstd_safe::{CCtx, CParameter, ResetDirective};
 use crossbeam::channel::Receiver as CrossbeamReceiver;
 use crossbeam::channel::Sender as CrossbeamSender;
 use crossbeam::channel::{self};
 
-enum MemoryManagerMessage {
+pub(super) enum MemoryManagerMessage {
     RequestAllocation(u64, channel::Sender<bool>),
+    /// Sent by the writer once a batch or entry held in memory is in the archive
+    ReleaseAllocation(u64),
 }
 
-pub async fn generate_zstd_with_progress(
-    paths_to_be_archived: Vec<PathBuf>,
-    archive_output_path: PathBuf,
-    args: ArchiveOptions,
-) -> Result<()> {
-    let (tx, rx) = mpsc::channel();
+/// Tar archives compressed with zstd, optionally encrypted with age.
+pub struct TarZstdFormat;
 
-    let zstd_handle = tokio::task::spawn_blocking(move || {
-        generate_zstd(paths_to_be_archived, archive_output_path, tx, args)
-    });
+impl ArchiveFormat for TarZstdFormat {
+    fn name(&self) -> &'static str {
+        "zstd"
+    }
 
-    // Handle progress updates on main thread
-    let progress_handle = tokio::task::spawn_blocking(move || handle_progress(rx));
+    fn extension(&self) -> &'static str {
+        "tar.zst"
+    }
 
-    zstd_handle.await??;
-    progress_handle.await?;
+    fn mime_type(&self) -> &'static str {
+        // browsers offer to open application/zstd with some program or save it without the .tar
+        "application/octet-stream"
+    }
 
-    Ok(())
+    fn compress(
+        &self,
+        files: Vec<FileToCompress>,
+        archive_output_path: PathBuf,
+        tx: Sender<ProgressMessage>,
+        options: ArchiveOptions,
+    ) -> Result<()> {
+        generate_zstd(files, archive_output_path, tx, options)
+    }
 }
 
 struct CompressedFileData {
+    batch_idx: usize,
     file_name: String,
     data: CompressedDataLocation,
 }
 
-enum CompressedDataLocation {
+pub(super) enum CompressedDataLocation {
     Memory(Vec<u8>),
     Disk(PathBuf), // path and size
 }
@@ -55,47 +72,64 @@ struct BatchToCompress {
 }
 
 pub fn generate_zstd(
-    paths_to_be_archived: Vec<PathBuf>,
+    all_files: Vec<FileToCompress>,
     archive_output_path: PathBuf,
     tx: Sender<ProgressMessage>,
     options: ArchiveOptions,
 ) -> Result<()> {
-    let all_files = scan_files(&tx, paths_to_be_archived, &options)?;
 
-    if options.threads == 1 {
+    if options.threads == 1 || options.single_stream {
         // --- Sequential Mode (Best Ratio) ---
-        println!("Using sequential mode");
+        if options.threads > 1 {
+            info!("Using sequential mode with {} zstd workers", options.threads);
+        } else {
+            info!("Using sequential mode");
+        }
         generate_zstd_sequential(all_files, archive_output_path, tx, options)
     } else {
         // --- Parallel Batch Mode (Fast + Good Ratio) ---
-        println!("Using parallel mode");
+        info!("Using parallel mode");
         generate_zstd_parallel(all_files, archive_output_path, tx, options)
     }
 }
 
-/// Sequential Mode: Single Thread, Single Dictionary, Best Compression. Recommended for smaller worlds. Entire world has to fit in RAM!
+/// Sequential Mode: Single Stream, Single Dictionary, Best Compression. Recommended for smaller worlds. Entire world has to fit in RAM!
+/// With more than one thread, libzstd compresses the stream with its own worker threads.
 fn generate_zstd_sequential(
     all_files: Vec<FileToCompress>,
     archive_output_path: PathBuf,
     tx: Sender<ProgressMessage>,
     args: ArchiveOptions,
 ) -> Result<()> {
+    let archive_size = space::estimate_archive_size(all_files.len() as u64, space::total_size(&all_files), &args);
+    space::check_free_space(&archive_output_path, archive_size, None, &args)?;
+
     tx.send(ProgressMessage::StartWriting(all_files.len() as u64))
         .ok();
 
-    let file = File::create(&archive_output_path)?;
-    let mut encoder = zstd::Encoder::new(file, args.compression_level as i32)?;
+    let file = ArchiveWriter::create(&archive_output_path, args.encryption.as_ref(), args.io_buffer_size, archive_size)?;
+    let mut encoder = new_encoder(file, args.compression_level, args.zstd_long)?;
+    if args.threads > 1 {
+        encoder.multithread(args.threads as u32)?;
+    }
 
     // We use standard tar builder here because we are strictly sequential
     let mut builder = tar::Builder::new(&mut encoder);
 
     for file_info in all_files.iter() {
+        args.cancel.check()?;
         tx.send(ProgressMessage::Compressing(0, file_info.file_name.clone()))
             .ok();
 
         let path_in_tar = Path::new(&file_info.file_name);
 
-        builder.append_path_with_name(&file_info.src_path, path_in_tar)?;
+        let Some(input_file) = archive::open_source_file(file_info, &args, &tx)? else {
+            tx.send(ProgressMessage::FileCompressed(0, file_info.file_name.clone())).ok();
+            continue;
+        };
+        let mut header = tar_header(file_info);
+        let input_file = ProgressReader::new(BufReader::with_capacity(args.io_buffer_size, archive::sized_reader(input_file, file_info.size)), &tx);
+        builder.append_data(&mut header, path_in_tar, input_file)?;
 
         // Sequential mode updates both compression and writing stats simultaneously
         tx.send(ProgressMessage::FileCompressed(
@@ -110,51 +144,75 @@ fn generate_zstd_sequential(
     builder.finish()?;
     drop(builder);
 
-    encoder.finish()?; // Finalizes Zstd stream
-
-    let final_size = std::fs::metadata(&archive_output_path)?.len();
-    tx.send(ProgressMessage::Complete(final_size)).ok();
+    encoder.finish()?.finish()?; // Finalizes Zstd stream, then the output file
 
     Ok(())
 }
 
-/// Spawns a worker thread receiving "RequestAllocation" messages.
+/// Creates an encoder with the given level, enabling long distance matching with the given window log if set.
+fn new_encoder<W: Write>(writer: W, compression_level: i8, zstd_long: Option<u32>) -> Result<zstd::Encoder<'static, W>> {
+    let mut encoder = zstd::Encoder::new(writer, compression_level as i32)?;
+    if let Some(window_log) = zstd_long {
+        encoder.long_distance_matching(true)?;
+        encoder.window_log(window_log)?;
+    }
+    Ok(encoder)
+}
+
+/// A compression context with the same settings as [`new_encoder`], for a worker to reuse for all of its batches.
+fn new_encoder_context(compression_level: i8, zstd_long: Option<u32>) -> Result<CCtx<'static>> {
+    let mut context = CCtx::try_create().ok_or_else(|| anyhow::anyhow!("Failed to create a zstd compression context"))?;
+    let mut parameters = vec![CParameter::CompressionLevel(compression_level as i32)];
+    if let Some(window_log) = zstd_long {
+        parameters.push(CParameter::EnableLongDistanceMatching(true));
+        parameters.push(CParameter::WindowLog(window_log));
+    }
+    for parameter in parameters {
+        context
+            .set_parameter(parameter)
+            .map_err(|code| anyhow::anyhow!("Failed to configure zstd: {}", zstd::zstd_safe::get_error_name(code)))?;
+    }
+    Ok(context)
+}
+
+/// Spawns a worker thread receiving "RequestAllocation" and "ReleaseAllocation" messages.
 /// It checks the "allocation" against the limit and returns a boolean response.
-/// Used for deciding whether to write a compressed batch to memory or to store it on disk.
+/// Used for deciding whether to keep a compressed batch, or an entry of a ZIP archive, in memory or to store it on disk.
 /// Useful when compressing large worlds with hundreds of GBs on a machine with a limited amount of RAM.
-fn spawn_memory_manager_thread(
+/// Batches are written while others are still being compressed, so released memory is available to the next ones.
+pub(super) fn spawn_memory_manager_thread(
     rx: CrossbeamReceiver<MemoryManagerMessage>,
     global_memory_limit_bytes: u64,
 ) -> JoinHandle<()> {
     std::thread::spawn(move || {
         let mut current_usage = 0u64;
         while let Ok(msg) = rx.recv() {
-            let MemoryManagerMessage::RequestAllocation(size, response_tx) = msg;
-            let can_allocate = current_usage + size <= global_memory_limit_bytes;
-            if can_allocate {
-                current_usage += size;
-            }
-            let _ = response_tx.send(can_allocate);
-        }
-        let mut current_usage = 0u64;
-        while let Ok(msg) = rx.recv() {
-            let MemoryManagerMessage::RequestAllocation(size, response_tx) = msg;
-            let can_allocate = current_usage + size <= global_memory_limit_bytes;
-            if can_allocate {
-                current_usage += size;
+            match msg {
+                MemoryManagerMessage::RequestAllocation(size, response_tx) => {
+                    let can_allocate = current_usage + size <= global_memory_limit_bytes;
+                    if can_allocate {
+                        current_usage += size;
+                    }
+                    let _ = response_tx.send(can_allocate);
+                }
+                MemoryManagerMessage::ReleaseAllocation(size) => {
+                    current_usage = current_usage.saturating_sub(size);
+                }
             }
-            let _ = response_tx.send(can_allocate);
         }
     })
 }
 
 /// Parallel Mode: Chunked Files, Parallel Compression, Concatenated Frames
 fn generate_zstd_parallel(
-    all_files: Vec<FileToCompress>,
+    mut all_files: Vec<FileToCompress>,
     archive_output_path: PathBuf,
     tx: Sender<ProgressMessage>,
     options: ArchiveOptions,
 ) -> Result<()> {
+    // the batches don't keep the order of the files, the manifest is written in front of them
+    let manifest = manifest::take_manifest(&mut all_files);
+
     // Prepare Temp Directory
     let (temp_dir, _cleanup_guard) = create_temp_dir()?;
 
@@ -164,9 +222,21 @@ fn generate_zstd_parallel(
     let (mem_tx, mem_rx) = channel::unbounded::<MemoryManagerMessage>();
     let mem_manager_handle = spawn_memory_manager_thread(mem_rx, global_memory_limit_bytes);
 
-    // Channels for Workers
-    let (work_tx, work_rx) = channel::unbounded::<(usize, BatchToCompress)>();
-    let (result_tx, result_rx) = channel::unbounded::<Result<(usize, CompressedFileData)>>();
+    // Channels for Workers. Both are bounded, so batches are only compressed as fast as they are written
+    let (work_tx, work_rx) = channel::bounded::<(usize, BatchToCompress)>(options.threads);
+    let (result_tx, result_rx) = channel::bounded::<Result<CompressedFileData>>(options.threads);
+    // One buffer per batch that's being compressed and one per batch waiting to be written
+    let buffers = BufferPool::new(2 * options.threads);
+    // With --target-duration, the workers ask for the level of every batch
+    let budget = options.target_duration.map(|target_duration| {
+        Arc::new(LevelBudget::new(
+            target_duration,
+            space::total_size(&all_files),
+            options.threads,
+            options.compression_format.level_range(),
+            options.compression_level,
+        ))
+    });
 
     // Spawn Workers
 
@@ -181,6 +251,10 @@ fn generate_zstd_parallel(
                 worker_id,
                 temp_dir: temp_dir.clone(),
                 compression_level: options.compression_level,
+                zstd_long: options.zstd_long,
+                buffers: buffers.clone(),
+                budget: budget.clone(),
+                options: options.clone(),
             };
             spawn_worker(ctx)
         })
@@ -193,10 +267,7 @@ fn generate_zstd_parallel(
     let mut total_uncompressed_size: u64 = 0;
 
     for file_info in all_files {
-        // Assuming file metadata is fast enough to fetch here
-        let size = std::fs::metadata(&file_info.src_path)
-            .map(|m| m.len())
-            .unwrap_or(0);
+        let size = file_info.size;
         total_uncompressed_size += size;
         files_with_size.push((file_info, size));
     }
@@ -213,103 +284,144 @@ fn generate_zstd_parallel(
         .unwrap_or(MAX_BATCH_SIZE_BYTES);
 
     // Set batch threshold: Clamp the target size between MIN and MAX.
-    let mut batch_threshold = target_size_per_thread
-        .max(MIN_BATCH_SIZE_BYTES)
-        .min(MAX_BATCH_SIZE_BYTES);
+    let mut batch_threshold = target_size_per_thread.clamp(MIN_BATCH_SIZE_BYTES, MAX_BATCH_SIZE_BYTES);
+    if options.target_duration.is_some() {
+        // Small batches, so the level can be adjusted often. The first ones run at the slowest level.
+        batch_threshold = MIN_BATCH_SIZE_BYTES;
+    }
 
     // Handle edge case: if total size is smaller than the calculated threshold, use total size.
     // Use .max(1) to avoid a zero-sized batch_threshold if total_uncompressed_size is 0.
     batch_threshold = batch_threshold.min(total_uncompressed_size.max(1));
 
-    println!(
+    debug!(
         "Total size: {}, Threads: {}, Calculated batch threshold: {}",
         crate::format_bytes(total_uncompressed_size),
         num_threads,
         crate::format_bytes(batch_threshold)
     );
 
-    // 3. Batching Logic
-    let mut current_batch = Vec::new();
-    let mut current_batch_size = 0u64;
-    let mut batch_index = 0;
-
-    for (file_info, size) in files_with_size {
-        current_batch.push(file_info);
-        current_batch_size += size;
-
-        // Check if we hit the dynamically calculated threshold
-        // We ensure the current batch is not empty to prevent sending a batch with just padding/headers
-        if current_batch_size >= batch_threshold && !current_batch.is_empty() {
-            // Send the batch
-            work_tx
-                .send((
-                    batch_index,
-                    BatchToCompress {
-                        files: current_batch,
-                        total_size: current_batch_size,
-                    },
-                ))
-                .ok();
+    // 3. Batching Logic. With --resume, the batches of an interrupted run are restored from its checkpoint
+    let (batches, mut checkpoint) = if options.resume {
+        let (batches, checkpoint) = plan_resumable_batches(files_with_size, batch_threshold, &archive_output_path)?;
+        (batches, Some(checkpoint))
+    } else {
+        Checkpoint::discard(&archive_output_path);
+        let batches = pack_batches(files_with_size, batch_threshold);
+        (batches.into_iter().enumerate().collect(), None)
+    };
 
-            current_batch = Vec::new();
-            current_batch_size = 0;
-            batch_index += 1;
-        }
-    }
+    // At most one batch per worker is being compressed, one per worker waits in the channel and about one per worker
+    // waits for the batches before it to be written.
+    // They only end up in the temp directory once they don't fit into the memory limit anymore.
+    let file_count = batches.iter().map(|(_, batch)| batch.files.len() as u64).sum();
+    let in_flight = space::estimate_archive_size(0, total_uncompressed_size.min(3 * num_threads * batch_threshold), &options);
+    let temp_bytes = if in_flight > global_memory_limit_bytes { in_flight } else { 0 };
+    let archive_size = space::estimate_archive_size(file_count, total_uncompressed_size, &options);
+    space::check_free_space(
+        &archive_output_path,
+        archive_size,
+        Some((&temp_dir, temp_bytes)),
+        &options,
+    )?;
+
+    let batch_count = batches.len() as u64;
+    // Position of each batch in the order it's handed to the workers
+    let position_of: HashMap<usize, usize> = batches
+        .iter()
+        .enumerate()
+        .map(|(position, (batch_idx, _))| (*batch_idx, position))
+        .collect();
 
-    // Send remaining files
-    if !current_batch.is_empty() {
-        work_tx
-            .send((
-                batch_index,
-                BatchToCompress {
-                    files: current_batch,
-                    total_size: current_batch_size,
-                },
-            ))
-            .ok();
-    }
+    // Feed the workers from their own thread, the work channel blocks until a worker is free
+    let feeder = std::thread::spawn(move || {
+        for (batch_idx, batch) in batches {
+            if work_tx.send((batch_idx, batch)).is_err() {
+                break;
+            }
+        }
+    });
 
-    drop(work_tx);
     drop(result_tx);
-    drop(mem_tx);
-
-    // Collect Results
-    let mut compressed_batches: Vec<(usize, CompressedFileData)> = Vec::new();
-    for result in result_rx {
-        compressed_batches.push(result?);
-    }
-    compressed_batches.sort_by_key(|(idx, _)| *idx);
 
-    for worker in workers {
-        worker.join().ok();
+    // Writing Phase: batches are written while the others are still being compressed, in the order they were
+    // handed out, so the archive's layout doesn't depend on which worker happened to be faster. Batches finishing
+    // ahead of that order wait for the ones before them, but no more than one per worker. Once more are waiting,
+    // the earliest of them is written anyway, so one slow batch can't hold back everything in memory or the temp directory.
+    let resumed = checkpoint.as_ref().is_some_and(|checkpoint| !checkpoint.completed.is_empty());
+    // an archive continued from a checkpoint got its manifest in the run that started it
+    let manifest = manifest.filter(|_| !resumed);
+    tx.send(ProgressMessage::StartWriting(batch_count + manifest.is_some() as u64)).ok();
+    let _write_span = info_span!("write").entered();
+    let mut output_file = match checkpoint {
+        Some(ref checkpoint) if resumed => {
+            ArchiveWriter::resume(&archive_output_path, checkpoint.archive_len, options.io_buffer_size)?
+        }
+        _ => ArchiveWriter::create(&archive_output_path, options.encryption.as_ref(), options.io_buffer_size, archive_size)?,
+    };
+    let mut archive_len = checkpoint.as_ref().map_or(0, |checkpoint| checkpoint.archive_len);
+    if let Some(ref manifest) = manifest {
+        tx.send(ProgressMessage::WritingFile(manifest.file_name.clone())).ok();
+        let frame = manifest_frame(manifest, &options, &tx)?;
+        output_file.write_all(&frame)?;
+        archive_len += frame.len() as u64;
     }
-    mem_manager_handle.join().ok();
 
-    // Writing Phase
-    tx.send(ProgressMessage::StartWriting(
-        compressed_batches.len() as u64
-    ))
-    .ok();
-    let mut output_file = std::fs::File::create(&archive_output_path)?;
+    let mut unwritten: BTreeSet<usize> = (0..position_of.len()).collect();
+    let mut waiting: BTreeMap<usize, CompressedFileData> = BTreeMap::new();
+    let mut results = result_rx.into_iter();
+    loop {
+        let next_in_order = unwritten.first().is_some_and(|next| waiting.contains_key(next));
+        let compressed_file = if next_in_order || waiting.len() > options.threads {
+            let (position, compressed_file) = waiting.pop_first().expect("checked above");
+            unwritten.remove(&position);
+            compressed_file
+        } else if let Some(result) = results.next() {
+            let compressed_file = result?;
+            waiting.insert(position_of[&compressed_file.batch_idx], compressed_file);
+            continue;
+        } else if let Some((position, compressed_file)) = waiting.pop_first() {
+            // only happens if a worker died without sending its batch
+            unwritten.remove(&position);
+            compressed_file
+        } else {
+            break;
+        };
 
-    for (_, compressed_file) in compressed_batches.iter() {
         tx.send(ProgressMessage::WritingFile(
             compressed_file.file_name.clone(),
         ))
         .ok();
 
-        match &compressed_file.data {
+        match compressed_file.data {
             CompressedDataLocation::Memory(data) => {
-                output_file.write_all(data)?;
+                output_file.write_all(&data)?;
+                archive_len += data.len() as u64;
+                mem_tx.send(MemoryManagerMessage::ReleaseAllocation(data.len() as u64)).ok();
+                buffers.give_back(data);
             }
             CompressedDataLocation::Disk(temp_file_path) => {
-                let mut temp_file = std::fs::File::open(temp_file_path)?;
-                std::io::copy(&mut temp_file, &mut output_file)?;
+                let mut temp_file = BufReader::with_capacity(options.io_buffer_size, File::open(&temp_file_path)?);
+                archive_len += archive::copy_buf(&mut temp_file, &mut output_file)?;
+                // Keeps the temp directory from growing to the size of the archive
+                std::fs::remove_file(&temp_file_path).ok();
             }
         }
+
+        if let Some(ref mut checkpoint) = checkpoint {
+            // the batch has to be on disk before the checkpoint says so
+            output_file.sync()?;
+            checkpoint.record(compressed_file.batch_idx, archive_len)?;
+        }
     }
 
+    feeder.join().ok();
+    for worker in workers {
+        worker.join().ok();
+    }
+    drop(mem_tx);
+    mem_manager_handle.join().ok();
+
     // Append Final Tar EOFs
     {
         let mut end_marker_data = Vec::new();
@@ -321,19 +433,121 @@ fn generate_zstd_parallel(
         output_file.write_all(&end_marker_data)?;
     }
 
-    output_file.sync_all()?;
-    let final_size = std::fs::metadata(&archive_output_path)?.len();
-    tx.send(ProgressMessage::Complete(final_size)).ok();
+    output_file.finish()?;
+    if checkpoint.is_some() {
+        Checkpoint::discard(&archive_output_path);
+    }
 
     Ok(())
 }
 
+/// Restores the batches that are still missing from the archive of an interrupted run, or plans new ones and starts a checkpoint.
+/// Files that appeared since the interrupted run get batches of their own, files that vanished are left out.
+fn plan_resumable_batches(
+    files_with_size: Vec<(FileToCompress, u64)>,
+    batch_threshold: u64,
+    archive_output_path: &Path,
+) -> Result<(Vec<(usize, BatchToCompress)>, Checkpoint)> {
+    let Some(mut checkpoint) = Checkpoint::load(archive_output_path)? else {
+        let batches = pack_batches(files_with_size, batch_threshold);
+        let checkpoint = Checkpoint::create(archive_output_path, batch_names(&batches))?;
+        return Ok((batches.into_iter().enumerate().collect(), checkpoint));
+    };
+
+    let planned: HashSet<&String> = checkpoint.batches.iter().flatten().collect();
+    let (known, new): (Vec<_>, Vec<_>) = files_with_size
+        .into_iter()
+        .partition(|(file_info, _)| planned.contains(&file_info.file_name));
+    let mut by_name: HashMap<String, (FileToCompress, u64)> = known
+        .into_iter()
+        .map(|(file_info, size)| (file_info.file_name.clone(), (file_info, size)))
+        .collect();
+
+    let mut remaining = Vec::new();
+    for (batch_idx, names) in checkpoint.batches.iter().enumerate() {
+        if checkpoint.completed.contains(&batch_idx) {
+            continue;
+        }
+        let mut batch = BatchToCompress { files: Vec::new(), total_size: 0 };
+        for name in names {
+            if let Some((file_info, size)) = by_name.remove(name) {
+                batch.files.push(file_info);
+                batch.total_size += size;
+            }
+        }
+        remaining.push((batch_idx, batch));
+    }
+    info!(
+        "Resuming the interrupted run, {} of {} batches are already in the archive",
+        checkpoint.completed.len(),
+        checkpoint.batches.len()
+    );
+
+    if !new.is_empty() {
+        let new_batches = pack_batches(new, batch_threshold);
+        let first_idx = checkpoint.extend(batch_names(&new_batches))?;
+        remaining.extend(new_batches.into_iter().enumerate().map(|(i, batch)| (first_idx + i, batch)));
+    }
+    Ok((remaining, checkpoint))
+}
+
+fn batch_names(batches: &[BatchToCompress]) -> Vec<Vec<String>> {
+    batches
+        .iter()
+        .map(|batch| batch.files.iter().map(|file_info| file_info.file_name.clone()).collect())
+        .collect()
+}
+
+/// Distributes the files over batches of roughly equal size, so all workers finish at about the same time.
+/// Files are packed largest first into the currently smallest batch. Within a batch they stay in scan order,
+/// which keeps files of the same dimension together for a better ratio. The biggest batches come first.
+fn pack_batches(files_with_size: Vec<(FileToCompress, u64)>, batch_threshold: u64) -> Vec<BatchToCompress> {
+    let total_size: u64 = files_with_size.iter().map(|(_, size)| size).sum();
+    let batch_count = total_size
+        .div_ceil(batch_threshold.max(1))
+        .clamp(1, files_with_size.len().max(1) as u64) as usize;
+
+    let mut by_size: Vec<(usize, u64)> = files_with_size
+        .iter()
+        .enumerate()
+        .map(|(scan_idx, (_, size))| (scan_idx, *size))
+        .collect();
+    by_size.sort_by_key(|&(_, size)| Reverse(size));
+
+    // Min-heap of (batch size, batch index)
+    let mut heap: BinaryHeap<Reverse<(u64, usize)>> = (0..batch_count).map(|i| Reverse((0, i))).collect();
+    let mut assigned: Vec<Vec<usize>> = vec![Vec::new(); batch_count];
+    for (scan_idx, size) in by_size {
+        let Reverse((batch_size, batch_idx)) = heap.pop().expect("at least one batch");
+        assigned[batch_idx].push(scan_idx);
+        heap.push(Reverse((batch_size + size, batch_idx)));
+    }
+
+    let mut files: Vec<Option<(FileToCompress, u64)>> = files_with_size.into_iter().map(Some).collect();
+    let mut batches: Vec<BatchToCompress> = assigned
+        .into_iter()
+        .filter(|scan_indices| !scan_indices.is_empty())
+        .map(|mut scan_indices| {
+            scan_indices.sort_unstable();
+            let mut batch = BatchToCompress { files: Vec::new(), total_size: 0 };
+            for scan_idx in scan_indices {
+                let (file_info, size) = files[scan_idx].take().expect("every file is packed once");
+                batch.files.push(file_info);
+                batch.total_size += size;
+            }
+            batch
+        })
+        .collect();
+    batches.sort_by_key(|batch| Reverse(batch.total_size));
+    batches
+}
+
 #[derive(Clone)]
 struct WorkerCtx {
     work_rx: CrossbeamReceiver<(usize, BatchToCompress)>,
 
     tx: Sender<ProgressMessage>,
-    result_tx: CrossbeamSender<Result<(usize, CompressedFileData)>>,
+    result_tx: CrossbeamSender<Result<CompressedFileData>>,
 
     mem_tx: CrossbeamSender<MemoryManagerMessage>,
     global_memory_limit_bytes: u64,
@@ -341,12 +555,25 @@ struct WorkerCtx {
     worker_id: usize,
     temp_dir: PathBuf,
     compression_level: i8,
+    zstd_long: Option<u32>,
+    /// Where the batches that stay in memory are compressed into
+    buffers: BufferPool,
+    /// Picks the level of every batch with `--target-duration`
+    budget: Option<Arc<LevelBudget>>,
+    /// For the cancellation token and the `--on-error` policy
+    options: ArchiveOptions,
 }
 
 fn spawn_worker(ctx: WorkerCtx) -> JoinHandle<()> {
+    let span = info_span!("worker", id = ctx.worker_id);
     std::thread::Builder::new()
         .name(format!("worker-{}", ctx.worker_id))
         .spawn(move || {
+            let _span = span.entered();
+            // threads don't inherit the priority on Windows
+            if ctx.options.background {
+                priority::lower_thread_priority();
+            }
             // Send an immediate "Idle" message to ensure the progress bar is created for this worker.
             ctx.tx
                 .send(ProgressMessage::Compressing(
@@ -355,23 +582,55 @@ fn spawn_worker(ctx: WorkerCtx) -> JoinHandle<()> {
                 ))
                 .ok();
 
+            // Every worker spills into a directory of its own, so workers never write to the same file
+            let temp_dir = ctx.temp_dir.join(format!("worker_{}", ctx.worker_id));
+            if let Err(err) = std::fs::create_dir(&temp_dir) {
+                ctx.result_tx.send(Err(anyhow::Error::new(err).context("Failed to create temp directory"))).ok();
+                return;
+            }
+
+            // Set up once and reset between batches, instead of allocating new tables for every batch
+            let mut encoder_context = match new_encoder_context(ctx.compression_level, ctx.zstd_long) {
+                Ok(context) => context,
+                Err(err) => {
+                    ctx.result_tx.send(Err(err)).ok();
+                    return;
+                }
+            };
+
             while let Ok((batch_idx, batch)) = ctx.work_rx.recv() {
+                let level = ctx.budget.as_ref().map(|budget| budget.next_level());
+                if let Some(level) = level {
+                    // parameters can only change in between frames
+                    let changed = encoder_context
+                        .reset(ResetDirective::SessionOnly)
+                        .and_then(|_| encoder_context.set_parameter(CParameter::CompressionLevel(level as i32)));
+                    if let Err(code) = changed {
+                        let err = anyhow::anyhow!("Failed to configure zstd: {}", zstd::zstd_safe::get_error_name(code));
+                        if ctx.result_tx.send(Err(err)).is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                }
+                let started = Instant::now();
                 let result = compress_batch_to_zstd_frame(
                     &batch,
-                    &ctx.temp_dir,
+                    &temp_dir,
                     batch_idx,
-                    ctx.compression_level,
+                    &mut encoder_context,
+                    &ctx.buffers,
+                    &ctx.options,
                     ctx.global_memory_limit_bytes,
                     &ctx.mem_tx,
                     &ctx.tx,
                     ctx.worker_id,
                 );
+                if let (Some(budget), Some(level), Ok(_)) = (&ctx.budget, level, &result) {
+                    budget.batch_done(level, batch.total_size, started.elapsed());
+                }
 
-                if ctx
-                    .result_tx
-                    .send(result.map(|data| (batch_idx, data)))
-                    .is_err()
-                {
+                if ctx.result_tx.send(result).is_err() {
                     break;
                 }
             }
@@ -379,11 +638,78 @@ fn spawn_worker(ctx: WorkerCtx) -> JoinHandle<()> {
         .expect("Failed to spawn thread")
 }
 
+/// The tar header of a file with the size, modification time and permissions from the scan.
+fn tar_header(file_info: &FileToCompress) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(file_info.size);
+    header.set_mode(file_info.mode);
+    let mtime = file_info
+        .modified
+        .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |since_epoch| since_epoch.as_secs());
+    header.set_mtime(mtime);
+    header
+}
+
+/// Writes the header of a tar entry. Paths that don't fit into the header's 100 bytes, like the ones of deeply nested
+/// datapack dimensions, get a GNU long name entry in front, the same way `tar::Builder` does it in sequential mode.
+fn write_tar_header(writer: &mut impl Write, header: &mut tar::Header, path: &str) -> Result<()> {
+    const TAR_BLOCK_SIZE: usize = 512;
+
+    if header.set_path(path).is_err() {
+        let name = path.as_bytes();
+        let mut long_name = tar::Header::new_gnu();
+        long_name.as_gnu_mut().unwrap().name[..13].copy_from_slice(b"././@LongLink");
+        long_name.set_mode(0o644);
+        long_name.set_uid(0);
+        long_name.set_gid(0);
+        long_name.set_mtime(0);
+        // GNU tar counts the terminating NUL
+        long_name.set_size(name.len() as u64 + 1);
+        long_name.set_entry_type(tar::EntryType::GNULongName);
+        long_name.set_cksum();
+        writer.write_all(long_name.as_bytes())?;
+        writer.write_all(name)?;
+        let padded_len = (name.len() + 1).div_ceil(TAR_BLOCK_SIZE) * TAR_BLOCK_SIZE;
+        writer.write_all(&vec![0u8; padded_len - name.len()])?;
+
+        // The entry's own header gets as much of the path as fits, without cutting a character in half
+        let field = &mut header.as_old_mut().name;
+        let mut truncated_len = name.len().min(field.len());
+        while !path.is_char_boundary(truncated_len) {
+            truncated_len -= 1;
+        }
+        field.fill(0);
+        field[..truncated_len].copy_from_slice(&name[..truncated_len]);
+    }
+    header.set_cksum();
+    writer.write_all(header.as_bytes())?;
+    Ok(())
+}
+
+/// The manifest in a zstd frame of its own. It's small enough to be read into memory.
+fn manifest_frame(manifest: &FileToCompress, options: &ArchiveOptions, tx: &Sender<ProgressMessage>) -> Result<Vec<u8>> {
+    const TAR_BLOCK_SIZE: usize = 512;
+
+    let contents = std::fs::read(&manifest.src_path)?;
+    let mut encoder = new_encoder(Vec::new(), options.compression_level, options.zstd_long)?;
+    write_tar_header(&mut encoder, &mut tar_header(manifest), &manifest.file_name)?;
+    encoder.write_all(&contents)?;
+    let padding_needed = (TAR_BLOCK_SIZE - contents.len() % TAR_BLOCK_SIZE) % TAR_BLOCK_SIZE;
+    encoder.write_all(&[0u8; TAR_BLOCK_SIZE][..padding_needed])?;
+    tx.send(ProgressMessage::BytesProcessed(contents.len() as u64)).ok();
+    Ok(encoder.finish()?)
+}
+
+#[allow(clippy::too_many_arguments)]
 fn compress_batch_to_zstd_frame(
     batch: &BatchToCompress,
     temp_dir: &Path,
     batch_idx: usize,
-    compression_level: i8,
+    encoder_context: &mut CCtx<'static>,
+    buffers: &BufferPool,
+    options: &ArchiveOptions,
     global_memory_limit_bytes: u64,
     mem_tx: &CrossbeamSender<MemoryManagerMessage>,
     progress_tx: &Sender<ProgressMessage>,
@@ -393,24 +719,28 @@ fn compress_batch_to_zstd_frame(
     // write straight to disk to avoid out-of-memory by holding compressed data in memory.
     let direct_to_disk = batch.total_size > global_memory_limit_bytes;
 
-    let mut disk_file: Option<File>;
+    let mut disk_file: Option<BufWriter<File>>;
     let mut mem_buffer: Option<Vec<u8>> = None;
 
     let mut sink: Box<dyn Write + Send> = if direct_to_disk {
         let temp_file_path = temp_dir.join(format!("batch_{}.zst", batch_idx));
         let f = File::create(&temp_file_path)?;
-        disk_file = Some(f);
+        disk_file = Some(BufWriter::with_capacity(options.io_buffer_size, f));
         Box::new(disk_file.as_mut().unwrap())
     } else {
-        mem_buffer = Some(Vec::new());
+        mem_buffer = Some(buffers.take());
         Box::new(mem_buffer.as_mut().unwrap())
     };
 
     {
-        let mut encoder = zstd::Encoder::new(&mut sink, compression_level as i32)?;
+        // A batch that failed halfway leaves its frame unfinished in the context
+        let mut operation = raw::Encoder::with_context(encoder_context);
+        raw::Operation::reinit(&mut operation)?;
+        let mut encoder = zio::Writer::new(&mut sink, operation);
 
         // Iterate files in the batch
         for file_info in &batch.files {
+            options.cancel.check()?;
             // Send progress update
             progress_tx
                 .send(ProgressMessage::Compressing(
@@ -419,30 +749,32 @@ fn compress_batch_to_zstd_frame(
                 ))
                 .ok();
 
+            let Some(input_file) = archive::open_source_file(file_info, options, progress_tx)? else {
+                progress_tx
+                    .send(ProgressMessage::FileCompressed(worker_id, file_info.file_name.clone()))
+                    .ok();
+                continue;
+            };
+
             // 1. Manual Tar Header
-            let mut header = tar::Header::new_gnu();
-            let meta = std::fs::metadata(&file_info.src_path)?;
-            header.set_metadata(&meta);
-            header.set_size(meta.len());
-
-            let path_in_tar = Path::new(&file_info.file_name);
-            if let Err(e) = header.set_path(path_in_tar) {
-                return Err(anyhow::anyhow!("Failed to set path: {}", e));
-            }
-            header.set_cksum();
-            encoder.write_all(header.as_bytes())?;
+            let mut header = tar_header(file_info);
+
+            write_tar_header(&mut encoder, &mut header, &file_info.file_name)?;
 
             // 2. File Content
-            let mut input_file = File::open(&file_info.src_path)?;
-            std::io::copy(&mut input_file, &mut encoder)?;
+            // The header promises the scanned size, even if the file changed since the scan
+            let mut input_file = ProgressReader::new(
+                BufReader::with_capacity(options.io_buffer_size, archive::sized_reader(input_file, file_info.size)),
+                progress_tx,
+            );
+            archive::copy_buf(&mut input_file, &mut encoder)?;
 
             // 3. Padding
             const TAR_BLOCK_SIZE: u64 = 512;
 
-            let padding_needed = (TAR_BLOCK_SIZE - (meta.len() % TAR_BLOCK_SIZE)) % TAR_BLOCK_SIZE;
+            let padding_needed = (TAR_BLOCK_SIZE - (file_info.size % TAR_BLOCK_SIZE)) % TAR_BLOCK_SIZE;
             if padding_needed > 0 {
-                let zeros = vec![0u8; padding_needed as usize];
-                encoder.write_all(&zeros)?;
+                encoder.write_all(&[0u8; TAR_BLOCK_SIZE as usize][..padding_needed as usize])?;
             }
 
             // Mark this file as done in the UI
@@ -457,6 +789,8 @@ fn compress_batch_to_zstd_frame(
         encoder.finish()?;
     }
 
+    // dropping the buffer would swallow a failed write of its rest
+    sink.flush()?;
     drop(sink);
 
     let batch_name = format!("Batch {}", batch_idx);
@@ -464,6 +798,7 @@ fn compress_batch_to_zstd_frame(
     if direct_to_disk {
         let temp_file_path = temp_dir.join(format!("batch_{}.zst", batch_idx));
         Ok(CompressedFileData {
+            batch_idx,
             file_name: batch_name,
             data: CompressedDataLocation::Disk(temp_file_path),
         })
@@ -479,18 +814,23 @@ fn compress_batch_to_zstd_frame(
             ))
             .ok();
 
-        // The Memory Manager checks if the global limit is exceeded.
-        if response_rx.try_recv().unwrap_or(false) {
+        // The Memory Manager checks if the global limit is exceeded. Waits for its answer, asking and
+        // not waiting for it would spill most batches to disk.
+        if response_rx.recv().unwrap_or(false) {
             // Allocation successful, keep in memory
             Ok(CompressedFileData {
+                batch_idx,
                 file_name: batch_name,
                 data: CompressedDataLocation::Memory(compressed_data),
             })
         } else {
             // Allocation failed (global limit reached), write to disk as a fallback
+            debug!("Batch {} doesn't fit into the memory limit, moving it to the temp directory", batch_idx);
             let temp_file_path = temp_dir.join(format!("batch_{}.zst", batch_idx));
             std::fs::write(&temp_file_path, &compressed_data)?;
+            buffers.give_back(compressed_data);
             Ok(CompressedFileData {
+                batch_idx,
                 file_name: batch_name,
                 data: CompressedDataLocation::Disk(temp_file_path),
             })
@@ -0,0 +1,137 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::Instant,
+};
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::{
+    ArchiveOptions, CompressionFormat, CompressionStats, FileToCompress, archive, format_bytes,
+    archive::progress::ProgressMode,
+};
+
+#[derive(Clone)]
+pub struct BenchOptions {
+    /// The world directory the sample is taken from
+    pub world_dir: PathBuf,
+    /// How many bytes of the world every run compresses
+    pub sample_size: u64,
+    /// Format and level of the runs. Each one is run with every thread count
+    pub compressions: Vec<(CompressionFormat, i8)>,
+    pub threads: Vec<usize>,
+}
+
+/// Compresses a sample of the world with every combination of level and thread count and prints how long it took
+/// and how small the archive got. The runs go through the same code as `mwdh compress`, so the numbers carry over
+/// to archiving the whole world.
+pub fn bench(options: &BenchOptions) -> Result<()> {
+    let sample = pick_sample(&options.world_dir, options.sample_size)?;
+    let sample_bytes = archive::space::total_size(&sample);
+    println!("Sample: {} files, {}", sample.len(), format_bytes(sample_bytes));
+    println!();
+    println!("{:<6} {:>5} {:>7} {:>9} {:>7} {:>14} {:>14}", "Format", "Level", "Threads", "Time", "Ratio", "Speed", "Per worker");
+
+    let temp_dir = archive::create_unique_temp_dir("mwdh_bench")?;
+    let _cleanup_guard = scopeguard::guard((), |_| {
+        std::fs::remove_dir_all(&temp_dir).ok();
+    });
+    for &(format, level) in &options.compressions {
+        for &threads in &options.threads {
+            let stats = run(&sample, sample_bytes, format, level, threads, &temp_dir)
+                .with_context(|| format!("Failed to compress the sample with {} level {} on {} threads", format, level, threads))?;
+            println!(
+                "{:<6} {:>5} {:>7} {:>8.2}s {:>7.2} {:>12}/s {:>12}/s",
+                format.to_string(),
+                level,
+                threads,
+                stats.elapsed.as_secs_f64(),
+                stats.ratio(),
+                format_bytes(stats.throughput() as u64),
+                format_bytes(stats.throughput_per_worker() as u64)
+            );
+        }
+    }
+    Ok(())
+}
+
+fn run(
+    sample: &[FileToCompress],
+    sample_bytes: u64,
+    format: CompressionFormat,
+    level: i8,
+    threads: usize,
+    temp_dir: &Path,
+) -> Result<CompressionStats> {
+    let options = ArchiveOptions::builder()
+        .compression_format(format)
+        .compression_level(level)
+        .threads(threads)
+        .progress(ProgressMode::Hidden)
+        .skip_space_check(true)
+        .build()?;
+    let archive_format = options.archive_format();
+    let output_path = temp_dir.join(format!("bench.{}", archive_format.extension()));
+    // nobody listens to the progress of a run
+    let (tx, _) = mpsc::channel();
+
+    let started = Instant::now();
+    // keeps messages like "Using parallel mode" out of the table
+    tracing::subscriber::with_default(tracing::subscriber::NoSubscriber::default(), || {
+        archive_format.compress(sample.to_vec(), output_path.clone(), tx, options)
+    })?;
+    let stats = CompressionStats {
+        archive_size: std::fs::metadata(&output_path)?.len(),
+        uncompressed_size: sample_bytes,
+        elapsed: started.elapsed(),
+        threads,
+    };
+    std::fs::remove_file(&output_path).ok();
+    Ok(stats)
+}
+
+/// Takes region files from all over the world until there are `sample_size` bytes of them, so the sample has
+/// both the busy areas and the barely explored ones. Worlds without region files are sampled as a whole.
+fn pick_sample(world_dir: &Path, sample_size: u64) -> Result<Vec<FileToCompress>> {
+    if !world_dir.is_dir() {
+        return Err(anyhow!("{} is not a directory", world_dir.display()));
+    }
+    let base = world_dir.parent().unwrap_or(world_dir);
+    let mut files = Vec::new();
+    let mut stack = vec![world_dir.to_path_buf()];
+    while let Some(dir) = stack.pop() {
+        for entry in std::fs::read_dir(&dir).with_context(|| format!("Failed to read: {}", dir.display()))? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            let path = entry.path();
+            if meta.is_dir() {
+                stack.push(path);
+            } else if meta.is_file() && meta.len() > 0 {
+                let file_name = path.strip_prefix(base).unwrap_or(&path).to_string_lossy().replace('\\', "/");
+                files.push(FileToCompress::new(path, file_name, &meta));
+            }
+        }
+    }
+    let is_region = |file: &FileToCompress| file.file_name.ends_with(".mca");
+    if files.iter().any(is_region) {
+        files.retain(is_region);
+    }
+    if files.is_empty() {
+        return Err(anyhow!("{} doesn't contain any files to compress", world_dir.display()));
+    }
+    files.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+
+    // every n-th file, so the sample is spread over the whole world instead of a single corner
+    let total = archive::space::total_size(&files);
+    let stride = (total / sample_size.max(1)).max(1) as usize;
+    let mut sample = Vec::new();
+    let mut sample_bytes = 0;
+    for file in files.into_iter().step_by(stride) {
+        if sample_bytes >= sample_size {
+            break;
+        }
+        sample_bytes += file.size;
+        sample.push(file);
+    }
+    Ok(sample)
+}
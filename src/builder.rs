@@ -0,0 +1,792 @@
+//! Builders for [`ArchiveOptions`] and [`ServerOptions`], for embedding mwdh as a library.
+//! They start out with the same defaults as the command line and check the options when building.
+
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use crate::{
+    ArchiveOptions, CompressionFormat,
+    archive::{DEFAULT_IO_BUFFER_SIZE, OnError, budget, format::ArchiveFormat, layout::Layout, priority, progress::{ProgressMode, ProgressSink}, throttle::IoLimit, zip::ZipMethod},
+    cancel::CancellationToken,
+    error::MwdhError,
+    expand_archive_name,
+    hooks::HookFailurePolicy,
+    prune::RetentionPolicy,
+    region::CropArea,
+};
+#[cfg(feature = "encryption")]
+use crate::archive::encrypt::AgeEncryption;
+#[cfg(feature = "notify")]
+use crate::notify::NotifyTarget;
+#[cfg(feature = "upload")]
+use crate::upload::UploadTarget;
+#[cfg(feature = "server")]
+use crate::{ServerOptions, server::{self, access_log::AccessLogTarget, acme::AcmeOptions, events::ProgressEvents, ip_filter::IpNet, limits::ClientLimits, live::LiveArchive, receive::UploadOptions, tls::TlsOptions, torrent::TorrentOptions}};
+#[cfg(feature = "server")]
+use url::Url;
+
+impl ArchiveOptions {
+    /// Archives the `world` directory in the current directory with all its dimensions into `world.tar.zst`,
+    /// using zstd's fastest level and one thread per CPU, until told otherwise.
+    pub fn builder() -> ArchiveOptionsBuilder {
+        ArchiveOptionsBuilder {
+            compression_level: None,
+            options: ArchiveOptions {
+                world_path: ".".to_string(),
+                world_name: "world".to_string(),
+                archive_name: "world".to_string(),
+                output_dir: None,
+                overwrite: false,
+                include_nether: true,
+                include_end: true,
+                include_overworld: true,
+                threads: 0,
+                compression_level: 0,
+                compression_format: CompressionFormat::TarZstd,
+                is_bukkit: false,
+                memory_limit_mb: 512,
+                #[cfg(feature = "upload")]
+                upload: None,
+                post_hook: None,
+                #[cfg(feature = "notify")]
+                notify: Vec::new(),
+                #[cfg(feature = "notify")]
+                healthcheck_url: None,
+                pre_hook: None,
+                pre_hook_failure: HookFailurePolicy::Abort,
+                auto_prune: None,
+                prune_inhabited_under: None,
+                crop: None,
+                raw_region_chunks: false,
+                validate_regions: false,
+                scrub_player_data: false,
+                include_server_files: false,
+                include_plugins: false,
+                exclude_plugin_jars: false,
+                layout: Layout::AsIs,
+                encryption: None,
+                zstd_long: None,
+                single_stream: false,
+                zip_method: ZipMethod::Deflated,
+                store_extensions: Vec::new(),
+                progress: ProgressMode::Hidden,
+                cancel: CancellationToken::new(),
+                keep_partial: false,
+                on_error: OnError::Abort,
+                skip_space_check: false,
+                resume: false,
+                update: false,
+                diff_base: None,
+                custom_format: None,
+                io_limit: None,
+                background: false,
+                io_buffer_size: DEFAULT_IO_BUFFER_SIZE,
+                target_duration: None,
+                manifest: false,
+            },
+        }
+    }
+
+    /// Checks for combinations of options that can't work. Done by [`ArchiveOptionsBuilder::build`] and the CLI.
+    pub fn validate(&self) -> Result<(), MwdhError> {
+        let levels = self.compression_format.level_range();
+        if !levels.contains(&self.compression_level) {
+            return Err(invalid(format!(
+                "Compression level {} is out of range for {}, use {} to {}",
+                self.compression_level,
+                self.compression_format,
+                levels.start(),
+                levels.end()
+            )));
+        }
+        if !(self.include_overworld || self.include_nether || self.include_end) {
+            return Err(invalid("At least one dimension has to be included"));
+        }
+        if self.zstd_long.is_some_and(|window_log| !(10..=31).contains(&window_log)) {
+            return Err(invalid("The zstd long distance matching window log has to be between 10 and 31"));
+        }
+        if self.io_limit.as_ref().is_some_and(|limit| limit.bytes_per_second() == 0) {
+            return Err(invalid("The I/O limit has to be greater than 0"));
+        }
+        if self.io_buffer_size == 0 {
+            return Err(invalid("The I/O buffer size has to be greater than 0"));
+        }
+        if let Some(target_duration) = self.target_duration {
+            if target_duration.is_zero() {
+                return Err(invalid("The target duration has to be greater than 0"));
+            }
+            if !matches!(self.compression_format, CompressionFormat::TarZstd) || self.threads == 1 || self.single_stream {
+                return Err(invalid(
+                    "A target duration only works for zstd archives compressed in parallel mode with more than one thread",
+                ));
+            }
+        }
+        if self.exclude_plugin_jars && !self.include_plugins {
+            return Err(invalid("Excluding the plugin jars only makes sense when including the plugins"));
+        }
+        let zstd = matches!(self.compression_format, CompressionFormat::TarZstd);
+        if self.encryption.is_some() && !zstd {
+            return Err(invalid("Encryption is only supported for zstd archives"));
+        }
+        if self.resume && (!zstd || self.threads == 1 || self.single_stream || self.encryption.is_some()) {
+            return Err(invalid(
+                "Resuming only works for unencrypted zstd archives compressed in parallel mode with more than one thread",
+            ));
+        }
+        if self.update && (zstd || self.custom_format.is_some()) {
+            return Err(invalid("Updating an existing archive only works for zip archives"));
+        }
+        if let Some(ref base) = self.diff_base {
+            if self.update {
+                return Err(invalid("A differential archive can't update an existing archive"));
+            }
+            if !base.is_file() {
+                return Err(invalid(format!("The base archive {} doesn't exist", base.display())));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn invalid(message: impl Into<String>) -> MwdhError {
+    MwdhError::InvalidOptions(message.into())
+}
+
+/// Builds [`ArchiveOptions`], see [`ArchiveOptions::builder`].
+pub struct ArchiveOptionsBuilder {
+    options: ArchiveOptions,
+    /// Depends on the format when not set
+    compression_level: Option<i8>,
+}
+
+impl ArchiveOptionsBuilder {
+    /// The server or saves directory containing the world directory.
+    pub fn world_path(mut self, world_path: impl Into<String>) -> Self {
+        self.options.world_path = world_path.into();
+        self
+    }
+
+    /// The world directory's name, or the prefix of the dimension directories of Bukkit servers.
+    pub fn world_name(mut self, world_name: impl Into<String>) -> Self {
+        self.options.world_name = world_name.into();
+        self
+    }
+
+    /// Archive name without the file ending. Placeholders like `{date}` get expanded when building, see [`expand_archive_name`].
+    pub fn archive_name(mut self, archive_name: impl Into<String>) -> Self {
+        self.options.archive_name = archive_name.into();
+        self
+    }
+
+    /// Directory to write the archive to instead of the current directory.
+    pub fn output_dir(mut self, output_dir: impl Into<PathBuf>) -> Self {
+        self.options.output_dir = Some(output_dir.into());
+        self
+    }
+
+    pub fn overwrite(mut self, overwrite: bool) -> Self {
+        self.options.overwrite = overwrite;
+        self
+    }
+
+    /// Which dimensions to archive. All of them by default.
+    pub fn dimensions(mut self, overworld: bool, nether: bool, end: bool) -> Self {
+        self.options.include_overworld = overworld;
+        self.options.include_nether = nether;
+        self.options.include_end = end;
+        self
+    }
+
+    /// Number of compression threads, 0 for one per CPU.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.options.threads = threads;
+        self
+    }
+
+    /// -7 to 22 for zstd, 0 to 9 for zip. Defaults to -7 for zstd and 6 for zip.
+    pub fn compression_level(mut self, compression_level: i8) -> Self {
+        self.compression_level = Some(compression_level);
+        self
+    }
+
+    pub fn compression_format(mut self, compression_format: CompressionFormat) -> Self {
+        self.options.compression_format = compression_format;
+        self
+    }
+
+    /// Whether the Nether and the End are in their own directories like on Bukkit/Spigot/Paper servers.
+    pub fn bukkit(mut self, is_bukkit: bool) -> Self {
+        self.options.is_bukkit = is_bukkit;
+        self
+    }
+
+    pub fn memory_limit_mb(mut self, memory_limit_mb: u64) -> Self {
+        self.options.memory_limit_mb = memory_limit_mb;
+        self
+    }
+
+    #[cfg(feature = "upload")]
+    pub fn upload(mut self, upload: UploadTarget) -> Self {
+        self.options.upload = Some(upload);
+        self
+    }
+
+    pub fn pre_hook(mut self, pre_hook: impl Into<String>, failure: HookFailurePolicy) -> Self {
+        self.options.pre_hook = Some(pre_hook.into());
+        self.options.pre_hook_failure = failure;
+        self
+    }
+
+    pub fn post_hook(mut self, post_hook: impl Into<String>) -> Self {
+        self.options.post_hook = Some(post_hook.into());
+        self
+    }
+
+    /// Send a notification here once the archive was created or compressing it failed. Can be called once per target.
+    #[cfg(feature = "notify")]
+    pub fn notify(mut self, target: NotifyTarget) -> Self {
+        self.options.notify.push(target);
+        self
+    }
+
+    /// Ping this health check at `/start` before archiving, as it is once the run succeeded and at `/fail` when it
+    /// failed, like a check of healthchecks.io expects.
+    #[cfg(feature = "notify")]
+    pub fn healthcheck_url(mut self, url: url::Url) -> Self {
+        self.options.healthcheck_url = Some(url);
+        self
+    }
+
+    pub fn auto_prune(mut self, policy: RetentionPolicy) -> Self {
+        self.options.auto_prune = Some(policy);
+        self
+    }
+
+    pub fn prune_inhabited_under(mut self, time: Duration) -> Self {
+        self.options.prune_inhabited_under = Some(time);
+        self
+    }
+
+    pub fn crop(mut self, crop: CropArea) -> Self {
+        self.options.crop = Some(crop);
+        self
+    }
+
+    pub fn raw_region_chunks(mut self, raw_region_chunks: bool) -> Self {
+        self.options.raw_region_chunks = raw_region_chunks;
+        self
+    }
+
+    pub fn validate_regions(mut self, validate_regions: bool) -> Self {
+        self.options.validate_regions = validate_regions;
+        self
+    }
+
+    pub fn scrub_player_data(mut self, scrub_player_data: bool) -> Self {
+        self.options.scrub_player_data = scrub_player_data;
+        self
+    }
+
+    pub fn include_server_files(mut self, include_server_files: bool) -> Self {
+        self.options.include_server_files = include_server_files;
+        self
+    }
+
+    /// Archive the plugins directory, optionally without the plugin .jar files.
+    pub fn include_plugins(mut self, include_plugins: bool, exclude_jars: bool) -> Self {
+        self.options.include_plugins = include_plugins;
+        self.options.exclude_plugin_jars = exclude_jars;
+        self
+    }
+
+    pub fn layout(mut self, layout: Layout) -> Self {
+        self.options.layout = layout;
+        self
+    }
+
+    #[cfg(feature = "encryption")]
+    pub fn encryption(mut self, encryption: AgeEncryption) -> Self {
+        self.options.encryption = Some(encryption);
+        self
+    }
+
+    pub fn zstd_long(mut self, window_log: u32) -> Self {
+        self.options.zstd_long = Some(window_log);
+        self
+    }
+
+    pub fn single_stream(mut self, single_stream: bool) -> Self {
+        self.options.single_stream = single_stream;
+        self
+    }
+
+    pub fn zip_method(mut self, zip_method: ZipMethod) -> Self {
+        self.options.zip_method = zip_method;
+        self
+    }
+
+    /// File extensions to store uncompressed in ZIP archives, with or without the leading dot.
+    pub fn store_extensions<S: AsRef<str>>(mut self, extensions: impl IntoIterator<Item = S>) -> Self {
+        self.options.store_extensions = extensions
+            .into_iter()
+            .map(|ext| ext.as_ref().trim_start_matches('.').to_lowercase())
+            .collect();
+        self
+    }
+
+    /// How to show the progress. Hidden by default.
+    pub fn progress(mut self, progress: ProgressMode) -> Self {
+        self.options.progress = progress;
+        self
+    }
+
+    /// Writes the archive in a format of your own instead of one of the built-in ones.
+    pub fn archive_format(mut self, format: impl ArchiveFormat + 'static) -> Self {
+        self.options.custom_format = Some(Arc::new(format));
+        self
+    }
+
+    /// Hands the progress to a sink of your own, e.g. to show it in a GUI.
+    pub fn progress_sink(mut self, sink: impl ProgressSink + 'static) -> Self {
+        self.options.progress = ProgressMode::Custom(Arc::new(sink));
+        self
+    }
+
+    /// Token to stop the archiving with from another thread.
+    pub fn cancel(mut self, cancel: CancellationToken) -> Self {
+        self.options.cancel = cancel;
+        self
+    }
+
+    pub fn keep_partial(mut self, keep_partial: bool) -> Self {
+        self.options.keep_partial = keep_partial;
+        self
+    }
+
+    pub fn on_error(mut self, on_error: OnError) -> Self {
+        self.options.on_error = on_error;
+        self
+    }
+
+    pub fn skip_space_check(mut self, skip_space_check: bool) -> Self {
+        self.options.skip_space_check = skip_space_check;
+        self
+    }
+
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.options.resume = resume;
+        self
+    }
+
+    /// Creates a differential archive holding only the files that are new or changed since the given archive of the
+    /// world, and which files were deleted since.
+    pub fn diff_base(mut self, base: impl Into<PathBuf>) -> Self {
+        self.options.diff_base = Some(base.into());
+        self
+    }
+
+    /// Updates an existing ZIP archive, compressing only the files whose size or modification time changed.
+    pub fn update(mut self, update: bool) -> Self {
+        self.options.update = update;
+        self
+    }
+
+    /// Archive with a lower CPU and disk priority than the rest of the system, using at most half of the CPUs.
+    pub fn background(mut self, background: bool) -> Self {
+        self.options.background = background;
+        self
+    }
+
+    /// Size in bytes of the buffers for reading the world's files and writing the archive. 256 KiB by default.
+    pub fn io_buffer_size(mut self, bytes: usize) -> Self {
+        self.options.io_buffer_size = bytes;
+        self
+    }
+
+    /// Lowers the zstd level batch by batch so compressing takes at most about this long. Starts at the compression
+    /// level, [`budget::START_LEVEL`] if none is set.
+    pub fn target_duration(mut self, target_duration: Duration) -> Self {
+        self.options.target_duration = Some(target_duration);
+        self
+    }
+
+    /// Puts a manifest with the size and SHA-256 of every file first into the archive, for `mwdh verify`.
+    pub fn manifest(mut self, manifest: bool) -> Self {
+        self.options.manifest = manifest;
+        self
+    }
+
+    /// Limit in bytes per second for reading the world's files, shared by all threads.
+    pub fn io_limit(mut self, bytes_per_second: u64) -> Self {
+        self.options.io_limit = Some(IoLimit::new(bytes_per_second));
+        self
+    }
+
+    /// Fills in the defaults that depend on other options, expands the archive name and validates the result.
+    pub fn build(self) -> Result<ArchiveOptions, MwdhError> {
+        let mut options = self.options;
+        options.compression_level = match self.compression_level {
+            Some(level) => level,
+            None if options.target_duration.is_some() && matches!(options.compression_format, CompressionFormat::TarZstd) => {
+                budget::START_LEVEL
+            }
+            None => options.compression_format.default_level(),
+        };
+        if options.threads == 0 {
+            options.threads = num_cpus::get();
+        }
+        if options.background {
+            options.threads = options.threads.min(priority::background_threads());
+        }
+        options.archive_name = expand_archive_name(&options.archive_name, &options.world_path, &options.world_name)
+            .map_err(|err| MwdhError::InvalidOptions(format!("{:#}", err)))?;
+        options.validate()?;
+        Ok(options)
+    }
+}
+
+#[cfg(feature = "server")]
+impl ServerOptions {
+    /// Serves on 0.0.0.0:3000 under `/world` with one thread per CPU. What to serve has to be set with
+    /// [`archive`](ServerOptionsBuilder::archive), [`live_archive`](ServerOptionsBuilder::live_archive),
+    /// [`serve_dir`](ServerOptionsBuilder::serve_dir), [`serve_latest`](ServerOptionsBuilder::serve_latest) or
+    /// [`named_archive`](ServerOptionsBuilder::named_archive).
+    pub fn builder() -> ServerOptionsBuilder {
+        ServerOptionsBuilder {
+            options: ServerOptions {
+                host_path: "world".to_string(),
+                bind: "0.0.0.0".to_string(),
+                port: 3000,
+                base_path: String::new(),
+                behind_proxy: false,
+                threads: 0,
+                path_to_archive: None,
+                compression_format: CompressionFormat::TarZstd,
+                download_filename: None,
+                content_type: None,
+                mime_types: Vec::new(),
+                zstd_content_encoding: false,
+                download_token: None,
+                idle_timeout: None,
+                max_connections: None,
+                rate_limit: None,
+                connection_rate_limit: None,
+                total_rate_limit: None,
+                access_log: None,
+                serve_dir: None,
+                serve_latest: None,
+                basic_auth: None,
+                tls: None,
+                acme: None,
+                mdns: None,
+                torrent: None,
+                named_archives: Vec::new(),
+                uploads: None,
+                cors_origins: Vec::new(),
+                client_limits: ClientLimits::default(),
+                allowed_ips: Vec::new(),
+                webhook_url: None,
+                notify: Vec::new(),
+                stats_file: None,
+                denied_ips: Vec::new(),
+                progress_events: None,
+                live_archive: None,
+                cancel: CancellationToken::new(),
+            },
+        }
+    }
+}
+
+/// Builds [`ServerOptions`], see [`ServerOptions::builder`].
+#[cfg(feature = "server")]
+pub struct ServerOptionsBuilder {
+    options: ServerOptions,
+}
+
+#[cfg(feature = "server")]
+impl ServerOptionsBuilder {
+    /// The URL path the download is served under.
+    pub fn host_path(mut self, host_path: impl Into<String>) -> Self {
+        self.options.host_path = host_path.into();
+        self
+    }
+
+    /// URL path everything is served under, like `/downloads/world` behind a reverse proxy.
+    pub fn base_path(mut self, base_path: impl AsRef<str>) -> Self {
+        self.options.base_path = server::normalize_base_path(base_path.as_ref());
+        self
+    }
+
+    /// Trust the `X-Forwarded-*` headers of the reverse proxy in front of the server.
+    pub fn behind_proxy(mut self, behind_proxy: bool) -> Self {
+        self.options.behind_proxy = behind_proxy;
+        self
+    }
+
+    pub fn bind(mut self, bind: impl Into<String>) -> Self {
+        self.options.bind = bind.into();
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.options.port = port;
+        self
+    }
+
+    /// Number of server threads, 0 for one per CPU.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.options.threads = threads;
+        self
+    }
+
+    /// Serve this .zip or .tar.zst archive. The format is taken from its file ending.
+    pub fn archive(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.path_to_archive = Some(path.into());
+        self
+    }
+
+    /// Serve the archive this currently points to, which can be swapped for another one while the server runs.
+    pub fn live_archive(mut self, live_archive: LiveArchive) -> Self {
+        self.options.live_archive = Some(live_archive);
+        self
+    }
+
+    /// Also serve this archive under `/<name>`. Can be called once per archive.
+    pub fn named_archive(mut self, name: impl Into<String>, path: impl Into<PathBuf>) -> Self {
+        self.options.named_archives.push((name.into(), path.into()));
+        self
+    }
+
+    /// List and serve all archives in this directory.
+    pub fn serve_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.options.serve_dir = Some(dir.into());
+        self
+    }
+
+    /// Always serve the newest archive in this directory.
+    pub fn serve_latest(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.options.serve_latest = Some(dir.into());
+        self
+    }
+
+    /// Offer the archive under the host path for download under this file name instead of its name on disk.
+    pub fn download_filename(mut self, file_name: impl Into<String>) -> Self {
+        self.options.download_filename = Some(file_name.into());
+        self
+    }
+
+    /// Send the archive under the host path with this Content-Type instead of the one of its format.
+    pub fn content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.options.content_type = Some(content_type.into());
+        self
+    }
+
+    /// Send archives of this format with this Content-Type, instead of the one the format comes with. Can be called
+    /// once per format.
+    pub fn mime_type(mut self, format: CompressionFormat, content_type: impl Into<String>) -> Self {
+        self.options.mime_types.retain(|(other, _)| *other != format);
+        self.options.mime_types.push((format, content_type.into()));
+        self
+    }
+
+    /// Send `.tar.zst` archives as `application/x-tar` with `Content-Encoding: zstd` to clients that accept zstd, so
+    /// browsers unpack the zstd while downloading and save a `.tar`.
+    pub fn zstd_content_encoding(mut self, zstd_content_encoding: bool) -> Self {
+        self.options.zstd_content_encoding = zstd_content_encoding;
+        self
+    }
+
+    pub fn download_token(mut self, token: impl Into<String>) -> Self {
+        self.options.download_token = Some(token.into());
+        self
+    }
+
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.options.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.options.max_connections = Some(max_connections);
+        self
+    }
+
+    /// Download speed limit per client IP in bytes per second.
+    pub fn rate_limit(mut self, bytes_per_second: u64) -> Self {
+        self.options.rate_limit = Some(bytes_per_second);
+        self
+    }
+
+    /// Download speed limit per connection in bytes per second.
+    pub fn connection_rate_limit(mut self, bytes_per_second: u64) -> Self {
+        self.options.connection_rate_limit = Some(bytes_per_second);
+        self
+    }
+
+    /// Download speed limit of all downloads together in bytes per second. Downloads running at the same time get an
+    /// even share of it, and what one doesn't use goes to the others.
+    pub fn total_rate_limit(mut self, bytes_per_second: u64) -> Self {
+        self.options.total_rate_limit = Some(bytes_per_second);
+        self
+    }
+
+    pub fn access_log(mut self, access_log: AccessLogTarget) -> Self {
+        self.options.access_log = Some(access_log);
+        self
+    }
+
+    /// `user:password` credentials for HTTP Basic authentication.
+    pub fn basic_auth(mut self, credentials: impl Into<String>) -> Self {
+        self.options.basic_auth = Some(credentials.into());
+        self
+    }
+
+    /// Serve HTTPS. With a [client CA](TlsOptions::client_ca) only clients with a certificate signed by it can
+    /// connect.
+    pub fn tls(mut self, tls: TlsOptions) -> Self {
+        self.options.tls = Some(tls);
+        self
+    }
+
+    /// Serve HTTPS with a certificate from an ACME CA like Let's Encrypt. The CA checks the domains on port 443, which
+    /// has to reach the server. Serving fails unless [`AcmeOptions::accept_tos`] is set.
+    pub fn acme(mut self, acme: AcmeOptions) -> Self {
+        self.options.acme = Some(acme);
+        self
+    }
+
+    /// Announce the server on the LAN with mDNS as `<name>.local`, so it can be found without knowing the host's IP.
+    pub fn mdns(mut self, name: impl Into<String>) -> Self {
+        self.options.mdns = Some(name.into());
+        self
+    }
+
+    /// Serve a `.torrent` of every single archive at `/<path>.torrent`, with the server as web seed.
+    pub fn torrent(mut self, torrent: TorrentOptions) -> Self {
+        self.options.torrent = Some(torrent);
+        self
+    }
+
+    /// Stream the progress reported to `progress_events` at `/events`. Pass a clone of it to the archiving as
+    /// progress sink.
+    pub fn progress_events(mut self, progress_events: ProgressEvents) -> Self {
+        self.options.progress_events = Some(progress_events);
+        self
+    }
+
+    /// Only serve clients in this network. Can be called once per network.
+    pub fn allow_ip(mut self, net: IpNet) -> Self {
+        self.options.allowed_ips.push(net);
+        self
+    }
+
+    /// Turn away clients in this network, even when they're in an allowed one. Can be called once per network.
+    pub fn deny_ip(mut self, net: IpNet) -> Self {
+        self.options.denied_ips.push(net);
+        self
+    }
+
+    /// Post every finished or broken off download to this URL as JSON, with the client's IP, the bytes sent, how
+    /// long it took and whether it was complete.
+    pub fn webhook_url(mut self, url: Url) -> Self {
+        self.options.webhook_url = Some(url);
+        self
+    }
+
+    /// Send a notification here when an archive was downloaded completely for the first time. Can be called once per
+    /// target.
+    pub fn notify(mut self, target: NotifyTarget) -> Self {
+        self.options.notify.push(target);
+        self
+    }
+
+    /// Keep the download statistics in this JSON file, so they add up across restarts.
+    pub fn stats_file(mut self, stats_file: impl Into<PathBuf>) -> Self {
+        self.options.stats_file = Some(stats_file.into());
+        self
+    }
+
+    /// Timeouts for slow clients and the watchdog dropping stalled downloads, see [`ClientLimits::default`].
+    pub fn client_limits(mut self, client_limits: ClientLimits) -> Self {
+        self.options.client_limits = client_limits;
+        self
+    }
+
+    /// Let scripts on this origin, like `https://map.example.com`, fetch from the server. `*` allows any origin. Can
+    /// be called once per origin.
+    pub fn cors_origin(mut self, origin: impl AsRef<str>) -> Self {
+        self.options.cors_origins.push(server::cors::normalize_origin(origin.as_ref()));
+        self
+    }
+
+    /// Accept archives sent with `mwdh push` at `PUT /upload/<file name>`.
+    pub fn uploads(mut self, uploads: UploadOptions) -> Self {
+        self.options.uploads = Some(uploads);
+        self
+    }
+
+    /// Token to stop the server with from another task.
+    pub fn cancel(mut self, cancel: CancellationToken) -> Self {
+        self.options.cancel = cancel;
+        self
+    }
+
+    pub fn build(self) -> Result<ServerOptions, MwdhError> {
+        let mut options = self.options;
+        let sources = [
+            options.path_to_archive.is_some() || options.live_archive.is_some(),
+            options.serve_dir.is_some(),
+            options.serve_latest.is_some(),
+        ];
+        match sources.iter().filter(|&&set| set).count() {
+            0 if !options.named_archives.is_empty() || options.uploads.is_some() => {}
+            0 => return Err(invalid("Nothing to serve, set an archive, a named archive, a directory to serve or a directory to serve the latest archive of")),
+            1 => {}
+            _ => return Err(invalid("Only one of an archive, a directory to serve and a directory to serve the latest archive of can be set")),
+        }
+        if let Some(ref path_to_archive) = options.path_to_archive {
+            options.compression_format = CompressionFormat::from_path(path_to_archive)
+                .ok_or_else(|| invalid(format!("Unknown archive type of {}, expected a .zip or .tar.zst file", path_to_archive.display())))?;
+        }
+        server::check_archive_names(&options).map_err(invalid)?;
+        server::check_download_overrides(&options).map_err(invalid)?;
+        if options.port == 0 {
+            return Err(invalid("The port can't be 0"));
+        }
+        if [options.rate_limit, options.connection_rate_limit, options.total_rate_limit].contains(&Some(0)) {
+            return Err(invalid("The rate limit has to be greater than 0"));
+        }
+        let limits = &options.client_limits;
+        if limits.header_timeout.is_zero() || limits.body_timeout.is_zero() || limits.stall_timeout.is_zero() {
+            return Err(invalid("Client timeouts have to be greater than 0"));
+        }
+        if let Some(ref uploads) = options.uploads {
+            if uploads.token.is_empty() {
+                return Err(invalid("The upload token can't be empty"));
+            }
+            if uploads.max_size == 0 {
+                return Err(invalid("The upload size limit has to be greater than 0"));
+            }
+        }
+        if let Some(ref acme) = options.acme {
+            if options.tls.is_some() {
+                return Err(invalid("Either a certificate or ACME can be used for HTTPS, not both"));
+            }
+            if acme.domains.is_empty() {
+                return Err(invalid("ACME needs at least one domain"));
+            }
+            if acme.domains.iter().any(|domain| domain.starts_with("*.")) {
+                return Err(invalid("ACME can't get wildcard certificates, the TLS-ALPN-01 challenge doesn't allow them"));
+            }
+        }
+        if let Some(ref name) = options.mdns {
+            server::check_mdns_name(name).map_err(invalid)?;
+        }
+        if options.basic_auth.as_ref().is_some_and(|auth| !auth.contains(':')) {
+            return Err(invalid("Basic authentication credentials have to be in the form user:password"));
+        }
+        if options.threads == 0 {
+            options.threads = num_cpus::get();
+        }
+        Ok(options)
+    }
+}
@@ -0,0 +1,241 @@
+//! `mwdh download`, the receiving side of `mwdh host`. Interrupted downloads continue where they stopped, as long as
+//! the archive on the server didn't change in between, and the archive is checked before it's used.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow};
+use futures_util::StreamExt;
+use reqwest::{
+    Client, Response, StatusCode,
+    header::{CONTENT_DISPOSITION, CONTENT_RANGE, ETAG, IF_RANGE, RANGE},
+};
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::{
+    CompressionFormat,
+    archive::{partial_path, progress::transfer_bar, sha256_file},
+    extract::{self, ExtractOptions},
+    format_bytes, logging,
+    verify::{self, VerifyOptions},
+};
+
+#[derive(Clone)]
+pub struct DownloadOptions {
+    /// URL of the archive, like `http://mc.example.com:3000/world`
+    pub url: Url,
+    /// Directory the archive is saved in
+    pub output_dir: PathBuf,
+    /// Extract the archive into the output directory once it's downloaded and checked
+    pub extract: bool,
+    /// Whether an archive with the same name and the extracted files may be replaced
+    pub overwrite: bool,
+}
+
+/// Downloads the archive into `<name>.part`, continuing an earlier download of the same archive, and only gives it
+/// its name once it's complete. It's then checked against the SHA-256 the server publishes at `<url>.sha256` or,
+/// without one, against its manifest.
+pub async fn download(options: &DownloadOptions) -> Result<()> {
+    let client = Client::new();
+    let head = check(client.head(options.url.clone()).send().await.context("Download request failed")?)?;
+    let file_name = file_name(&head, &options.url)?;
+    let archive = options.output_dir.join(&file_name);
+    if archive.exists() && !options.overwrite {
+        return Err(anyhow!("{} already exists. Pass --overwrite to replace it", archive.display()));
+    }
+    std::fs::create_dir_all(&options.output_dir)
+        .with_context(|| format!("Failed to create: {}", options.output_dir.display()))?;
+
+    let part_path = partial_path(&archive);
+    download_to(&client, &options.url, &part_path).await?;
+    std::fs::rename(&part_path, &archive)?;
+    std::fs::remove_file(etag_path(&part_path)).ok();
+    info!(target: logging::SUMMARY, "Downloaded {}", archive.display());
+
+    if let Err(err) = check_archive(&client, &options.url, &archive).await {
+        // so the next try starts from scratch instead of keeping the broken archive
+        std::fs::remove_file(&archive).ok();
+        return Err(err);
+    }
+
+    if options.extract {
+        extract::extract(&ExtractOptions {
+            archive: archive.clone(),
+            output_dir: options.output_dir.clone(),
+            overwrite: options.overwrite,
+            recompress_regions: true,
+            apply_diff: false,
+        })?;
+    }
+    Ok(())
+}
+
+/// Downloads into `part_path`. What's already in there is kept if the server still has the same archive, which it
+/// tells by answering the `If-Range` with the ETag of the first try with just the rest.
+async fn download_to(client: &Client, url: &Url, part_path: &Path) -> Result<()> {
+    let etag_path = etag_path(part_path);
+    let downloaded = std::fs::metadata(part_path).map(|metadata| metadata.len()).unwrap_or(0);
+    let saved_etag = std::fs::read_to_string(&etag_path).ok();
+    let mut request = client.get(url.clone());
+    if let Some(ref etag) = saved_etag
+        && downloaded > 0
+    {
+        request = request.header(RANGE, format!("bytes={}-", downloaded)).header(IF_RANGE, etag.trim());
+    }
+    let response = request.send().await.context("Download request failed")?;
+
+    match response.status() {
+        StatusCode::PARTIAL_CONTENT
+            if range_start(&response) == Some(downloaded)
+                && response.headers().get(ETAG).and_then(|etag| etag.to_str().ok()) == saved_etag.as_deref().map(str::trim) =>
+        {
+            info!("Continuing the download at {}", format_bytes(downloaded));
+            write_body(response, part_path, &etag_path, downloaded).await
+        }
+        // a server or proxy that ignores If-Range could send the rest of another archive
+        StatusCode::PARTIAL_CONTENT => {
+            std::fs::remove_file(part_path).ok();
+            std::fs::remove_file(&etag_path).ok();
+            Err(anyhow!("The server sent a part of a different archive, run the same command again to start over"))
+        }
+        // the part file already holds all of it
+        StatusCode::RANGE_NOT_SATISFIABLE if range_total(&response) == Some(downloaded) => Ok(()),
+        _ => {
+            let response = check(response)?;
+            if downloaded > 0 {
+                info!("The archive changed since the last try, starting over");
+            }
+            write_body(response, part_path, &etag_path, 0).await
+        }
+    }
+}
+
+/// Writes the body to `part_path`, appending to the first `offset` bytes that are already there.
+async fn write_body(response: Response, part_path: &Path, etag_path: &Path, offset: u64) -> Result<()> {
+    // weak ETags can't be used to continue a download
+    match response.headers().get(ETAG).and_then(|etag| etag.to_str().ok()) {
+        Some(etag) if !etag.starts_with("W/") => std::fs::write(etag_path, etag)?,
+        _ => {
+            std::fs::remove_file(etag_path).ok();
+        }
+    }
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(offset > 0)
+        .truncate(offset == 0)
+        .open(part_path)
+        .await
+        .with_context(|| format!("Failed to open: {}", part_path.display()))?;
+
+    let progress_bar = transfer_bar(offset + response.content_length().unwrap_or(0), "Downloading");
+    progress_bar.set_position(offset);
+    let mut body = response.bytes_stream();
+    let result = async {
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.context("Download interrupted, run the same command again to continue")?;
+            file.write_all(&chunk).await?;
+            progress_bar.inc(chunk.len() as u64);
+        }
+        file.sync_all().await?;
+        anyhow::Ok(())
+    }
+    .await;
+    match result {
+        Ok(()) => progress_bar.finish_with_message("Download complete"),
+        Err(_) => progress_bar.abandon_with_message("Download failed"),
+    }
+    result
+}
+
+/// Compares the archive to the SHA-256 the server publishes next to it, or to its manifest if there's none.
+async fn check_archive(client: &Client, url: &Url, archive: &Path) -> Result<()> {
+    if let Some(expected) = published_sha256(client, url).await? {
+        let sha256 = sha256_file(archive)?;
+        if !sha256.eq_ignore_ascii_case(&expected) {
+            return Err(anyhow!(
+                "{} doesn't match the SHA-256 the server published, it was deleted. Download it again",
+                archive.display()
+            ));
+        }
+        info!(target: logging::SUMMARY, "The SHA-256 matches the one the server published");
+        return Ok(());
+    }
+    // encrypted archives can't be opened to look for the manifest
+    if CompressionFormat::from_file_extension(archive.extension()).is_some() && verify::has_manifest(archive)? {
+        return verify::verify(&VerifyOptions {
+            archive: archive.to_path_buf(),
+        });
+    }
+    warn!("Couldn't check {}, the server publishes no SHA-256 and the archive has no manifest", archive.display());
+    Ok(())
+}
+
+/// The SHA-256 at `<url>.sha256`, in the format of `sha256sum` or just the hash. `None` when there's none.
+async fn published_sha256(client: &Client, url: &Url) -> Result<Option<String>> {
+    let mut sha256_url = url.clone();
+    sha256_url.set_path(&format!("{}.sha256", url.path()));
+    let response = client.get(sha256_url).send().await.context("Request for the SHA-256 failed")?;
+    if !response.status().is_success() {
+        return Ok(None);
+    }
+    let text = response.text().await?;
+    Ok(text
+        .split_whitespace()
+        .next()
+        .filter(|hash| hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()))
+        .map(str::to_string))
+}
+
+/// The file name from `Content-Disposition`, or the last segment of the URL.
+fn file_name(response: &Response, url: &Url) -> Result<String> {
+    let from_header = response
+        .headers()
+        .get(CONTENT_DISPOSITION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_once("filename=\""))
+        .and_then(|(_, rest)| rest.split_once('"'))
+        .map(|(name, _)| name);
+    let from_url = url.path_segments().and_then(|mut segments| segments.next_back());
+    // only the name, the server doesn't get to pick the directory
+    from_header
+        .or(from_url)
+        .and_then(|name| Path::new(name).file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| anyhow!("Can't tell the archive's file name from {}", url))
+}
+
+/// Where the ETag of a download is kept until it's complete.
+fn etag_path(part_path: &Path) -> PathBuf {
+    let mut path = part_path.as_os_str().to_owned();
+    path.push(".etag");
+    PathBuf::from(path)
+}
+
+fn check(response: Response) -> Result<Response> {
+    if response.status().is_success() {
+        Ok(response)
+    } else {
+        Err(anyhow!("Download failed: HTTP {}", response.status()))
+    }
+}
+
+/// Where the range of a `206 Partial Content` starts.
+fn range_start(response: &Response) -> Option<u64> {
+    content_range(response)?
+        .strip_prefix("bytes ")?
+        .split_once('-')?
+        .0
+        .parse()
+        .ok()
+}
+
+/// The size of the whole archive from a `Content-Range`.
+fn range_total(response: &Response) -> Option<u64> {
+    content_range(response)?.rsplit_once('/')?.1.parse().ok()
+}
+
+fn content_range(response: &Response) -> Option<&str> {
+    response.headers().get(CONTENT_RANGE)?.to_str().ok()
+}
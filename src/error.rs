@@ -0,0 +1,135 @@
+use std::{error::Error, fmt, path::PathBuf};
+
+use crate::cancel::Cancelled;
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Why [`do_compression`](crate::archive::do_compression) or [`run_server`](crate::server::run_server) failed.
+/// The boxed errors carry the details, their chain of [`source`](Error::source)s explains the cause.
+#[non_exhaustive]
+pub enum MwdhError {
+    /// The options don't work together, see [`ArchiveOptions::validate`](crate::ArchiveOptions::validate)
+    InvalidOptions(String),
+    /// The world path doesn't exist
+    WorldNotFound(PathBuf),
+    /// The world path isn't a directory
+    WorldNotADirectory(PathBuf),
+    /// The archive already exists and overwriting wasn't allowed
+    ArchiveExists(PathBuf),
+    /// Reading the world's files failed
+    Scan(BoxError),
+    /// Compressing or writing the archive failed
+    Compression(BoxError),
+    /// The pre-hook or the post-hook failed
+    Hook(BoxError),
+    /// Uploading the finished archive failed
+    Upload(BoxError),
+    /// Pruning old archives after archiving failed
+    Prune(BoxError),
+    /// The server couldn't start or stopped with an error
+    Server(BoxError),
+    /// The operation was stopped with its [`CancellationToken`](crate::cancel::CancellationToken)
+    Cancelled,
+    /// Any other I/O error
+    Io(std::io::Error),
+}
+
+impl MwdhError {
+    /// Sorts an error of the archiving into cancellation, scanning or compression.
+    pub(crate) fn from_archiving(err: anyhow::Error) -> MwdhError {
+        if err.downcast_ref::<Cancelled>().is_some() {
+            MwdhError::Cancelled
+        } else if err.downcast_ref::<ScanFailed>().is_some() {
+            MwdhError::Scan(err.into())
+        } else {
+            MwdhError::Compression(err.into())
+        }
+    }
+
+    fn inner(&self) -> Option<&(dyn Error + Send + Sync + 'static)> {
+        match self {
+            MwdhError::Scan(err)
+            | MwdhError::Compression(err)
+            | MwdhError::Hook(err)
+            | MwdhError::Upload(err)
+            | MwdhError::Prune(err)
+            | MwdhError::Server(err) => Some(err.as_ref()),
+            MwdhError::Io(err) => Some(err),
+            MwdhError::InvalidOptions(_)
+            | MwdhError::WorldNotFound(_)
+            | MwdhError::WorldNotADirectory(_)
+            | MwdhError::ArchiveExists(_)
+            | MwdhError::Cancelled => None,
+        }
+    }
+}
+
+impl fmt::Display for MwdhError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MwdhError::InvalidOptions(message) => f.write_str(message),
+            MwdhError::WorldNotFound(path) => write!(f, "{} does not exist", path.display()),
+            MwdhError::WorldNotADirectory(path) => write!(f, "{} should be a directory", path.display()),
+            MwdhError::ArchiveExists(path) => write!(
+                f,
+                "{} already exists. Pass --overwrite to replace it or use placeholders like {{date}} in the file name",
+                path.display()
+            ),
+            MwdhError::Cancelled => write!(f, "{}", Cancelled),
+            // `{:#}` prints the whole chain of causes, like it does for anyhow errors. Boxed anyhow errors only print
+            // their own message, so the chain is walked here.
+            _ => match self.inner() {
+                Some(err) if f.alternate() => {
+                    write!(f, "{}", err)?;
+                    let mut source = err.source();
+                    while let Some(cause) = source {
+                        write!(f, ": {}", cause)?;
+                        source = cause.source();
+                    }
+                    Ok(())
+                }
+                Some(err) => write!(f, "{}", err),
+                None => Ok(()),
+            },
+        }
+    }
+}
+
+/// Prints the message and its causes, which is what `main` shows when returning an error.
+impl fmt::Debug for MwdhError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // io::Error's Debug prints its fields, its Display the message
+            MwdhError::Io(err) => fmt::Display::fmt(err, f),
+            _ => match self.inner() {
+                Some(err) => fmt::Debug::fmt(err, f),
+                None => fmt::Display::fmt(self, f),
+            },
+        }
+    }
+}
+
+impl Error for MwdhError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        // the inner error's message is this error's message, so its causes come next
+        self.inner().and_then(|err| err.source())
+    }
+}
+
+impl From<std::io::Error> for MwdhError {
+    fn from(err: std::io::Error) -> Self {
+        MwdhError::Io(err)
+    }
+}
+
+/// Context marking errors of the scan phase, so they can be told apart from compression errors.
+#[derive(Debug)]
+pub(crate) struct ScanFailed;
+
+impl fmt::Display for ScanFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to scan the world")
+    }
+}
+
+impl Error for ScanFailed {}
@@ -0,0 +1,536 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, Read},
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use tracing::{info, warn};
+
+use crate::{CompressionFormat, archive::{diff::{DIFF_NAME, DiffInfo}, zip::zip_time_to_system_time}, logging, manifest::MANIFEST_NAME, paths, region::{self, Region}};
+
+/// A region file's header and every chunk at the most sectors it can take up
+const MAX_REGION_SIZE: u64 = ((2 + region::CHUNKS_PER_REGION * region::MAX_CHUNK_SECTORS) * region::SECTOR_SIZE) as u64;
+
+#[derive(Clone)]
+pub struct ExtractOptions {
+    /// The .zip or .tar.zst archive to extract
+    pub archive: PathBuf,
+    /// Directory to extract into
+    pub output_dir: PathBuf,
+    /// Whether existing files may be replaced
+    pub overwrite: bool,
+    /// Compress region file chunks that were stored uncompressed with `--raw-region-chunks` again, the way they were before
+    pub recompress_regions: bool,
+    /// Delete the files a differential archive lists as deleted from the output directory
+    pub apply_diff: bool,
+}
+
+/// Extracts an archive created by mwdh, undoing the region file transforms. The manifest and the list of deleted
+/// files of a differential archive aren't part of the world and stay in the archive.
+pub fn extract(options: &ExtractOptions) -> Result<()> {
+    if options.archive.extension().is_some_and(|ext| ext == "age") {
+        return Err(anyhow!("The archive is encrypted, decrypt it first with `age -d`"));
+    }
+    let format = CompressionFormat::from_file_extension(options.archive.extension())
+        .ok_or_else(|| anyhow!("Unknown archive type, expected a .zip or .tar.zst file"))?;
+    std::fs::create_dir_all(&options.output_dir)
+        .with_context(|| format!("Failed to create: {}", options.output_dir.display()))?;
+    let file = File::open(&options.archive)
+        .with_context(|| format!("Failed to open: {}", options.archive.display()))?;
+    let mut diff = None;
+    let extracted = match format {
+        CompressionFormat::TarZstd => extract_tar_zstd(file, options, &mut diff)?,
+        CompressionFormat::ZipDeflate => extract_zip(file, options, &mut diff)?,
+    };
+    info!(target: logging::SUMMARY, "Extracted {} files to {}", extracted, options.output_dir.display());
+    match (diff, options.apply_diff) {
+        (Some(diff), true) => {
+            let deleted = delete_files(&options.output_dir, &diff.deleted)?;
+            info!(target: logging::SUMMARY, "Deleted {} files that were deleted since {}", deleted, diff.base);
+        }
+        (Some(diff), false) => warn!(
+            "{} only contains the changes since {}. Extract that one first and apply this one onto it with --apply-onto",
+            options.archive.display(),
+            diff.base
+        ),
+        (None, true) => warn!("{} isn't a differential archive, no files were deleted", options.archive.display()),
+        (None, false) => {}
+    }
+    Ok(())
+}
+
+/// Deletes the files of a differential archive's list that are still there.
+fn delete_files(output_dir: &Path, paths: &[String]) -> Result<usize> {
+    let mut deleted = 0;
+    for path in paths {
+        let target = target_path(output_dir, Path::new(path))?;
+        // a link extracted from the archive mustn't lead the deletion outside the output directory
+        if let Some(parent) = target.parent()
+            && !is_inside(output_dir, parent)?
+        {
+            return Err(anyhow!("Refusing to delete {}, a symbolic link leads it outside the output directory", path));
+        }
+        match std::fs::remove_file(&target) {
+            Ok(()) => deleted += 1,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err).with_context(|| format!("Failed to delete: {}", target.display())),
+        }
+    }
+    Ok(deleted)
+}
+
+fn read_diff(entry: &mut impl Read) -> Result<DiffInfo> {
+    let mut bytes = Vec::new();
+    entry.read_to_end(&mut bytes)?;
+    DiffInfo::from_json(&bytes)
+}
+
+fn extract_tar_zstd(file: File, options: &ExtractOptions, diff: &mut Option<DiffInfo>) -> Result<usize> {
+    // the parallel mode writes one zstd frame per batch, the decoder reads through all of them
+    let mut decoder = zstd::Decoder::new(file)?;
+    decoder.window_log_max(31)?; // archives created with a large --zstd-long window
+    let mut archive = tar::Archive::new(decoder);
+    archive.set_preserve_mtime(true);
+    let output_root = paths::long_path(&options.output_dir);
+    let mut extracted = 0;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if path == Path::new(DIFF_NAME) {
+            *diff = Some(read_diff(&mut entry)?);
+            continue;
+        }
+        if path == Path::new(MANIFEST_NAME) {
+            continue;
+        }
+        let target = target_path(&options.output_dir, &path)?;
+        if entry.header().entry_type().is_dir() {
+            prepare_dir(&options.output_dir, &target)?;
+            continue;
+        }
+        check_overwrite(&target, options)?;
+        if options.recompress_regions && is_region_file(&path) {
+            let bytes = read_region_entry(&mut entry, &path)?;
+            write_region_file(&options.output_dir, &target, bytes)?;
+            if let Ok(mtime) = entry.header().mtime() {
+                set_mtime(&target, std::time::UNIX_EPOCH + std::time::Duration::from_secs(mtime))?;
+            }
+        } else if !entry.unpack_in(&output_root)? {
+            // tar checks that links and the files written through them stay inside the output directory
+            return Err(anyhow!("Refusing to extract {}, it points outside the output directory", path.display()));
+        }
+        extracted += 1;
+    }
+    Ok(extracted)
+}
+
+fn extract_zip(file: File, options: &ExtractOptions, diff: &mut Option<DiffInfo>) -> Result<usize> {
+    let mut archive = zip::ZipArchive::new(BufReader::new(file))?;
+    let mut extracted = 0;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let path = entry
+            .enclosed_name()
+            .ok_or_else(|| anyhow!("Refusing to extract {}, it points outside the output directory", entry.name()))?;
+        if path == Path::new(DIFF_NAME) {
+            *diff = Some(read_diff(&mut entry)?);
+            continue;
+        }
+        if path == Path::new(MANIFEST_NAME) {
+            continue;
+        }
+        let target = target_path(&options.output_dir, &path)?;
+        if entry.is_dir() {
+            prepare_dir(&options.output_dir, &target)?;
+            continue;
+        }
+        check_overwrite(&target, options)?;
+        if options.recompress_regions && is_region_file(&path) {
+            let bytes = read_region_entry(&mut entry, &path)?;
+            write_region_file(&options.output_dir, &target, bytes)?;
+        } else {
+            prepare_target(&options.output_dir, &target)?;
+            let mut file = File::create(&target).with_context(|| format!("Failed to write: {}", target.display()))?;
+            io::copy(&mut entry, &mut file).with_context(|| format!("Failed to write: {}", target.display()))?;
+        }
+        if let Some(mtime) = entry.last_modified().and_then(zip_time_to_system_time) {
+            set_mtime(&target, mtime)?;
+        }
+        #[cfg(unix)]
+        if let Some(mode) = entry.unix_mode() {
+            use std::os::unix::fs::PermissionsExt;
+            // no setuid, setgid or sticky bits from an archive that may come from anywhere
+            std::fs::set_permissions(&target, std::fs::Permissions::from_mode(mode & 0o777))?;
+        }
+        extracted += 1;
+    }
+    Ok(extracted)
+}
+
+/// Joins an archive entry's path onto the output directory, rejecting absolute paths, `..` and names Windows can't create.
+fn target_path(output_dir: &Path, path: &Path) -> Result<PathBuf> {
+    let mut target = paths::long_path(output_dir);
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => {
+                if cfg!(windows) && paths::is_reserved_name(&name.to_string_lossy()) {
+                    return Err(anyhow!(
+                        "Can't extract {}, {} is a reserved file name on Windows",
+                        path.display(),
+                        name.to_string_lossy()
+                    ));
+                }
+                target.push(name);
+            }
+            Component::CurDir => {}
+            _ => {
+                return Err(anyhow!(
+                    "Refusing to extract {}, it points outside the output directory",
+                    path.display()
+                ));
+            }
+        }
+    }
+    Ok(target)
+}
+
+fn check_overwrite(target: &Path, options: &ExtractOptions) -> Result<()> {
+    if !options.overwrite && target.exists() {
+        return Err(anyhow!(
+            "{} already exists. Pass --overwrite to replace existing files",
+            target.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Creates the parent directories of a file that's written without tar's help, making sure a symbolic link extracted
+/// before doesn't lead the file outside the output directory. A link in place of the file itself is replaced.
+fn prepare_target(output_dir: &Path, target: &Path) -> Result<()> {
+    let Some(parent) = target.parent() else {
+        return Ok(());
+    };
+    if !is_inside(output_dir, parent)? {
+        return Err(anyhow!("Refusing to extract {}, a symbolic link leads it outside the output directory", target.display()));
+    }
+    std::fs::create_dir_all(parent)?;
+    if std::fs::symlink_metadata(target).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+        std::fs::remove_file(target)?;
+    }
+    Ok(())
+}
+
+/// Creates the directory of a directory entry, with the same check as [`prepare_target`].
+fn prepare_dir(output_dir: &Path, target: &Path) -> Result<()> {
+    if !is_inside(output_dir, target)? {
+        return Err(anyhow!("Refusing to extract {}, a symbolic link leads it outside the output directory", target.display()));
+    }
+    std::fs::create_dir_all(target)?;
+    Ok(())
+}
+
+/// Whether `path` resolves to somewhere inside the output directory once symbolic links are followed. Only the part of
+/// it that exists is looked at, the directories created below that can't be links.
+fn is_inside(output_dir: &Path, path: &Path) -> Result<bool> {
+    let mut existing = path;
+    while std::fs::symlink_metadata(existing).is_err() {
+        match existing.parent() {
+            Some(parent) => existing = parent,
+            None => return Ok(false),
+        }
+    }
+    // a dangling link can't be resolved, and could point anywhere once its target appears
+    let Ok(existing) = std::fs::canonicalize(existing) else {
+        return Ok(false);
+    };
+    Ok(existing.starts_with(std::fs::canonicalize(output_dir)?))
+}
+
+fn is_region_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(region::region_coords)
+        .is_some()
+}
+
+/// Reads a region file out of the archive to recompress its chunks. The size the archive claims isn't trusted, the
+/// entry is read up to the largest size a region file can have.
+fn read_region_entry(entry: &mut impl Read, path: &Path) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    entry.take(MAX_REGION_SIZE + 1).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 > MAX_REGION_SIZE {
+        bail!("{} is larger than a region file can be", path.display());
+    }
+    Ok(bytes)
+}
+
+/// Writes a region file with the chunks `--raw-region-chunks` decompressed compressed again. Files that don't parse cleanly are written untouched.
+fn write_region_file(output_dir: &Path, target: &Path, bytes: Vec<u8>) -> Result<()> {
+    let (mut region, issues) = Region::parse(&bytes);
+    let bytes = if issues.is_empty() && region.compress_raw_chunks()? {
+        region.to_bytes()?
+    } else {
+        bytes
+    };
+    prepare_target(output_dir, target)?;
+    std::fs::write(target, bytes).with_context(|| format!("Failed to write: {}", target.display()))
+}
+
+fn set_mtime(target: &Path, mtime: SystemTime) -> Result<()> {
+    File::options().write(true).open(target)?.set_modified(mtime)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of its own under the system's temp directory, removed again when dropped.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("mwdh-extract-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            TestDir(dir)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    enum Entry<'a> {
+        File(&'a str, &'a [u8]),
+        Dir(&'a str),
+        Symlink(&'a str, &'a str),
+        HardLink(&'a str, &'a str),
+    }
+
+    /// Writes a .tar.zst with the entry names as they are, which `tar::Header::set_path` wouldn't allow for `..`.
+    fn write_archive(path: &Path, entries: &[Entry]) {
+        let encoder = zstd::Encoder::new(File::create(path).unwrap(), 3).unwrap().auto_finish();
+        let mut builder = tar::Builder::new(encoder);
+        for entry in entries {
+            let (name, entry_type, data, link): (&str, tar::EntryType, &[u8], &str) = match *entry {
+                Entry::File(name, data) => (name, tar::EntryType::Regular, data, ""),
+                Entry::Dir(name) => (name, tar::EntryType::Directory, &[], ""),
+                Entry::Symlink(name, link) => (name, tar::EntryType::Symlink, &[], link),
+                Entry::HardLink(name, link) => (name, tar::EntryType::Link, &[], link),
+            };
+            let mut header = tar::Header::new_gnu();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+            header.set_entry_type(entry_type);
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, data).unwrap();
+        }
+        builder.into_inner().unwrap();
+    }
+
+    fn extract_options(archive: PathBuf, output_dir: PathBuf) -> ExtractOptions {
+        ExtractOptions {
+            archive,
+            output_dir,
+            overwrite: false,
+            recompress_regions: true,
+            apply_diff: false,
+        }
+    }
+
+    #[test]
+    fn target_paths_keep_the_saves_layout() {
+        let output_dir = Path::new("out");
+        let target = target_path(output_dir, Path::new("saves/New World/region/r.0.0.mca")).unwrap();
+        assert_eq!(target, paths::long_path(output_dir).join("saves").join("New World").join("region").join("r.0.0.mca"));
+        let target = target_path(output_dir, Path::new("./saves/New World/level.dat")).unwrap();
+        assert_eq!(target, paths::long_path(output_dir).join("saves").join("New World").join("level.dat"));
+    }
+
+    #[test]
+    fn target_paths_stay_inside_the_output_directory() {
+        let output_dir = Path::new("out");
+        for path in ["../level.dat", "saves/../../level.dat", "saves/world/..", "/etc/passwd"] {
+            assert!(target_path(output_dir, Path::new(path)).is_err(), "{} should be refused", path);
+        }
+    }
+
+    #[test]
+    fn reserved_names_are_refused_on_windows() {
+        let target = target_path(Path::new("out"), Path::new("saves/world/data/CON.dat"));
+        assert_eq!(target.is_err(), cfg!(windows));
+    }
+
+    #[test]
+    fn extracts_a_world() {
+        let dir = TestDir::new("world");
+        let archive = dir.0.join("world.tar.zst");
+        let region = vec![0u8; 8192];
+        write_archive(&archive, &[
+            Entry::File("saves/world/level.dat", b"level"),
+            Entry::File("saves/world/region/r.0.0.mca", &region),
+        ]);
+        let output_dir = dir.0.join("out");
+        extract(&extract_options(archive, output_dir.clone())).unwrap();
+        assert_eq!(std::fs::read(output_dir.join("saves/world/level.dat")).unwrap(), b"level");
+        assert_eq!(std::fs::read(output_dir.join("saves/world/region/r.0.0.mca")).unwrap(), region);
+    }
+
+    #[test]
+    fn extracts_a_zip_recompressing_region_files() {
+        let dir = TestDir::new("zip");
+        let nbt = [10, 0, 0, 0];
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, &nbt).unwrap();
+        let mut region = Region {
+            chunks: vec![None; region::CHUNKS_PER_REGION],
+        };
+        region.chunks[0] = Some(region::RegionChunk {
+            timestamp: 1,
+            compression: region::COMPRESSION_ZLIB,
+            data: encoder.finish().unwrap(),
+            decompressed_from: None,
+        });
+        // like --raw-region-chunks
+        assert!(region.decompress_chunks());
+        let stored_raw = region.to_bytes().unwrap();
+
+        let archive = dir.0.join("world.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&archive).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        zip.start_file("world/level.dat", options).unwrap();
+        std::io::Write::write_all(&mut zip, b"level").unwrap();
+        zip.start_file("world/region/r.0.0.mca", options).unwrap();
+        std::io::Write::write_all(&mut zip, &stored_raw).unwrap();
+        zip.finish().unwrap();
+
+        let output_dir = dir.0.join("out");
+        extract(&extract_options(archive, output_dir.clone())).unwrap();
+        assert_eq!(std::fs::read(output_dir.join("world/level.dat")).unwrap(), b"level");
+        let (extracted, issues) = Region::read(&output_dir.join("world/region/r.0.0.mca")).unwrap();
+        assert!(issues.is_empty());
+        let chunk = extracted.chunks[0].as_ref().unwrap();
+        assert_eq!(chunk.compression, region::COMPRESSION_ZLIB);
+        assert_eq!(chunk.decompress().unwrap().unwrap(), nbt);
+    }
+
+    #[test]
+    fn region_entries_are_read_up_to_the_largest_region_file() {
+        let mut entry = std::io::repeat(0).take(3 * region::SECTOR_SIZE as u64);
+        assert_eq!(read_region_entry(&mut entry, Path::new("r.0.0.mca")).unwrap().len(), 3 * region::SECTOR_SIZE);
+    }
+
+    #[test]
+    fn refuses_entries_leaving_the_output_directory() {
+        let dir = TestDir::new("dotdot");
+        let archive = dir.0.join("evil.tar.zst");
+        write_archive(&archive, &[Entry::File("../escaped.txt", b"pwned")]);
+        let output_dir = dir.0.join("out");
+        assert!(extract(&extract_options(archive, output_dir)).is_err());
+        assert!(!dir.0.join("escaped.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_writing_through_symlinks() {
+        let dir = TestDir::new("symlink");
+        let outside = dir.0.join("outside");
+        std::fs::create_dir(&outside).unwrap();
+        let outside_str = outside.to_str().unwrap();
+        let region = vec![0u8; 8192];
+        let cases: [&[Entry]; 3] = [
+            &[Entry::Symlink("saves", outside_str), Entry::File("saves/.bashrc", b"pwned")],
+            // region files are written by mwdh itself, not by tar
+            &[Entry::Symlink("region", outside_str), Entry::File("region/r.0.0.mca", &region)],
+            &[Entry::Symlink("saves", outside_str), Entry::Symlink("saves/world", "."), Entry::File("saves/world/x", b"pwned")],
+        ];
+        for (i, entries) in cases.into_iter().enumerate() {
+            let archive = dir.0.join(format!("evil{}.tar.zst", i));
+            write_archive(&archive, entries);
+            let output_dir = dir.0.join(format!("out{}", i));
+            assert!(extract(&extract_options(archive, output_dir)).is_err(), "case {} should fail", i);
+            assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 0, "case {} wrote outside", i);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_directories_through_symlinks() {
+        let dir = TestDir::new("symlink-dir");
+        let outside = dir.0.join("outside");
+        std::fs::create_dir(&outside).unwrap();
+        let archive = dir.0.join("evil.tar.zst");
+        write_archive(&archive, &[Entry::Symlink("saves", outside.to_str().unwrap()), Entry::Dir("saves/world/")]);
+        let output_dir = dir.0.join("out");
+        assert!(extract(&extract_options(archive, output_dir)).is_err());
+        assert!(!outside.join("world").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_inside_the_output_directory_are_followed() {
+        let dir = TestDir::new("symlink-inside");
+        let output_dir = dir.0.join("out");
+        std::fs::create_dir_all(output_dir.join("real")).unwrap();
+        std::os::unix::fs::symlink("real", output_dir.join("link")).unwrap();
+        std::os::unix::fs::symlink(dir.0.join("missing"), output_dir.join("dangling")).unwrap();
+        std::os::unix::fs::symlink(&dir.0, output_dir.join("up")).unwrap();
+
+        assert!(is_inside(&output_dir, &output_dir.join("link/new/dirs")).unwrap());
+        assert!(!is_inside(&output_dir, &output_dir.join("up/new")).unwrap());
+        assert!(!is_inside(&output_dir, &output_dir.join("dangling/new")).unwrap());
+
+        prepare_dir(&output_dir, &output_dir.join("link/world/region")).unwrap();
+        assert!(output_dir.join("real/world/region").is_dir());
+        prepare_target(&output_dir, &output_dir.join("link/world/level.dat")).unwrap();
+        assert!(prepare_dir(&output_dir, &output_dir.join("up/world")).is_err());
+        assert!(prepare_target(&output_dir, &output_dir.join("up/world/level.dat")).is_err());
+        assert!(!dir.0.join("world").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_symlink_in_place_of_a_file_is_replaced() {
+        let dir = TestDir::new("symlink-file");
+        let output_dir = dir.0.join("out");
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let secret = dir.0.join("secret.txt");
+        std::fs::write(&secret, b"secret").unwrap();
+        std::os::unix::fs::symlink(&secret, output_dir.join("level.dat")).unwrap();
+        prepare_target(&output_dir, &output_dir.join("level.dat")).unwrap();
+        assert!(!output_dir.join("level.dat").exists());
+        assert_eq!(std::fs::read(&secret).unwrap(), b"secret");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn diffs_dont_delete_through_symlinks() {
+        let dir = TestDir::new("symlink-delete");
+        let output_dir = dir.0.join("out");
+        std::fs::create_dir_all(&output_dir).unwrap();
+        std::fs::write(dir.0.join("keep.txt"), b"keep").unwrap();
+        std::os::unix::fs::symlink(&dir.0, output_dir.join("up")).unwrap();
+        assert!(delete_files(&output_dir, &["up/keep.txt".to_string()]).is_err());
+        assert!(dir.0.join("keep.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_hard_links_to_files_outside() {
+        let dir = TestDir::new("hardlink");
+        let secret = dir.0.join("secret.txt");
+        std::fs::write(&secret, b"secret").unwrap();
+        let archive = dir.0.join("evil.tar.zst");
+        write_archive(&archive, &[Entry::HardLink("saves/world/level.dat", secret.to_str().unwrap())]);
+        let output_dir = dir.0.join("out");
+        assert!(extract(&extract_options(archive, output_dir.clone())).is_err());
+        assert!(!output_dir.join("saves/world/level.dat").exists());
+    }
+}
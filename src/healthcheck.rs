@@ -0,0 +1,58 @@
+//! `--healthcheck-url`, pinging a monitoring service like healthchecks.io at the start and the end of a backup run,
+//! so a backup that fails or doesn't happen at all raises an alert there.
+
+use std::time::Duration;
+
+use tracing::{debug, warn};
+use url::Url;
+
+/// How long the monitoring service gets to answer
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// healthchecks.io keeps the first 100 kB of a ping's body
+const MAX_BODY_LEN: usize = 100_000;
+
+/// What a ping reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ping {
+    /// The run started, `<url>/start`
+    Start,
+    /// The run succeeded, `<url>`
+    Success,
+    /// The run failed, `<url>/fail`
+    Fail,
+}
+
+/// Pings the check, with `body` as the log healthchecks.io shows for it. Failures are only logged, monitoring being
+/// down shouldn't stop the backup.
+pub async fn ping(url: &Url, ping: Ping, body: String) {
+    let mut ping_url = url.clone();
+    let suffix = match ping {
+        Ping::Start => Some("start"),
+        Ping::Success => None,
+        Ping::Fail => Some("fail"),
+    };
+    if let Some(suffix) = suffix
+        && let Ok(mut segments) = ping_url.path_segments_mut()
+    {
+        segments.pop_if_empty().push(suffix);
+    }
+    let mut body = body;
+    if body.len() > MAX_BODY_LEN {
+        let mut end = MAX_BODY_LEN;
+        while !body.is_char_boundary(end) {
+            end -= 1;
+        }
+        body.truncate(end);
+    }
+    let result = reqwest::Client::new()
+        .post(ping_url)
+        .timeout(TIMEOUT)
+        .body(body)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    match result {
+        Ok(_) => debug!("Pinged the health check ({:?})", ping),
+        Err(err) => warn!("Failed to ping the health check: {:#}", anyhow::Error::from(err.without_url())),
+    }
+}
@@ -0,0 +1,49 @@
+use std::process::Command;
+
+use anyhow::{Context, Result, anyhow};
+use tracing::info;
+
+/// Runs a user supplied shell command with the given extra environment variables, inheriting stdout/stderr.
+/// Fails if the command can't be started or exits unsuccessfully.
+pub async fn run_hook(name: &str, command: &str, envs: Vec<(&'static str, String)>) -> Result<()> {
+    info!("Running {}: {}", name, command);
+    let command = command.to_string();
+    let status = tokio::task::spawn_blocking(move || {
+        let mut process = if cfg!(windows) {
+            let mut process = Command::new("cmd");
+            process.arg("/C").arg(&command);
+            process
+        } else {
+            let mut process = Command::new("sh");
+            process.arg("-c").arg(&command);
+            process
+        };
+        process.envs(envs).status()
+    })
+    .await?
+    .with_context(|| format!("Failed to start {}", name))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!("{} exited with {}", name, status))
+    }
+}
+
+/// What to do when a hook fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookFailurePolicy {
+    Abort,
+    Continue,
+}
+
+impl std::str::FromStr for HookFailurePolicy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "abort" => Ok(HookFailurePolicy::Abort),
+            "continue" => Ok(HookFailurePolicy::Continue),
+            _ => Err(anyhow!("Unknown hook failure policy: {} (expected abort or continue)", s)),
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Local};
+
+use crate::{format_bytes, nbt};
+
+#[derive(Clone)]
+pub struct InfoOptions {
+    /// Path to the world directory containing level.dat
+    pub world_path: PathBuf,
+    /// Whether to print the world seed
+    pub show_seed: bool,
+}
+
+/// Prints what level.dat knows about a world plus the on-disk size of each dimension.
+pub fn print_world_info(options: &InfoOptions) -> Result<()> {
+    let world_dir = &options.world_path;
+    let level_dat = world_dir.join("level.dat");
+    if !level_dat.is_file() {
+        return Err(anyhow!(
+            "{} doesn't contain a level.dat. Point mwdh info at the world directory itself, e.g. `mwdh info ./world`",
+            world_dir.display()
+        ));
+    }
+    let root = nbt::read_gzip_file(&level_dat)?;
+    let data = root
+        .get("Data")
+        .ok_or_else(|| anyhow!("level.dat has no Data tag"))?;
+
+    let text = |path: &str| data.get(path).and_then(|tag| tag.as_str()).unwrap_or("unknown");
+    let hardcore = data.get("hardcore").and_then(|tag| tag.as_i64()).unwrap_or(0) != 0;
+    let game_mode = match data.get("GameType").and_then(|tag| tag.as_i64()) {
+        Some(0) => "Survival",
+        Some(1) => "Creative",
+        Some(2) => "Adventure",
+        Some(3) => "Spectator",
+        _ => "unknown",
+    };
+
+    println!("World name:    {}", text("LevelName"));
+    println!("Version:       {}", text("Version/Name"));
+    println!(
+        "Game mode:     {}{}",
+        game_mode,
+        if hardcore { " (Hardcore)" } else { "" }
+    );
+    if let Some(last_played) = data
+        .get("LastPlayed")
+        .and_then(|tag| tag.as_i64())
+        .and_then(DateTime::from_timestamp_millis)
+    {
+        println!(
+            "Last played:   {}",
+            DateTime::<Local>::from(last_played).format("%Y-%m-%d %H:%M:%S")
+        );
+    }
+    if let Some((x, z)) = spawn_position(data) {
+        println!("Spawn:         {} / {}", x, z);
+    }
+    if options.show_seed {
+        // 1.16+ keeps the seed in WorldGenSettings, older versions in RandomSeed
+        let seed = data
+            .get("WorldGenSettings/seed")
+            .or_else(|| data.get("RandomSeed"))
+            .and_then(|tag| tag.as_i64());
+        match seed {
+            Some(seed) => println!("Seed:          {}", seed),
+            None => println!("Seed:          unknown"),
+        }
+    }
+
+    println!();
+    for (dimension, dir) in dimension_dirs(world_dir) {
+        match dir {
+            Some(dir) => {
+                // the overworld lives in the world directory itself, the nested dimensions must not be counted twice
+                let size = dir_size(&dir, &["DIM-1", "DIM1"])?;
+                println!("{:<14} {:>12}  ({})", format!("{}:", dimension), format_bytes(size), dir.display());
+            }
+            None => println!("{:<14} {:>12}", format!("{}:", dimension), "not found"),
+        }
+    }
+    Ok(())
+}
+
+/// The world spawn's x and z block coordinates from level.dat's Data tag.
+pub fn spawn_position(data: &nbt::Tag) -> Option<(i32, i32)> {
+    // 1.21.5+ stores the spawn as an int array in a "spawn" compound, older versions in SpawnX/SpawnZ
+    if let Some(nbt::Tag::IntArray(pos)) = data.get("spawn/pos")
+        && let [x, _, z] = pos[..]
+    {
+        return Some((x, z));
+    }
+    let x = data.get("SpawnX")?.as_i64()?;
+    let z = data.get("SpawnZ")?.as_i64()?;
+    Some((x as i32, z as i32))
+}
+
+/// Reads the spawn position from the world's level.dat.
+pub fn read_spawn_position(world_dir: &Path) -> Result<(i32, i32)> {
+    let root = nbt::read_gzip_file(&world_dir.join("level.dat"))?;
+    root.get("Data")
+        .and_then(spawn_position)
+        .ok_or_else(|| anyhow!("level.dat doesn't contain a spawn position"))
+}
+
+/// Finds the directories of the three dimensions for both the vanilla and the Bukkit layout.
+fn dimension_dirs(world_dir: &Path) -> [(&'static str, Option<PathBuf>); 3] {
+    let world_name = world_dir
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let sibling = |suffix: &str, dim: &str| {
+        let nested = world_dir.join(dim);
+        if nested.is_dir() {
+            return Some(nested);
+        }
+        let bukkit = world_dir.with_file_name(format!("{}{}", world_name, suffix)).join(dim);
+        bukkit.is_dir().then_some(bukkit)
+    };
+    [
+        ("Overworld", Some(world_dir.to_path_buf())),
+        ("Nether", sibling("_nether", "DIM-1")),
+        ("The End", sibling("_the_end", "DIM1")),
+    ]
+}
+
+fn dir_size(dir: &Path, skip_dirs: &[&str]) -> Result<u64> {
+    let mut total = 0;
+    let mut stack = vec![dir.to_path_buf()];
+    let mut is_top_level = true;
+    while let Some(current) = stack.pop() {
+        for entry in std::fs::read_dir(&current)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            if meta.is_dir() {
+                if is_top_level && skip_dirs.iter().any(|skip| entry.file_name() == *skip) {
+                    continue;
+                }
+                stack.push(entry.path());
+            } else {
+                total += meta.len();
+            }
+        }
+        is_top_level = false;
+    }
+    Ok(total)
+}
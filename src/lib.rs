@@ -1,51 +1,198 @@
+#[cfg(feature = "cli")]
 pub mod cli;
 pub mod archive;
+pub mod bench;
+pub mod builder;
+pub mod cancel;
+#[cfg(feature = "download")]
+pub mod download;
+pub mod error;
+pub mod extract;
+#[cfg(feature = "notify")]
+pub mod healthcheck;
+pub mod hooks;
+pub mod info;
+pub mod logging;
+pub mod manifest;
+pub mod nbt;
+#[cfg(feature = "notify")]
+pub mod notify;
+pub mod paths;
+pub mod prune;
+#[cfg(feature = "upload")]
+pub mod push;
+pub mod region;
+pub mod saves;
+#[cfg(feature = "server")]
 pub mod server;
-
-use anyhow::{Context, Result};
-use clap::ValueEnum;
+pub mod systemd;
+#[cfg(feature = "upload")]
+pub mod upload;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
+pub mod verify;
+
+use anyhow::Result;
+use cancel::CancellationToken;
+use archive::{OnError, encrypt::AgeEncryption, format::ArchiveFormat, layout::Layout, progress::ProgressMode, throttle::IoLimit, zip::{ZipFormat, ZipMethod}, zstd::TarZstdFormat};
+use hooks::HookFailurePolicy;
+use prune::RetentionPolicy;
+use region::CropArea;
+#[cfg(feature = "upload")]
+use upload::UploadTarget;
 use std::{
-    error,
+    ffi::OsStr,
     fmt::Display,
+    fs::Metadata,
+    ops::RangeInclusive,
     path::{Path, PathBuf},
     str::FromStr,
-    sync::mpsc,
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
 
 #[derive(Debug, Clone)]
 pub enum ProgressMessage {
     StartScanning,
     FileFound(String),             // File name
-    StartCompression(u64),         // total files to compress
+    Preprocessing(String),         // region file being rewritten
+    RegionIssue(String),           // problem found in a region file
+    FileSkipped(String, String),   // filename, why it couldn't be read
+    Hashing(String),               // file being hashed for the manifest
+    StartCompression(u64, u64),    // total files, total bytes to compress
+    BytesProcessed(u64),           // bytes read from the files being compressed since the last message
     Compressing(usize, String),    // worker_id, filename
     FileCompressed(usize, String), // worker_id, filename
     StartWriting(u64),             // total files to write
     WritingFile(String),           // filename being written to final ZIP
-    Complete(u64),                 // final zip file size in bytes
+    Complete(CompressionStats),    // the finished archive
+}
+
+/// How the compression of an archive went, sent with [`ProgressMessage::Complete`].
+#[derive(Debug, Clone, Copy)]
+pub struct CompressionStats {
+    /// Size of the archive file in bytes
+    pub archive_size: u64,
+    /// Total size of the archived files in bytes
+    pub uncompressed_size: u64,
+    /// How long compressing and writing took, without the scan
+    pub elapsed: Duration,
+    /// Number of compression workers
+    pub threads: usize,
+}
+
+impl CompressionStats {
+    /// How many times smaller the archive is than the files in it
+    pub fn ratio(&self) -> f64 {
+        if self.archive_size == 0 {
+            return 0.0;
+        }
+        self.uncompressed_size as f64 / self.archive_size as f64
+    }
+
+    /// Uncompressed bytes per second
+    pub fn throughput(&self) -> f64 {
+        self.uncompressed_size as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Uncompressed bytes per second each worker got through on average
+    pub fn throughput_per_worker(&self) -> f64 {
+        self.throughput() / self.threads.max(1) as f64
+    }
+}
+
+impl Display for CompressionStats {
+    /// Like `114.50 MiB of 457.76 MiB, ratio 4.00 in 3.2s, 35.70 MiB/s per worker`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of {}, ratio {:.2} in {:.1}s, {}/s per worker",
+            format_bytes(self.archive_size),
+            format_bytes(self.uncompressed_size),
+            self.ratio(),
+            self.elapsed.as_secs_f64(),
+            format_bytes(self.throughput_per_worker() as u64)
+        )
+    }
 }
 
 #[derive(Clone)]
 pub struct FileToCompress {
     pub src_path: PathBuf,
     pub file_name: String, // when compressing with Deflate/ZIP, this is the path to a compressed file located in the temp folder
+    /// Size in bytes when the world was scanned. The archive gets exactly this many bytes of the file.
+    pub size: u64,
+    /// Modification time when the world was scanned
+    pub modified: Option<SystemTime>,
+    /// Unix permissions, `0o644` (or `0o444` if read-only) on other systems
+    pub mode: u32,
+}
+
+impl FileToCompress {
+    /// Takes size, modification time and permissions from the metadata the scan got anyway, so archiving doesn't
+    /// have to stat every file again. That's slow for hundreds of thousands of files on network filesystems.
+    pub fn new(src_path: PathBuf, file_name: String, meta: &Metadata) -> Self {
+        #[cfg(unix)]
+        let mode = std::os::unix::fs::PermissionsExt::mode(&meta.permissions());
+        #[cfg(not(unix))]
+        let mode = if meta.permissions().readonly() { 0o444 } else { 0o644 };
+        FileToCompress {
+            src_path,
+            file_name,
+            size: meta.len(),
+            modified: meta.modified().ok(),
+            mode,
+        }
+    }
 }
 
 impl CompressionFormat {
+    /// The implementation of the built-in format.
+    pub fn archive_format(&self) -> Arc<dyn ArchiveFormat> {
+        match self {
+            CompressionFormat::ZipDeflate => Arc::new(ZipFormat),
+            CompressionFormat::TarZstd => Arc::new(TarZstdFormat),
+        }
+    }
     pub fn get_mime_type(&self) -> &'static str {
+        self.archive_format().mime_type()
+    }
+    /// The compression levels the format supports.
+    pub fn level_range(&self) -> RangeInclusive<i8> {
         match self {
-            CompressionFormat::ZipDeflate => "application/zip",
-            CompressionFormat::TarZstd => "application/zstd",
+            CompressionFormat::TarZstd => -7..=22,
+            CompressionFormat::ZipDeflate => 0..=9,
         }
     }
-    pub fn get_file_ending(&self) -> &'static str {
+    /// The level used when none is given. zstd optimizes for speed, zip uses the usual default.
+    pub fn default_level(&self) -> i8 {
         match self {
-            CompressionFormat::ZipDeflate => "zip",
-            CompressionFormat::TarZstd => "tar.zst",
+            CompressionFormat::TarZstd => -7,
+            CompressionFormat::ZipDeflate => 6,
+        }
+    }
+    pub fn get_file_ending(&self) -> &'static str {
+        self.archive_format().extension()
+    }
+    /// Detects the format from a file name like `world.tar.zst`, looking through an `.age` encryption suffix.
+    pub fn from_path(path: &Path) -> Option<CompressionFormat> {
+        if path.extension().is_some_and(|ext| ext == "age") {
+            return CompressionFormat::from_file_extension(path.with_extension("").extension());
         }
+        CompressionFormat::from_file_extension(path.extension())
+    }
+    pub fn from_file_extension(ext: Option<&OsStr>) -> Option<CompressionFormat> {
+        ext.and_then(|os_str| os_str.to_str())
+            .and_then(|str| match str {
+                "zst" => Some(CompressionFormat::TarZstd),
+                "zip" => Some(CompressionFormat::ZipDeflate),
+                _ => None,
+            })
     }
 }
 
-#[derive(Debug, Clone, Copy, ValueEnum)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
 pub enum CompressionFormat {
     ZipDeflate,
     TarZstd,
@@ -71,11 +218,46 @@ impl FromStr for CompressionFormat {
     }
 }
 
+/// A format with an optional level, like `zstd:19` or `zip`.
+#[derive(Debug, Clone, Copy)]
+pub struct CompressionSpec {
+    pub format: CompressionFormat,
+    pub level: Option<i8>,
+}
+
+impl FromStr for CompressionSpec {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (format, level) = match s.split_once(':') {
+            Some((format, level)) => (format, Some(level)),
+            None => (s, None),
+        };
+        let format = format
+            .parse::<CompressionFormat>()
+            .map_err(|_| anyhow::anyhow!("Unknown compression format: {} (expected zstd or zip)", format))?;
+        let levels = format.level_range();
+        let level = match level {
+            Some(level) => {
+                let level = level
+                    .parse::<i8>()
+                    .ok()
+                    .filter(|level| levels.contains(level))
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Invalid {} level: {} (use {} to {})", format, level, levels.start(), levels.end())
+                    })?;
+                Some(level)
+            }
+            None => None,
+        };
+        Ok(CompressionSpec { format, level })
+    }
+}
+
 #[derive(Debug)]
 pub struct CompressionFormatParseError;
 
-impl error::Error for CompressionFormatParseError {
-    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+impl std::error::Error for CompressionFormatParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         None
     }
 }
@@ -102,17 +284,31 @@ pub fn format_bytes(bytes: u64) -> String {
     }
 }
 
+#[cfg(feature = "cli")]
 #[derive(Clone)]
+#[allow(clippy::large_enum_variant)] // only ever constructed once
 pub enum MwdhOptions {
     Server(ServerOptions),
     Archive(ArchiveOptions),
     Both {
         server: ServerOptions,
         archive: ArchiveOptions,
+        /// Serve the archive of an earlier run when compressing fails instead of exiting
+        serve_stale_on_failure: bool,
     },
+    Prune(prune::PruneOptions),
+    Info(info::InfoOptions),
+    Extract(extract::ExtractOptions),
+    Bench(bench::BenchOptions),
+    List(verify::ListOptions),
+    Verify(verify::VerifyOptions),
+    Download(download::DownloadOptions),
+    Push(push::PushOptions),
 }
 
+/// Options of an archiving run. Create them with [`ArchiveOptions::builder`].
 #[derive(Clone)]
+#[non_exhaustive]
 pub struct ArchiveOptions {
     /// Path to the minecraft server/saves directory that contains /world, /world_nether and /world_the_end
     pub world_path: String,
@@ -120,9 +316,15 @@ pub struct ArchiveOptions {
     /// Name of the world directory defined in server.properties if you're hosting a singleplayer world on a desktop system
     pub world_name: String,
 
-    /// Specify the name of the archive - Note: (mwdh will append a file-ending to it)
+    /// Specify the name of the archive - Note: (mwdh will append a file-ending to it). Placeholders are already expanded, see [`expand_archive_name`]
     pub archive_name: String,
 
+    /// Directory the archive is written to, the current directory if not set. Created if it doesn't exist.
+    pub output_dir: Option<PathBuf>,
+
+    /// Whether an already existing archive at the output path may be replaced
+    pub overwrite: bool,
+
     /// Include the Nether dimension
     pub include_nether: bool,
 
@@ -147,13 +349,161 @@ pub struct ArchiveOptions {
 
     /// Limit in MB until the compression algorithm stores the compression intermediaries on disk in a temp directory.
     pub memory_limit_mb: u64,
+
+    /// Where to upload the finished archive to, if anywhere.
+    #[cfg(feature = "upload")]
+    pub upload: Option<UploadTarget>,
+
+    /// Shell command to run after the archive was created successfully.
+    pub post_hook: Option<String>,
+
+    /// Where to send a notification to once the archive was created or compressing it failed.
+    #[cfg(feature = "notify")]
+    pub notify: Vec<notify::NotifyTarget>,
+
+    /// Health check URL, like one of healthchecks.io, pinged at `/start` before archiving, as it is once the run
+    /// succeeded and at `/fail` when it failed.
+    #[cfg(feature = "notify")]
+    pub healthcheck_url: Option<url::Url>,
+
+    /// Shell command to run before scanning starts, e.g. to flush the world to disk.
+    pub pre_hook: Option<String>,
+
+    /// Whether to abort or continue archiving when the pre-hook fails.
+    pub pre_hook_failure: HookFailurePolicy,
+
+    /// Prune old archives in the output directory with this policy after a successful run.
+    pub auto_prune: Option<RetentionPolicy>,
+
+    /// Drop chunks from region files whose players spent less than this time in them.
+    pub prune_inhabited_under: Option<Duration>,
+
+    /// Only archive the chunks overlapping this area (in every dimension).
+    pub crop: Option<CropArea>,
+
+    /// Store region file chunks uncompressed, so they compress much better as part of the archive.
+    pub raw_region_chunks: bool,
+
+    /// Check region files for truncated and corrupt chunks before archiving.
+    pub validate_regions: bool,
+
+    /// Leave out player data like inventories, stats and the server's player lists, see [`is_player_data`].
+    pub scrub_player_data: bool,
+
+    /// Also archive server.properties and the other config files of the server, see [`SERVER_FILES`].
+    pub include_server_files: bool,
+
+    /// Also archive the plugins directory.
+    pub include_plugins: bool,
+
+    /// Leave out the .jar files directly inside the plugins directory.
+    pub exclude_plugin_jars: bool,
+
+    /// Directory layout of the worlds inside the archive.
+    pub layout: Layout,
+
+    /// Encrypt the archive with age. Only supported for tar.zst archives.
+    pub encryption: Option<AgeEncryption>,
+
+    /// Enable zstd's long distance matching with this window log (window size = 2^n bytes).
+    pub zstd_long: Option<u32>,
+
+    /// Compress into a single zstd stream using libzstd's multithreading instead of independently compressed batches.
+    pub single_stream: bool,
+
+    /// Default compression method for ZIP entries.
+    pub zip_method: ZipMethod,
+
+    /// Lowercase file extensions (without the dot) of files stored uncompressed in ZIP archives.
+    pub store_extensions: Vec<String>,
+
+    /// Progress bars, JSON lines or a custom [`ProgressSink`](archive::progress::ProgressSink).
+    pub progress: ProgressMode,
+
+    /// Stops the archiving once cancelled, e.g. on Ctrl-C.
+    pub cancel: CancellationToken,
+
+    /// Keep the partial archive when the archiving gets cancelled instead of deleting it.
+    pub keep_partial: bool,
+
+    /// What to do with files that can't be read.
+    pub on_error: OnError,
+
+    /// Don't check for enough free disk space before compressing.
+    pub skip_space_check: bool,
+
+    /// Keep a checkpoint while compressing in parallel zstd mode and continue an interrupted run from its checkpoint.
+    pub resume: bool,
+
+    /// Copy the entries of files that didn't change since the ZIP archive at the output path was written over from it,
+    /// and only compress the new and changed files.
+    pub update: bool,
+
+    /// Only archive the files that are new or changed since this archive of the world, plus a list of the deleted ones.
+    pub diff_base: Option<PathBuf>,
+
+    /// Write the archive in this format instead of [`compression_format`](ArchiveOptions::compression_format),
+    /// e.g. one from a plugin. The compression level is passed on as is.
+    pub custom_format: Option<Arc<dyn ArchiveFormat>>,
+
+    /// Read the world's files no faster than this, so the game server isn't slowed down by the backup.
+    pub io_limit: Option<IoLimit>,
+
+    /// Archive with a lower CPU and disk priority. Limits the threads to half of the CPUs when building the options.
+    pub background: bool,
+
+    /// Size in bytes of the buffers every worker reads the world's files with, and the archive is written with.
+    /// Bigger buffers mean fewer, larger reads, which helps on network filesystems and HDDs.
+    pub io_buffer_size: usize,
+
+    /// How long compressing may take. The zstd level is lowered batch by batch whenever the archive wouldn't be done
+    /// in time, starting at `compression_level`. Only for zstd archives compressed in parallel mode.
+    pub target_duration: Option<Duration>,
+
+    /// Put a [`manifest`] with the size and SHA-256 of every file first into the archive.
+    pub manifest: bool,
+}
+
+impl ArchiveOptions {
+    /// The format the archive is written in, [`custom_format`](ArchiveOptions::custom_format) if there is one.
+    pub fn archive_format(&self) -> Arc<dyn ArchiveFormat> {
+        match self.custom_format {
+            Some(ref format) => Arc::clone(format),
+            None => self.compression_format.archive_format(),
+        }
+    }
+
+    /// The archive name with the format's file ending (and `.age` when encrypting) appended. Not using `Path::with_extension`, because that would cut off names containing dots like "world-1.21.4".
+    pub fn archive_output_path(&self) -> PathBuf {
+        let file_name = format!(
+            "{}.{}{}",
+            self.archive_name,
+            self.archive_format().extension(),
+            if self.encryption.is_some() { ".age" } else { "" }
+        );
+        match self.output_dir {
+            Some(ref output_dir) => output_dir.join(file_name),
+            None => PathBuf::from(file_name),
+        }
+    }
 }
 
+/// Options of the download server. Create them with [`ServerOptions::builder`].
+#[cfg(feature = "server")]
 #[derive(Clone)]
+#[non_exhaustive]
 pub struct ServerOptions {
     /// Host path from where to download the world files
     pub host_path: String,
 
+    /// URL path everything is served under, like `/downloads/world` when a reverse proxy passes on a sub path as it
+    /// is. Empty to serve from the root.
+    pub base_path: String,
+
+    /// Take the client's address, the scheme and the host from the `X-Forwarded-For`, `X-Forwarded-Proto` and
+    /// `X-Forwarded-Host` headers of a reverse proxy. Any client could set them without one.
+    pub behind_proxy: bool,
+
     /// IP address to serve on
     pub bind: String,
 
@@ -167,10 +517,195 @@ pub struct ServerOptions {
     
     /// Compression format used in the http header to signal to the browser what kind of data is downloaded.
     pub compression_format: CompressionFormat,
+
+    /// File name the archive under the host path is downloaded as, like `world-2024-06-01.tar.zst`, instead of its
+    /// name on disk.
+    pub download_filename: Option<String>,
+
+    /// Content-Type the archive under the host path is sent with, instead of the one of its format.
+    pub content_type: Option<String>,
+
+    /// Content-Type per format, instead of the one the format comes with, like `application/zstd` for `.tar.zst`.
+    pub mime_types: Vec<(CompressionFormat, String)>,
+
+    /// Send `.tar.zst` archives as `application/x-tar` with `Content-Encoding: zstd` to clients accepting zstd, so
+    /// browsers unpack the zstd while downloading and save a `.tar`.
+    pub zstd_content_encoding: bool,
+
+    /// Secret that has to be present as a `?token=` query parameter or as a trailing path segment to download the archive.
+    pub download_token: Option<String>,
+
+    /// Shut the server down after no archive was downloaded for this long. Other requests don't keep it running.
+    pub idle_timeout: Option<Duration>,
+
+    /// Maximum number of connections handled at the same time. Further connections wait until a slot frees up.
+    pub max_connections: Option<usize>,
+
+    /// Download speed limit per client IP in bytes per second.
+    pub rate_limit: Option<u64>,
+
+    /// Download speed limit per connection in bytes per second.
+    pub connection_rate_limit: Option<u64>,
+
+    /// Download speed limit of all downloads together in bytes per second, split evenly between the downloads running
+    /// at the same time.
+    pub total_rate_limit: Option<u64>,
+
+    /// Where to write the access log to, if at all.
+    pub access_log: Option<server::access_log::AccessLogTarget>,
+
+    /// Directory of archives to list and serve under the host path instead of a single archive.
+    pub serve_dir: Option<PathBuf>,
+
+    /// Directory in which the newest archive gets looked up on every request instead of serving a fixed archive.
+    pub serve_latest: Option<PathBuf>,
+
+    /// `user:password` credentials required via HTTP Basic authentication.
+    pub basic_auth: Option<String>,
+
+    /// Serve HTTPS with this certificate, and only to clients with a certificate of a CA when one is set.
+    pub tls: Option<server::tls::TlsOptions>,
+
+    /// Serve HTTPS with a certificate from Let's Encrypt or another ACME CA, renewed while the server runs.
+    pub acme: Option<server::acme::AcmeOptions>,
+
+    /// Announce the server on the LAN with mDNS as `<name>.local`.
+    pub mdns: Option<String>,
+
+    /// Serve a `.torrent` of every single archive at `/<path>.torrent`, with the server as web seed.
+    pub torrent: Option<server::torrent::TorrentOptions>,
+
+    /// Further archives served under `/<name>` next to the host path, as `(name, path)` pairs.
+    pub named_archives: Vec<(String, PathBuf)>,
+
+    /// Networks clients have to be in. Everyone who isn't denied may connect when empty.
+    pub allowed_ips: Vec<server::ip_filter::IpNet>,
+
+    /// Networks whose clients are turned away, even when they're in an allowed network as well.
+    pub denied_ips: Vec<server::ip_filter::IpNet>,
+
+    /// URL every finished or broken off download of an archive gets posted to as JSON.
+    pub webhook_url: Option<url::Url>,
+
+    /// Where to send a notification to when an archive was downloaded for the first time.
+    pub notify: Vec<notify::NotifyTarget>,
+
+    /// JSON file the download statistics are kept in across restarts. They only last while the server runs without one.
+    pub stats_file: Option<PathBuf>,
+
+    /// Timeouts and limits for clients that hold on to a connection without getting anywhere.
+    pub client_limits: server::limits::ClientLimits,
+
+    /// Origins like `https://map.example.com` allowed to fetch from the server in the browser, `*` for any.
+    pub cors_origins: Vec<String>,
+
+    /// Progress of the archive being compressed, streamed at `/events`.
+    pub progress_events: Option<server::events::ProgressEvents>,
+
+    /// Accept archives sent with `mwdh push` at `PUT /upload/<file name>`.
+    pub uploads: Option<server::receive::UploadOptions>,
+
+    /// Serve whatever archive this points to instead of [`path_to_archive`](ServerOptions::path_to_archive), so it
+    /// can be swapped while the server runs.
+    pub live_archive: Option<server::live::LiveArchive>,
+
+    /// Stops accepting connections once cancelled.
+    pub cancel: CancellationToken,
+}
+
+/// Expands the placeholders `{world}`, `{date}`, `{time}`, `{hostname}` and `{mc_version}` in an archive name template.
+pub fn expand_archive_name(template: &str, world_path: &str, world_name: &str) -> Result<String> {
+    let now = chrono::Local::now();
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| anyhow::anyhow!("Unclosed placeholder in file name: {}", template))?;
+        match &rest[start + 1..end] {
+            "world" => expanded.push_str(world_name),
+            "date" => expanded.push_str(&now.format("%Y-%m-%d").to_string()),
+            "time" => expanded.push_str(&now.format("%H-%M-%S").to_string()), // no colons, those aren't allowed in Windows file names
+            "hostname" => expanded.push_str(&hostname::get()?.to_string_lossy()),
+            "mc_version" => {
+                let version = read_mc_version(world_path, world_name).unwrap_or_else(|| "unknown".to_string());
+                expanded.push_str(&version);
+            }
+            placeholder => {
+                return Err(anyhow::anyhow!(
+                    "Unknown placeholder {{{}}} in file name. Available: {{world}}, {{date}}, {{time}}, {{hostname}}, {{mc_version}}",
+                    placeholder
+                ));
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// The Minecraft version the world was last played with, from its level.dat.
+pub fn read_mc_version(world_path: &str, world_name: &str) -> Option<String> {
+    let level_dat = Path::new(world_path).join(world_name).join("level.dat");
+    nbt::read_gzip_file(&level_dat)
+        .ok()
+        .and_then(|root| root.get("Data/Version/Name").and_then(|name| name.as_str()).map(str::to_string))
+}
+
+/// The `level-name` from the `server.properties` in the server directory, which is the name of the world directory.
+/// `None` if there is no `server.properties` or it doesn't set a name.
+pub fn read_level_name(server_dir: &Path) -> Result<Option<String>> {
+    let path = server_dir.join("server.properties");
+    let properties = match std::fs::read_to_string(&path) {
+        Ok(properties) => properties,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(anyhow::Error::new(err).context(format!("Failed to read {}", path.display()))),
+    };
+    for line in properties.lines() {
+        let line = line.trim_start();
+        if line.starts_with('#') || line.starts_with('!') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once(['=', ':']) else {
+            continue;
+        };
+        if key.trim_end() == "level-name" {
+            let value = unescape_property(value.trim());
+            return Ok((!value.is_empty()).then_some(value));
+        }
+    }
+    Ok(None)
+}
+
+/// Undoes the escaping Java's `Properties` writes, like `\:` for colons in world names.
+fn unescape_property(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('u') => {
+                let code: String = chars.by_ref().take(4).collect();
+                if let Some(c) = u32::from_str_radix(&code, 16).ok().and_then(char::from_u32) {
+                    unescaped.push(c);
+                }
+            }
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => {}
+        }
+    }
+    unescaped
 }
 
 pub fn paths_to_be_archived(args: &ArchiveOptions) -> Vec<PathBuf> {
-    let base = PathBuf::from(&args.world_path);
+    let base = paths::long_path(Path::new(&args.world_path));
 
     let mut paths_to_be_archived = Vec::with_capacity(3);
     
@@ -188,61 +723,59 @@ pub fn paths_to_be_archived(args: &ArchiveOptions) -> Vec<PathBuf> {
         paths_to_be_archived.push(base.join(&args.world_name));
         // else: if is not bukkit and nether and/or end are not included we need to skip DIM-1 and/or DIM1 directories later in the file collection.
     } 
-    paths_to_be_archived
-}
-
-pub fn collect_files_recursive(
-    base_dir: &Path,
-    archive_prefix: &str,
-    all_files: &mut Vec<FileToCompress>,
-    args: &ArchiveOptions,
-    tx: &mpsc::Sender<ProgressMessage>,
-) -> Result<()> {
-    let mut stack = vec![(base_dir.to_path_buf(), archive_prefix.to_string())]; // current path, current zip path
-
-    while let Some((curr_fs_path, curr_zip_path)) = stack.pop() {
-        let read_dir = std::fs::read_dir(&curr_fs_path)
-            .with_context(|| format!("Failed to read: {}", curr_fs_path.display()))?;
-
-        for entry in read_dir {
-            let entry = entry?;
-            let path = entry.path();
-            let name = entry.file_name().to_string_lossy().to_string();
-            let child_zip_path = format!("{}/{}", curr_zip_path, name);
-
-            let meta = entry.metadata()?;
-
-            if meta.is_dir() {
-                if !args.is_bukkit {
-                    if !args.include_end && entry.file_name() == "DIM1" {
-                        continue;
-                    }
-                    if !args.include_nether && entry.file_name() == "DIM-1" {
-                        continue;
-                    }
-                    if !args.include_overworld
-                        && entry
-                            .path()
-                            .parent()
-                            .and_then(|parent| parent.file_name())
-                            .and_then(|file_name| file_name.to_str())
-                            .is_some_and(|file_name| dbg!(file_name) == args.world_name) // basically checks if parent dir is the world dir that contains the overworld. just looks crazy because of all the conversions and Options.
-                        && (entry.file_name() == "region" || entry.file_name() == "entities" || entry.file_name() == "poi")
-                    {
-                        continue; // skip region, entities and poi directories in the main world directory.
-                    }
-                }
-                stack.push((path, child_zip_path));
-            } else if meta.is_file() {
-                all_files.push(FileToCompress {
-                    src_path: path.clone(),
-                    file_name: child_zip_path,
-                });
-                tx.send(ProgressMessage::FileFound(path.display().to_string()))
-                    .ok();
+    if args.include_server_files {
+        for name in SERVER_FILES.iter().chain(SERVER_DIRS.iter()) {
+            let path = base.join(name);
+            if path.exists() && !(args.scrub_player_data && is_player_data(name, path.is_dir())) {
+                paths_to_be_archived.push(path);
             }
         }
     }
+    if args.include_plugins {
+        let plugins = base.join("plugins");
+        if plugins.is_dir() {
+            paths_to_be_archived.push(plugins);
+        }
+    }
+    paths_to_be_archived
+}
 
-    Ok(())
+/// Top level server files archived with `--include-server-files`.
+const SERVER_FILES: [&str; 12] = [
+    "server.properties",
+    "eula.txt",
+    "bukkit.yml",
+    "spigot.yml",
+    "commands.yml",
+    "help.yml",
+    "permissions.yml",
+    "ops.json",
+    "whitelist.json",
+    "banned-players.json",
+    "banned-ips.json",
+    "usercache.json",
+];
+
+/// Config directories of Paper and of Fabric/Forge mods, archived with `--include-server-files`.
+const SERVER_DIRS: [&str; 1] = ["config"];
+
+/// Directories of a world that hold per-player data named by UUID.
+const PLAYER_DATA_DIRS: [&str; 3] = ["playerdata", "stats", "advancements"];
+
+/// Server files listing player names, UUIDs and IPs.
+const PLAYER_DATA_FILES: [&str; 5] = [
+    "usercache.json",
+    "ops.json",
+    "whitelist.json",
+    "banned-players.json",
+    "banned-ips.json",
+];
+
+/// Whether a top level entry of a world or server directory is left out by `--scrub-player-data`.
+pub fn is_player_data(name: &str, is_dir: bool) -> bool {
+    if is_dir {
+        PLAYER_DATA_DIRS.contains(&name)
+    } else {
+        PLAYER_DATA_FILES.contains(&name)
+    }
 }
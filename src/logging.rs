@@ -0,0 +1,129 @@
+use std::{fmt, path::PathBuf};
+
+use anyhow::{Context, Result};
+use tracing::{Event, Level, Subscriber, level_filters::LevelFilter};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{
+    Layer,
+    filter::Targets,
+    fmt::{FmtContext, FormatEvent, FormatFields, MakeWriter, format::Writer, writer::MakeWriterExt},
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+};
+
+/// Target of the final summary, like the `Created <archive>` line, which is printed even with `--quiet`.
+pub const SUMMARY: &str = concat!(env!("CARGO_CRATE_NAME"), "::summary");
+
+/// How many rotated log files `--log-file` keeps
+const KEPT_LOG_FILES: usize = 14;
+
+/// Where `--log-file` writes the log to and how often it starts a new file.
+#[derive(Debug, Clone)]
+pub struct LogFile {
+    pub path: PathBuf,
+    /// Rotated files get the date appended to their name
+    pub rotation: Rotation,
+}
+
+/// Installs the global subscriber printing mwdh's log messages up to `level`.
+/// Info messages go to stdout without a prefix like mwdh always printed them, warnings and errors go to stderr.
+/// Other crates only get to log warnings and errors. `RUST_LOG` overrides the level, e.g. `RUST_LOG=mwdh=trace,hyper=debug`.
+pub fn init(level: LevelFilter, log_file: Option<&LogFile>) -> Result<()> {
+    init_with_writer(level, std::io::stderr.with_max_level(Level::WARN).or_else(std::io::stdout), log_file)
+}
+
+/// Like [`init`], but writes every message to `writer`, e.g. when there is no terminal.
+pub fn init_with_writer<W>(level: LevelFilter, writer: W, log_file: Option<&LogFile>) -> Result<()>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let rust_log = rust_log()?;
+    let filter = rust_log.clone().unwrap_or_else(|| {
+        Targets::new()
+            .with_target(env!("CARGO_CRATE_NAME"), level)
+            .with_target(SUMMARY, LevelFilter::INFO)
+            .with_default(LevelFilter::WARN.min(level))
+    });
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .event_format(PlainFormat)
+        .with_writer(writer)
+        .with_filter(filter);
+
+    // the log file gets timestamps and the spans of the messages, and the info messages even with --quiet
+    let file_layer = match log_file {
+        Some(log_file) => {
+            let filter = rust_log.unwrap_or_else(|| {
+                Targets::new()
+                    .with_target(env!("CARGO_CRATE_NAME"), level.max(LevelFilter::INFO))
+                    .with_default(LevelFilter::WARN)
+            });
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_ansi(false)
+                    .with_writer(open_log_file(log_file)?)
+                    .with_filter(filter),
+            )
+        }
+        None => None,
+    };
+    tracing_subscriber::registry().with(fmt_layer).with(file_layer).init();
+    Ok(())
+}
+
+/// The filter set with the `RUST_LOG` environment variable, if it is set.
+fn rust_log() -> Result<Option<Targets>> {
+    match std::env::var("RUST_LOG") {
+        Ok(directives) if !directives.trim().is_empty() => {
+            let targets = directives
+                .parse::<Targets>()
+                .with_context(|| format!("Invalid RUST_LOG: {}", directives))?;
+            Ok(Some(targets))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn open_log_file(log_file: &LogFile) -> Result<RollingFileAppender> {
+    let file_name = log_file
+        .path
+        .file_name()
+        .with_context(|| format!("Invalid log file: {}", log_file.path.display()))?
+        .to_string_lossy()
+        .to_string();
+    let dir = match log_file.path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create: {}", dir.display()))?;
+    let mut builder = RollingFileAppender::builder()
+        .rotation(log_file.rotation.clone())
+        .filename_prefix(file_name);
+    if log_file.rotation != Rotation::NEVER {
+        builder = builder.max_log_files(KEPT_LOG_FILES);
+    }
+    builder
+        .build(&dir)
+        .with_context(|| format!("Failed to open the log file {}", log_file.path.display()))
+}
+
+/// Just the message, prefixed with the level unless it's an info message.
+struct PlainFormat;
+
+impl<S, N> FormatEvent<S, N> for PlainFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        match *event.metadata().level() {
+            Level::ERROR => write!(writer, "ERROR: ")?,
+            Level::WARN => write!(writer, "WARN: ")?,
+            Level::INFO => {}
+            Level::DEBUG => write!(writer, "DEBUG: ")?,
+            Level::TRACE => write!(writer, "TRACE: ")?,
+        }
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
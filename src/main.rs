@@ -1,15 +1,34 @@
 use anyhow::{Result};
 use mwdh::cli::{self};
-use mwdh::{MwdhOptions, archive, server};
+use std::sync::Arc;
+
+use mwdh::{ArchiveOptions, MwdhOptions, ServerOptions, archive::{self, ArchiveSummary, progress::{ProgressMode, TeeSink}}, error::MwdhError, logging, server::{self, events::ProgressEvents, live::LiveArchive}};
+use tracing::{error, info, warn};
+
+#[cfg(windows)]
+mod service;
+
+/// Exit code after cancelling with Ctrl-C, the same shells use for SIGINT.
+const EXIT_CANCELLED: i32 = 130;
 
 fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let cli = cli::create_cli();
-    let options = cli::parse_args(cli)?;
+    let matches = cli::create_cli().get_matches();
+    #[cfg(windows)]
+    if let Some(("service", matches)) = matches.subcommand() {
+        return service::command(matches);
+    }
+    logging::init(cli::parse_log_level(&matches), cli::parse_log_file(&matches).as_ref())?;
+    run(cli::parse_args(&matches)?)
+}
 
+/// Runs mwdh on a runtime with as many threads as the options ask for.
+fn run(options: MwdhOptions) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let threads = match options {
         MwdhOptions::Server(ref server_options) => server_options.threads,
         MwdhOptions::Archive(ref archive_options) => archive_options.threads,
-        MwdhOptions::Both { ref server, archive: _ } => server.threads,
+        MwdhOptions::Both { ref server, .. } => server.threads,
+        MwdhOptions::Prune(_) | MwdhOptions::Info(_) | MwdhOptions::Extract(_) | MwdhOptions::Bench(_)
+        | MwdhOptions::List(_) | MwdhOptions::Verify(_) | MwdhOptions::Download(_) | MwdhOptions::Push(_) => 1,
     };
 
     tokio::runtime::Builder::new_multi_thread()
@@ -23,12 +42,112 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
 async fn run_mwdh(options: MwdhOptions) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     match options {
-        MwdhOptions::Server(server_options) => server::run_server(server_options).await?,
-        MwdhOptions::Archive(archive_options) => archive::do_compression(archive_options).await?,
-        MwdhOptions::Both { server, archive } => {
-            archive::do_compression(archive).await?;
-            server::run_server(server).await?
+        MwdhOptions::Server(server_options) => serve_until_ctrl_c(server_options).await?,
+        MwdhOptions::Archive(archive_options) => {
+            compress_cancellable(archive_options).await?;
+        }
+        MwdhOptions::Both { mut server, mut archive, serve_stale_on_failure } => {
+            // the progress is shown as usual and streamed at /events
+            let progress_events = ProgressEvents::new();
+            archive.progress = ProgressMode::Custom(Arc::new(TeeSink(vec![
+                archive.progress.sink(),
+                Arc::new(progress_events.clone()),
+            ])));
+            server.progress_events = Some(progress_events.clone());
+            // the archive of an earlier run is served while the new one is compressed, if there is one
+            let previous = Some(archive.archive_output_path()).filter(|path| path.is_file());
+            let live_archive = LiveArchive::new(previous.clone());
+            live_archive.start_compressing();
+            server.live_archive = Some(live_archive.clone());
+            let stop_serving = server.cancel.clone();
+            let stop_compressing = archive.cancel.clone();
+            let mut serving = tokio::spawn(serve_until_ctrl_c(server));
+            let compression = compress_cancellable(archive);
+            tokio::pin!(compression);
+            let compressed = tokio::select! {
+                result = &mut compression => result,
+                // the server failed to start or was stopped, nobody would get the new archive
+                served = &mut serving => {
+                    stop_compressing.cancel();
+                    let _ = compression.await;
+                    served??;
+                    return Ok(());
+                }
+            };
+            match compressed {
+                Ok(summary) => {
+                    info!("Serving {} from now on", summary.output_path.display());
+                    live_archive.publish(summary.output_path);
+                }
+                Err(err) => {
+                    progress_events.fail(&format!("{:#}", err));
+                    match previous {
+                        // a cancelled run is supposed to stop, not to keep serving
+                        Some(previous) if serve_stale_on_failure && !matches!(err, MwdhError::Cancelled) => {
+                            error!("Compressing failed: {:#}", err);
+                            warn!("Serving the archive of an earlier run instead: {}", previous.display());
+                            live_archive.fail(format!("{:#}", err));
+                        }
+                        _ => {
+                            stop_serving.cancel();
+                            let _ = serving.await;
+                            return Err(err.into());
+                        }
+                    }
+                }
+            }
+            serving.await??;
         },
+        MwdhOptions::Prune(prune_options) => {
+            mwdh::prune::prune(&prune_options.dir, &prune_options.policy, prune_options.dry_run)?;
+        }
+        MwdhOptions::Info(info_options) => mwdh::info::print_world_info(&info_options)?,
+        MwdhOptions::Extract(extract_options) => mwdh::extract::extract(&extract_options)?,
+        MwdhOptions::Bench(bench_options) => mwdh::bench::bench(&bench_options)?,
+        MwdhOptions::List(list_options) => mwdh::verify::list(&list_options)?,
+        MwdhOptions::Verify(verify_options) => mwdh::verify::verify(&verify_options)?,
+        MwdhOptions::Download(download_options) => mwdh::download::download(&download_options).await?,
+        MwdhOptions::Push(push_options) => mwdh::push::push(&push_options).await?,
     }
     Ok(())
 }
+
+/// Serves until the idle timeout or Ctrl-C.
+async fn serve_until_ctrl_c(options: ServerOptions) -> Result<(), MwdhError> {
+    let cancel = options.cancel.clone();
+    // once Ctrl-C was listened for, it doesn't end the process by itself anymore
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            cancel.cancel();
+        }
+    });
+    server::run_server(options).await
+}
+
+/// Compresses the world, cancelling cleanly on Ctrl-C. Pressing Ctrl-C a second time quits right away.
+async fn compress_cancellable(options: ArchiveOptions) -> Result<ArchiveSummary, MwdhError> {
+    let cancel = options.cancel.clone();
+    let compression = archive::do_compression(options);
+    tokio::pin!(compression);
+    let mut interrupted = false;
+    let result = tokio::select! {
+        result = &mut compression => result,
+        Ok(()) = tokio::signal::ctrl_c() => {
+            interrupted = true;
+            warn!("Cancelling, press Ctrl-C again to quit immediately");
+            cancel.cancel();
+            tokio::spawn(async {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    std::process::exit(EXIT_CANCELLED);
+                }
+            });
+            compression.await
+        }
+    };
+    // when cancelled some other way, like stopping the Windows service, the caller decides what happens
+    if result.is_err() && interrupted {
+        warn!("Cancelled");
+        std::process::exit(EXIT_CANCELLED);
+    }
+    result
+}
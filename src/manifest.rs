@@ -0,0 +1,210 @@
+//! `mwdh-manifest.json`, put first into archives created with `--manifest`. It tells which mwdh created the archive
+//! from which world and has the size and SHA-256 of every file in it, so `mwdh verify` can check that an archive
+//! someone shared is complete and unchanged.
+
+use std::{
+    fs::File,
+    io::BufReader,
+    path::Path,
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+        mpsc::Sender,
+    },
+};
+
+use anyhow::{Context, Result, anyhow};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    ArchiveOptions, FileToCompress, ProgressMessage,
+    archive::{self, throttle::ThrottledReader},
+    read_mc_version,
+};
+
+/// Name of the manifest inside the archive
+pub const MANIFEST_NAME: &str = "mwdh-manifest.json";
+
+pub struct Manifest {
+    /// Version of the mwdh that created the archive
+    pub mwdh_version: String,
+    /// When the manifest was written, as RFC 3339
+    pub created: String,
+    pub world_name: String,
+    /// Minecraft version from the world's level.dat, if it could be read
+    pub minecraft_version: Option<String>,
+    /// Like `minecraft:the_nether`
+    pub dimensions: Vec<String>,
+    /// Problems found in region files while preprocessing them
+    pub region_issues: Vec<String>,
+    pub files: Vec<ManifestEntry>,
+}
+
+/// A file in the archive.
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    /// Hex encoded SHA-256 of the file's contents
+    pub sha256: String,
+}
+
+impl Manifest {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "mwdh_version": self.mwdh_version,
+            "created": self.created,
+            "world_name": self.world_name,
+            "minecraft_version": self.minecraft_version,
+            "dimensions": self.dimensions,
+            "region_issues": self.region_issues,
+            "files": self
+                .files
+                .iter()
+                .map(|entry| json!({"path": entry.path, "size": entry.size, "sha256": entry.sha256}))
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    pub fn from_json(bytes: &[u8]) -> Result<Self> {
+        let value: Value = serde_json::from_slice(bytes).context("The manifest isn't valid JSON")?;
+        let text = |key: &str| value.get(key).and_then(Value::as_str).map(str::to_string);
+        let texts = |key: &str| {
+            value
+                .get(key)
+                .and_then(Value::as_array)
+                .map(|values| values.iter().filter_map(Value::as_str).map(str::to_string).collect())
+                .unwrap_or_default()
+        };
+        let files = value
+            .get("files")
+            .and_then(Value::as_array)
+            .ok_or_else(|| anyhow!("The manifest doesn't list any files"))?
+            .iter()
+            .map(|file| {
+                Some(ManifestEntry {
+                    path: file.get("path")?.as_str()?.to_string(),
+                    size: file.get("size")?.as_u64()?,
+                    sha256: file.get("sha256")?.as_str()?.to_string(),
+                })
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| anyhow!("The manifest has a file without path, size or SHA-256"))?;
+        Ok(Manifest {
+            mwdh_version: text("mwdh_version").unwrap_or_else(|| "unknown".to_string()),
+            created: text("created").unwrap_or_else(|| "unknown".to_string()),
+            world_name: text("world_name").unwrap_or_default(),
+            minecraft_version: text("minecraft_version"),
+            dimensions: texts("dimensions"),
+            region_issues: texts("region_issues"),
+            files,
+        })
+    }
+}
+
+/// Hashes the files, writes the manifest into `dir` and puts it in front of them. Files that get skipped under the
+/// `--on-error` policy while hashing are left out of the archive, they'd be missing from the manifest otherwise.
+pub fn add_manifest(
+    files: Vec<FileToCompress>,
+    dir: &Path,
+    region_issues: &[String],
+    options: &ArchiveOptions,
+    tx: &Sender<ProgressMessage>,
+) -> Result<Vec<FileToCompress>> {
+    let hashes = hash_files(&files, options, tx)?;
+    let files: Vec<_> = files
+        .into_iter()
+        .zip(hashes)
+        .filter_map(|(file, hash)| hash.map(|hash| (file, hash)))
+        .collect();
+
+    let dimensions = [
+        (options.include_overworld, "minecraft:overworld"),
+        (options.include_nether, "minecraft:the_nether"),
+        (options.include_end, "minecraft:the_end"),
+    ];
+    let manifest = Manifest {
+        mwdh_version: env!("CARGO_PKG_VERSION").to_string(),
+        created: chrono::Local::now().to_rfc3339(),
+        world_name: options.world_name.clone(),
+        minecraft_version: read_mc_version(&options.world_path, &options.world_name),
+        dimensions: dimensions
+            .iter()
+            .filter(|(included, _)| *included)
+            .map(|(_, dimension)| dimension.to_string())
+            .collect(),
+        region_issues: region_issues.to_vec(),
+        files: files
+            .iter()
+            .map(|(file, hash)| ManifestEntry { path: file.file_name.clone(), size: file.size, sha256: hash.clone() })
+            .collect(),
+    };
+    let path = dir.join(MANIFEST_NAME);
+    std::fs::write(&path, serde_json::to_vec_pretty(&manifest.to_json())?)
+        .with_context(|| format!("Failed to write: {}", path.display()))?;
+    let meta = std::fs::metadata(&path)?;
+
+    let mut with_manifest = Vec::with_capacity(files.len() + 1);
+    with_manifest.push(FileToCompress::new(path, MANIFEST_NAME.to_string(), &meta));
+    with_manifest.extend(files.into_iter().map(|(file, _)| file));
+    Ok(with_manifest)
+}
+
+/// Takes the manifest out of the files, for formats that don't write the files in the order they're given.
+/// Those have to write it first themselves.
+pub fn take_manifest(files: &mut Vec<FileToCompress>) -> Option<FileToCompress> {
+    if files.first().is_some_and(|file| file.file_name == MANIFEST_NAME) {
+        Some(files.remove(0))
+    } else {
+        None
+    }
+}
+
+/// SHA-256 of every file on as many threads as compressing uses, `None` for the skipped ones.
+fn hash_files(files: &[FileToCompress], options: &ArchiveOptions, tx: &Sender<ProgressMessage>) -> Result<Vec<Option<String>>> {
+    let next = AtomicUsize::new(0);
+    let hashes = Mutex::new(vec![None; files.len()]);
+    let failure = Mutex::new(None);
+    std::thread::scope(|scope| {
+        for _ in 0..options.threads.max(1) {
+            scope.spawn(|| {
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(file) = files.get(index) else {
+                        return;
+                    };
+                    // no point in hashing the rest once the archive failed
+                    if failure.lock().unwrap().is_some() {
+                        return;
+                    }
+                    tx.send(ProgressMessage::Hashing(file.file_name.clone())).ok();
+                    let result = options.cancel.check().and_then(|_| {
+                        archive::with_error_policy(&file.file_name, options, tx, || hash_file(file, options))
+                    });
+                    match result {
+                        Ok(hash) => hashes.lock().unwrap()[index] = hash,
+                        Err(err) => {
+                            failure.lock().unwrap().get_or_insert(err);
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    match failure.into_inner().unwrap() {
+        Some(err) => Err(err),
+        None => Ok(hashes.into_inner().unwrap()),
+    }
+}
+
+/// Hashes the same bytes the archive gets, exactly the scanned size of the file.
+fn hash_file(file: &FileToCompress, options: &ArchiveOptions) -> Result<String> {
+    let input = File::open(&file.src_path).with_context(|| format!("Failed to open: {}", file.src_path.display()))?;
+    // unlike the archiving, this keeps the file in the page cache, it's read again right after
+    let input = ThrottledReader::new(input, options.io_limit.as_ref());
+    let mut input = BufReader::with_capacity(options.io_buffer_size, archive::sized_reader(input, file.size));
+    let mut hasher = Sha256::new();
+    archive::copy_buf(&mut input, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
@@ -0,0 +1,155 @@
+use std::{
+    collections::HashMap,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+use anyhow::{Context, Result, anyhow};
+use flate2::read::GzDecoder;
+
+/// A decoded NBT tag. Only what's needed to read Minecraft's world files.
+#[derive(Debug, Clone)]
+pub enum Tag {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<i8>),
+    String(String),
+    List(Vec<Tag>),
+    Compound(HashMap<String, Tag>),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+impl Tag {
+    /// Looks up a nested tag by a `/` separated path of compound keys, e.g. `Data/Version/Name`.
+    pub fn get(&self, path: &str) -> Option<&Tag> {
+        path.split('/').try_fold(self, |tag, key| match tag {
+            Tag::Compound(map) => map.get(key),
+            _ => None,
+        })
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Tag::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Returns any integer tag widened to i64.
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Tag::Byte(v) => Some(v as i64),
+            Tag::Short(v) => Some(v as i64),
+            Tag::Int(v) => Some(v as i64),
+            Tag::Long(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+/// Reads a gzip compressed NBT file like `level.dat` and returns its root tag.
+pub fn read_gzip_file(path: &Path) -> Result<Tag> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open: {}", path.display()))?;
+    read_root(&mut GzDecoder::new(BufReader::new(file)))
+        .with_context(|| format!("Failed to parse NBT: {}", path.display()))
+}
+
+/// Reads an uncompressed named root tag.
+pub fn read_root<R: Read>(reader: &mut R) -> Result<Tag> {
+    let tag_type = read_u8(reader)?;
+    if tag_type == 0 {
+        return Err(anyhow!("Empty NBT document"));
+    }
+    read_string(reader)?; // the root's name is always empty
+    read_payload(reader, tag_type, 0)
+}
+
+/// Guards against maliciously deep nesting blowing the stack.
+const MAX_DEPTH: usize = 512;
+
+fn read_payload<R: Read>(reader: &mut R, tag_type: u8, depth: usize) -> Result<Tag> {
+    if depth > MAX_DEPTH {
+        return Err(anyhow!("NBT nested too deeply"));
+    }
+    Ok(match tag_type {
+        1 => Tag::Byte(read_u8(reader)? as i8),
+        2 => Tag::Short(i16::from_be_bytes(read_array(reader)?)),
+        3 => Tag::Int(i32::from_be_bytes(read_array(reader)?)),
+        4 => Tag::Long(i64::from_be_bytes(read_array(reader)?)),
+        5 => Tag::Float(f32::from_be_bytes(read_array(reader)?)),
+        6 => Tag::Double(f64::from_be_bytes(read_array(reader)?)),
+        7 => {
+            let len = read_len(reader)?;
+            let mut bytes = vec![0u8; len];
+            reader.read_exact(&mut bytes)?;
+            Tag::ByteArray(bytes.into_iter().map(|b| b as i8).collect())
+        }
+        8 => Tag::String(read_string(reader)?),
+        9 => {
+            let element_type = read_u8(reader)?;
+            let len = read_len(reader)?;
+            let mut list = Vec::with_capacity(len.min(1024));
+            for _ in 0..len {
+                list.push(read_payload(reader, element_type, depth + 1)?);
+            }
+            Tag::List(list)
+        }
+        10 => {
+            let mut map = HashMap::new();
+            loop {
+                let child_type = read_u8(reader)?;
+                if child_type == 0 {
+                    break;
+                }
+                let name = read_string(reader)?;
+                map.insert(name, read_payload(reader, child_type, depth + 1)?);
+            }
+            Tag::Compound(map)
+        }
+        11 => {
+            let len = read_len(reader)?;
+            let mut ints = Vec::with_capacity(len.min(1024));
+            for _ in 0..len {
+                ints.push(i32::from_be_bytes(read_array(reader)?));
+            }
+            Tag::IntArray(ints)
+        }
+        12 => {
+            let len = read_len(reader)?;
+            let mut longs = Vec::with_capacity(len.min(1024));
+            for _ in 0..len {
+                longs.push(i64::from_be_bytes(read_array(reader)?));
+            }
+            Tag::LongArray(longs)
+        }
+        _ => return Err(anyhow!("Unknown NBT tag type {}", tag_type)),
+    })
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> Result<u8> {
+    Ok(read_array::<R, 1>(reader)?[0])
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_len<R: Read>(reader: &mut R) -> Result<usize> {
+    let len = i32::from_be_bytes(read_array(reader)?);
+    usize::try_from(len).map_err(|_| anyhow!("Negative NBT length"))
+}
+
+/// NBT strings are "modified UTF-8", which only differs from UTF-8 for NUL and supplementary characters. Lossy conversion is good enough here.
+fn read_string<R: Read>(reader: &mut R) -> Result<String> {
+    let len = u16::from_be_bytes(read_array(reader)?) as usize;
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes)?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
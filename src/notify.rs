@@ -0,0 +1,172 @@
+//! Push notifications for `--notify`, so a backup started before going to bed reports in the morning whether it
+//! worked, and the host hears when someone downloaded the world.
+
+use std::{fmt::Display, str::FromStr, time::Duration};
+
+use anyhow::{Context, Result, anyhow};
+use reqwest::{Client, header::CONTENT_TYPE};
+use serde_json::json;
+use tracing::{debug, warn};
+use url::Url;
+
+/// How long a notification service gets to answer
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const PUSHOVER_API: &str = "https://api.pushover.net/1/messages.json";
+
+/// Where notifications go, parsed from an URL like `ntfy://ntfy.sh/topic`, `pushover://token@user` or
+/// `https://example.com/hook`.
+#[derive(Debug, Clone)]
+pub enum NotifyTarget {
+    /// The topic URL of an ntfy server, credentials in it are sent with basic auth
+    Ntfy(Url),
+    /// An application token and user key of Pushover
+    Pushover { token: String, user: String },
+    /// Any URL taking the notification as JSON
+    Webhook(Url),
+}
+
+impl FromStr for NotifyTarget {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = s
+            .split_once("://")
+            .ok_or_else(|| anyhow!("Notification target has to be an URL like ntfy://ntfy.sh/topic"))?;
+        match scheme {
+            // ntfy.sh and most self-hosted servers are reached over HTTPS, ntfy+http:// is for the others
+            "ntfy" | "ntfy+http" => {
+                let http_scheme = if scheme == "ntfy" { "https" } else { "http" };
+                let url = Url::parse(&format!("{}://{}", http_scheme, rest)).context("Invalid ntfy URL")?;
+                if ntfy_topic(&url).is_none() {
+                    return Err(anyhow!("Missing topic in {}", s));
+                }
+                Ok(NotifyTarget::Ntfy(url))
+            }
+            "pushover" => {
+                let (token, user) = rest
+                    .trim_end_matches('/')
+                    .split_once('@')
+                    .filter(|(token, user)| !token.is_empty() && !user.is_empty())
+                    .ok_or_else(|| anyhow!("Pushover targets look like pushover://<app token>@<user key>"))?;
+                Ok(NotifyTarget::Pushover {
+                    token: token.to_string(),
+                    user: user.to_string(),
+                })
+            }
+            "http" | "https" => Ok(NotifyTarget::Webhook(Url::parse(s).context("Invalid webhook URL")?)),
+            _ => Err(anyhow!("Unsupported notification target scheme: {}://", scheme)),
+        }
+    }
+}
+
+/// The server's URL and the topic of an ntfy topic URL like `https://ntfy.sh/backups`.
+fn ntfy_topic(url: &Url) -> Option<(Url, String)> {
+    let mut server = url.clone();
+    let topic = url.path_segments()?.rfind(|segment| !segment.is_empty())?.to_string();
+    server.path_segments_mut().ok()?.pop_if_empty().pop().push("");
+    Some((server, topic))
+}
+
+impl Display for NotifyTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            // without the credentials
+            NotifyTarget::Ntfy(url) | NotifyTarget::Webhook(url) => {
+                write!(f, "{}{}", url.host_str().unwrap_or_default(), url.path())
+            }
+            NotifyTarget::Pushover { .. } => write!(f, "Pushover"),
+        }
+    }
+}
+
+/// What happened, decides the wording and priority of a notification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotifyEvent {
+    Compressed,
+    CompressionFailed,
+    FirstDownload,
+}
+
+impl NotifyEvent {
+    /// Name of the event in webhook payloads
+    fn name(self) -> &'static str {
+        match self {
+            NotifyEvent::Compressed => "compressed",
+            NotifyEvent::CompressionFailed => "compression_failed",
+            NotifyEvent::FirstDownload => "first_download",
+        }
+    }
+
+    fn is_failure(self) -> bool {
+        self == NotifyEvent::CompressionFailed
+    }
+}
+
+/// A notification, sent to every target.
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub event: NotifyEvent,
+    pub title: String,
+    pub message: String,
+}
+
+/// Sends the notification to all targets. Failures are only logged, they shouldn't fail a backup that worked.
+pub async fn send(targets: &[NotifyTarget], notification: &Notification) {
+    if targets.is_empty() {
+        return;
+    }
+    let client = Client::new();
+    let sends = targets.iter().map(|target| {
+        let client = &client;
+        async move {
+            match send_to(client, target, notification).await {
+                Ok(()) => debug!("Sent a notification to {}", target),
+                Err(err) => warn!("Failed to send a notification to {}: {:#}", target, err),
+            }
+        }
+    });
+    futures_util::future::join_all(sends).await;
+}
+
+async fn send_to(client: &Client, target: &NotifyTarget, notification: &Notification) -> Result<()> {
+    let request = match target {
+        NotifyTarget::Ntfy(url) => {
+            // published as JSON, headers can't carry the non-ASCII characters world names may have
+            let (server, topic) = ntfy_topic(url).context("Missing topic")?;
+            let (priority, tag) = match notification.event.is_failure() {
+                true => (4, "x"),
+                false => (3, "white_check_mark"),
+            };
+            client.post(server).header(CONTENT_TYPE, "application/json").body(
+                json!({
+                    "topic": topic,
+                    "title": notification.title,
+                    "message": notification.message,
+                    "priority": priority,
+                    "tags": [tag],
+                })
+                .to_string(),
+            )
+        }
+        NotifyTarget::Pushover { token, user } => {
+            let priority = if notification.event.is_failure() { "1" } else { "0" };
+            client.post(PUSHOVER_API).form(&[
+                ("token", token.as_str()),
+                ("user", user.as_str()),
+                ("title", &notification.title),
+                ("message", &notification.message),
+                ("priority", priority),
+            ])
+        }
+        NotifyTarget::Webhook(url) => client.post(url.clone()).header(CONTENT_TYPE, "application/json").body(
+            json!({
+                "event": notification.event.name(),
+                "title": notification.title,
+                "message": notification.message,
+            })
+            .to_string(),
+        ),
+    };
+    request.timeout(TIMEOUT).send().await?.error_for_status()?;
+    Ok(())
+}
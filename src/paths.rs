@@ -0,0 +1,136 @@
+//! The platform specific parts of handling paths. Everything here is a no-op outside of Windows.
+
+use std::path::{Path, PathBuf};
+
+/// Turns a path into an extended-length `\\?\` path on Windows, so worlds nested deeper than the 260 character
+/// `MAX_PATH` limit can still be read and extracted. Such paths skip Windows' own normalization, so only
+/// join path components onto the result, never strings with forward slashes.
+pub fn long_path(path: &Path) -> PathBuf {
+    #[cfg(windows)]
+    {
+        use std::path::{Component, Prefix};
+        let Ok(absolute) = std::path::absolute(path) else {
+            return path.to_path_buf();
+        };
+        let mut components = absolute.components();
+        let rest = |components: std::path::Components| components.collect::<PathBuf>();
+        match components.next() {
+            Some(Component::Prefix(prefix)) => match prefix.kind() {
+                Prefix::Disk(drive) => {
+                    components.next(); // the root directory
+                    PathBuf::from(format!(r"\\?\{}:\", drive as char)).join(rest(components))
+                }
+                Prefix::UNC(server, share) => {
+                    components.next();
+                    PathBuf::from(format!(r"\\?\UNC\{}\{}\", server.to_string_lossy(), share.to_string_lossy()))
+                        .join(rest(components))
+                }
+                // already verbatim or a device path
+                _ => absolute,
+            },
+            _ => absolute,
+        }
+    }
+    #[cfg(not(windows))]
+    {
+        path.to_path_buf()
+    }
+}
+
+/// The name of a file or directory as it goes into an archive entry's path, which always uses forward slashes.
+pub fn entry_name(name: &str) -> String {
+    if cfg!(windows) {
+        name.replace('\\', "/")
+    } else {
+        name.to_string()
+    }
+}
+
+/// Whether Windows refuses to create a file with this name, like `CON`, `aux.json` or `LPT1.txt`.
+/// Names ending with a dot or space are silently changed by Windows, so they count as well.
+pub fn is_reserved_name(name: &str) -> bool {
+    const DEVICE_NAMES: [&str; 4] = ["CON", "PRN", "AUX", "NUL"];
+    if name.ends_with('.') || name.ends_with(' ') {
+        return true;
+    }
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    if DEVICE_NAMES.iter().any(|device| stem.eq_ignore_ascii_case(device)) {
+        return true;
+    }
+    let upper = stem.to_ascii_uppercase();
+    ["COM", "LPT"].iter().any(|device| {
+        upper
+            .strip_prefix(device)
+            .is_some_and(|number| matches!(number, "1" | "2" | "3" | "4" | "5" | "6" | "7" | "8" | "9" | "¹" | "²" | "³"))
+    })
+}
+
+/// Whether reading a file failed because another program holds a lock on it, like the `session.lock` of a world
+/// that is open in a Minecraft client.
+pub fn is_locked(err: &anyhow::Error) -> bool {
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
+    cfg!(windows)
+        && err.chain().any(|cause| {
+            cause
+                .downcast_ref::<std::io::Error>()
+                .and_then(std::io::Error::raw_os_error)
+                .is_some_and(|code| code == ERROR_SHARING_VIOLATION || code == ERROR_LOCK_VIOLATION)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_names_are_reserved() {
+        for name in ["CON", "con", "Prn", "AUX", "nul", "COM1", "com9", "LPT1", "lpt²", "CON.txt", "nul.txt", "aux.tar.gz", "NUL .txt"] {
+            assert!(is_reserved_name(name), "{} should be reserved", name);
+        }
+    }
+
+    #[test]
+    fn trailing_dots_and_spaces_are_reserved() {
+        for name in ["level.dat.", "region ", "world.", ". "] {
+            assert!(is_reserved_name(name), "{:?} should be reserved", name);
+        }
+    }
+
+    #[test]
+    fn ordinary_names_are_not_reserved() {
+        for name in ["level.dat", "r.0.0.mca", "console", "CONFIG", "com", "COM0", "LPT10", "nullable.json", "auxiliary", ".minecraft"] {
+            assert!(!is_reserved_name(name), "{} shouldn't be reserved", name);
+        }
+    }
+
+    #[test]
+    fn entry_names_use_forward_slashes() {
+        assert_eq!(entry_name("saves/world/level.dat"), "saves/world/level.dat");
+        if cfg!(windows) {
+            assert_eq!(entry_name(r"saves\world\region\r.0.0.mca"), "saves/world/region/r.0.0.mca");
+        } else {
+            // a backslash is a valid character in a file name elsewhere
+            assert_eq!(entry_name(r"odd\name"), r"odd\name");
+        }
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn long_paths_are_untouched_outside_of_windows() {
+        assert_eq!(long_path(Path::new("saves/world")), PathBuf::from("saves/world"));
+        assert_eq!(long_path(Path::new("/srv/mc/saves/world")), PathBuf::from("/srv/mc/saves/world"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn long_paths_get_the_verbatim_prefix() {
+        assert_eq!(long_path(Path::new(r"C:\mc\saves\world")), PathBuf::from(r"\\?\C:\mc\saves\world"));
+        assert_eq!(long_path(Path::new(r"\\nas\backups\world")), PathBuf::from(r"\\?\UNC\nas\backups\world"));
+        // already verbatim
+        assert_eq!(long_path(Path::new(r"\\?\C:\mc")), PathBuf::from(r"\\?\C:\mc"));
+        // relative paths are made absolute first
+        assert!(long_path(Path::new(r"saves\world")).starts_with(r"\\?\"));
+        assert!(long_path(Path::new(r"saves\world")).ends_with(r"saves\world"));
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, anyhow};
+use tracing::info;
+use chrono::{DateTime, Datelike, Local};
+
+use crate::CompressionFormat;
+
+/// Which archives to keep when pruning a backup directory. An archive is kept if any of the rules selects it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetentionPolicy {
+    /// Keep the N newest archives
+    pub keep_last: usize,
+    /// Keep the newest archive of each of the last D days that have archives
+    pub keep_daily: usize,
+    /// Keep the newest archive of each of the last W weeks that have archives
+    pub keep_weekly: usize,
+}
+
+impl RetentionPolicy {
+    pub fn keeps_anything(&self) -> bool {
+        self.keep_last > 0 || self.keep_daily > 0 || self.keep_weekly > 0
+    }
+}
+
+#[derive(Clone)]
+pub struct PruneOptions {
+    /// Directory containing the archives
+    pub dir: PathBuf,
+    pub policy: RetentionPolicy,
+    /// Only print what would be deleted
+    pub dry_run: bool,
+}
+
+/// Lists all .zip/.tar.zst(.age) archives directly inside `dir` with their modification time.
+fn list_archives(dir: &Path) -> Result<Vec<(PathBuf, DateTime<Local>)>> {
+    let mut archives = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to read: {}", dir.display()))? {
+        let entry = entry?;
+        let path = entry.path();
+        let meta = entry.metadata()?;
+        if !meta.is_file() || CompressionFormat::from_path(&path).is_none() {
+            continue;
+        }
+        archives.push((path, DateTime::<Local>::from(meta.modified()?)));
+    }
+    Ok(archives)
+}
+
+/// Applies the policy to the archives and returns the ones that should be deleted.
+fn select_for_deletion(
+    mut archives: Vec<(PathBuf, DateTime<Local>)>,
+    policy: &RetentionPolicy,
+) -> Vec<PathBuf> {
+    archives.sort_by_key(|(_, modified)| std::cmp::Reverse(*modified));
+
+    let mut keep = vec![false; archives.len()];
+    keep.iter_mut().take(policy.keep_last).for_each(|keep| *keep = true);
+
+    // archives are sorted newest first, so the first archive seen for a day/week is the one to keep
+    let mut days = HashSet::new();
+    let mut weeks = HashSet::new();
+    for (i, (_, modified)) in archives.iter().enumerate() {
+        if days.len() < policy.keep_daily && days.insert(modified.date_naive()) {
+            keep[i] = true;
+        }
+        let week = modified.iso_week();
+        if weeks.len() < policy.keep_weekly && weeks.insert((week.year(), week.week())) {
+            keep[i] = true;
+        }
+    }
+
+    archives
+        .into_iter()
+        .zip(keep)
+        .filter(|(_, keep)| !keep)
+        .map(|((path, _), _)| path)
+        .collect()
+}
+
+/// Deletes all archives in `dir` that aren't kept by the retention policy. Returns the deleted (or, on a dry run, the to be deleted) archives.
+pub fn prune(dir: &Path, policy: &RetentionPolicy, dry_run: bool) -> Result<Vec<PathBuf>> {
+    if !policy.keeps_anything() {
+        return Err(anyhow!(
+            "Refusing to prune without any --keep-* rule, that would delete every archive"
+        ));
+    }
+    let to_delete = select_for_deletion(list_archives(dir)?, policy);
+    for path in &to_delete {
+        if dry_run {
+            println!("Would delete {}", path.display());
+        } else {
+            std::fs::remove_file(path).with_context(|| format!("Failed to delete: {}", path.display()))?;
+            info!("Deleted {}", path.display());
+        }
+    }
+    Ok(to_delete)
+}
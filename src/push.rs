@@ -0,0 +1,87 @@
+//! `mwdh push`, sending an archive straight to another mwdh that was started with `--accept-uploads`, like the
+//! new machine when moving a server.
+
+use std::path::PathBuf;
+
+use anyhow::{Context, Result, anyhow};
+use reqwest::{Body, Client, StatusCode, header::CONTENT_LENGTH};
+use tokio_util::io::ReaderStream;
+use tracing::info;
+use url::Url;
+
+use crate::{archive::{SHA256_HEADER, progress::transfer_bar, sha256_file}, logging};
+
+#[derive(Clone)]
+pub struct PushOptions {
+    /// The archive to send
+    pub archive: PathBuf,
+    /// URL of the receiving mwdh, like `http://new-host:3000`
+    pub url: Url,
+    /// The receiving side's `--upload-token`
+    pub token: String,
+}
+
+/// Sends the archive to `PUT <url>/upload/<file name>`, along with its SHA-256.
+pub async fn push(options: &PushOptions) -> Result<()> {
+    let file_name = options
+        .archive
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| anyhow!("{} is not a file", options.archive.display()))?;
+    let mut upload_url = options.url.clone();
+    upload_url
+        .path_segments_mut()
+        .map_err(|_| anyhow!("{} can't be pushed to", options.url))?
+        .pop_if_empty()
+        .extend(["upload", &file_name]);
+
+    info!("Hashing {}", options.archive.display());
+    let sha256 = {
+        let archive = options.archive.clone();
+        tokio::task::spawn_blocking(move || sha256_file(&archive)).await??
+    };
+    let file = tokio::fs::File::open(&options.archive)
+        .await
+        .with_context(|| format!("Failed to open: {}", options.archive.display()))?;
+    let file_size = file.metadata().await?.len();
+
+    let progress_bar = transfer_bar(file_size, "Pushing");
+    let stream = {
+        let progress_bar = progress_bar.clone();
+        futures_util::StreamExt::inspect(ReaderStream::new(file), move |chunk| {
+            if let Ok(chunk) = chunk {
+                progress_bar.inc(chunk.len() as u64);
+            }
+        })
+    };
+    let result = async {
+        let response = Client::new()
+            .put(upload_url)
+            .bearer_auth(&options.token)
+            .header(CONTENT_LENGTH, file_size)
+            .header(SHA256_HEADER, &sha256)
+            .body(Body::wrap_stream(stream))
+            .send()
+            .await
+            .context("Push request failed")?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(match status {
+                StatusCode::UNAUTHORIZED => anyhow!("Push failed: the receiving mwdh has a different --upload-token"),
+                _ => match response.text().await.unwrap_or_default().trim() {
+                    "" => anyhow!("Push failed: HTTP {}", status),
+                    reason => anyhow!("Push failed: {} (HTTP {})", reason, status),
+                },
+            });
+        }
+        anyhow::Ok(())
+    }
+    .await;
+    match result {
+        Ok(()) => progress_bar.finish_with_message("Push complete"),
+        Err(_) => progress_bar.abandon_with_message("Push failed"),
+    }
+    result?;
+    info!(target: logging::SUMMARY, "Pushed {} to {}", file_name, options.url);
+    Ok(())
+}
@@ -0,0 +1,454 @@
+use std::{
+    io::{Read, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{Context, Result, anyhow};
+use flate2::{
+    read::{GzDecoder, ZlibDecoder},
+    write::{GzEncoder, ZlibEncoder},
+};
+
+use crate::nbt;
+
+pub const SECTOR_SIZE: usize = 4096;
+pub const CHUNKS_PER_REGION: usize = 1024;
+/// The sector count is stored in a single byte
+pub const MAX_CHUNK_SECTORS: usize = 255;
+
+pub const COMPRESSION_GZIP: u8 = 1;
+pub const COMPRESSION_ZLIB: u8 = 2;
+pub const COMPRESSION_NONE: u8 = 3;
+pub const COMPRESSION_LZ4: u8 = 4;
+/// Set on the compression type when the chunk is stored in a separate `c.<x>.<z>.mcc` file because it didn't fit into 255 sectors
+pub const EXTERNAL_FLAG: u8 = 128;
+/// Written after the data of a chunk [`Region::decompress_chunks`] stored uncompressed, followed by the compression type
+/// it had. The game doesn't read past the chunk's length, so the region file stays valid.
+const DECOMPRESSED_MARKER: &[u8; 4] = b"MWDH";
+
+/// One chunk as stored in a region file, still compressed.
+#[derive(Debug, Clone)]
+pub struct RegionChunk {
+    pub timestamp: u32,
+    pub compression: u8,
+    pub data: Vec<u8>,
+    /// Compression type the chunk had before [`Region::decompress_chunks`]
+    pub decompressed_from: Option<u8>,
+}
+
+impl RegionChunk {
+    pub fn is_external(&self) -> bool {
+        self.compression & EXTERNAL_FLAG != 0
+    }
+
+    /// Decompresses the chunk's NBT payload. Returns `None` for external chunks and compression types we can't decode (LZ4, custom).
+    pub fn decompress(&self) -> Result<Option<Vec<u8>>> {
+        let mut decompressed = Vec::new();
+        match self.compression {
+            COMPRESSION_GZIP => {
+                GzDecoder::new(self.data.as_slice()).read_to_end(&mut decompressed)?;
+            }
+            COMPRESSION_ZLIB => {
+                ZlibDecoder::new(self.data.as_slice()).read_to_end(&mut decompressed)?;
+            }
+            COMPRESSION_NONE => decompressed.extend_from_slice(&self.data),
+            _ => return Ok(None),
+        }
+        Ok(Some(decompressed))
+    }
+
+    /// Parses the chunk's NBT. Returns `None` if the chunk can't be decompressed, see [`RegionChunk::decompress`].
+    pub fn read_nbt(&self) -> Result<Option<nbt::Tag>> {
+        match self.decompress()? {
+            Some(decompressed) => Ok(Some(nbt::read_root(&mut decompressed.as_slice())?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A region (`r.<x>.<z>.mca`) file: a grid of 32x32 chunks.
+pub struct Region {
+    pub chunks: Vec<Option<RegionChunk>>,
+}
+
+/// Problems found while reading a region file. Reading continues past them, the affected chunks are left out.
+#[derive(Debug, Clone)]
+pub struct RegionIssue {
+    /// `None` if the whole file is affected
+    pub chunk_index: Option<usize>,
+    pub message: String,
+}
+
+impl Region {
+    pub fn read(path: &Path) -> Result<(Region, Vec<RegionIssue>)> {
+        let bytes = std::fs::read(path).with_context(|| format!("Failed to read: {}", path.display()))?;
+        Ok(Region::parse(&bytes))
+    }
+
+    /// Parses a region file, collecting issues like truncated or overlapping chunks instead of failing.
+    pub fn parse(bytes: &[u8]) -> (Region, Vec<RegionIssue>) {
+        let mut chunks = vec![None; CHUNKS_PER_REGION];
+        let mut issues = Vec::new();
+        if bytes.is_empty() {
+            // the game creates empty region files sometimes
+            return (Region { chunks }, issues);
+        }
+        if bytes.len() < 2 * SECTOR_SIZE {
+            issues.push(RegionIssue {
+                chunk_index: None,
+                message: format!("File is only {} bytes, too small for a region header", bytes.len()),
+            });
+            return (Region { chunks }, issues);
+        }
+        let total_sectors = bytes.len().div_ceil(SECTOR_SIZE);
+        let mut used_sectors = vec![false; total_sectors];
+        used_sectors[0] = true;
+        used_sectors[1] = true;
+
+        for (index, chunk) in chunks.iter_mut().enumerate() {
+            let location = u32::from_be_bytes(bytes[index * 4..index * 4 + 4].try_into().unwrap());
+            if location == 0 {
+                continue;
+            }
+            let timestamp = u32::from_be_bytes(
+                bytes[SECTOR_SIZE + index * 4..SECTOR_SIZE + index * 4 + 4].try_into().unwrap(),
+            );
+            let sector_offset = (location >> 8) as usize;
+            let sector_count = (location & 0xFF) as usize;
+            let mut issue = |message: String| {
+                issues.push(RegionIssue { chunk_index: Some(index), message });
+            };
+
+            if sector_offset < 2 || sector_count == 0 {
+                issue(format!("Invalid location (sector {}, {} sectors)", sector_offset, sector_count));
+                continue;
+            }
+            if sector_offset + sector_count > total_sectors {
+                issue(format!(
+                    "Chunk (sector {}, {} sectors) lies past the end of the file, it's probably truncated",
+                    sector_offset, sector_count
+                ));
+                continue;
+            }
+            if used_sectors[sector_offset..sector_offset + sector_count].iter().any(|used| *used) {
+                issue("Overlaps with another chunk".to_string());
+                continue;
+            }
+            used_sectors[sector_offset..sector_offset + sector_count].fill(true);
+
+            let start = sector_offset * SECTOR_SIZE;
+            if start + 5 > bytes.len() {
+                issue("Truncated chunk header".to_string());
+                continue;
+            }
+            let length = u32::from_be_bytes(bytes[start..start + 4].try_into().unwrap()) as usize;
+            if length == 0 {
+                issue("Chunk has a length of 0".to_string());
+                continue;
+            }
+            let compression = bytes[start + 4];
+            let data_end = start + 4 + length;
+            if data_end > bytes.len() || length + 4 > sector_count * SECTOR_SIZE {
+                issue(format!("Chunk data of {} bytes is truncated", length));
+                continue;
+            }
+            let padding = &bytes[data_end..(start + sector_count * SECTOR_SIZE).min(bytes.len())];
+            let decompressed_from = padding
+                .strip_prefix(DECOMPRESSED_MARKER)
+                .and_then(|rest| rest.first().copied())
+                .filter(|original| compression == COMPRESSION_NONE && matches!(*original, COMPRESSION_GZIP | COMPRESSION_ZLIB));
+            *chunk = Some(RegionChunk {
+                timestamp,
+                compression,
+                data: bytes[start + 5..data_end].to_vec(),
+                decompressed_from,
+            });
+        }
+        (Region { chunks }, issues)
+    }
+
+    /// Stores every chunk uncompressed (type 3), so the outer archive compression can see the actual data. The compression type
+    /// they had is kept for [`Region::compress_raw_chunks`].
+    /// Chunks that can't be decompressed or would no longer fit into a region file uncompressed are left as they are. Returns whether any chunk changed.
+    pub fn decompress_chunks(&mut self) -> bool {
+        let mut changed = false;
+        for chunk in self.chunks.iter_mut().flatten() {
+            if chunk.compression == COMPRESSION_NONE {
+                continue;
+            }
+            let Ok(Some(raw)) = chunk.decompress() else {
+                continue;
+            };
+            if raw.len() + 5 + DECOMPRESSED_MARKER.len() + 1 > MAX_CHUNK_SECTORS * SECTOR_SIZE {
+                continue;
+            }
+            chunk.decompressed_from = Some(chunk.compression);
+            chunk.compression = COMPRESSION_NONE;
+            chunk.data = raw;
+            changed = true;
+        }
+        changed
+    }
+
+    /// Reverse of [`Region::decompress_chunks`]: compresses the chunks it stored uncompressed the way they were before.
+    /// Chunks the game itself stored uncompressed stay that way.
+    pub fn compress_raw_chunks(&mut self) -> Result<bool> {
+        let mut changed = false;
+        for chunk in self.chunks.iter_mut().flatten() {
+            let Some(original) = chunk.decompressed_from.take() else {
+                continue;
+            };
+            chunk.data = match original {
+                COMPRESSION_GZIP => {
+                    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                    encoder.write_all(&chunk.data)?;
+                    encoder.finish()?
+                }
+                _ => {
+                    let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                    encoder.write_all(&chunk.data)?;
+                    encoder.finish()?
+                }
+            };
+            chunk.compression = original;
+            changed = true;
+        }
+        Ok(changed)
+    }
+
+    /// Decompresses and parses every chunk, returning the ones that fail. Slow, as it touches all the data.
+    pub fn validate_chunks(&self) -> Vec<RegionIssue> {
+        self.chunks
+            .iter()
+            .enumerate()
+            .filter_map(|(index, chunk)| {
+                let err = chunk.as_ref()?.read_nbt().err()?;
+                Some(RegionIssue {
+                    chunk_index: Some(index),
+                    message: format!("Chunk data is corrupt: {:#}", err),
+                })
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.iter().all(Option::is_none)
+    }
+
+    /// Serializes the region, laying out chunks back to back. Fails if a chunk is too large for a region file (>255 sectors).
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = vec![0u8; 2 * SECTOR_SIZE];
+        for (index, chunk) in self.chunks.iter().enumerate() {
+            let Some(chunk) = chunk else {
+                continue;
+            };
+            let sector_offset = bytes.len() / SECTOR_SIZE;
+            let length = chunk.data.len() + 1;
+            let marker_len = if chunk.decompressed_from.is_some() { DECOMPRESSED_MARKER.len() + 1 } else { 0 };
+            let sector_count = (length + 4 + marker_len).div_ceil(SECTOR_SIZE);
+            if sector_count > MAX_CHUNK_SECTORS {
+                return Err(anyhow!("Chunk {} is too large for a region file", index));
+            }
+            bytes.extend_from_slice(&(length as u32).to_be_bytes());
+            bytes.push(chunk.compression);
+            bytes.extend_from_slice(&chunk.data);
+            if let Some(original) = chunk.decompressed_from {
+                bytes.extend_from_slice(DECOMPRESSED_MARKER);
+                bytes.push(original);
+            }
+            bytes.resize(sector_offset * SECTOR_SIZE + sector_count * SECTOR_SIZE, 0);
+
+            let location = ((sector_offset as u32) << 8) | sector_count as u32;
+            bytes[index * 4..index * 4 + 4].copy_from_slice(&location.to_be_bytes());
+            bytes[SECTOR_SIZE + index * 4..SECTOR_SIZE + index * 4 + 4]
+                .copy_from_slice(&chunk.timestamp.to_be_bytes());
+        }
+        Ok(bytes)
+    }
+}
+
+/// Counts the chunks of a region file by only reading its location table.
+pub fn count_chunks(path: &Path) -> Result<usize> {
+    let mut header = Vec::with_capacity(SECTOR_SIZE);
+    std::fs::File::open(path)
+        .with_context(|| format!("Failed to open: {}", path.display()))?
+        .take(SECTOR_SIZE as u64)
+        .read_to_end(&mut header)?;
+    Ok(header.chunks_exact(4).filter(|location| *location != [0; 4]).count())
+}
+
+/// Parses the region coordinates out of a file name like `r.-1.3.mca`.
+pub fn region_coords(file_name: &str) -> Option<(i32, i32)> {
+    let mut parts = file_name.strip_prefix("r.")?.strip_suffix(".mca")?.split('.');
+    let x = parts.next()?.parse().ok()?;
+    let z = parts.next()?.parse().ok()?;
+    parts.next().is_none().then_some((x, z))
+}
+
+/// For an external chunk file like `c.-1.40.mcc`, the name of the region file holding its header entry and the
+/// chunk's index in there.
+pub fn external_chunk_region(file_name: &str) -> Option<(String, usize)> {
+    let mut parts = file_name.strip_prefix("c.")?.strip_suffix(".mcc")?.split('.');
+    let x: i32 = parts.next()?.parse().ok()?;
+    let z: i32 = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    let index = (z.rem_euclid(32) * 32 + x.rem_euclid(32)) as usize;
+    Some((format!("r.{}.{}.mca", x.div_euclid(32), z.div_euclid(32)), index))
+}
+
+/// Rectangular area in block coordinates, bounds inclusive.
+#[derive(Debug, Clone, Copy)]
+pub struct CropArea {
+    pub min_x: i32,
+    pub min_z: i32,
+    pub max_x: i32,
+    pub max_z: i32,
+}
+
+impl CropArea {
+    pub fn new(x1: i32, z1: i32, x2: i32, z2: i32) -> CropArea {
+        CropArea {
+            min_x: x1.min(x2),
+            min_z: z1.min(z2),
+            max_x: x1.max(x2),
+            max_z: z1.max(z2),
+        }
+    }
+
+    /// Square with the given radius in blocks around a center.
+    pub fn around(center_x: i32, center_z: i32, radius: u32) -> CropArea {
+        let radius = radius.min(i32::MAX as u32) as i32;
+        CropArea::new(
+            center_x.saturating_sub(radius),
+            center_z.saturating_sub(radius),
+            center_x.saturating_add(radius),
+            center_z.saturating_add(radius),
+        )
+    }
+
+    fn overlaps(&self, min_x: i64, min_z: i64, size: i64) -> bool {
+        min_x <= self.max_x as i64
+            && min_x + size > self.min_x as i64
+            && min_z <= self.max_z as i64
+            && min_z + size > self.min_z as i64
+    }
+
+    /// Whether any block of the region at the given region coordinates lies inside the area.
+    pub fn overlaps_region(&self, region_x: i32, region_z: i32) -> bool {
+        self.overlaps(region_x as i64 * 512, region_z as i64 * 512, 512)
+    }
+
+    /// Whether any block of the chunk at `index` of the given region lies inside the area.
+    pub fn overlaps_chunk(&self, region_x: i32, region_z: i32, index: usize) -> bool {
+        let chunk_x = region_x as i64 * 32 + (index % 32) as i64;
+        let chunk_z = region_z as i64 * 32 + (index / 32) as i64;
+        self.overlaps(chunk_x * 16, chunk_z * 16, 16)
+    }
+}
+
+impl FromStr for CropArea {
+    type Err = anyhow::Error;
+
+    /// Parses `x1,z1,x2,z2`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let coords = s
+            .split(',')
+            .map(|coord| coord.trim().parse::<i32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| anyhow!("Expected x1,z1,x2,z2 block coordinates, got \"{}\"", s))?;
+        match coords[..] {
+            [x1, z1, x2, z2] => Ok(CropArea::new(x1, z1, x2, z2)),
+            _ => Err(anyhow!("Expected x1,z1,x2,z2 block coordinates, got \"{}\"", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A chunk as the game stores it, with a bit of NBT that differs per chunk.
+    fn chunk(index: usize, compression: u8) -> RegionChunk {
+        let mut nbt = vec![10, 0, 0, 4, 0, 13];
+        nbt.extend_from_slice(b"InhabitedTime");
+        nbt.extend_from_slice(&(index as i64).to_be_bytes());
+        nbt.push(0);
+        let data = match compression {
+            COMPRESSION_GZIP => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&nbt).unwrap();
+                encoder.finish().unwrap()
+            }
+            COMPRESSION_ZLIB => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&nbt).unwrap();
+                encoder.finish().unwrap()
+            }
+            _ => nbt,
+        };
+        RegionChunk {
+            timestamp: index as u32,
+            compression,
+            data,
+            decompressed_from: None,
+        }
+    }
+
+    fn region_with(chunks: &[(usize, u8)]) -> Region {
+        let mut region = Region {
+            chunks: vec![None; CHUNKS_PER_REGION],
+        };
+        for &(index, compression) in chunks {
+            region.chunks[index] = Some(chunk(index, compression));
+        }
+        region
+    }
+
+    #[test]
+    fn raw_chunks_get_their_compression_back() {
+        let chunks = [(0, COMPRESSION_GZIP), (1, COMPRESSION_ZLIB), (2, COMPRESSION_NONE), (1023, COMPRESSION_ZLIB)];
+        let original = region_with(&chunks);
+
+        let mut raw = region_with(&chunks);
+        assert!(raw.decompress_chunks());
+        let (mut raw, issues) = Region::parse(&raw.to_bytes().unwrap());
+        assert!(issues.is_empty());
+        for (index, compression) in chunks {
+            let chunk = raw.chunks[index].as_ref().unwrap();
+            assert_eq!(chunk.compression, COMPRESSION_NONE);
+            let decompressed_from = (compression != COMPRESSION_NONE).then_some(compression);
+            assert_eq!(chunk.decompressed_from, decompressed_from, "chunk {}", index);
+        }
+
+        assert!(raw.compress_raw_chunks().unwrap());
+        let (restored, issues) = Region::parse(&raw.to_bytes().unwrap());
+        assert!(issues.is_empty());
+        for (index, compression) in chunks {
+            let restored = restored.chunks[index].as_ref().unwrap();
+            let original = original.chunks[index].as_ref().unwrap();
+            assert_eq!(restored.compression, compression, "chunk {}", index);
+            assert_eq!(restored.timestamp, original.timestamp);
+            assert_eq!(restored.decompress().unwrap(), original.decompress().unwrap());
+        }
+    }
+
+    #[test]
+    fn chunks_stored_uncompressed_by_the_game_stay_that_way() {
+        let mut region = region_with(&[(5, COMPRESSION_NONE)]);
+        assert!(!region.decompress_chunks());
+        let (mut region, _) = Region::parse(&region.to_bytes().unwrap());
+        assert!(!region.compress_raw_chunks().unwrap());
+        assert_eq!(region.chunks[5].as_ref().unwrap().compression, COMPRESSION_NONE);
+    }
+
+    #[test]
+    fn external_chunks_belong_to_their_region() {
+        assert_eq!(external_chunk_region("c.5.0.mcc"), Some(("r.0.0.mca".to_string(), 5)));
+        assert_eq!(external_chunk_region("c.-1.33.mcc"), Some(("r.-1.1.mca".to_string(), 63)));
+        assert_eq!(external_chunk_region("c.1.2.3.mcc"), None);
+        assert_eq!(external_chunk_region("r.0.0.mca"), None);
+    }
+}
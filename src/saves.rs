@@ -0,0 +1,96 @@
+use std::{
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, Local};
+
+use crate::nbt;
+
+/// A singleplayer world inside the client's saves directory.
+#[derive(Debug, Clone)]
+pub struct Save {
+    /// Name of the world's directory
+    pub dir_name: String,
+    /// Display name from level.dat
+    pub level_name: String,
+    pub last_played: Option<DateTime<Local>>,
+}
+
+/// The saves directory of the default Minecraft launcher on this platform.
+pub fn default_saves_dir() -> Option<PathBuf> {
+    let minecraft_dir = if cfg!(target_os = "windows") {
+        PathBuf::from(std::env::var_os("APPDATA")?).join(".minecraft")
+    } else if cfg!(target_os = "macos") {
+        PathBuf::from(std::env::var_os("HOME")?).join("Library/Application Support/minecraft")
+    } else {
+        PathBuf::from(std::env::var_os("HOME")?).join(".minecraft")
+    };
+    Some(minecraft_dir.join("saves"))
+}
+
+/// Lists all worlds in a saves directory, most recently played first.
+pub fn list_saves(saves_dir: &Path) -> Result<Vec<Save>> {
+    let mut saves = Vec::new();
+    for entry in std::fs::read_dir(saves_dir).with_context(|| format!("Failed to read: {}", saves_dir.display()))? {
+        let entry = entry?;
+        let level_dat = entry.path().join("level.dat");
+        if !level_dat.is_file() {
+            continue;
+        }
+        let dir_name = entry.file_name().to_string_lossy().to_string();
+        // a broken level.dat shouldn't hide the world from the list
+        let data = nbt::read_gzip_file(&level_dat).ok().and_then(|root| root.get("Data").cloned());
+        let level_name = data
+            .as_ref()
+            .and_then(|data| data.get("LevelName"))
+            .and_then(|tag| tag.as_str())
+            .unwrap_or(&dir_name)
+            .to_string();
+        let last_played = data
+            .as_ref()
+            .and_then(|data| data.get("LastPlayed"))
+            .and_then(|tag| tag.as_i64())
+            .and_then(DateTime::from_timestamp_millis)
+            .map(DateTime::<Local>::from);
+        saves.push(Save { dir_name, level_name, last_played });
+    }
+    saves.sort_by_key(|save| std::cmp::Reverse(save.last_played));
+    Ok(saves)
+}
+
+/// Finds a save by its directory name or, if that doesn't match, by its display name.
+pub fn find_save<'a>(saves: &'a [Save], name: &str) -> Option<&'a Save> {
+    saves
+        .iter()
+        .find(|save| save.dir_name == name)
+        .or_else(|| saves.iter().find(|save| save.level_name.eq_ignore_ascii_case(name)))
+}
+
+/// Lets the user pick one of the saves by entering its number.
+pub fn pick_save(saves: &[Save]) -> Result<&Save> {
+    if saves.is_empty() {
+        return Err(anyhow!("No worlds found in the saves directory"));
+    }
+    for (i, save) in saves.iter().enumerate() {
+        let last_played = save
+            .last_played
+            .map(|last_played| last_played.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        println!("{:>3}) {:<32} {:<24} {}", i + 1, save.level_name, save.dir_name, last_played);
+    }
+    let stdin = std::io::stdin();
+    loop {
+        print!("Which world do you want to archive? [1-{}]: ", saves.len());
+        std::io::stdout().flush()?;
+        let mut input = String::new();
+        if stdin.lock().read_line(&mut input)? == 0 {
+            return Err(anyhow!("No world selected"));
+        }
+        match input.trim().parse::<usize>() {
+            Ok(number) if (1..=saves.len()).contains(&number) => return Ok(&saves[number - 1]),
+            _ => println!("Please enter a number between 1 and {}", saves.len()),
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    net::IpAddr,
+    path::PathBuf,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use chrono::{DateTime, Local};
+use tracing::error;
+use http_body_util::{BodyExt, combinators::BoxBody};
+use hyper::{
+    Request, Response,
+    body::{Body, Bytes, Frame, SizeHint},
+    header::{REFERER, USER_AGENT},
+};
+
+/// Where the access log gets written to.
+#[derive(Debug, Clone)]
+pub enum AccessLogTarget {
+    Stdout,
+    File(PathBuf),
+}
+
+impl From<&str> for AccessLogTarget {
+    fn from(value: &str) -> Self {
+        match value {
+            "stdout" | "-" => AccessLogTarget::Stdout,
+            path => AccessLogTarget::File(PathBuf::from(path)),
+        }
+    }
+}
+
+/// Writes one line per request in the combined log format, followed by the request duration in seconds.
+pub struct AccessLog {
+    file: Option<Mutex<File>>,
+}
+
+impl AccessLog {
+    pub fn open(target: &AccessLogTarget) -> std::io::Result<Self> {
+        let file = match target {
+            AccessLogTarget::Stdout => None,
+            AccessLogTarget::File(path) => Some(Mutex::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+        };
+        Ok(Self { file })
+    }
+
+    fn write_line(&self, line: &str) {
+        match self.file {
+            Some(ref file) => {
+                if let Err(err) = writeln!(file.lock().unwrap(), "{}", line) {
+                    error!("Failed to write access log: {}", err);
+                }
+            }
+            None => println!("{}", line),
+        }
+    }
+}
+
+/// The request related half of an access log line, captured before the request is handled.
+pub struct AccessLogEntry {
+    remote_ip: IpAddr,
+    time: DateTime<Local>,
+    started: Instant,
+    request_line: String,
+    referer: String,
+    user_agent: String,
+}
+
+impl AccessLogEntry {
+    pub fn new<B>(req: &Request<B>, remote_ip: IpAddr) -> Self {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|value: &hyper::header::HeaderValue| value.to_str().ok())
+                .unwrap_or("-")
+                .to_string()
+        };
+        Self {
+            remote_ip,
+            time: Local::now(),
+            started: Instant::now(),
+            request_line: format!("{} {} {:?}", req.method(), req.uri(), req.version()),
+            referer: header(REFERER),
+            user_agent: header(USER_AGENT),
+        }
+    }
+
+    /// Wraps the response body so the entry is logged with the actual number of bytes sent once the body is done or dropped.
+    pub fn log_response(
+        self,
+        log: Arc<AccessLog>,
+        response: Response<BoxBody<Bytes, std::io::Error>>,
+    ) -> Response<BoxBody<Bytes, std::io::Error>> {
+        let status = response.status().as_u16();
+        response.map(|inner| {
+            LoggedBody {
+                inner,
+                bytes_sent: 0,
+                status,
+                entry: Some(self),
+                log,
+            }
+            .boxed()
+        })
+    }
+}
+
+impl AccessLogEntry {
+    /// Writes the line for a response of `status` that sent `bytes_sent` bytes of body.
+    pub fn log(self, log: &AccessLog, status: u16, bytes_sent: u64) {
+        log.write_line(&format!(
+            "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\" {:.3}",
+            self.remote_ip,
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.request_line,
+            status,
+            bytes_sent,
+            self.referer,
+            self.user_agent,
+            self.started.elapsed().as_secs_f64()
+        ));
+    }
+}
+
+struct LoggedBody {
+    inner: BoxBody<Bytes, std::io::Error>,
+    bytes_sent: u64,
+    status: u16,
+    entry: Option<AccessLogEntry>,
+    log: Arc<AccessLog>,
+}
+
+impl Body for LoggedBody {
+    type Data = Bytes;
+    type Error = std::io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(ref frame))) = poll
+            && let Some(data) = frame.data_ref()
+        {
+            self.bytes_sent += data.len() as u64;
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            entry.log(&self.log, self.status, self.bytes_sent);
+        }
+    }
+}
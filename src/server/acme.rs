@@ -0,0 +1,544 @@
+//! `--acme-domain`, getting a certificate from Let's Encrypt (or another ACME CA) and renewing it while the server
+//! runs. The CA checks that the domain points to the server with the TLS-ALPN-01 challenge: it connects to port 443
+//! asking for the `acme-tls/1` protocol, and the server answers with a certificate made for that check. So port 443
+//! has to reach the server, with `--port 443` or a port forwarding.
+//!
+//! The account key and the certificate are kept in the state directory, a restart only asks for a new certificate
+//! when the one there expires soon.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use reqwest::{Client, Response, header::CONTENT_TYPE};
+use ring::{
+    rand::SystemRandom,
+    signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair},
+};
+use rustls::{
+    ServerConfig, ServerConnection,
+    crypto::ring as ring_provider,
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, pem::PemObject},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::cancel::CancellationToken;
+
+/// The ACME directory of Let's Encrypt
+pub const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+/// The ACME directory of Let's Encrypt's staging environment, for testing without running into its rate limits
+pub const LETS_ENCRYPT_STAGING: &str = "https://acme-staging-v02.api.letsencrypt.org/directory";
+
+/// ALPN protocol of the TLS-ALPN-01 challenge (RFC 8737)
+const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+/// Certificates are renewed once they expire within this long
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// How often the certificate's expiry is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+/// How long to wait after getting a certificate failed
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How long the CA gets to check a challenge or issue the certificate
+const POLL_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Which certificate to get, from where, and where to keep it.
+#[derive(Clone, Debug)]
+pub struct AcmeOptions {
+    /// Domains the certificate is for. The first one names the files in the state directory.
+    pub domains: Vec<String>,
+
+    /// Directory the account key and the certificate are kept in
+    pub state_dir: PathBuf,
+
+    /// Email address the CA may write to about the certificate, like before it expires when renewing failed
+    pub contact: Option<String>,
+
+    /// ACME directory of the CA, [`LETS_ENCRYPT`] by default
+    pub directory_url: Url,
+
+    /// Whether the operator read and accepted the CA's terms of service. Without, the server doesn't start and says
+    /// where they are.
+    pub accept_tos: bool,
+}
+
+/// Picks the certificate for a TLS handshake: the one from the CA, or the one of a challenge when the CA checks the
+/// domain.
+#[derive(Debug, Default)]
+pub(super) struct CertResolver {
+    current: RwLock<Option<Current>>,
+    /// Certificates for the TLS-ALPN-01 challenges in progress, by domain
+    challenges: Mutex<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+#[derive(Debug)]
+struct Current {
+    key: Arc<CertifiedKey>,
+    not_after: SystemTime,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let is_challenge = client_hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|protocol| protocol == ACME_TLS_ALPN));
+        if is_challenge {
+            let domain = client_hello.server_name()?;
+            return self.challenges.lock().unwrap().get(domain).cloned();
+        }
+        self.current.read().unwrap().as_ref().map(|current| current.key.clone())
+    }
+}
+
+/// Accepts connections with the certificates of `resolver`. Handshakes fail until there is one.
+pub(super) fn acceptor(resolver: Arc<CertResolver>) -> Result<TlsAcceptor> {
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring_provider::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"http/1.1".to_vec(), ACME_TLS_ALPN.to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Whether the connection is the CA checking a challenge, which is over after the handshake.
+pub(super) fn is_challenge(connection: &ServerConnection) -> bool {
+    connection.alpn_protocol() == Some(ACME_TLS_ALPN)
+}
+
+/// Fails with the link to the CA's terms of service unless they were accepted, an account can't be created without.
+pub(super) async fn check_terms(options: &AcmeOptions) -> Result<()> {
+    if options.accept_tos {
+        return Ok(());
+    }
+    let terms = match Directory::fetch(&Client::new(), &options.directory_url).await {
+        Ok(directory) => directory.terms_of_service,
+        Err(err) => {
+            warn!("Failed to look up the terms of service of {}: {:#}", options.directory_url, err);
+            None
+        }
+    };
+    match terms {
+        Some(terms) => bail!("Read the terms of service of the CA at {} and pass --acme-accept-tos to accept them", terms),
+        None => bail!("Read the terms of service of the CA of {} and pass --acme-accept-tos to accept them", options.directory_url),
+    }
+}
+
+/// Gets a certificate when there's none in the state directory or it expires soon, and keeps renewing it until
+/// `cancel` is cancelled.
+pub(super) async fn keep_certificate(options: AcmeOptions, resolver: Arc<CertResolver>, cancel: CancellationToken) {
+    loop {
+        let wait = match renew_if_needed(&options, &resolver).await {
+            Ok(()) => CHECK_INTERVAL,
+            Err(err) => {
+                warn!(
+                    "Failed to get a certificate for {}, trying again in {}: {:#}",
+                    options.domains.join(", "),
+                    humantime::format_duration(RETRY_INTERVAL),
+                    err
+                );
+                RETRY_INTERVAL
+            }
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = cancel.cancelled() => return,
+        }
+    }
+}
+
+async fn renew_if_needed(options: &AcmeOptions, resolver: &CertResolver) -> Result<()> {
+    let cert_path = options.state_dir.join(format!("{}.pem", options.domains[0]));
+    let key_path = options.state_dir.join(format!("{}.key", options.domains[0]));
+    if resolver.current.read().unwrap().is_none() && tokio::fs::try_exists(&cert_path).await.unwrap_or(false) {
+        match load_certificate(&cert_path, &key_path).await {
+            Ok(current) => *resolver.current.write().unwrap() = Some(current),
+            Err(err) => warn!("Failed to load the certificate in {}, getting a new one: {:#}", cert_path.display(), err),
+        }
+    }
+    let expires_soon = match *resolver.current.read().unwrap() {
+        Some(ref current) => current.not_after < SystemTime::now() + RENEW_BEFORE,
+        None => true,
+    };
+    if !expires_soon {
+        return Ok(());
+    }
+
+    info!("Getting a certificate for {} from {}", options.domains.join(", "), options.directory_url);
+    let (chain, key) = order_certificate(options, resolver).await?;
+    tokio::fs::create_dir_all(&options.state_dir).await?;
+    write_private(&key_path, key.as_bytes()).await?;
+    tokio::fs::write(&cert_path, chain).await?;
+    let current = load_certificate(&cert_path, &key_path).await?;
+    info!(
+        "Got a certificate for {}, valid until {}",
+        options.domains.join(", "),
+        humantime::format_rfc3339_seconds(current.not_after)
+    );
+    *resolver.current.write().unwrap() = Some(current);
+    Ok(())
+}
+
+async fn load_certificate(cert_path: &Path, key_path: &Path) -> Result<Current> {
+    let chain = tokio::fs::read(cert_path).await.context("Failed to read the certificate")?;
+    let chain = CertificateDer::pem_slice_iter(&chain)
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to read the certificate")?;
+    let not_after = chain
+        .first()
+        .and_then(|cert| not_after(cert))
+        .ok_or_else(|| anyhow!("Can't tell when the certificate expires"))?;
+    let key = tokio::fs::read(key_path).await.context("Failed to read the private key")?;
+    let key = PrivateKeyDer::from_pem_slice(&key).context("Failed to read the private key")?;
+    let signing_key = ring_provider::sign::any_supported_type(&key)?;
+    Ok(Current {
+        key: Arc::new(CertifiedKey::new(chain, signing_key)),
+        not_after,
+    })
+}
+
+/// Orders a certificate for the domains and answers the challenges, returns the certificate chain and its key as PEM.
+async fn order_certificate(options: &AcmeOptions, resolver: &CertResolver) -> Result<(String, String)> {
+    let mut acme = AcmeClient::new(options).await?;
+    acme.register(options).await?;
+
+    let identifiers: Vec<_> = options.domains.iter().map(|domain| json!({"type": "dns", "value": domain})).collect();
+    let (order, order_url) = acme.post_json(&acme.directory.new_order.clone(), Some(&json!({ "identifiers": identifiers }))).await?;
+    let order_url = order_url.ok_or_else(|| anyhow!("The CA didn't say where the order is"))?;
+    for authorization_url in string_array(&order["authorizations"]) {
+        acme.authorize(&authorization_url, resolver).await?;
+    }
+
+    let key = rcgen::KeyPair::generate()?;
+    let csr = rcgen::CertificateParams::new(options.domains.clone())?.serialize_request(&key)?;
+    let finalize_url = string(&order["finalize"])?;
+    acme.post_json(&finalize_url, Some(&json!({ "csr": URL_SAFE_NO_PAD.encode(csr.der()) }))).await?;
+    let order = acme.poll(&order_url, &["valid", "invalid"]).await?;
+    if order["status"] != "valid" {
+        bail!("The CA didn't issue the certificate: {}", order["error"]);
+    }
+    let chain = acme.post(&string(&order["certificate"])?, None).await?.text().await?;
+    Ok((chain, key.serialize_pem()))
+}
+
+/// The URLs of the CA's directory that are needed.
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+    terms_of_service: Option<String>,
+}
+
+impl Directory {
+    async fn fetch(client: &Client, directory_url: &Url) -> Result<Self> {
+        let directory = json_body(client.get(directory_url.clone()).send().await?.error_for_status()?).await?;
+        Ok(Directory {
+            new_nonce: string(&directory["newNonce"])?,
+            new_account: string(&directory["newAccount"])?,
+            new_order: string(&directory["newOrder"])?,
+            terms_of_service: directory["meta"]["termsOfService"].as_str().map(str::to_string),
+        })
+    }
+}
+
+/// Signs requests to the CA with the account key, as RFC 8555 describes.
+struct AcmeClient {
+    client: Client,
+    rng: SystemRandom,
+    key: EcdsaKeyPair,
+    directory: Directory,
+    nonce: Option<String>,
+    /// The account's URL once registered, requests are signed with the key itself before
+    account_url: Option<String>,
+}
+
+impl AcmeClient {
+    /// Loads the account key from the state directory, or creates one.
+    async fn new(options: &AcmeOptions) -> Result<Self> {
+        let key_path = options.state_dir.join("account.key");
+        let key = match tokio::fs::read_to_string(&key_path).await {
+            Ok(pem) => rcgen::KeyPair::from_pem(&pem).with_context(|| format!("Invalid account key in {}", key_path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let key = rcgen::KeyPair::generate()?;
+                tokio::fs::create_dir_all(&options.state_dir).await?;
+                write_private(&key_path, key.serialize_pem().as_bytes()).await?;
+                key
+            }
+            Err(err) => return Err(err).with_context(|| format!("Failed to read {}", key_path.display())),
+        };
+        let rng = SystemRandom::new();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &key.serialize_der(), &rng)
+            .map_err(|err| anyhow!("The account key isn't a P-256 key: {}", err))?;
+
+        let client = Client::new();
+        let directory = Directory::fetch(&client, &options.directory_url).await?;
+        Ok(AcmeClient {
+            client,
+            rng,
+            key,
+            directory,
+            nonce: None,
+            account_url: None,
+        })
+    }
+
+    /// Creates the account, or looks up the one of the key.
+    async fn register(&mut self, options: &AcmeOptions) -> Result<()> {
+        if !options.accept_tos {
+            bail!("The terms of service of the CA weren't accepted");
+        }
+        let mut account = json!({ "termsOfServiceAgreed": true });
+        if let Some(ref contact) = options.contact {
+            account["contact"] = json!([format!("mailto:{}", contact)]);
+        }
+        let (_, account_url) = self.post_json(&self.directory.new_account.clone(), Some(&account)).await?;
+        self.account_url = Some(account_url.ok_or_else(|| anyhow!("The CA didn't say where the account is"))?);
+        Ok(())
+    }
+
+    /// Answers the TLS-ALPN-01 challenge of an authorization and waits until the CA checked it.
+    async fn authorize(&mut self, authorization_url: &str, resolver: &CertResolver) -> Result<()> {
+        let (authorization, _) = self.post_json(authorization_url, None).await?;
+        if authorization["status"] == "valid" {
+            return Ok(());
+        }
+        let domain = string(&authorization["identifier"]["value"])?;
+        let challenge = authorization["challenges"]
+            .as_array()
+            .and_then(|challenges| challenges.iter().find(|challenge| challenge["type"] == "tls-alpn-01"))
+            .ok_or_else(|| anyhow!("The CA doesn't offer the TLS-ALPN-01 challenge for {}", domain))?;
+        let key_authorization = format!("{}.{}", string(&challenge["token"])?, self.thumbprint());
+        resolver
+            .challenges
+            .lock()
+            .unwrap()
+            .insert(domain.clone(), challenge_certificate(&domain, &key_authorization)?);
+        debug!("Answering the TLS-ALPN-01 challenge for {}", domain);
+
+        let result = async {
+            self.post_json(&string(&challenge["url"])?, Some(&json!({}))).await?;
+            self.poll(authorization_url, &["valid", "invalid", "deactivated", "expired", "revoked"]).await
+        }
+        .await;
+        resolver.challenges.lock().unwrap().remove(&domain);
+        let authorization = result?;
+        if authorization["status"] != "valid" {
+            let error = authorization["challenges"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .find_map(|challenge| challenge["error"]["detail"].as_str())
+                .unwrap_or("no reason given");
+            bail!("The CA couldn't check {}: {}", domain, error);
+        }
+        Ok(())
+    }
+
+    /// Fetches `url` until its status is one of `done`.
+    async fn poll(&mut self, url: &str, done: &[&str]) -> Result<Value> {
+        let started = Instant::now();
+        loop {
+            let (value, _) = self.post_json(url, None).await?;
+            if done.iter().any(|status| value["status"] == *status) {
+                return Ok(value);
+            }
+            if started.elapsed() > POLL_TIMEOUT {
+                bail!("The CA is still at {} after {}", value["status"], humantime::format_duration(POLL_TIMEOUT));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Posts `payload`, or nothing for a POST-as-GET, and returns the JSON answer with its `Location`.
+    async fn post_json(&mut self, url: &str, payload: Option<&Value>) -> Result<(Value, Option<String>)> {
+        let response = self.post(url, payload).await?;
+        let location = response
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .map(str::to_string);
+        Ok((json_body(response).await?, location))
+    }
+
+    /// Signs and posts `payload`. Retries when the CA rejected the nonce, which it does now and then.
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<Response> {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let body = self.sign(url, payload).await?;
+            let response = self
+                .client
+                .post(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(body)
+                .send()
+                .await?;
+            self.nonce = response
+                .headers()
+                .get("Replay-Nonce")
+                .and_then(|nonce| nonce.to_str().ok())
+                .map(str::to_string);
+            if response.status().is_success() {
+                return Ok(response);
+            }
+            let status = response.status();
+            let problem = json_body(response).await.unwrap_or_default();
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" && attempts < 3 {
+                continue;
+            }
+            bail!("HTTP {} from the CA: {}", status, problem["detail"].as_str().unwrap_or("no details"));
+        }
+    }
+
+    /// The request as JWS in flattened JSON, signed with ES256.
+    async fn sign(&mut self, url: &str, payload: Option<&Value>) -> Result<String> {
+        let nonce = match self.nonce.take() {
+            Some(nonce) => nonce,
+            None => self
+                .client
+                .head(&self.directory.new_nonce)
+                .send()
+                .await?
+                .headers()
+                .get("Replay-Nonce")
+                .and_then(|nonce| nonce.to_str().ok())
+                .map(str::to_string)
+                .ok_or_else(|| anyhow!("The CA didn't hand out a nonce"))?,
+        };
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match self.account_url {
+            Some(ref account_url) => protected["kid"] = json!(account_url),
+            None => protected["jwk"] = self.jwk(),
+        }
+        let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+        let payload = payload.map(|payload| URL_SAFE_NO_PAD.encode(payload.to_string())).unwrap_or_default();
+        let signature = self
+            .key
+            .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|_| anyhow!("Failed to sign the request to the CA"))?;
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+        })
+        .to_string())
+    }
+
+    /// The public account key as JWK, with the members in the order RFC 7638 hashes them in.
+    fn jwk(&self) -> Value {
+        // an uncompressed point, 0x04 followed by x and y
+        let point = self.key.public_key().as_ref();
+        json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..65]),
+        })
+    }
+
+    /// The JWK thumbprint of the account key (RFC 7638), part of every key authorization.
+    fn thumbprint(&self) -> String {
+        // serde_json sorts the members and leaves out whitespace, as the thumbprint needs it
+        URL_SAFE_NO_PAD.encode(Sha256::digest(self.jwk().to_string()))
+    }
+}
+
+/// The self-signed certificate proving to the CA that the server holds the account key (RFC 8737).
+fn challenge_certificate(domain: &str, key_authorization: &str) -> Result<Arc<CertifiedKey>> {
+    let key = rcgen::KeyPair::generate()?;
+    let mut params = rcgen::CertificateParams::new(vec![domain.to_string()])?;
+    params.custom_extensions = vec![rcgen::CustomExtension::new_acme_identifier(&Sha256::digest(key_authorization))];
+    let cert = params.self_signed(&key)?;
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()));
+    Ok(Arc::new(CertifiedKey::new(vec![cert.der().clone()], ring_provider::sign::any_supported_type(&key)?)))
+}
+
+/// Writes a private key, readable only by the owner on Unix.
+async fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).await?;
+    file.write_all(contents).await?;
+    file.flush().await
+}
+
+fn string(value: &Value) -> Result<String> {
+    value
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("Unexpected answer from the CA, expected a string instead of {}", value))
+}
+
+fn string_array(value: &Value) -> Vec<String> {
+    value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|value| value.as_str().map(str::to_string))
+        .collect()
+}
+
+/// The JSON body of a response, reqwest is built without its `json` feature.
+async fn json_body(response: Response) -> Result<Value> {
+    Ok(serde_json::from_slice(&response.bytes().await?)?)
+}
+
+/// When the DER certificate expires, read by hand instead of pulling in an X.509 parser for a single field.
+fn not_after(cert: &[u8]) -> Option<SystemTime> {
+    let (_, cert, _) = der_element(cert)?;
+    let (_, mut tbs, _) = der_element(cert)?;
+    // the version is optional, it's tagged [0]
+    if tbs.first() == Some(&0xa0) {
+        tbs = der_element(tbs)?.2;
+    }
+    // serial number, signature algorithm and issuer come before the validity
+    for _ in 0..3 {
+        tbs = der_element(tbs)?.2;
+    }
+    let (_, validity, _) = der_element(tbs)?;
+    let (_, _, not_after) = der_element(validity)?;
+    let (tag, time, _) = der_element(not_after)?;
+    let time = std::str::from_utf8(time).ok()?.strip_suffix('Z')?;
+    let time = match tag {
+        // UTCTime, two digit years from 1950 to 2049
+        0x17 => format!("{}{}", if time.get(..2)? >= "50" { "19" } else { "20" }, time),
+        // GeneralizedTime
+        0x18 => time.to_string(),
+        _ => return None,
+    };
+    let time = chrono::NaiveDateTime::parse_from_str(&time, "%Y%m%d%H%M%S").ok()?;
+    Some(SystemTime::from(time.and_utc()))
+}
+
+/// Splits off the first DER element, returns its tag, its contents and what follows it.
+fn der_element(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = der.split_first()?;
+    let (&len, rest) = rest.split_first()?;
+    let (len, rest) = if len < 0x80 {
+        (len as usize, rest)
+    } else {
+        let len_bytes = (len & 0x7f) as usize;
+        if len_bytes == 0 || len_bytes > 4 || rest.len() < len_bytes {
+            return None;
+        }
+        let (len, rest) = rest.split_at(len_bytes);
+        (len.iter().fold(0, |len, &byte| len << 8 | byte as usize), rest)
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let (contents, rest) = rest.split_at(len);
+    Some((tag, contents, rest))
+}
@@ -0,0 +1,76 @@
+//! `/<path>.sha256` and `/<path>.manifest.json` next to every single archive, so `mwdh download` and other
+//! automation can check and inspect an archive without getting its checksum some other way.
+//!
+//! Like the torrents, the SHA-256 is computed once per archive and again only when the archive changed.
+
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::{CompressionFormat, archive::sha256_file, verify};
+
+/// The SHA-256 of the archives so far, by archive.
+#[derive(Default)]
+pub(super) struct ChecksumCache {
+    sums: Mutex<HashMap<PathBuf, CachedSha256>>,
+}
+
+struct CachedSha256 {
+    len: u64,
+    modified: Option<SystemTime>,
+    sha256: String,
+}
+
+impl ChecksumCache {
+    /// `archive`'s SHA-256 in the format of `sha256sum`, for the file name it's downloaded as. The archive is hashed
+    /// when it wasn't yet or it changed, requests wait while that's going on.
+    pub(super) async fn sha256sum(&self, archive: &Path, file_name: &str) -> io::Result<String> {
+        let metadata = tokio::fs::metadata(archive).await?;
+        let modified = metadata.modified().ok();
+        let mut sums = self.sums.lock().await;
+        if let Some(cached) = sums.get(archive)
+            && cached.len == metadata.len()
+            && cached.modified == modified
+        {
+            return Ok(format!("{}  {}\n", cached.sha256, file_name));
+        }
+
+        info!("Hashing {} for its SHA-256", archive.display());
+        let sha256 = {
+            let archive = archive.to_path_buf();
+            tokio::task::spawn_blocking(move || sha256_file(&archive))
+                .await
+                .map_err(io::Error::other)?
+                .map_err(io::Error::other)?
+        };
+        let line = format!("{}  {}\n", sha256, file_name);
+        sums.insert(
+            archive.to_path_buf(),
+            CachedSha256 {
+                len: metadata.len(),
+                modified,
+                sha256,
+            },
+        );
+        Ok(line)
+    }
+}
+
+/// The manifest of an archive created with `--manifest`. `None` for other archives and encrypted ones, which can't
+/// be looked into.
+pub(super) async fn manifest(archive: &Path) -> io::Result<Option<Vec<u8>>> {
+    if CompressionFormat::from_file_extension(archive.extension()).is_none() {
+        return Ok(None);
+    }
+    let archive = archive.to_path_buf();
+    tokio::task::spawn_blocking(move || verify::manifest_json(&archive))
+        .await
+        .map_err(io::Error::other)?
+        .map_err(io::Error::other)
+}
@@ -0,0 +1,83 @@
+//! CORS for `--cors-origin`, so web map viewers and server panels on other sites can fetch the archives, `/status`
+//! and `/events` from the browser.
+
+use hyper::{
+    HeaderMap, Method, Request, Response, StatusCode,
+    header::{
+        ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+        ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE,
+        ACCESS_CONTROL_REQUEST_METHOD, HeaderValue, ORIGIN, VARY,
+    },
+};
+use http_body_util::{BodyExt, Empty, combinators::BoxBody};
+use hyper::body::Bytes;
+
+use crate::ServerOptions;
+
+/// Request headers scripts may send, for downloads, conditional requests and `mwdh push`
+const ALLOWED_HEADERS: &str = "Authorization, Range, If-Range, If-None-Match, If-Modified-Since, Content-Type, X-Mwdh-Sha256";
+/// Response headers scripts may read besides the safelisted ones
+const EXPOSED_HEADERS: &str = "Content-Disposition, Content-Length, Content-Range, Accept-Ranges, ETag, Last-Modified";
+/// How long browsers may cache a preflight, in seconds
+const MAX_AGE: &str = "86400";
+
+/// `https://map.example.com` for `https://map.example.com/`, `*` stays as it is.
+pub(crate) fn normalize_origin(origin: &str) -> String {
+    origin.trim().trim_end_matches('/').to_string()
+}
+
+/// The answer to a preflight request from an allowed origin, `None` for anything else.
+pub(super) fn preflight<B>(
+    options: &ServerOptions,
+    req: &Request<B>,
+) -> Option<Response<BoxBody<Bytes, std::io::Error>>> {
+    if req.method() != Method::OPTIONS || !req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD) {
+        return None;
+    }
+    allowed_origin(options, req.headers())?;
+    let methods = if options.uploads.is_some() { "GET, HEAD, PUT" } else { "GET, HEAD" };
+    let mut response = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(ACCESS_CONTROL_ALLOW_METHODS, methods)
+        .header(ACCESS_CONTROL_ALLOW_HEADERS, ALLOWED_HEADERS)
+        .header(ACCESS_CONTROL_MAX_AGE, MAX_AGE)
+        .body(Empty::new().map_err(|_| std::io::Error::other("infallible")).boxed())
+        .unwrap();
+    add_headers(options, req.headers(), &mut response);
+    Some(response)
+}
+
+/// Lets the requesting origin read the response, if it's allowed to.
+pub(super) fn add_headers<B>(options: &ServerOptions, request_headers: &HeaderMap, response: &mut Response<B>) {
+    if options.cors_origins.is_empty() {
+        return;
+    }
+    let headers = response.headers_mut();
+    // the answer depends on the origin unless every origin gets the same
+    if !options.cors_origins.iter().any(|origin| origin == "*") {
+        headers.append(VARY, HeaderValue::from_static("Origin"));
+    }
+    let Some(origin) = allowed_origin(options, request_headers) else {
+        return;
+    };
+    // credentials can't be combined with `*`
+    if options.basic_auth.is_some() && origin != "*" {
+        headers.insert(ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+    }
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_static(EXPOSED_HEADERS));
+}
+
+/// The value of `Access-Control-Allow-Origin` for the request's origin.
+fn allowed_origin(options: &ServerOptions, headers: &HeaderMap) -> Option<HeaderValue> {
+    let origin = headers.get(ORIGIN)?;
+    for allowed in &options.cors_origins {
+        if allowed == "*" {
+            return Some(HeaderValue::from_static("*"));
+        }
+        if allowed.as_bytes().eq_ignore_ascii_case(origin.as_bytes()) {
+            return Some(origin.clone());
+        }
+    }
+    None
+}
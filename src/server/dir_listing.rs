@@ -0,0 +1,121 @@
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local};
+use percent_encoding::{AsciiSet, CONTROLS, percent_decode_str, utf8_percent_encode};
+
+use crate::{CompressionFormat, archive};
+
+/// Characters that have to be escaped in a single URL path segment.
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+struct ListedFile {
+    name: String,
+    size: u64,
+    modified: Option<DateTime<Local>>,
+}
+
+/// Picks the Content-Type for a served file based on its extension.
+pub fn content_type_for(path: &Path) -> &'static str {
+    // age encrypted archives (.age) have no registered media type and end up as opaque binary data
+    CompressionFormat::from_file_extension(path.extension())
+        .map(|format| format.get_mime_type())
+        .unwrap_or("application/octet-stream")
+}
+
+/// Resolves a percent-encoded file name from the URL to a file directly inside `dir`.
+/// Returns `None` for anything that would escape the directory or isn't a regular file.
+pub fn resolve_file(dir: &Path, encoded_name: &str) -> Option<PathBuf> {
+    let name = percent_decode_str(encoded_name).decode_utf8().ok()?;
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        return None;
+    }
+    // archives that are still being written are cut off
+    if archive::is_partial(Path::new(name.as_ref())) {
+        return None;
+    }
+    let path = dir.join(name.as_ref());
+    path.is_file().then_some(path)
+}
+
+/// Finds the most recently modified .zip or .tar.zst archive directly inside `dir`.
+pub fn latest_archive(dir: &Path) -> std::io::Result<Option<PathBuf>> {
+    let mut latest: Option<(std::time::SystemTime, PathBuf)> = None;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        // `.part` files aren't archives yet
+        if CompressionFormat::from_path(&path).is_none() {
+            continue;
+        }
+        let meta = entry.metadata()?;
+        if !meta.is_file() {
+            continue;
+        }
+        let modified = meta.modified()?;
+        if latest.as_ref().is_none_or(|(latest_modified, _)| modified > *latest_modified) {
+            latest = Some((modified, path));
+        }
+    }
+    Ok(latest.map(|(_, path)| path))
+}
+
+/// Renders an HTML index of all files in `dir`, newest first. `query` is appended to every link, e.g. to pass on a download token.
+pub fn render_index(dir: &Path, url_prefix: &str, query: &str) -> std::io::Result<String> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !meta.is_file() || name.starts_with('.') || archive::is_partial(Path::new(&name)) {
+            continue;
+        }
+        files.push(ListedFile {
+            name,
+            size: meta.len(),
+            modified: meta.modified().ok().map(DateTime::<Local>::from),
+        });
+    }
+    files.sort_by_key(|file| std::cmp::Reverse(file.modified));
+
+    let title = escape_html(url_prefix);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {title}</title></head>\n<body>\n<h1>Index of {title}</h1>\n<table>\n<tr><th align=\"left\">Name</th><th align=\"right\">Size</th><th align=\"left\">Last modified</th></tr>\n"
+    );
+    for file in files {
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"{}/{}{}\">{}</a></td><td align=\"right\">{}</td><td>{}</td></tr>",
+            title,
+            utf8_percent_encode(&file.name, PATH_SEGMENT),
+            escape_html(query),
+            escape_html(&file.name),
+            crate::format_bytes(file.size),
+            file.modified
+                .map(|modified| modified.format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default()
+        );
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    Ok(html)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
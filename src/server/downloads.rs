@@ -0,0 +1,233 @@
+//! Following downloads of archives to their end, for the download statistics, `--webhook-url`, the notification
+//! about the first download of an archive and `--idle-timeout`.
+
+use std::{
+    net::IpAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use http_body_util::{BodyExt, combinators::BoxBody};
+use hyper::{
+    Response,
+    body::{Body, Bytes, Frame, SizeHint},
+};
+
+use super::ServerState;
+use crate::notify::{self, Notification, NotifyEvent};
+
+/// Put on the response of a `GET` of an archive, so the download can be followed to its end.
+#[derive(Clone, Debug)]
+pub(super) struct ArchiveDownload {
+    /// File name of the archive
+    pub(super) archive: String,
+    /// Bytes of the archive in the response, less than its size for a range
+    pub(super) len: u64,
+}
+
+/// A download that finished or broke off.
+pub(super) struct FinishedDownload<'a> {
+    pub(super) download: &'a ArchiveDownload,
+    pub(super) client_ip: IpAddr,
+    pub(super) bytes_sent: u64,
+    pub(super) duration: Duration,
+}
+
+impl FinishedDownload<'_> {
+    /// Whether the whole response got through
+    pub(super) fn is_complete(&self) -> bool {
+        self.bytes_sent == self.download.len
+    }
+}
+
+/// A download that's counted as active until it's dropped, and reported as [finished] then. Dropping it also covers
+/// connections that are cut off or cancelled in the middle of the download.
+pub(super) struct DownloadInProgress {
+    state: Arc<ServerState>,
+    download: ArchiveDownload,
+    client_ip: IpAddr,
+    started: Instant,
+    pub(super) bytes_sent: u64,
+}
+
+impl DownloadInProgress {
+    pub(super) fn start(state: Arc<ServerState>, download: ArchiveDownload, client_ip: IpAddr) -> Self {
+        state.active_downloads.send_modify(|active| *active += 1);
+        DownloadInProgress {
+            state,
+            download,
+            client_ip,
+            started: Instant::now(),
+            bytes_sent: 0,
+        }
+    }
+}
+
+impl Drop for DownloadInProgress {
+    fn drop(&mut self) {
+        self.state.active_downloads.send_modify(|active| *active -= 1);
+        finished(
+            &self.state,
+            FinishedDownload {
+                download: &self.download,
+                client_ip: self.client_ip,
+                bytes_sent: self.bytes_sent,
+                duration: self.started.elapsed(),
+            },
+        );
+    }
+}
+
+/// Reports a download that finished or broke off.
+fn finished(state: &ServerState, finished: FinishedDownload) {
+    state.stats.record(&finished);
+    if let Some(ref webhook) = state.webhook {
+        webhook.report(&finished);
+    }
+    if state.options.notify.is_empty() || !finished.is_complete() {
+        return;
+    }
+    // resumed downloads count once the rest got through
+    let first = state.downloaded.lock().unwrap().insert(finished.download.archive.clone());
+    // the server may be shutting down already
+    if let (true, Ok(runtime)) = (first, tokio::runtime::Handle::try_current()) {
+        let targets = state.options.notify.clone();
+        let notification = Notification {
+            event: NotifyEvent::FirstDownload,
+            title: format!("{} was downloaded", finished.download.archive),
+            message: format!("{} downloaded {}, the first download since mwdh started", finished.client_ip, finished.download.archive),
+        };
+        runtime.spawn(async move { notify::send(&targets, &notification).await });
+    }
+}
+
+/// Wraps the body of a download so it's reported once it's done or dropped.
+pub(super) fn watch(
+    state: Arc<ServerState>,
+    response: Response<BoxBody<Bytes, std::io::Error>>,
+    client_ip: IpAddr,
+) -> Response<BoxBody<Bytes, std::io::Error>> {
+    let Some(download) = response.extensions().get::<ArchiveDownload>().cloned() else {
+        return response;
+    };
+    let progress = DownloadInProgress::start(state, download, client_ip);
+    response.map(|inner| WatchedBody { inner, progress }.boxed())
+}
+
+struct WatchedBody {
+    inner: BoxBody<Bytes, std::io::Error>,
+    progress: DownloadInProgress,
+}
+
+impl Body for WatchedBody {
+    type Data = Bytes;
+    type Error = std::io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(ref frame))) = poll
+            && let Some(data) = frame.data_ref()
+        {
+            self.progress.bytes_sent += data.len() as u64;
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ServerOptions, server::wait_until_idle};
+
+    fn test_state() -> Arc<ServerState> {
+        let options = ServerOptions::builder().serve_dir(std::env::temp_dir()).build().unwrap();
+        Arc::new(ServerState::new(options, None, None))
+    }
+
+    fn download(len: u64) -> ArchiveDownload {
+        ArchiveDownload {
+            archive: "world.tar.zst".to_string(),
+            len,
+        }
+    }
+
+    fn active(state: &ServerState) -> usize {
+        *state.active_downloads.borrow()
+    }
+
+    #[test]
+    fn counts_downloads_until_dropped() {
+        let state = test_state();
+        let client_ip = IpAddr::from([127, 0, 0, 1]);
+        let first = DownloadInProgress::start(state.clone(), download(10), client_ip);
+        let mut second = DownloadInProgress::start(state.clone(), download(10), client_ip);
+        assert_eq!(active(&state), 2);
+        drop(first);
+        assert_eq!(active(&state), 1);
+        second.bytes_sent = 10;
+        drop(second);
+        assert_eq!(active(&state), 0);
+        let stats = state.stats.to_json();
+        assert_eq!(stats["world.tar.zst"]["completed"], 1);
+        assert_eq!(stats["world.tar.zst"]["partial"], 1);
+        assert_eq!(stats["world.tar.zst"]["bytes_served"], 10);
+    }
+
+    #[tokio::test]
+    async fn cancelled_download_is_finished() {
+        let state = test_state();
+        let sending = {
+            let state = state.clone();
+            async move {
+                let mut progress = DownloadInProgress::start(state, download(100), IpAddr::from([127, 0, 0, 1]));
+                progress.bytes_sent = 40;
+                std::future::pending::<()>().await;
+            }
+        };
+        // like the client limits' watchdog cutting off a stalled download
+        tokio::select! {
+            _ = sending => unreachable!(),
+            _ = tokio::time::sleep(Duration::from_millis(10)) => {}
+        }
+        assert_eq!(active(&state), 0);
+        let stats = state.stats.to_json();
+        assert_eq!(stats["world.tar.zst"]["partial"], 1);
+        assert_eq!(stats["world.tar.zst"]["bytes_served"], 40);
+    }
+
+    #[tokio::test]
+    async fn idle_timeout_waits_for_downloads() {
+        let state = test_state();
+        let idle_timeout = Duration::from_millis(50);
+        let started = Instant::now();
+        wait_until_idle(state.active_downloads.subscribe(), idle_timeout).await;
+        assert!(started.elapsed() >= idle_timeout);
+
+        let progress = DownloadInProgress::start(state.clone(), download(10), IpAddr::from([127, 0, 0, 1]));
+        let started = Instant::now();
+        let idle = wait_until_idle(state.active_downloads.subscribe(), idle_timeout);
+        let finish = async {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            drop(progress);
+            std::future::pending::<()>().await;
+        };
+        tokio::select! {
+            _ = idle => {}
+            _ = finish => unreachable!(),
+        }
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,131 @@
+//! `GET /events`, the progress of the archive `compress-host` is building as Server-Sent Events. Each event carries
+//! one of the JSON objects of `--progress json`, so browsers can show a live progress bar with an `EventSource`.
+//! The events of single files are left out, a browser doesn't need thousands of them per second.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use http_body_util::{BodyExt, StreamBody, combinators::BoxBody};
+use hyper::{
+    Response,
+    body::{Bytes, Frame},
+    header::{CACHE_CONTROL, CONTENT_TYPE},
+};
+use serde_json::json;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    ProgressMessage,
+    archive::progress::{JsonEvents, ProgressSink},
+};
+
+/// How many events a client may fall behind before it misses some
+const CAPACITY: usize = 256;
+/// How often a comment is sent while nothing happens, so proxies don't close the connection
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Progress sink relaying the progress to everyone connected to `/events`. Clones share the clients.
+#[derive(Clone)]
+pub struct ProgressEvents(Arc<Inner>);
+
+struct Inner {
+    events: JsonEvents,
+    sender: broadcast::Sender<Arc<str>>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Sent first to clients that connect later, it has the totals so far
+    last: Option<Arc<str>>,
+    last_written_percent: Option<f64>,
+}
+
+impl ProgressEvents {
+    pub fn new() -> Self {
+        Self(Arc::new(Inner {
+            events: JsonEvents::new(),
+            sender: broadcast::Sender::new(CAPACITY),
+            state: Mutex::new(State::default()),
+        }))
+    }
+
+    /// Tells the clients that compressing failed, there won't be a `complete` event.
+    pub fn fail(&self, error: &str) {
+        self.send(json!({"phase": "failed", "event": "failed", "message": error}));
+    }
+
+    fn send(&self, event: serde_json::Value) {
+        let event: Arc<str> = event.to_string().into();
+        let mut state = self.0.state.lock().unwrap();
+        state.last = Some(event.clone());
+        // nobody listening is fine
+        let _ = self.0.sender.send(event);
+    }
+
+    /// The stream of events, starting with the latest one.
+    pub(super) fn response(&self) -> Response<BoxBody<Bytes, std::io::Error>> {
+        let (last, receiver) = {
+            // under the lock, so no event is sent in between
+            let state = self.0.state.lock().unwrap();
+            (state.last.clone(), self.0.sender.subscribe())
+        };
+        let first = futures_util::stream::iter(last.map(|event| Ok(event_frame(&event))));
+        let rest = futures_util::stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                tokio::select! {
+                    event = receiver.recv() => match event {
+                        Ok(event) => return Some((Ok(event_frame(&event)), receiver)),
+                        // the next event has the current totals anyway
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => return None,
+                    },
+                    _ = tokio::time::sleep(KEEP_ALIVE) => {
+                        return Some((Ok(Frame::data(Bytes::from_static(b": keep-alive\n\n"))), receiver));
+                    }
+                }
+            }
+        });
+        Response::builder()
+            .header(CONTENT_TYPE, "text/event-stream")
+            .header(CACHE_CONTROL, "no-cache")
+            .body(StreamBody::new(futures_util::StreamExt::chain(first, rest)).boxed())
+            .unwrap()
+    }
+}
+
+impl Default for ProgressEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressSink for ProgressEvents {
+    fn report(&self, message: ProgressMessage) {
+        let Some(event) = self.0.events.event(message) else {
+            return;
+        };
+        match (event["phase"].as_str(), event["event"].as_str()) {
+            (_, Some("file_found" | "preprocessing" | "hashing" | "file_started" | "file_done")) => return,
+            (Some("writing"), Some("file")) => {
+                let percent = event["percent"].as_f64();
+                let mut state = self.0.state.lock().unwrap();
+                if state.last_written_percent == percent {
+                    return;
+                }
+                state.last_written_percent = percent;
+            }
+            (Some("scanning"), Some("start")) => {
+                self.0.state.lock().unwrap().last_written_percent = None;
+            }
+            _ => {}
+        }
+        self.send(event);
+    }
+}
+
+fn event_frame(event: &str) -> Frame<Bytes> {
+    Frame::data(Bytes::from(format!("data: {}\n\n", event)))
+}
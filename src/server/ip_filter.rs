@@ -0,0 +1,68 @@
+//! `--allow-ip` and `--deny-ip`, restricting the server to known networks, like the ones of a friend group.
+
+use std::{fmt::Display, net::IpAddr, str::FromStr};
+
+use anyhow::anyhow;
+
+/// A network in CIDR notation like `192.168.1.0/24` or `2001:db8::/32`. A single address is a network of its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // clients connecting over IPv4 to a dual stack socket show up as ::ffff:a.b.c.d
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(u32::from(net).into(), u32::from(ip).into(), 32, self.prefix_len),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => prefix_matches(u128::from(net), u128::from(ip), 128, self.prefix_len),
+            _ => false,
+        }
+    }
+}
+
+/// Whether the first `prefix_len` of the `bits` lowest bits match.
+fn prefix_matches(net: u128, ip: u128, bits: u8, prefix_len: u8) -> bool {
+    if prefix_len == 0 {
+        return true;
+    }
+    let shift = bits - prefix_len;
+    net >> shift == ip >> shift
+}
+
+impl FromStr for IpNet {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr = IpAddr::from_str(addr.trim()).map_err(|_| anyhow!("Invalid IP address in {}", s))?.to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|&len| len <= max)
+                .ok_or_else(|| anyhow!("Invalid prefix length in {}, expected 0 to {}", s, max))?,
+            None => max,
+        };
+        Ok(IpNet { addr, prefix_len })
+    }
+}
+
+impl Display for IpNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Whether a client may use the server. Denied networks win over allowed ones, and without allowed networks
+/// everyone who isn't denied is allowed.
+pub(super) fn is_allowed(allowed: &[IpNet], denied: &[IpNet], ip: IpAddr) -> bool {
+    if denied.iter().any(|net| net.contains(ip)) {
+        return false;
+    }
+    allowed.is_empty() || allowed.iter().any(|net| net.contains(ip))
+}
@@ -0,0 +1,146 @@
+//! Protection against clients that hold on to a connection without getting anywhere: a timeout for the request head
+//! and the body of uploads, a cap on the size of the head, and a watchdog dropping downloads the client stopped
+//! taking in or takes in slower than a minimum rate.
+
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::info;
+
+/// How long clients may take and how slow they may be.
+#[derive(Clone, Debug)]
+pub struct ClientLimits {
+    /// Time a client gets to send the head of a request, once it started sending it
+    pub header_timeout: Duration,
+
+    /// Longest pause between two chunks of an upload's body
+    pub body_timeout: Duration,
+
+    /// Window over which downloads are checked by the watchdog
+    pub stall_timeout: Duration,
+
+    /// Bytes per second a download has to reach over [`stall_timeout`](ClientLimits::stall_timeout), while the
+    /// server is waiting for the client. 0 only drops downloads that didn't get a single byte through.
+    pub min_download_rate: u64,
+
+    /// Largest request head accepted, in bytes
+    pub max_header_size: usize,
+}
+
+impl Default for ClientLimits {
+    fn default() -> Self {
+        ClientLimits {
+            header_timeout: Duration::from_secs(30),
+            body_timeout: Duration::from_secs(60),
+            stall_timeout: Duration::from_secs(60),
+            min_download_rate: 0,
+            max_header_size: 16 * 1024,
+        }
+    }
+}
+
+/// What a connection sent so far and whether it's waiting for the client to take in more.
+#[derive(Default)]
+pub(super) struct TransferMeter {
+    sent: AtomicU64,
+    blocked: AtomicBool,
+}
+
+impl TransferMeter {
+    pub(super) fn sent(&self, bytes: u64) {
+        self.sent.fetch_add(bytes, Ordering::Relaxed);
+        self.blocked.store(false, Ordering::Relaxed);
+    }
+
+    pub(super) fn blocked(&self) {
+        self.blocked.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Resolves once the connection should be dropped: the client kept the server waiting to send while less than the
+/// minimum rate got through during a whole window. Our own rate limit doesn't count, the server isn't waiting for
+/// the client then.
+pub(super) async fn watchdog(meter: &TransferMeter, limits: &ClientLimits) {
+    let min_bytes = (limits.min_download_rate as f64 * limits.stall_timeout.as_secs_f64()) as u64;
+    let mut last_sent = meter.sent.load(Ordering::Relaxed);
+    loop {
+        tokio::time::sleep(limits.stall_timeout).await;
+        let sent = meter.sent.load(Ordering::Relaxed);
+        if meter.blocked.load(Ordering::Relaxed) && sent - last_sent <= min_bytes {
+            info!(
+                "Dropping a download that got {} through in {}",
+                crate::format_bytes(sent - last_sent),
+                humantime::format_duration(limits.stall_timeout)
+            );
+            return;
+        }
+        last_sent = sent;
+    }
+}
+
+/// Reports what hyper writes to the connection to a [`TransferMeter`].
+pub(super) struct MeteredIo<T> {
+    inner: T,
+    meter: Arc<TransferMeter>,
+}
+
+impl<T> MeteredIo<T> {
+    pub(super) fn new(inner: T, meter: Arc<TransferMeter>) -> Self {
+        MeteredIo { inner, meter }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for MeteredIo<T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for MeteredIo<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.record(&result);
+        result
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        self.record(&result);
+        result
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<T> MeteredIo<T> {
+    fn record(&self, result: &Poll<io::Result<usize>>) {
+        match result {
+            Poll::Ready(Ok(written)) => self.meter.sent(*written as u64),
+            Poll::Pending => self.meter.blocked(),
+            Poll::Ready(Err(_)) => {}
+        }
+    }
+}
@@ -0,0 +1,69 @@
+//! The archive served by `compress-host`. The server starts right away with the archive of an earlier run, if there
+//! is one, and is pointed at the new archive once it's compressed, without a restart.
+
+use std::{path::PathBuf, sync::Arc};
+
+use tokio::sync::watch;
+
+/// The archive a running server serves, which can be swapped for another one at any time. Clones share it.
+/// Downloads that already started keep sending the archive they opened.
+#[derive(Clone)]
+pub struct LiveArchive(Arc<watch::Sender<ServedArchive>>);
+
+/// What a [`LiveArchive`] points to right now.
+#[derive(Clone, Debug, Default)]
+pub struct ServedArchive {
+    /// `None` until the first archive is ready
+    pub path: Option<PathBuf>,
+    /// Whether a new archive is being compressed
+    pub compressing: bool,
+    /// Why compressing the new archive failed, `path` is the archive of an earlier run then
+    pub compression_error: Option<String>,
+}
+
+impl LiveArchive {
+    /// Serves `path`, or answers downloads with `503 Service Unavailable` until [`publish`](LiveArchive::publish) is
+    /// called when it's `None`.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self(Arc::new(watch::Sender::new(ServedArchive {
+            path,
+            ..ServedArchive::default()
+        })))
+    }
+
+    pub fn current(&self) -> ServedArchive {
+        self.0.borrow().clone()
+    }
+
+    /// Marks a new archive as being compressed, reported on `/status`.
+    pub fn start_compressing(&self) {
+        self.0.send_modify(|served| served.compressing = true);
+    }
+
+    /// Serves `path` from now on.
+    pub fn publish(&self, path: PathBuf) {
+        self.0.send_modify(|served| {
+            served.path = Some(path);
+            served.compressing = false;
+            served.compression_error = None;
+        });
+    }
+
+    /// Keeps serving the current archive and reports `error` on `/status` as the reason it's outdated.
+    pub fn fail(&self, error: String) {
+        self.0.send_modify(|served| {
+            served.compressing = false;
+            served.compression_error = Some(error);
+        });
+    }
+
+    pub(super) fn subscribe(&self) -> watch::Receiver<ServedArchive> {
+        self.0.subscribe()
+    }
+
+    /// Resolves once there is an archive to serve and no new one is being compressed.
+    pub(super) async fn settled(&self) {
+        // the sender lives in `self`, so this can't fail
+        let _ = self.0.subscribe().wait_for(|served| served.path.is_some() && !served.compressing).await;
+    }
+}
@@ -0,0 +1,47 @@
+//! `--mdns`, announcing the server on the LAN with mDNS and DNS-SD, so friends in the same network reach it as
+//! `http://mwdh-world.local:3000/world` or find it in the service browsers of their devices, without knowing the
+//! host's IP.
+
+use std::{net::IpAddr, time::Duration};
+
+use anyhow::Result;
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use tracing::info;
+
+use crate::ServerOptions;
+
+/// Keeps the server announced until it's dropped, then says goodbye so it disappears from the browsers right away.
+pub(super) struct Announcement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+/// Announces the server as `<name>.local` under `_http._tcp`, or `_https._tcp` when it serves HTTPS.
+pub(super) fn announce(options: &ServerOptions, name: &str, port: u16) -> Result<Announcement> {
+    let daemon = ServiceDaemon::new()?;
+    let scheme = super::scheme(options);
+    let service_type = format!("_{}._tcp.local.", scheme);
+    let host_name = format!("{}.local.", name);
+    // where the download is, DNS-SD has a `path` for web pages
+    let path = format!("{}/{}", options.base_path, options.host_path);
+    let properties = [("path", path.as_str())];
+    let service = match options.bind.parse::<IpAddr>() {
+        Ok(ip) if !ip.is_unspecified() => ServiceInfo::new(&service_type, name, &host_name, ip, port, &properties[..])?,
+        // all addresses of the host, kept up to date when they change
+        _ => ServiceInfo::new(&service_type, name, &host_name, (), port, &properties[..])?.enable_addr_auto(),
+    };
+    let fullname = service.get_fullname().to_string();
+    daemon.register(service)?;
+    info!("Announcing the server on the LAN as {}://{}.local:{}{}", scheme, name, port, path);
+    Ok(Announcement { daemon, fullname })
+}
+
+impl Drop for Announcement {
+    fn drop(&mut self) {
+        if let Ok(unregistered) = self.daemon.unregister(&self.fullname) {
+            // the goodbye has to go out before the daemon stops
+            let _ = unregistered.recv_timeout(Duration::from_secs(1));
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
@@ -0,0 +1,1237 @@
+pub mod access_log;
+pub mod acme;
+mod checksum;
+pub mod cors;
+pub mod dir_listing;
+mod downloads;
+pub mod events;
+pub mod ip_filter;
+pub mod limits;
+pub mod live;
+mod mdns;
+pub mod rate_limit;
+pub mod receive;
+mod stats;
+pub mod tls;
+pub mod torrent;
+mod webhook;
+mod proxy;
+#[cfg(target_os = "linux")]
+mod sendfile;
+
+pub(crate) use proxy::normalize_base_path;
+
+use crate::{
+    CompressionFormat, ServerOptions,
+    error::MwdhError,
+    systemd,
+    server::{
+        access_log::{AccessLog, AccessLogEntry},
+        checksum::ChecksumCache,
+        downloads::ArchiveDownload,
+        limits::{MeteredIo, TransferMeter},
+        live::LiveArchive,
+        rate_limit::{FairShare, PerIpRateLimiter, Throttle, TokenBucket},
+        stats::DownloadStats,
+        torrent::TorrentCache,
+        webhook::Webhook,
+    },
+};
+use anyhow::Result;
+use serde_json::json;
+use tracing::{Instrument, error, info, info_span, warn};
+use futures_util::{Stream, TryStreamExt};
+use http_body_util::combinators::BoxBody;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use tokio_util::io::ReaderStream;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+
+use http_body_util::{BodyExt, Empty, Full, StreamBody};
+use hyper::body::{Bytes, Frame};
+use chrono::{DateTime, Utc};
+use hyper::HeaderMap;
+use hyper::header::HeaderValue;
+use hyper::header::{
+    ACCEPT, ACCEPT_ENCODING, ACCEPT_RANGES, AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_ENCODING, CONTENT_LENGTH,
+    CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, IF_UNMODIFIED_SINCE, LAST_MODIFIED, RANGE, VARY,
+    WWW_AUTHENTICATE,
+};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Semaphore, watch};
+use tokio_rustls::TlsAcceptor;
+
+/// Serves the archive (or directory of archives) until the idle timeout hits, if there is one,
+/// or the [`cancel`](ServerOptions::cancel) token is cancelled. Downloads that already started keep going.
+pub async fn run_server(options: ServerOptions) -> Result<(), MwdhError> {
+    serve(options).instrument(info_span!("serve")).await.map_err(|err| MwdhError::Server(err.into()))
+}
+
+async fn serve(options: ServerOptions) -> Result<()> {
+    // a systemd socket unit already bound the socket, --bind and --port are ignored then
+    let listener = match systemd::take_listener()? {
+        Some(listener) => TcpListener::from_std(listener)?,
+        None => TcpListener::bind(SocketAddr::from_str(&format!("{}:{}", options.bind, options.port))?).await?,
+    };
+    let addr = listener.local_addr()?;
+    let token_query = options
+        .download_token
+        .as_ref()
+        .map(|token| format!("?token={}", url::form_urlencoded::byte_serialize(token.as_bytes()).collect::<String>()))
+        .unwrap_or_default();
+    let archive = match options.serve_dir {
+        Some(ref serve_dir) => {
+            info!(
+                "Hosting archives in {} at {}{}/{}/{}",
+                serve_dir.display(),
+                addr,
+                options.base_path,
+                options.host_path,
+                token_query
+            );
+            None
+        }
+        None => match options.serve_latest {
+            Some(ref serve_latest) => {
+                info!(
+                    "Hosting the latest archive in {} at {}{}/{}{}",
+                    serve_latest.display(),
+                    addr,
+                    options.base_path,
+                    options.host_path,
+                    token_query
+                );
+                None
+            }
+            None => {
+                let archive = match options.live_archive {
+                    Some(ref live_archive) => Some(live_archive.clone()),
+                    None => options.path_to_archive.clone().map(|path| LiveArchive::new(Some(path))),
+                };
+                if archive.is_some() {
+                    info!("Hosting world files at {}{}/{}{}", addr, options.base_path, options.host_path, token_query);
+                }
+                archive
+            }
+        },
+    };
+    for (name, path) in &options.named_archives {
+        info!("Hosting {} at {}{}/{}{}", path.display(), addr, options.base_path, name, token_query);
+    }
+    if let Some(ref uploads) = options.uploads {
+        info!(
+            "Accepting archives of up to {} into {}, send them with `mwdh push {}://{}{} <archive> --upload-token {}`",
+            crate::format_bytes(uploads.max_size),
+            uploads.dir.display(),
+            scheme(&options),
+            addr,
+            options.base_path,
+            uploads.token
+        );
+    }
+    let access_log = match options.access_log {
+        Some(ref target) => Some(Arc::new(AccessLog::open(target)?)),
+        None => None,
+    };
+    let tls = match (&options.tls, &options.acme) {
+        (Some(tls_options), _) => {
+            if tls_options.client_ca.is_some() {
+                info!("Only clients with a certificate of the client CA can connect");
+            }
+            Some(tls::acceptor(tls_options)?)
+        }
+        (None, Some(acme_options)) => {
+            acme::check_terms(acme_options).await?;
+            let resolver = Arc::new(acme::CertResolver::default());
+            tokio::spawn(acme::keep_certificate(acme_options.clone(), resolver.clone(), options.cancel.clone()));
+            Some(acme::acceptor(resolver)?)
+        }
+        (None, None) => None,
+    };
+
+    // announced until the server stops
+    let _announcement = match options.mdns {
+        Some(ref name) => match mdns::announce(&options, name, addr.port()) {
+            Ok(announcement) => Some(announcement),
+            Err(err) => {
+                warn!("Failed to announce the server on the LAN: {}", err);
+                None
+            }
+        },
+        None => None,
+    };
+
+    systemd::notify_ready(&format!("Serving at {}", addr));
+
+    let state = Arc::new(ServerState::new(options, archive, tls));
+    if state.options.torrent.is_some() {
+        let state = state.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = prepare_torrents(&state) => {}
+                _ = state.options.cancel.cancelled() => {}
+            }
+        });
+    }
+    let options = &state.options;
+    let connection_slots = options.max_connections.map(|max| Arc::new(Semaphore::new(max)));
+    let total_share = options.total_rate_limit.map(|rate| Arc::new(FairShare::new(rate)));
+    // one timer for the whole loop, connections that don't download an archive don't restart it
+    let idle = async {
+        match options.idle_timeout {
+            Some(idle_timeout) => {
+                // don't shut down while the archive people are waiting for is still being compressed
+                if let Some(ref archive) = state.archive {
+                    archive.settled().await;
+                }
+                wait_until_idle(state.active_downloads.subscribe(), idle_timeout).await;
+                idle_timeout
+            }
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(idle);
+    loop {
+        // when at the connection cap, stop accepting until a connection finishes. Pending clients wait in the listen backlog.
+        let connection_permit = match connection_slots {
+            Some(ref slots) => tokio::select! {
+                permit = slots.clone().acquire_owned() => Some(permit?),
+                _ = options.cancel.cancelled() => break,
+            },
+            None => None,
+        };
+        let (stream, remote_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            idle_timeout = &mut idle => {
+                info!(
+                    "No downloads for {}, shutting down",
+                    humantime::format_duration(idle_timeout)
+                );
+                state.stats.log_summary();
+                systemd::notify_stopping();
+                return Ok(());
+            }
+            _ = options.cancel.cancelled() => break,
+        };
+        let state = state.clone();
+        let access_log = access_log.clone();
+        // the client's bucket is added per request, behind a proxy every request can come from someone else
+        let throttle = Throttle {
+            client: None,
+            connection: options.connection_rate_limit.map(|rate| Arc::new(TokenBucket::new(rate))),
+            total: total_share.clone(),
+        };
+        let span = info_span!("connection", remote = %remote_addr);
+        tokio::task::spawn(async move {
+            let _connection_permit = connection_permit;
+            let meter = Arc::new(TransferMeter::default());
+            let limits = state.options.client_limits.clone();
+            tokio::select! {
+                _ = serve_connection(stream, remote_addr, state, throttle, access_log, meter.clone()) => {}
+                // dropping the connection closes it
+                _ = limits::watchdog(&meter, &limits) => {}
+            }
+        }.instrument(span));
+    }
+    info!("Stopped accepting connections");
+    state.stats.log_summary();
+    systemd::notify_stopping();
+    Ok(())
+}
+
+/// `https` with a certificate, `http` otherwise.
+fn scheme(options: &ServerOptions) -> &'static str {
+    if options.tls.is_some() || options.acme.is_some() { "https" } else { "http" }
+}
+
+/// Serves the requests of a connection, downloads with `sendfile` where possible.
+async fn serve_connection(
+    stream: TcpStream,
+    remote_addr: SocketAddr,
+    state: Arc<ServerState>,
+    throttle: Throttle,
+    access_log: Option<Arc<AccessLog>>,
+    meter: Arc<TransferMeter>,
+) {
+    if let Some(ref tls) = state.tls {
+        // clients without a certificate of the client CA are turned away here
+        let stream = match tokio::time::timeout(state.options.client_limits.header_timeout, tls.accept(stream)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(err)) => {
+                warn!("TLS handshake failed: {}", err);
+                return;
+            }
+            Err(_) => {
+                warn!("TLS handshake timed out");
+                return;
+            }
+        };
+        if acme::is_challenge(stream.get_ref().1) {
+            return;
+        }
+        serve_http(stream, remote_addr, state, throttle, access_log, meter).await;
+        return;
+    }
+    #[cfg(target_os = "linux")]
+    let mut stream = stream;
+    #[cfg(target_os = "linux")]
+    match sendfile::serve_downloads(&mut stream, &state, &throttle, access_log.as_ref(), remote_addr, &meter).await {
+        Ok(sendfile::Handover::Hyper) => {}
+        Ok(sendfile::Handover::Closed) => return,
+        Err(err) => {
+            warn!("Error serving connection: {:?}", err);
+            return;
+        }
+    }
+    serve_http(stream, remote_addr, state, throttle, access_log, meter).await;
+}
+
+/// Serves the requests of a connection with hyper.
+async fn serve_http<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    stream: S,
+    remote_addr: SocketAddr,
+    state: Arc<ServerState>,
+    throttle: Throttle,
+    access_log: Option<Arc<AccessLog>>,
+    meter: Arc<TransferMeter>,
+) {
+    let limits = &state.options.client_limits;
+    let io = TokioIo::new(MeteredIo::new(stream, meter));
+    if let Err(err) = http1::Builder::new()
+        .timer(TokioTimer::new())
+        .header_read_timeout(limits.header_timeout)
+        .max_buf_size(limits.max_header_size.max(MIN_BUF_SIZE))
+        .serve_connection(
+            io,
+            service_fn(move |req| {
+                let state = state.clone();
+                let access_log = access_log.clone();
+                let throttle = throttle.clone();
+                async move {
+                    let client_ip = proxy::client_ip(&state.options, req.headers(), remote_addr.ip());
+                    let throttle = state.throttle_for(&throttle, client_ip);
+                    let entry = access_log.as_ref().map(|_| AccessLogEntry::new(&req, client_ip));
+                    let mut response = respond(req, &state, throttle, remote_addr.ip()).await?;
+                    response = downloads::watch(state.clone(), response, client_ip);
+                    if let (Some(entry), Some(access_log)) = (entry, access_log) {
+                        response = entry.log_response(access_log, response);
+                    }
+                    Ok::<_, anyhow::Error>(response)
+                }
+            }),
+        )
+        .await
+    {
+        warn!("Error serving connection: {:?}", err);
+    }
+}
+
+/// The smallest read buffer hyper accepts
+const MIN_BUF_SIZE: usize = 8192;
+
+/// Everything the request handler needs for the lifetime of the server.
+struct ServerState {
+    options: ServerOptions,
+    /// `None` when serving a directory, the latest archive of one or only [named archives](ServerOptions::named_archives)
+    archive: Option<LiveArchive>,
+    torrents: TorrentCache,
+    checksums: ChecksumCache,
+    /// Told about every download when `--webhook-url` is set
+    webhook: Option<Webhook>,
+    /// Archives that were downloaded completely at least once, for the notification about the first download
+    downloaded: Mutex<HashSet<String>>,
+    stats: DownloadStats,
+    /// Set when serving HTTPS, with a certificate from `--tls-cert` or ACME
+    tls: Option<TlsAcceptor>,
+    /// One bucket per client IP for `--rate-limit`
+    rate_limiter: Option<PerIpRateLimiter>,
+    /// Archive downloads in progress. Only they count as activity for the idle timeout, not health checks or
+    /// status polling.
+    active_downloads: watch::Sender<usize>,
+}
+
+impl ServerState {
+    fn new(options: ServerOptions, archive: Option<LiveArchive>, tls: Option<TlsAcceptor>) -> Self {
+        ServerState {
+            archive,
+            torrents: TorrentCache::default(),
+            checksums: ChecksumCache::default(),
+            webhook: options.webhook_url.clone().map(Webhook::new),
+            downloaded: Mutex::new(HashSet::new()),
+            stats: DownloadStats::load(options.stats_file.clone()),
+            tls,
+            active_downloads: watch::Sender::new(0),
+            rate_limiter: options.rate_limit.map(PerIpRateLimiter::new),
+            options,
+        }
+    }
+
+    /// The limits a request is held to: those of its connection, and the bucket of the client's IP. Behind a proxy
+    /// that's the IP the proxy passed on, not its own.
+    fn throttle_for(&self, connection: &Throttle, client_ip: IpAddr) -> Option<Throttle> {
+        Throttle {
+            client: self.rate_limiter.as_ref().map(|rate_limiter| rate_limiter.bucket_for(client_ip)),
+            ..connection.clone()
+        }
+        .limiting()
+    }
+}
+
+/// Checks that the names of the [named archives](ServerOptions::named_archives) are single URL path segments that
+/// clash neither with each other nor with the host path, `/ping`, `/status` or `/events`.
+pub(crate) fn check_archive_names(options: &ServerOptions) -> Result<(), String> {
+    let mut names = std::collections::HashSet::new();
+    for (name, _) in &options.named_archives {
+        if name.is_empty() || name.contains('/') {
+            return Err(format!("Invalid archive name \"{}\", it has to be a single URL path segment", name));
+        }
+        if *name == options.host_path || matches!(name.as_str(), "ping" | "status" | "events") || !names.insert(name) {
+            return Err(format!("The archive name \"{}\" is already taken", name));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the [download file name](ServerOptions::download_filename) is a plain file name, that the
+/// [Content-Type](ServerOptions::content_type) and the [ones per format](ServerOptions::mime_types) fit into a header
+/// and that there is an archive under the host path for the first two, not a directory.
+pub(crate) fn check_download_overrides(options: &ServerOptions) -> Result<(), String> {
+    if options.serve_dir.is_some() && (options.download_filename.is_some() || options.content_type.is_some()) {
+        return Err("The download file name and Content-Type can't be set when serving a directory".to_string());
+    }
+    if let Some(ref file_name) = options.download_filename
+        && (file_name.trim().is_empty() || file_name.contains(['/', '\\']) || file_name.chars().any(char::is_control))
+    {
+        return Err(format!("\"{}\" is not a valid file name to download the archive as", file_name));
+    }
+    let content_types = options.content_type.iter().chain(options.mime_types.iter().map(|(_, content_type)| content_type));
+    for content_type in content_types {
+        if !content_type.contains('/') || HeaderValue::from_str(content_type).is_err() {
+            return Err(format!("\"{}\" is not a valid Content-Type, expected something like application/octet-stream", content_type));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the [mDNS name](ServerOptions::mdns) works as a host name under `.local`.
+pub(crate) fn check_mdns_name(name: &str) -> Result<(), String> {
+    let is_label = !name.is_empty()
+        && name.len() <= 63
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && !name.starts_with('-')
+        && !name.ends_with('-');
+    if !is_label {
+        return Err(format!(
+            "\"{}\" can't be announced on the LAN, names can only have letters, digits and dashes in between",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// A named archive's name and path.
+type NamedArchive<'a> = (&'a str, &'a Path);
+
+/// Splits a request path without its leading slash into the named archive it is for (`None` for the host path) and
+/// the single sub path after it, if there is one.
+fn route<'a>(options: &'a ServerOptions, path: &'a str) -> Option<(Option<NamedArchive<'a>>, Option<&'a str>)> {
+    let sub_path = |prefix: &str| match path.strip_prefix(prefix)? {
+        "" => Some(None),
+        rest => rest.strip_prefix('/').map(Some),
+    };
+    if let Some(sub_path) = sub_path(&options.host_path) {
+        return Some((None, sub_path));
+    }
+    options
+        .named_archives
+        .iter()
+        .find_map(|(name, archive)| sub_path(name).map(|sub_path| (Some((name.as_str(), archive.as_path())), sub_path)))
+}
+
+/// What of an archive a request is for.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Resource {
+    Archive,
+    Torrent,
+    Sha256,
+    Manifest,
+}
+
+/// Routes the files next to an archive, like `/<host_path>.sha256` or `/<name>.torrent` of a named archive, also
+/// behind a token in the path like `/<host_path>/<token>.sha256`. Only for single archives, not for the files of a
+/// served directory.
+fn route_sidecar<'a>(options: &'a ServerOptions, path: &'a str) -> Option<(Option<NamedArchive<'a>>, Option<&'a str>, Resource)> {
+    let (resource, path) = [
+        (Resource::Torrent, ".torrent"),
+        (Resource::Sha256, ".sha256"),
+        (Resource::Manifest, ".manifest.json"),
+    ]
+    .into_iter()
+    .find_map(|(resource, suffix)| Some((resource, path.strip_suffix(suffix)?)))?;
+    if resource == Resource::Torrent && options.torrent.is_none() {
+        return None;
+    }
+    match route(options, path)? {
+        (named_archive, sub_path) if named_archive.is_some() || options.serve_dir.is_none() => {
+            Some((named_archive, sub_path, resource))
+        }
+        _ => None,
+    }
+}
+
+/// Hashes the archives for their torrents right away, and the host path's archive again whenever it's swapped, so
+/// the first downloader of a torrent doesn't have to wait for it.
+async fn prepare_torrents(state: &ServerState) {
+    for (name, path) in &state.options.named_archives {
+        if let Err(err) = torrent_of(state, name, path, None).await {
+            warn!("Failed to create the torrent of {}: {}", path.display(), err);
+        }
+    }
+    let Some(ref archive) = state.archive else {
+        return;
+    };
+    let mut updates = archive.subscribe();
+    loop {
+        let path = updates.borrow_and_update().path.clone();
+        if let Some(path) = path
+            && let Err(err) = torrent_of(state, &state.options.host_path, &path, None).await
+        {
+            warn!("Failed to create the torrent of {}: {}", path.display(), err);
+        }
+        if updates.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// The torrent of an archive served under `/<url_path>`, with that URL as web seed. `forwarded_base_url` is the URL
+/// a reverse proxy says the client used, which wins over the hostname but not over `--torrent-base-url`.
+async fn torrent_of(
+    state: &ServerState,
+    url_path: &str,
+    archive: &Path,
+    forwarded_base_url: Option<String>,
+) -> std::io::Result<Bytes> {
+    let options = &state.options;
+    let torrent_options = options.torrent.as_ref().expect("Only called when serving torrents");
+    let base_url = match (&torrent_options.base_url, forwarded_base_url) {
+        (Some(base_url), _) => base_url.trim_end_matches('/').to_string(),
+        (None, Some(forwarded_base_url)) => forwarded_base_url,
+        (None, None) => format!(
+            "{}://{}:{}",
+            scheme(options),
+            hostname::get()?.to_string_lossy(),
+            options.port
+        ),
+    };
+    // web seeds are used as they are, so the token goes into the path
+    let web_seed = match options.download_token {
+        Some(ref token) => format!(
+            "{}{}/{}/{}",
+            base_url,
+            options.base_path,
+            url_path,
+            percent_encoding::utf8_percent_encode(token, percent_encoding::NON_ALPHANUMERIC)
+        ),
+        None => format!("{}{}/{}", base_url, options.base_path, url_path),
+    };
+    state.torrents.get(archive, &web_seed, &torrent_options.trackers).await
+}
+
+/// The archive served under `/<url_path>`, or one of the files next to it.
+async fn archive_response<B>(
+    req: &Request<B>,
+    state: &ServerState,
+    url_path: &str,
+    archive: &Path,
+    resource: Resource,
+    throttle: Option<Throttle>,
+) -> Result<Response<BoxBody<Bytes, std::io::Error>>> {
+    // --download-filename and --content-type are about the archive under the host path, named archives keep theirs
+    let is_host_path = url_path == state.options.host_path;
+    let file_name = match state.options.download_filename {
+        Some(ref file_name) if is_host_path => file_name.clone(),
+        _ => archive.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default(),
+    };
+    match resource {
+        Resource::Archive => {
+            let download_as = match state.options.content_type {
+                Some(ref content_type) if is_host_path => DownloadAs {
+                    file_name,
+                    content_type,
+                    zstd_encoded: false,
+                    varies_by_encoding: false,
+                },
+                _ => download_as(req, &state.options, archive, file_name),
+            };
+            get_archive_file_as_response(req, archive, download_as, throttle).await
+        }
+        Resource::Torrent => torrent_response(req, state, url_path, archive).await,
+        Resource::Sha256 => match state.checksums.sha256sum(archive, &file_name).await {
+            Ok(sha256sum) => Ok(file_info_response(req, "text/plain; charset=utf-8", Bytes::from(sha256sum))),
+            Err(err) => {
+                error!("Failed to hash {}: {}", archive.display(), err);
+                Ok(text_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to hash the archive"))
+            }
+        },
+        Resource::Manifest => match checksum::manifest(archive).await {
+            Ok(Some(manifest)) => Ok(file_info_response(req, "application/json", Bytes::from(manifest))),
+            Ok(None) => Ok(text_response(StatusCode::NOT_FOUND, "The archive was created without --manifest")),
+            Err(err) => {
+                error!("Failed to read the manifest of {}: {}", archive.display(), err);
+                Ok(text_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to read the manifest"))
+            }
+        },
+    }
+}
+
+async fn torrent_response<B>(
+    req: &Request<B>,
+    state: &ServerState,
+    url_path: &str,
+    archive: &Path,
+) -> Result<Response<BoxBody<Bytes, std::io::Error>>> {
+    let forwarded_base_url = proxy::forwarded_base_url(&state.options, req.headers());
+    let torrent = match torrent_of(state, url_path, archive, forwarded_base_url).await {
+        Ok(torrent) => torrent,
+        Err(err) => {
+            error!("Failed to create the torrent of {}: {}", archive.display(), err);
+            return Ok(text_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to create the torrent"));
+        }
+    };
+    let file_name = archive.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let mut response = file_info_response(req, "application/x-bittorrent", torrent);
+    response.headers_mut().insert(
+        CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{}.torrent\"", file_name).parse().unwrap(),
+    );
+    Ok(response)
+}
+
+/// A small file about an archive, without the body for `HEAD`.
+fn file_info_response<B>(req: &Request<B>, content_type: &str, contents: Bytes) -> Response<BoxBody<Bytes, std::io::Error>> {
+    let len = contents.len();
+    let body = match *req.method() {
+        Method::HEAD => Empty::new().map_err(|_| std::io::Error::other("infallible")).boxed(),
+        _ => Full::new(contents).map_err(|_| std::io::Error::other("infallible")).boxed(),
+    };
+    Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .header(CONTENT_LENGTH, len.to_string())
+        .body(body)
+        .unwrap()
+}
+
+/// Resolves once no archive has been downloaded for `idle_timeout`.
+async fn wait_until_idle(mut active_downloads: watch::Receiver<usize>, idle_timeout: Duration) {
+    loop {
+        if active_downloads.wait_for(|active| *active == 0).await.is_err() {
+            return;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(idle_timeout) => return,
+            _ = active_downloads.changed() => continue,
+        }
+    }
+}
+
+/// Answers requests that reach hyper. Uploads need the body, everything else is answered by [`handle`].
+async fn respond(
+    req: Request<hyper::body::Incoming>,
+    state: &ServerState,
+    throttle: Option<Throttle>,
+    remote_ip: IpAddr,
+) -> Result<Response<BoxBody<Bytes, std::io::Error>>> {
+    // uploads are protected by their own token, not by --auth
+    if let Some(ref uploads) = state.options.uploads
+        && is_client_allowed(&state.options, req.headers(), remote_ip)
+        && req.method() == Method::PUT
+        && let Some(path) = proxy::strip_base_path(&state.options, req.uri().path())
+        && let Some(name) = receive::upload_name(path)
+    {
+        let name = name.to_string();
+        let request_headers = req.headers().clone();
+        let mut response = receive::receive(req, uploads, state.options.client_limits.body_timeout, &name).await?;
+        cors::add_headers(&state.options, &request_headers, &mut response);
+        return Ok(response);
+    }
+    handle(&req, state, throttle, remote_ip).await
+}
+
+/// Answers everything but uploads, including CORS preflights.
+async fn handle<B>(
+    req: &Request<B>,
+    state: &ServerState,
+    throttle: Option<Throttle>,
+    remote_ip: IpAddr,
+) -> Result<Response<BoxBody<Bytes, std::io::Error>>> {
+    if !is_client_allowed(&state.options, req.headers(), remote_ip) {
+        return Ok(text_response(StatusCode::FORBIDDEN, "Forbidden"));
+    }
+    if let Some(response) = cors::preflight(&state.options, req) {
+        return Ok(response);
+    }
+    let mut response = dispatch(req, state, throttle).await?;
+    cors::add_headers(&state.options, req.headers(), &mut response);
+    Ok(response)
+}
+
+/// Checks the client against `--allow-ip` and `--deny-ip`.
+fn is_client_allowed(options: &ServerOptions, headers: &HeaderMap, remote_ip: IpAddr) -> bool {
+    let client_ip = proxy::client_ip(options, headers, remote_ip);
+    ip_filter::is_allowed(&options.allowed_ips, &options.denied_ips, client_ip)
+}
+
+async fn dispatch<B>(
+    req: &Request<B>,
+    state: &ServerState,
+    throttle: Option<Throttle>,
+) -> Result<Response<BoxBody<Bytes, std::io::Error>>> {
+    let options = &state.options;
+    let Some(path) = proxy::strip_base_path(options, req.uri().path()) else {
+        return Ok(text_response(StatusCode::NOT_FOUND, "Not Found"));
+    };
+    match path {
+        "/ping" => Ok(Response::new(
+            Full::new(Bytes::from("Pong!"))
+                .map_err(|_| std::io::Error::other("infallible"))
+                .boxed(),
+        )),
+        _ => {
+            if let Some(ref credentials) = options.basic_auth
+                && !is_authorized(req.headers(), credentials)
+            {
+                return Ok(Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header(WWW_AUTHENTICATE, "Basic realm=\"mwdh\", charset=\"UTF-8\"")
+                    .body(
+                        Full::new(Bytes::from("Unauthorized"))
+                            .map_err(|_| std::io::Error::other("infallible"))
+                            .boxed(),
+                    )
+                    .unwrap());
+            }
+            if path == "/status" {
+                return Ok(status_response(state));
+            }
+            if path == "/events"
+                && let Some(ref progress_events) = options.progress_events
+            {
+                return Ok(progress_events.response());
+            }
+            // everything we serve lives under /<host_path> or /<name> of a named archive, optionally followed by a
+            // single sub path
+            let (named_archive, sub_path, resource) = match route_sidecar(options, &path[1..]) {
+                Some(routed) => routed,
+                None => match route(options, &path[1..]) {
+                    Some((named_archive, sub_path)) => (named_archive, sub_path, Resource::Archive),
+                    None => return Ok(text_response(StatusCode::NOT_FOUND, "Not Found")),
+                },
+            };
+            if !matches!(*req.method(), Method::GET | Method::HEAD) {
+                return Ok(text_response(StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed"));
+            }
+            if let Some(ref serve_dir) = options.serve_dir
+                && named_archive.is_none()
+            {
+                // sub paths are file names here, so the token can only be passed as a query parameter
+                if let Some(ref token) = options.download_token
+                    && !has_download_token(req, token, None)
+                {
+                    return Ok(text_response(StatusCode::FORBIDDEN, "Forbidden"));
+                }
+                return serve_dir_request(req, options, serve_dir, sub_path, throttle).await;
+            }
+
+            match options.download_token {
+                Some(ref token) if !has_download_token(req, token, sub_path) => {
+                    return Ok(text_response(StatusCode::FORBIDDEN, "Forbidden"));
+                }
+                None if sub_path.is_some() => {
+                    return Ok(text_response(StatusCode::NOT_FOUND, "Not Found"));
+                }
+                _ => {}
+            }
+            if let Some((name, path_to_archive)) = named_archive {
+                return archive_response(req, state, name, path_to_archive, resource, throttle).await;
+            }
+            if let Some(ref serve_latest) = options.serve_latest {
+                return match dir_listing::latest_archive(serve_latest) {
+                    Ok(Some(latest)) => archive_response(req, state, &options.host_path, &latest, resource, throttle).await,
+                    Ok(None) => Ok(text_response(StatusCode::NOT_FOUND, "No archive available yet")),
+                    Err(err) => {
+                        error!("Failed to look up the latest archive in {}: {}", serve_latest.display(), err);
+                        Ok(text_response(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "Failed to serve archive file",
+                        ))
+                    }
+                };
+            }
+            // only named archives are served
+            let Some(ref archive) = state.archive else {
+                return Ok(text_response(StatusCode::NOT_FOUND, "Not Found"));
+            };
+            let Some(path_to_archive) = archive.current().path else {
+                // browsers get a page following the progress, which starts the download once it's done
+                if options.progress_events.is_some() && accepts_html(req.headers()) {
+                    let page = PROGRESS_PAGE.replace("{events_url}", &format!("{}/events", options.base_path));
+                    return Ok(html_response(StatusCode::SERVICE_UNAVAILABLE, page));
+                }
+                return Ok(text_response(StatusCode::SERVICE_UNAVAILABLE, "The archive is still being compressed"));
+            };
+            archive_response(req, state, &options.host_path, &path_to_archive, resource, throttle).await
+        }
+    }
+}
+
+async fn serve_dir_request<B>(
+    req: &Request<B>,
+    options: &ServerOptions,
+    serve_dir: &Path,
+    sub_path: Option<&str>,
+    throttle: Option<Throttle>,
+) -> Result<Response<BoxBody<Bytes, std::io::Error>>> {
+    match sub_path {
+        None | Some("") => {
+            let query = req
+                .uri()
+                .query()
+                .map(|query| format!("?{}", query))
+                .unwrap_or_default();
+            match dir_listing::render_index(serve_dir, &format!("{}/{}", options.base_path, options.host_path), &query) {
+                Ok(html) => Ok(Response::builder()
+                    .header(CONTENT_TYPE, "text/html; charset=utf-8")
+                    .body(
+                        Full::new(Bytes::from(html))
+                            .map_err(|_| std::io::Error::other("infallible"))
+                            .boxed(),
+                    )
+                    .unwrap()),
+                Err(err) => {
+                    error!("Failed to list {}: {}", serve_dir.display(), err);
+                    Ok(text_response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "Failed to list directory",
+                    ))
+                }
+            }
+        }
+        Some(file_name) => match dir_listing::resolve_file(serve_dir, file_name) {
+            Some(file_path) => {
+                let file_name = file_path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+                let download_as = download_as(req, options, &file_path, file_name);
+                get_archive_file_as_response(req, &file_path, download_as, throttle).await
+            }
+            None => Ok(text_response(StatusCode::NOT_FOUND, "Not Found")),
+        },
+    }
+}
+
+/// What is being served, whether `compress-host` is still compressing a new archive and, when that failed, why the
+/// archive of an earlier run is served instead. Also how often each archive was downloaded.
+fn status_response(state: &ServerState) -> Response<BoxBody<Bytes, std::io::Error>> {
+    let served = state.archive.as_ref().map(LiveArchive::current).unwrap_or_default();
+    let status = json!({
+        "archive": served
+            .path
+            .as_deref()
+            .and_then(Path::file_name)
+            .map(|name| name.to_string_lossy()),
+        "compressing": served.compressing,
+        "stale": served.compression_error.is_some(),
+        "compression_error": served.compression_error,
+        "downloads": state.stats.to_json(),
+    });
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(
+            Full::new(Bytes::from(status.to_string()))
+                .map_err(|_| std::io::Error::other("infallible"))
+                .boxed(),
+        )
+        .unwrap()
+}
+
+/// Shown to browsers while the first archive is being compressed.
+const PROGRESS_PAGE: &str = include_str!("progress.html");
+
+fn accepts_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"))
+}
+
+fn html_response(status: StatusCode, html: String) -> Response<BoxBody<Bytes, std::io::Error>> {
+    let mut response = Response::new(
+        Full::new(Bytes::from(html))
+            .map_err(|_| std::io::Error::other("infallible"))
+            .boxed(),
+    );
+    *response.status_mut() = status;
+    response.headers_mut().insert(CONTENT_TYPE, "text/html; charset=utf-8".parse().unwrap());
+    response
+}
+
+pub(super) fn text_response(status: StatusCode, text: &'static str) -> Response<BoxBody<Bytes, std::io::Error>> {
+    let mut response = Response::new(
+        Full::new(Bytes::from(text))
+            .map_err(|_| std::io::Error::other("infallible"))
+            .boxed(),
+    );
+    *response.status_mut() = status;
+    response
+}
+
+async fn get_archive_file_as_response<B>(
+    req: &Request<B>,
+    path_to_archive: &Path,
+    download_as: DownloadAs<'_>,
+    throttle: Option<Throttle>,
+) -> Result<Response<BoxBody<Bytes, std::io::Error>>> {
+    let file = tokio::fs::File::open(path_to_archive).await;
+    match file {
+        Ok(file) => {
+            let metadata = file.metadata().await?;
+            let file_size = metadata.len();
+            let etag = etag(&metadata);
+            let last_modified = metadata.modified().ok().map(DateTime::<Utc>::from);
+
+            // the archive changed since the client saw it, appending to what it has would corrupt it
+            if is_precondition_failed(req.headers(), etag.as_deref(), last_modified) {
+                let mut response = Response::builder().status(StatusCode::PRECONDITION_FAILED);
+                if let Some(ref etag) = etag {
+                    response = response.header(ETAG, etag);
+                }
+                if let Some(last_modified) = last_modified {
+                    response = response.header(LAST_MODIFIED, http_date(last_modified));
+                }
+                return Ok(response
+                    .body(Empty::new().map_err(|_| std::io::Error::other("infallible")).boxed())
+                    .unwrap());
+            }
+
+            if is_not_modified(req.headers(), etag.as_deref(), last_modified) {
+                let mut response = Response::builder().status(StatusCode::NOT_MODIFIED);
+                if let Some(ref etag) = etag {
+                    response = response.header(ETAG, etag);
+                }
+                if let Some(last_modified) = last_modified {
+                    response = response.header(LAST_MODIFIED, http_date(last_modified));
+                }
+                return Ok(response
+                    .body(Empty::new().map_err(|_| std::io::Error::other("infallible")).boxed())
+                    .unwrap());
+            }
+
+            let Ok(range) = requested_range(req.headers(), file_size, etag.as_deref()) else {
+                return Ok(Response::builder()
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(CONTENT_RANGE, format!("bytes */{}", file_size))
+                    .body(Empty::new().map_err(|_| std::io::Error::other("infallible")).boxed())
+                    .unwrap());
+            };
+            let (offset, len) = range.unwrap_or((0, file_size));
+
+            #[cfg(target_os = "linux")]
+            let sendfile_source = match *req.method() {
+                Method::GET => sendfile::source_for(&file, offset, len).await,
+                _ => None,
+            };
+            let boxed_body = if req.method() == Method::HEAD {
+                // a HEAD response carries the same headers as a GET would, just without streaming the file
+                Empty::new().map_err(|_| std::io::Error::other("infallible")).boxed()
+            } else {
+                let reader_stream = file_stream(file, offset, len).await?;
+                match throttle {
+                    Some(throttle) => StreamBody::new(rate_limit::throttle(reader_stream, throttle).map_ok(Frame::data)).boxed(),
+                    None => StreamBody::new(reader_stream.map_ok(Frame::data)).boxed(),
+                }
+            };
+
+            let mut response = Response::builder()
+                .header(CONTENT_TYPE, download_as.content_type)
+                .header(CONTENT_DISPOSITION, content_disposition(&download_as.file_name))
+                .header(CONTENT_LENGTH, len.to_string())
+                .header(ACCEPT_RANGES, "bytes")
+                .status(StatusCode::OK);
+            if range.is_some() {
+                response = response
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header(CONTENT_RANGE, format!("bytes {}-{}/{}", offset, offset + len - 1, file_size));
+            }
+            if download_as.zstd_encoded {
+                response = response.header(CONTENT_ENCODING, "zstd");
+            }
+            if download_as.varies_by_encoding {
+                response = response.header(VARY, "Accept-Encoding");
+            }
+            if let Some(etag) = etag {
+                response = response.header(ETAG, etag);
+            }
+            if let Some(last_modified) = last_modified {
+                response = response.header(LAST_MODIFIED, http_date(last_modified));
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(sendfile_source) = sendfile_source {
+                response = response.extension(sendfile_source);
+            }
+            if req.method() == Method::GET {
+                response = response.extension(ArchiveDownload {
+                    archive: path_to_archive.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+                    len,
+                });
+            }
+            let response = response
+                .body(boxed_body)
+                .unwrap();
+
+            Ok(response)
+        }
+        Err(err) => {
+            error!("Failed to read the archive file: {}", err);
+            Ok(text_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to serve archive file",
+            ))
+        }
+    }
+}
+
+/// Characters kept as they are in the `filename*` of a `Content-Disposition`, a subset of RFC 8187's `attr-char`.
+const FILENAME_ATTR_CHARS: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_');
+
+/// `Content-Disposition` offering a download as `file_name`. Names that aren't plain ASCII get an ASCII fallback
+/// for old clients and the real name in `filename*`, as RFC 6266 describes.
+fn content_disposition(file_name: &str) -> HeaderValue {
+    let fallback: String = file_name
+        .chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    let value = if fallback == file_name {
+        format!("attachment; filename=\"{}\"", file_name)
+    } else {
+        format!(
+            "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+            fallback,
+            utf8_percent_encode(file_name, FILENAME_ATTR_CHARS)
+        )
+    };
+    HeaderValue::from_str(&value).expect("Only visible ASCII is left")
+}
+
+/// The file name, Content-Type and encoding an archive is sent with.
+struct DownloadAs<'a> {
+    file_name: String,
+    content_type: &'a str,
+    /// `Content-Encoding: zstd`, for `--zstd-content-encoding`
+    zstd_encoded: bool,
+    /// Whether the response depends on `Accept-Encoding`, for caches
+    varies_by_encoding: bool,
+}
+
+/// How `archive` is sent to the client of `req`: with the Content-Type of its format or the one set for that format
+/// with `--mime-type`, or as `.tar` with `Content-Encoding: zstd` when that's enabled and the client takes it.
+fn download_as<'a, B>(req: &Request<B>, options: &'a ServerOptions, archive: &Path, file_name: String) -> DownloadAs<'a> {
+    let format = CompressionFormat::from_file_extension(archive.extension());
+    let varies_by_encoding = options.zstd_content_encoding && format == Some(CompressionFormat::TarZstd);
+    if varies_by_encoding && accepts_zstd(req.headers()) {
+        // the browser saves what it unpacked, which is the tar
+        let file_name = match file_name.strip_suffix(".zst") {
+            Some(tar_name) => tar_name.to_string(),
+            None => file_name,
+        };
+        return DownloadAs {
+            file_name,
+            content_type: "application/x-tar",
+            zstd_encoded: true,
+            varies_by_encoding,
+        };
+    }
+    let content_type = format
+        .and_then(|format| options.mime_types.iter().rev().find(|(other, _)| *other == format))
+        .map(|(_, content_type)| content_type.as_str())
+        .unwrap_or_else(|| dir_listing::content_type_for(archive));
+    DownloadAs {
+        file_name,
+        content_type,
+        zstd_encoded: false,
+        varies_by_encoding,
+    }
+}
+
+/// Whether `Accept-Encoding` lists zstd without ruling it out with `q=0`.
+fn accepts_zstd(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';');
+            params.next().is_some_and(|name| name.trim().eq_ignore_ascii_case("zstd"))
+                && !params.any(|param| {
+                    param.trim().strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+                })
+        })
+}
+
+/// The chunks of the `len` bytes at `offset` of the archive to send. Whole archives are read through io_uring from
+/// a blocking thread with the `io-uring` feature.
+async fn file_stream(
+    mut file: tokio::fs::File,
+    offset: u64,
+    len: u64,
+) -> std::io::Result<std::pin::Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send + Sync>>> {
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if offset == 0 && crate::uring::available() {
+        /// Bigger than the reader stream's 4 KiB, a thread is woken up for every chunk
+        const CHUNK_SIZE: usize = 256 * 1024;
+        return Ok(Box::pin(crate::uring::stream_file(file.into_std().await, len, CHUNK_SIZE)));
+    }
+    if offset > 0 {
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+    }
+    Ok(Box::pin(ReaderStream::new(file.take(len))))
+}
+
+/// The single byte range the `Range` header asks for as `(offset, len)`, `None` for the whole file. Several ranges,
+/// unknown units and an `If-Range` that doesn't match the ETag get the whole file. `Err` when the range starts
+/// behind the end of the file.
+fn requested_range(headers: &HeaderMap, file_size: u64, etag: Option<&str>) -> Result<Option<(u64, u64)>, ()> {
+    let Some(range) = headers.get(RANGE).and_then(|value| value.to_str().ok()) else {
+        return Ok(None);
+    };
+    // dates in If-Range can't be compared as strongly as RFC 9110 wants, so only the ETag counts. It has to match
+    // byte for byte, a weak `W/` ETag from the client never does. Anything else gets the whole, current archive.
+    if let Some(if_range) = headers.get(IF_RANGE)
+        && Some(if_range.as_bytes()) != etag.map(str::as_bytes)
+    {
+        return Ok(None);
+    }
+    let Some((start, end)) = range
+        .strip_prefix("bytes=")
+        .filter(|spec| !spec.contains(','))
+        .and_then(|spec| spec.trim().split_once('-'))
+    else {
+        return Ok(None);
+    };
+    let last = file_size.saturating_sub(1);
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(last)),
+        (Ok(start), Err(_)) if end.is_empty() => (start, last),
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => (file_size.saturating_sub(suffix), last),
+        _ => return Ok(None),
+    };
+    if start >= file_size {
+        return Err(());
+    }
+    Ok(Some((start, end - start + 1)))
+}
+
+/// Strong ETag derived from the archive's size and modification time, which both change whenever the archive gets rebuilt.
+/// On Unix the inode goes in as well: a rebuilt archive is a new file renamed into place, so it gets a new ETag even
+/// when it has the same size and the file system only keeps the modification time to the second.
+fn etag(metadata: &std::fs::Metadata) -> Option<String> {
+    let modified = metadata
+        .modified()
+        .ok()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Some(format!("\"{:x}-{:x}-{:x}\"", metadata.len(), modified.as_nanos(), metadata.ino()))
+    }
+    #[cfg(not(unix))]
+    Some(format!("\"{:x}-{:x}\"", metadata.len(), modified.as_nanos()))
+}
+
+/// Evaluates `If-Match` and `If-Unmodified-Since` the way RFC 9110 wants it, `true` when the request has to be
+/// answered with 412. Download managers send them to make sure the bytes they append come from the same archive.
+/// `If-Match` compares strongly, so weak ETags never match, and when it's present `If-Unmodified-Since` is ignored.
+fn is_precondition_failed(headers: &HeaderMap, etag: Option<&str>, last_modified: Option<DateTime<Utc>>) -> bool {
+    if let Some(if_match) = headers.get(IF_MATCH) {
+        let Some(etag) = etag else {
+            return true;
+        };
+        return !if_match
+            .to_str()
+            .is_ok_and(|if_match| if_match.split(',').map(str::trim).any(|candidate| candidate == "*" || candidate == etag));
+    }
+    let if_unmodified_since = headers
+        .get(IF_UNMODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| DateTime::parse_from_rfc2822(value).ok());
+    match (if_unmodified_since, last_modified) {
+        // HTTP dates only have second precision
+        (Some(since), Some(modified)) => modified.timestamp() > since.timestamp(),
+        _ => false,
+    }
+}
+
+/// Whether the request passes the download token as `?token=`, or as `sub_path` if there is one. Both are
+/// percent-decoded and compared without leaking timing information.
+fn has_download_token<B>(req: &Request<B>, token: &str, sub_path: Option<&str>) -> bool {
+    use subtle::ConstantTimeEq;
+    let is_token = |provided: &[u8]| bool::from(provided.ct_eq(token.as_bytes()));
+    let in_query = req.uri().query().is_some_and(|query| {
+        url::form_urlencoded::parse(query.as_bytes()).any(|(name, value)| name == "token" && is_token(value.as_bytes()))
+    });
+    let in_path = sub_path.is_some_and(|sub_path| is_token(&percent_encoding::percent_decode_str(sub_path).collect::<Vec<_>>()));
+    in_query || in_path
+}
+
+/// Checks the `Authorization` header against the configured `user:password` without leaking timing information.
+fn is_authorized(headers: &HeaderMap, credentials: &str) -> bool {
+    use base64::Engine;
+    use subtle::ConstantTimeEq;
+    let Some(provided) = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok())
+    else {
+        return false;
+    };
+    provided.ct_eq(credentials.as_bytes()).into()
+}
+
+/// Evaluates `If-None-Match` and `If-Modified-Since` the way RFC 9110 wants it: when `If-None-Match` is present, `If-Modified-Since` is ignored.
+fn is_not_modified(
+    headers: &HeaderMap,
+    etag: Option<&str>,
+    last_modified: Option<DateTime<Utc>>,
+) -> bool {
+    if let Some(if_none_match) = headers.get(IF_NONE_MATCH).and_then(|value| value.to_str().ok()) {
+        return etag.is_some_and(|etag| {
+            if_none_match
+                .split(',')
+                .map(|candidate| candidate.trim().trim_start_matches("W/"))
+                .any(|candidate| candidate == "*" || candidate == etag)
+        });
+    }
+    let if_modified_since = headers
+        .get(IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| DateTime::parse_from_rfc2822(value).ok());
+    match (if_modified_since, last_modified) {
+        // HTTP dates only have second precision
+        (Some(since), Some(modified)) => modified.timestamp() <= since.timestamp(),
+        _ => false,
+    }
+}
+
+fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
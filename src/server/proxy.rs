@@ -0,0 +1,66 @@
+//! Serving under a sub path (`--base-path`) and from behind a reverse proxy (`--behind-proxy`), which tells who the
+//! client is and which URL it used in `X-Forwarded-*` headers.
+
+use std::net::IpAddr;
+
+use hyper::{HeaderMap, header::HeaderName};
+
+use crate::ServerOptions;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// `/downloads/world` for `downloads/world/` and the like, empty for the root.
+pub(crate) fn normalize_base_path(base_path: &str) -> String {
+    let trimmed = base_path.trim_matches('/');
+    if trimmed.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", trimmed)
+    }
+}
+
+/// The request path below the base path, starting with a slash. `None` for paths outside of it.
+pub(super) fn strip_base_path<'a>(options: &ServerOptions, path: &'a str) -> Option<&'a str> {
+    match path.strip_prefix(options.base_path.as_str())? {
+        "" => Some("/"),
+        rest if rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+/// The client's address, as the proxy saw it when behind one.
+pub(super) fn client_ip(options: &ServerOptions, headers: &HeaderMap, remote_ip: IpAddr) -> IpAddr {
+    if !options.behind_proxy {
+        return remote_ip;
+    }
+    // the last entry is the one the proxy added, the ones before it come from the client and can't be trusted
+    headers
+        .get(X_FORWARDED_FOR)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse().ok())
+        .unwrap_or(remote_ip)
+}
+
+/// The scheme and host the client used, like `https://mc.example.com`, when the proxy passed them on.
+pub(super) fn forwarded_base_url(options: &ServerOptions, headers: &HeaderMap) -> Option<String> {
+    if !options.behind_proxy {
+        return None;
+    }
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+    };
+    let host = header(X_FORWARDED_HOST).filter(|host| !host.contains(['/', '\\', '@']))?;
+    let proto = match header(X_FORWARDED_PROTO) {
+        Some("https") => "https",
+        _ => "http",
+    };
+    Some(format!("{}://{}", proto, host))
+}
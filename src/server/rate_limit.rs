@@ -0,0 +1,268 @@
+//! Download speed limits: per client IP with `--rate-limit`, per connection with `--connection-rate-limit`, and for
+//! all downloads together with `--total-rate-limit`, which is split fairly between the downloads running at once.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use futures_util::{Stream, StreamExt};
+use hyper::body::Bytes;
+use tokio::sync::{mpsc, oneshot};
+
+/// Token bucket that hands out bytes at a fixed rate. Allows bursts of up to one second worth of data.
+/// Consumers that take more than what's available go into debt and sleep until it's paid back,
+/// so multiple streams sharing one bucket split the rate between them.
+pub struct TokenBucket {
+    bytes_per_second: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_second: u64) -> Self {
+        // a rate of 0 would never pay anything back
+        let bytes_per_second = bytes_per_second.max(1) as f64;
+        Self {
+            bytes_per_second,
+            state: Mutex::new(BucketState {
+                tokens: bytes_per_second,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes `bytes` out of the bucket, waiting until the bucket would have refilled enough.
+    pub async fn consume(&self, bytes: usize) {
+        let wait = self.take(bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Takes `bytes` out of the bucket right away and returns how long it takes until they're paid back.
+    fn take(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.bytes_per_second).min(self.bytes_per_second);
+        state.last_refill = now;
+        state.tokens -= bytes as f64;
+        if state.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-state.tokens / self.bytes_per_second)
+    }
+}
+
+/// How many bytes a download gets per turn of a [`FairShare`]
+const QUANTUM: usize = 64 * 1024;
+
+/// A rate shared by all downloads, handed out in turns of [`QUANTUM`] bytes to the downloads waiting for it. Every
+/// download gets the same share no matter how big its chunks are, and what a download doesn't take because its
+/// client is slow goes to the others.
+pub struct FairShare {
+    requests: mpsc::UnboundedSender<(usize, oneshot::Sender<()>)>,
+}
+
+impl FairShare {
+    /// Starts handing out the rate on the current runtime, until the share is dropped.
+    pub fn new(bytes_per_second: u64) -> Self {
+        let (requests, requests_rx) = mpsc::unbounded_channel();
+        tokio::spawn(hand_out(TokenBucket::new(bytes_per_second), requests_rx));
+        Self { requests }
+    }
+
+    /// Waits until the download got `bytes` in its turns.
+    pub async fn consume(&self, bytes: usize) {
+        let (granted, granted_rx) = oneshot::channel();
+        if self.requests.send((bytes, granted)).is_ok() {
+            let _ = granted_rx.await;
+        }
+    }
+}
+
+/// Goes round the waiting downloads, paying a quantum for each out of `bucket`.
+async fn hand_out(bucket: TokenBucket, mut requests: mpsc::UnboundedReceiver<(usize, oneshot::Sender<()>)>) {
+    let mut waiting = VecDeque::new();
+    loop {
+        // downloads that started waiting join the end of the round
+        while let Ok(request) = requests.try_recv() {
+            waiting.push_back(request);
+        }
+        let Some((bytes, granted)) = waiting.pop_front() else {
+            match requests.recv().await {
+                Some(request) => {
+                    waiting.push_back(request);
+                    continue;
+                }
+                None => return,
+            }
+        };
+        // the connection was closed while waiting
+        if granted.is_closed() {
+            continue;
+        }
+        let quantum = bytes.min(QUANTUM);
+        bucket.consume(quantum).await;
+        if bytes > quantum {
+            waiting.push_back((bytes - quantum, granted));
+        } else {
+            let _ = granted.send(());
+        }
+    }
+}
+
+/// Hands out one shared [`TokenBucket`] per client IP, so opening several connections doesn't get a client more bandwidth.
+pub struct PerIpRateLimiter {
+    bytes_per_second: u64,
+    buckets: Mutex<HashMap<IpAddr, Weak<TokenBucket>>>,
+}
+
+impl PerIpRateLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn bucket_for(&self, ip: IpAddr) -> Arc<TokenBucket> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if let Some(bucket) = buckets.get(&ip).and_then(Weak::upgrade) {
+            return bucket;
+        }
+        // drop buckets of clients that are gone before adding a new one
+        buckets.retain(|_, bucket| bucket.strong_count() > 0);
+        let bucket = Arc::new(TokenBucket::new(self.bytes_per_second));
+        buckets.insert(ip, Arc::downgrade(&bucket));
+        bucket
+    }
+}
+
+/// The limits the downloads of a connection are held to.
+#[derive(Clone, Default)]
+pub struct Throttle {
+    /// Shared with the other connections of the client's IP
+    pub client: Option<Arc<TokenBucket>>,
+    /// Of this connection alone
+    pub connection: Option<Arc<TokenBucket>>,
+    /// Shared fairly with all downloads
+    pub total: Option<Arc<FairShare>>,
+}
+
+impl Throttle {
+    /// `None` when there's no limit at all, so downloads don't have to go through one.
+    pub fn limiting(self) -> Option<Self> {
+        (self.client.is_some() || self.connection.is_some() || self.total.is_some()).then_some(self)
+    }
+
+    /// Waits until `bytes` may be sent under every limit.
+    pub async fn consume(&self, bytes: usize) {
+        // the buckets of the client and the connection refill at the same time, so the longest wait covers them all
+        let wait = [&self.client, &self.connection]
+            .into_iter()
+            .flatten()
+            .map(|bucket| bucket.take(bytes))
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        if let Some(ref total) = self.total {
+            total.consume(bytes).await;
+        }
+    }
+}
+
+/// Wraps a body stream so every chunk has to get through `throttle` before it's passed on.
+pub fn throttle<S>(stream: S, throttle: Throttle) -> impl Stream<Item = std::io::Result<Bytes>>
+where
+    S: Stream<Item = std::io::Result<Bytes>>,
+{
+    stream.then(move |chunk| {
+        let throttle = throttle.clone();
+        async move {
+            if let Ok(ref bytes) = chunk {
+                throttle.consume(bytes.len()).await;
+            }
+            chunk
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ServerOptions,
+        server::{ServerState, proxy},
+    };
+
+    fn assert_about(wait: Duration, expected: Duration) {
+        let difference = wait.abs_diff(expected);
+        assert!(difference < Duration::from_millis(50), "waited {:?}, expected {:?}", wait, expected);
+    }
+
+    #[test]
+    fn bursts_up_to_one_second() {
+        let bucket = TokenBucket::new(1000);
+        assert_eq!(bucket.take(1000), Duration::ZERO);
+        assert_about(bucket.take(500), Duration::from_millis(500));
+        // the debt adds up
+        assert_about(bucket.take(500), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn chunks_bigger_than_the_bucket_wait_for_the_rest() {
+        let bucket = TokenBucket::new(1000);
+        assert_about(bucket.take(3000), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn a_rate_of_zero_still_pays_back() {
+        let bucket = TokenBucket::new(0);
+        assert_about(bucket.take(3), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn connections_of_one_ip_share_a_bucket() {
+        let rate_limiter = PerIpRateLimiter::new(1000);
+        let first = rate_limiter.bucket_for(IpAddr::from([192, 168, 0, 2]));
+        let second = rate_limiter.bucket_for(IpAddr::from([192, 168, 0, 2]));
+        let other = rate_limiter.bucket_for(IpAddr::from([192, 168, 0, 3]));
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(!Arc::ptr_eq(&first, &other));
+        drop(other);
+        // the bucket of a client that's gone is dropped once the next one comes along
+        rate_limiter.bucket_for(IpAddr::from([192, 168, 0, 4]));
+        assert_eq!(rate_limiter.buckets.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn clients_behind_a_proxy_get_a_bucket_each() {
+        let options = ServerOptions::builder()
+            .serve_dir(std::env::temp_dir())
+            .behind_proxy(true)
+            .rate_limit(1000)
+            .build()
+            .unwrap();
+        let state = ServerState::new(options, None, None);
+        let proxy_ip = IpAddr::from([127, 0, 0, 1]);
+        let bucket_of = |forwarded_for: &str| {
+            let mut headers = hyper::HeaderMap::new();
+            headers.insert("x-forwarded-for", forwarded_for.parse().unwrap());
+            let client_ip = proxy::client_ip(&state.options, &headers, proxy_ip);
+            state.throttle_for(&Throttle::default(), client_ip).unwrap().client.unwrap()
+        };
+        let first = bucket_of("203.0.113.1");
+        assert!(Arc::ptr_eq(&first, &bucket_of("203.0.113.1")));
+        assert!(!Arc::ptr_eq(&first, &bucket_of("203.0.113.2")));
+    }
+}
@@ -0,0 +1,221 @@
+//! `PUT /upload/<file name>`, receiving archives sent with `mwdh push` from another mwdh. Moving a server to a new
+//! machine then doesn't need any storage in between.
+//!
+//! The upload is written to `<file name>.part` and only gets its name once it's complete and its SHA-256 matches the
+//! one `mwdh push` sent along.
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Result;
+use http_body_util::{BodyExt, combinators::BoxBody};
+use hyper::{
+    HeaderMap, Request, Response, StatusCode,
+    body::{Bytes, Incoming},
+    header::{AUTHORIZATION, CONTENT_LENGTH, WWW_AUTHENTICATE},
+};
+use percent_encoding::percent_decode_str;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+use super::text_response;
+use crate::{archive::{self, SHA256_HEADER}, format_bytes, logging};
+
+/// Where and from whom archives are accepted.
+#[derive(Clone, Debug)]
+pub struct UploadOptions {
+    /// Directory received archives are saved in
+    pub dir: PathBuf,
+
+    /// Secret `mwdh push` has to send as bearer token
+    pub token: String,
+
+    /// Biggest archive accepted, in bytes
+    pub max_size: u64,
+}
+
+/// The file name of an upload, if the request path below the base path is one.
+pub(super) fn upload_name(path: &str) -> Option<&str> {
+    path.strip_prefix("/upload/")
+}
+
+/// Saves the request's body as `encoded_name` in the upload directory.
+pub(super) async fn receive(
+    req: Request<Incoming>,
+    options: &UploadOptions,
+    body_timeout: Duration,
+    encoded_name: &str,
+) -> Result<Response<BoxBody<Bytes, std::io::Error>>> {
+    if !is_authorized(req.headers(), &options.token) {
+        let mut response = text_response(StatusCode::UNAUTHORIZED, "Unauthorized");
+        response.headers_mut().insert(WWW_AUTHENTICATE, "Bearer realm=\"mwdh\"".parse().unwrap());
+        return Ok(response);
+    }
+    let Some(name) = file_name(encoded_name) else {
+        return Ok(text_response(StatusCode::BAD_REQUEST, "Invalid file name"));
+    };
+    let announced_len = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok());
+    if announced_len.is_some_and(|len| len > options.max_size) {
+        return Ok(text_response(StatusCode::PAYLOAD_TOO_LARGE, "The archive is too big"));
+    }
+    let expected_sha256 = req
+        .headers()
+        .get(SHA256_HEADER)
+        .and_then(|sha256| sha256.to_str().ok())
+        .map(str::to_ascii_lowercase);
+
+    let archive = options.dir.join(&name);
+    // saves receiving the whole archive for nothing, it's checked again when the upload gets its name
+    if archive.exists() {
+        return Ok(text_response(StatusCode::CONFLICT, "An archive with this name already exists"));
+    }
+    tokio::fs::create_dir_all(&options.dir).await?;
+    let part_path = archive::partial_path(&archive);
+    // another push of the same archive that is still running owns the part file
+    let file = match tokio::fs::OpenOptions::new().write(true).create_new(true).open(&part_path).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+            return Ok(text_response(StatusCode::CONFLICT, "This archive is already being uploaded"));
+        }
+        Err(err) => return Err(err.into()),
+    };
+    // also deleted when the request is dropped halfway, because the client went away or the server stops
+    let part_path = scopeguard::guard(part_path, |part_path| {
+        std::fs::remove_file(part_path).ok();
+    });
+
+    info!("Receiving {}", name);
+    let received = match write_body(req.into_body(), file, options.max_size, body_timeout).await {
+        Ok(received) => received,
+        Err(err) => {
+            return match err {
+                BodyError::TooLarge => Ok(text_response(StatusCode::PAYLOAD_TOO_LARGE, "The archive is too big")),
+                BodyError::TimedOut => {
+                    warn!("Receiving {} failed: nothing arrived for {}", name, humantime::format_duration(body_timeout));
+                    Ok(text_response(StatusCode::REQUEST_TIMEOUT, "Request Timeout"))
+                }
+                BodyError::Io(err) => {
+                    warn!("Receiving {} failed: {}", name, err);
+                    Err(err.into())
+                }
+            };
+        }
+    };
+    if let Some(expected) = expected_sha256
+        && received.sha256 != expected
+    {
+        warn!("{} doesn't match the SHA-256 it was sent with, it was deleted", name);
+        return Ok(text_response(StatusCode::BAD_REQUEST, "The archive doesn't match its SHA-256"));
+    }
+    let (from, to) = (part_path.clone(), archive.clone());
+    match tokio::task::spawn_blocking(move || rename_no_clobber(&from, &to)).await? {
+        Ok(()) => {
+            scopeguard::ScopeGuard::into_inner(part_path);
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+            return Ok(text_response(StatusCode::CONFLICT, "An archive with this name already exists"));
+        }
+        Err(err) => return Err(err.into()),
+    }
+    info!(target: logging::SUMMARY, "Received {} ({})", archive.display(), format_bytes(received.len));
+    Ok(text_response(StatusCode::CREATED, "Created"))
+}
+
+struct Received {
+    len: u64,
+    sha256: String,
+}
+
+enum BodyError {
+    TooLarge,
+    TimedOut,
+    Io(std::io::Error),
+}
+
+/// Writes the body to `file`, giving up once it's bigger than `max_size` or the client paused for `body_timeout`.
+async fn write_body(
+    mut body: Incoming,
+    mut file: tokio::fs::File,
+    max_size: u64,
+    body_timeout: Duration,
+) -> Result<Received, BodyError> {
+    let mut hasher = Sha256::new();
+    let mut len = 0u64;
+    while let Some(frame) = tokio::time::timeout(body_timeout, body.frame())
+        .await
+        .map_err(|_| BodyError::TimedOut)?
+    {
+        let frame = frame.map_err(|err| BodyError::Io(std::io::Error::other(err)))?;
+        let Ok(chunk) = frame.into_data() else {
+            continue;
+        };
+        len += chunk.len() as u64;
+        if len > max_size {
+            return Err(BodyError::TooLarge);
+        }
+        hasher.update(&chunk);
+        file.write_all(&chunk).await.map_err(BodyError::Io)?;
+    }
+    file.sync_all().await.map_err(BodyError::Io)?;
+    Ok(Received {
+        len,
+        sha256: format!("{:x}", hasher.finalize()),
+    })
+}
+
+/// Gives the finished upload its name, failing with `AlreadyExists` instead of replacing an archive that was pushed
+/// while it was being received.
+fn rename_no_clobber(from: &Path, to: &Path) -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::{ffi::CString, os::unix::ffi::OsStrExt};
+        let from_c = CString::new(from.as_os_str().as_bytes())?;
+        let to_c = CString::new(to.as_os_str().as_bytes())?;
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_renameat2,
+                libc::AT_FDCWD,
+                from_c.as_ptr(),
+                libc::AT_FDCWD,
+                to_c.as_ptr(),
+                libc::RENAME_NOREPLACE,
+            )
+        };
+        if result == 0 {
+            return Ok(());
+        }
+        let err = std::io::Error::last_os_error();
+        // file systems that don't know RENAME_NOREPLACE get the link below
+        if !matches!(err.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS)) {
+            return Err(err);
+        }
+    }
+    std::fs::hard_link(from, to)?;
+    std::fs::remove_file(from)
+}
+
+/// The decoded file name, as long as it can't point outside of the upload directory.
+fn file_name(encoded_name: &str) -> Option<String> {
+    let name = percent_decode_str(encoded_name).decode_utf8().ok()?;
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) || archive::is_partial(Path::new(name.as_ref())) {
+        return None;
+    }
+    Some(name.into_owned())
+}
+
+/// Checks the bearer token without leaking timing information.
+fn is_authorized(headers: &HeaderMap, token: &str) -> bool {
+    use subtle::ConstantTimeEq;
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|provided| provided.trim().as_bytes().ct_eq(token.as_bytes()).into())
+}
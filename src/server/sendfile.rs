@@ -0,0 +1,218 @@
+//! Sending downloads with `sendfile` on Linux, so the archive goes from the page cache straight to the socket
+//! instead of being copied through mwdh chunk by chunk.
+//!
+//! hyper can't hand a response body to the kernel, so plain `GET` requests are peeked at before hyper sees the
+//! connection. They're answered by the same [`handle`](super::handle) as everything else. If that results in a file
+//! download, its head is written here and the file is sent with `sendfile`. Anything else, like directory listings,
+//! `304 Not Modified` or requests that don't fit into one read, is left untouched for hyper to serve.
+
+use std::{
+    fs::File,
+    io::{self, Write},
+    net::SocketAddr,
+    os::fd::AsRawFd,
+    sync::Arc,
+};
+
+use chrono::Utc;
+use hyper::{
+    Request, Version,
+    header::{CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, Interest},
+    net::TcpStream,
+};
+
+use super::{
+    ServerState,
+    access_log::{AccessLog, AccessLogEntry},
+    downloads::{ArchiveDownload, DownloadInProgress},
+    handle, http_date,
+    limits::TransferMeter,
+    proxy,
+    rate_limit::Throttle,
+};
+
+/// Largest request head that is peeked at. hyper handles requests with bigger heads.
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+/// How much is sent per `sendfile` call, and paid for at once when the download is rate limited
+const CHUNK_SIZE: usize = 1024 * 1024;
+const MAX_HEADERS: usize = 64;
+
+/// Marks a response whose body is `len` bytes of the file starting at `offset`, so it can be sent with `sendfile`
+/// instead.
+#[derive(Clone)]
+pub(super) struct SendfileSource {
+    pub file: Arc<File>,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// What's left to do with the connection after [`serve_downloads`].
+pub(super) enum Handover {
+    /// The next request is for hyper, nothing of it was read yet
+    Hyper,
+    Closed,
+}
+
+/// Answers the downloads at the start of the connection, for as long as the client keeps asking for them.
+pub(super) async fn serve_downloads(
+    stream: &mut TcpStream,
+    state: &Arc<ServerState>,
+    connection_throttle: &Throttle,
+    access_log: Option<&Arc<AccessLog>>,
+    remote_addr: SocketAddr,
+    meter: &TransferMeter,
+) -> io::Result<Handover> {
+    let mut buf = vec![0u8; MAX_HEAD_SIZE];
+    loop {
+        // clients that don't send anything are dropped, those that send their head slowly are hyper's business
+        let Ok(peeked) = tokio::time::timeout(state.options.client_limits.header_timeout, stream.peek(&mut buf)).await else {
+            return Ok(Handover::Closed);
+        };
+        let peeked = peeked?;
+        if peeked == 0 {
+            return Ok(Handover::Closed);
+        }
+        let Some((req, head_len)) = parse_download_request(&buf[..peeked]) else {
+            return Ok(Handover::Hyper);
+        };
+
+        let client_ip = proxy::client_ip(&state.options, req.headers(), remote_addr.ip());
+        let throttle = state.throttle_for(connection_throttle, client_ip);
+        let entry = access_log.map(|_| AccessLogEntry::new(&req, client_ip));
+        let response = handle(&req, state, throttle.clone(), remote_addr.ip())
+            .await
+            .map_err(io::Error::other)?;
+        let Some(source) = response.extensions().get::<SendfileSource>().cloned() else {
+            return Ok(Handover::Hyper);
+        };
+
+        // the request is answered here, take it off the socket
+        stream.read_exact(&mut buf[..head_len]).await?;
+        let keep_alive = req.version() == Version::HTTP_11
+            && !req
+                .headers()
+                .get(CONNECTION)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|value| value.eq_ignore_ascii_case("close"));
+
+        let mut head = Vec::new();
+        write!(head, "HTTP/1.1 {}\r\n", response.status())?;
+        for (name, value) in response.headers() {
+            head.extend_from_slice(name.as_str().as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value.as_bytes());
+            head.extend_from_slice(b"\r\n");
+        }
+        write!(head, "date: {}\r\n", http_date(Utc::now()))?;
+        if !keep_alive {
+            head.extend_from_slice(b"connection: close\r\n");
+        }
+        head.extend_from_slice(b"\r\n");
+        stream.write_all(&head).await?;
+        meter.sent(head.len() as u64);
+
+        // reported once it's dropped, also when the connection is cut off in the middle of the download
+        let mut progress = response
+            .extensions()
+            .get::<ArchiveDownload>()
+            .cloned()
+            .map(|download| DownloadInProgress::start(state.clone(), download, client_ip));
+        let mut untracked = 0;
+        let sent = match progress {
+            Some(ref mut progress) => &mut progress.bytes_sent,
+            None => &mut untracked,
+        };
+        let result = send_file(stream, &source, throttle.as_ref(), meter, sent).await;
+        if let (Some(entry), Some(access_log)) = (entry, access_log) {
+            entry.log(access_log, response.status().as_u16(), *sent);
+        }
+        drop(progress);
+        result?;
+        if !keep_alive {
+            stream.shutdown().await.ok();
+            return Ok(Handover::Closed);
+        }
+    }
+}
+
+/// A complete `GET` request without a body, the only kind that can turn into a file download.
+fn parse_download_request(buf: &[u8]) -> Option<(Request<()>, usize)> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut parsed = httparse::Request::new(&mut headers);
+    let httparse::Status::Complete(head_len) = parsed.parse(buf).ok()? else {
+        return None;
+    };
+    if parsed.method != Some("GET") {
+        return None;
+    }
+    let version = match parsed.version? {
+        0 => Version::HTTP_10,
+        _ => Version::HTTP_11,
+    };
+    let mut req = Request::builder().method("GET").uri(parsed.path?).version(version);
+    for header in parsed.headers.iter() {
+        req = req.header(header.name, header.value);
+    }
+    let req = req.body(()).ok()?;
+    let has_body = req.headers().contains_key(TRANSFER_ENCODING)
+        || req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .is_some_and(|len| len.as_bytes() != b"0");
+    if has_body {
+        return None;
+    }
+    Some((req, head_len))
+}
+
+/// Sends the file to the socket with `sendfile`, counting what was sent in `sent` and the `meter`.
+async fn send_file(
+    stream: &TcpStream,
+    source: &SendfileSource,
+    throttle: Option<&Throttle>,
+    meter: &TransferMeter,
+    sent: &mut u64,
+) -> io::Result<()> {
+    let mut offset = source.offset as libc::off_t;
+    let end = source.offset + source.len;
+    while (offset as u64) < end {
+        let chunk = (end - offset as u64).min(CHUNK_SIZE as u64) as usize;
+        if let Some(throttle) = throttle {
+            throttle.consume(chunk).await;
+        }
+        let chunk_end = offset as u64 + chunk as u64;
+        while (offset as u64) < chunk_end {
+            stream.writable().await?;
+            let remaining = (chunk_end - offset as u64) as usize;
+            // advances `offset`, the file's own position stays where it is
+            let result = stream.try_io(Interest::WRITABLE, || {
+                match unsafe { libc::sendfile(stream.as_raw_fd(), source.file.as_raw_fd(), &mut offset, remaining) } {
+                    -1 => Err(io::Error::last_os_error()),
+                    sent => Ok(sent as u64),
+                }
+            });
+            match result {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The archive got shorter while sending it")),
+                Ok(bytes) => {
+                    *sent += bytes;
+                    meter.sent(bytes);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    meter.blocked();
+                    continue;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Used by the response of a download, `None` if the file can't be shared with the `sendfile` path.
+pub(super) async fn source_for(file: &tokio::fs::File, offset: u64, len: u64) -> Option<SendfileSource> {
+    let file = file.try_clone().await.ok()?.into_std().await;
+    Some(SendfileSource { file: Arc::new(file), offset, len })
+}
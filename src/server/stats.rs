@@ -0,0 +1,146 @@
+//! How often each archive was downloaded, shown on `/status` and logged when the server stops. With `--stats-file`
+//! the counters are kept in a JSON file, so they add up across restarts.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde_json::{Value, json};
+use tracing::{info, warn};
+
+use super::downloads::FinishedDownload;
+use crate::{format_bytes, logging};
+
+/// The counters of all archives, by file name.
+pub(super) struct DownloadStats {
+    file: Option<PathBuf>,
+    archives: Mutex<BTreeMap<String, ArchiveStats>>,
+}
+
+#[derive(Default)]
+struct ArchiveStats {
+    /// Responses that got through completely, including ranges
+    completed: u64,
+    /// Responses that broke off
+    partial: u64,
+    bytes_served: u64,
+    clients: HashSet<IpAddr>,
+}
+
+impl ArchiveStats {
+    fn to_json(&self, with_clients: bool) -> Value {
+        let mut stats = json!({
+            "completed": self.completed,
+            "partial": self.partial,
+            "bytes_served": self.bytes_served,
+            "unique_ips": self.clients.len(),
+        });
+        if with_clients {
+            let mut clients: Vec<_> = self.clients.iter().map(IpAddr::to_string).collect();
+            clients.sort();
+            stats["ips"] = json!(clients);
+        }
+        stats
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        let count = |key| value.get(key).and_then(Value::as_u64);
+        Some(ArchiveStats {
+            completed: count("completed")?,
+            partial: count("partial")?,
+            bytes_served: count("bytes_served")?,
+            clients: value
+                .get("ips")?
+                .as_array()?
+                .iter()
+                .filter_map(|ip| ip.as_str()?.parse().ok())
+                .collect(),
+        })
+    }
+}
+
+impl DownloadStats {
+    /// Counters starting from the ones in `file`, if there is one. A file that can't be read is warned about and
+    /// replaced.
+    pub(super) fn load(file: Option<PathBuf>) -> Self {
+        let archives = file.as_deref().map(read_stats).unwrap_or_default();
+        DownloadStats {
+            file,
+            archives: Mutex::new(archives),
+        }
+    }
+
+    /// Counts a download that finished or broke off and saves the counters.
+    pub(super) fn record(&self, finished: &FinishedDownload) {
+        let mut archives = self.archives.lock().unwrap();
+        let stats = archives.entry(finished.download.archive.clone()).or_default();
+        if finished.is_complete() {
+            stats.completed += 1;
+        } else {
+            stats.partial += 1;
+        }
+        stats.bytes_served += finished.bytes_sent;
+        stats.clients.insert(finished.client_ip);
+        if let Some(ref file) = self.file {
+            // small enough to write while holding the lock, which keeps the writes in order
+            if let Err(err) = write_stats(file, &archives) {
+                warn!("Failed to save the download statistics to {}: {}", file.display(), err);
+            }
+        }
+    }
+
+    /// The counters for `/status`, without the IP addresses.
+    pub(super) fn to_json(&self) -> Value {
+        let archives = self.archives.lock().unwrap();
+        Value::Object(archives.iter().map(|(name, stats)| (name.clone(), stats.to_json(false))).collect())
+    }
+
+    /// Logs the counters of every archive, when the server stops.
+    pub(super) fn log_summary(&self) {
+        for (name, stats) in self.archives.lock().unwrap().iter() {
+            info!(
+                target: logging::SUMMARY,
+                "Downloads of {}: {} complete, {} broken off, {} served to {} clients",
+                name,
+                stats.completed,
+                stats.partial,
+                format_bytes(stats.bytes_served),
+                stats.clients.len()
+            );
+        }
+    }
+}
+
+fn read_stats(file: &Path) -> BTreeMap<String, ArchiveStats> {
+    let contents = match std::fs::read(file) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return BTreeMap::new(),
+        Err(err) => {
+            warn!("Failed to read the download statistics from {}, starting over: {}", file.display(), err);
+            return BTreeMap::new();
+        }
+    };
+    let archives = serde_json::from_slice::<Value>(&contents).ok().and_then(|value| {
+        value
+            .as_object()?
+            .iter()
+            .map(|(name, stats)| Some((name.clone(), ArchiveStats::from_json(stats)?)))
+            .collect::<Option<BTreeMap<_, _>>>()
+    });
+    archives.unwrap_or_else(|| {
+        warn!("{} doesn't hold download statistics of mwdh, starting over", file.display());
+        BTreeMap::new()
+    })
+}
+
+/// Replaces the file at once, so a crash can't leave half of it behind.
+fn write_stats(file: &Path, archives: &BTreeMap<String, ArchiveStats>) -> std::io::Result<()> {
+    let stats = Value::Object(archives.iter().map(|(name, stats)| (name.clone(), stats.to_json(true))).collect());
+    let mut temp_path = file.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    std::fs::write(&temp_path, serde_json::to_vec_pretty(&stats)?)?;
+    std::fs::rename(&temp_path, file)
+}
@@ -0,0 +1,66 @@
+//! HTTPS with `--tls-cert` and `--tls-key`, and mutual TLS with `--tls-client-ca`: only clients with a certificate
+//! signed by that CA get past the handshake, so machines can fetch archives from each other without shared passwords.
+//!
+//! The kernel can't encrypt what `sendfile` sends, so over TLS every download goes through hyper.
+
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::{Context, Result, anyhow};
+use rustls::{
+    RootCertStore, ServerConfig,
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::WebPkiClientVerifier,
+};
+use tokio_rustls::TlsAcceptor;
+
+/// Certificate and key of the server, and the CA client certificates have to be signed by, if any.
+#[derive(Clone, Debug)]
+pub struct TlsOptions {
+    /// PEM file with the server's certificate, followed by the intermediate certificates
+    pub cert: PathBuf,
+
+    /// PEM file with the server's private key
+    pub key: PathBuf,
+
+    /// PEM file with the CA certificates clients have to present a certificate of. Any client may connect when
+    /// `None`.
+    pub client_ca: Option<PathBuf>,
+}
+
+/// Loads the certificates and the key for accepting connections.
+pub(super) fn acceptor(options: &TlsOptions) -> Result<TlsAcceptor> {
+    let cert_chain = CertificateDer::pem_file_iter(&options.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read the certificates in {}", options.cert.display()))?;
+    if cert_chain.is_empty() {
+        return Err(anyhow!("No certificate in {}", options.cert.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(&options.key)
+        .with_context(|| format!("Failed to read the private key in {}", options.key.display()))?;
+
+    // picked explicitly, rustls can't tell which one to use when another dependency enables a second provider
+    let builder = ServerConfig::builder_with_provider(Arc::new(ring::default_provider())).with_safe_default_protocol_versions()?;
+    let builder = match options.client_ca {
+        Some(ref client_ca) => {
+            let mut roots = RootCertStore::empty();
+            let certs = CertificateDer::pem_file_iter(client_ca)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .with_context(|| format!("Failed to read the client CA certificates in {}", client_ca.display()))?;
+            let (added, _) = roots.add_parsable_certificates(certs);
+            if added == 0 {
+                return Err(anyhow!("No usable CA certificate in {}", client_ca.display()));
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), Arc::new(ring::default_provider()))
+                .build()
+                .context("Invalid client CA")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder
+        .with_single_cert(cert_chain, key)
+        .context("The private key doesn't fit the certificate")?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
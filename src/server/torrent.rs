@@ -0,0 +1,188 @@
+//! `.torrent` files of the served archives, with the server itself as web seed (BEP 19). Downloaders can then share
+//! the upload of a big world with each other, and fall back to the server whenever no peer has a piece.
+//!
+//! Hashing the pieces means reading the whole archive, so it's done once per archive and again only when the archive
+//! changed. The web seed can differ between requests behind a reverse proxy and is filled in for each one. Each
+//! torrent is also written next to its archive as `<archive>.torrent`, with the web seed of the request that hashed it.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use hyper::body::Bytes;
+use sha1::{Digest, Sha1};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// Smallest and biggest piece size. Between them the archive is split into about [`TARGET_PIECES`] pieces.
+const MIN_PIECE_LENGTH: u64 = 256 * 1024;
+const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+const TARGET_PIECES: u64 = 1500;
+
+/// How the torrents of the served archives are made.
+#[derive(Clone, Debug, Default)]
+pub struct TorrentOptions {
+    /// URL the server is reachable under for downloaders, like `https://mc.example.com`. The web seed of an archive is
+    /// this plus its path. `http://<hostname>:<port>` when not set.
+    pub base_url: Option<String>,
+
+    /// Trackers announced in the torrents. Without any, clients find each other through the DHT.
+    pub trackers: Vec<String>,
+}
+
+/// The hashed pieces of the archives so far, by archive.
+#[derive(Default)]
+pub(super) struct TorrentCache {
+    torrents: Mutex<HashMap<PathBuf, CachedInfo>>,
+}
+
+struct CachedInfo {
+    len: u64,
+    modified: Option<SystemTime>,
+    /// The bencoded info dictionary, which only depends on the archive
+    info: Bytes,
+}
+
+impl TorrentCache {
+    /// The torrent of `archive` downloadable from `web_seed`. The archive is hashed when it wasn't yet or it changed,
+    /// requests wait while that's going on.
+    pub(super) async fn get(&self, archive: &Path, web_seed: &str, trackers: &[String]) -> io::Result<Bytes> {
+        let metadata = tokio::fs::metadata(archive).await?;
+        let modified = metadata.modified().ok();
+        let mut torrents = self.torrents.lock().await;
+        if let Some(cached) = torrents.get(archive)
+            && cached.len == metadata.len()
+            && cached.modified == modified
+        {
+            return Ok(Bytes::from(encode_torrent(&cached.info, web_seed, trackers)));
+        }
+
+        info!("Hashing {} for its torrent", archive.display());
+        let info = {
+            let archive = archive.to_path_buf();
+            tokio::task::spawn_blocking(move || hash_archive(&archive))
+                .await
+                .map_err(io::Error::other)??
+        };
+        let info = Bytes::from(info);
+        let torrent = Bytes::from(encode_torrent(&info, web_seed, trackers));
+        let mut torrent_path = archive.as_os_str().to_owned();
+        torrent_path.push(".torrent");
+        if let Err(err) = tokio::fs::write(&torrent_path, &torrent).await {
+            warn!("Failed to write {}: {}", Path::new(&torrent_path).display(), err);
+        }
+        torrents.insert(
+            archive.to_path_buf(),
+            CachedInfo {
+                len: metadata.len(),
+                modified,
+                info,
+            },
+        );
+        Ok(torrent)
+    }
+}
+
+/// Bencodes the info dictionary of a single file torrent of `archive`, with the SHA-1 of every piece.
+fn hash_archive(archive: &Path) -> io::Result<Vec<u8>> {
+    let mut file = File::open(archive)?;
+    let len = file.metadata()?.len();
+    let piece_length = piece_length(len);
+    let mut pieces = Vec::with_capacity(len.div_ceil(piece_length) as usize * 20);
+    let mut piece = Vec::with_capacity(piece_length as usize);
+    loop {
+        piece.clear();
+        (&mut file).take(piece_length).read_to_end(&mut piece)?;
+        if piece.is_empty() {
+            break;
+        }
+        pieces.extend_from_slice(&Sha1::digest(&piece));
+    }
+
+    let name = archive
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let info = Value::Dict(vec![
+        ("length", Value::Int(len as i64)),
+        ("name", Value::Bytes(name.as_bytes())),
+        ("piece length", Value::Int(piece_length as i64)),
+        ("pieces", Value::Bytes(&pieces)),
+    ]);
+    let mut encoded = Vec::new();
+    info.encode(&mut encoded);
+    Ok(encoded)
+}
+
+/// Bencodes the torrent around an info dictionary from [`hash_archive`].
+fn encode_torrent(info: &[u8], web_seed: &str, trackers: &[String]) -> Vec<u8> {
+    let mut torrent = vec![
+        ("created by", Value::Bytes(concat!("mwdh ", env!("CARGO_PKG_VERSION")).as_bytes())),
+        ("creation date", Value::Int(chrono::Utc::now().timestamp())),
+        ("info", Value::Encoded(info)),
+        ("url-list", Value::List(vec![Value::Bytes(web_seed.as_bytes())])),
+    ];
+    if let Some(tracker) = trackers.first() {
+        torrent.push(("announce", Value::Bytes(tracker.as_bytes())));
+        // one tier per tracker, clients try them one after another
+        let tiers = trackers
+            .iter()
+            .map(|tracker| Value::List(vec![Value::Bytes(tracker.as_bytes())]))
+            .collect();
+        torrent.push(("announce-list", Value::List(tiers)));
+    }
+    let mut encoded = Vec::new();
+    Value::Dict(torrent).encode(&mut encoded);
+    encoded
+}
+
+/// A power of two that splits `len` bytes into about [`TARGET_PIECES`] pieces.
+fn piece_length(len: u64) -> u64 {
+    (len / TARGET_PIECES)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+/// The bencoded values torrents are made of.
+enum Value<'a> {
+    Int(i64),
+    Bytes(&'a [u8]),
+    List(Vec<Value<'a>>),
+    Dict(Vec<(&'a str, Value<'a>)>),
+    /// Bencoded already
+    Encoded(&'a [u8]),
+}
+
+impl Value<'_> {
+    fn encode(self, out: &mut Vec<u8>) {
+        match self {
+            Value::Int(int) => out.extend_from_slice(format!("i{}e", int).as_bytes()),
+            Value::Bytes(bytes) => {
+                out.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+            }
+            Value::List(values) => {
+                out.push(b'l');
+                for value in values {
+                    value.encode(out);
+                }
+                out.push(b'e');
+            }
+            Value::Encoded(bytes) => out.extend_from_slice(bytes),
+            Value::Dict(mut entries) => {
+                // bencoded dictionaries are sorted by their raw keys
+                entries.sort_by(|(a, _), (b, _)| a.as_bytes().cmp(b.as_bytes()));
+                out.push(b'd');
+                for (key, value) in entries {
+                    Value::Bytes(key.as_bytes()).encode(out);
+                    value.encode(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+}
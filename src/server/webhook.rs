@@ -0,0 +1,58 @@
+//! `--webhook-url`, posting every download that finished or broke off as JSON, so scripts can shut the server down
+//! once everyone has the world, revoke a token or tell the admins.
+
+use std::time::Duration;
+
+use hyper::header::CONTENT_TYPE;
+use serde_json::json;
+use tracing::{debug, warn};
+use url::Url;
+
+use super::downloads::FinishedDownload;
+
+/// How long the receiving end gets to answer
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
+pub(super) struct Webhook {
+    url: Url,
+    client: reqwest::Client,
+}
+
+impl Webhook {
+    pub(super) fn new(url: Url) -> Self {
+        Webhook {
+            url,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Posts the download to the webhook in the background.
+    pub(super) fn report(&self, finished: &FinishedDownload) {
+        let payload = json!({
+            "archive": finished.download.archive,
+            "client_ip": finished.client_ip.to_string(),
+            "bytes": finished.bytes_sent,
+            "expected_bytes": finished.download.len,
+            "duration_secs": finished.duration.as_secs_f64(),
+            "success": finished.is_complete(),
+        });
+        // the server may be shutting down already
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let request = self
+            .client
+            .post(self.url.clone())
+            .timeout(TIMEOUT)
+            .header(CONTENT_TYPE, "application/json")
+            .body(payload.to_string());
+        let url = self.url.clone();
+        runtime.spawn(async move {
+            match request.send().await.and_then(|response| response.error_for_status()) {
+                Ok(_) => debug!("Posted the download to {}", url),
+                Err(err) => warn!("Failed to post the download to the webhook: {}", err),
+            }
+        });
+    }
+}
@@ -0,0 +1,309 @@
+//! `mwdh service`, hosting a world download as a Windows service on machines that aren't dedicated servers.
+//! Part of the binary, the service runs the same code as the command line.
+
+use std::{
+    error::Error,
+    ffi::{OsStr, OsString},
+    io::{self, Write},
+    os::windows::ffi::OsStrExt,
+    sync::OnceLock,
+    time::Duration,
+};
+
+use anyhow::{Context, anyhow};
+use clap::ArgMatches;
+use mwdh::{MwdhOptions, cancel::CancellationToken, cli, logging};
+use tracing::{Level, Metadata, error, info};
+use tracing_subscriber::fmt::MakeWriter;
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceAccess, ServiceAction, ServiceActionType, ServiceControl, ServiceControlAccept, ServiceErrorControl,
+        ServiceExitCode, ServiceFailureActions, ServiceFailureResetPeriod, ServiceInfo, ServiceStartType,
+        ServiceState, ServiceStatus, ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+use windows_sys::Win32::{
+    Foundation::HANDLE,
+    System::EventLog::{
+        EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE, REPORT_EVENT_TYPE,
+        RegisterEventSourceW, ReportEventW,
+    },
+};
+
+type BoxResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+/// How long Windows waits before restarting the service after it failed
+const RESTART_DELAY: Duration = Duration::from_secs(10);
+
+/// Name and command line of the service, handed from `mwdh service run` to [`service_main`].
+static SERVICE: OnceLock<(String, Vec<String>)> = OnceLock::new();
+
+pub fn command(matches: &ArgMatches) -> BoxResult {
+    let level = cli::parse_log_level(matches);
+    let (subcommand, matches) = matches.subcommand().expect("clap requires a subcommand");
+    let name = matches.get_one::<String>("name").unwrap().clone();
+    let command = matches.get_many::<String>("command").into_iter().flatten().cloned().collect::<Vec<_>>();
+    match subcommand {
+        "install" => {
+            logging::init(level, cli::parse_log_file(matches).as_ref())?;
+            install(&name, command)?;
+        }
+        "uninstall" => {
+            logging::init(level, cli::parse_log_file(matches).as_ref())?;
+            uninstall(&name)?;
+        }
+        "run" => {
+            // services start in C:\Windows\System32, the paths of the command line are relative to where it was installed
+            std::env::set_current_dir(matches.get_one::<String>("working-dir").unwrap())?;
+            // -q, -v and --log-file are part of the service's command line
+            let service_matches = service_matches(&command)?;
+            logging::init_with_writer(
+                cli::parse_log_level(&service_matches),
+                EventLog::register(&name)?,
+                cli::parse_log_file(&service_matches).as_ref(),
+            )?;
+            SERVICE.get_or_init(|| (name.clone(), command));
+            service_dispatcher::start(&name, ffi_service_main)?;
+        }
+        _ => unreachable!("clap should ensure we don't get here"),
+    }
+    Ok(())
+}
+
+/// Parses the command line the service runs, which has to be one that serves.
+fn service_matches(command: &[String]) -> anyhow::Result<ArgMatches> {
+    let matches = cli::create_cli()
+        .try_get_matches_from(std::iter::once("mwdh").chain(command.iter().map(String::as_str)))?;
+    if !matches!(matches.subcommand_name(), Some("host" | "compress-host" | "serve-dir")) {
+        return Err(anyhow!("A service can only run host, compress-host or serve-dir"));
+    }
+    Ok(matches)
+}
+
+fn parse_service_command(command: &[String]) -> anyhow::Result<MwdhOptions> {
+    cli::parse_args(&service_matches(command)?)
+}
+
+fn install(name: &str, command: Vec<String>) -> anyhow::Result<()> {
+    // better to find mistakes now than in the event log
+    parse_service_command(&command)?;
+
+    let mut launch_arguments: Vec<OsString> = vec![
+        "service".into(),
+        "run".into(),
+        "--name".into(),
+        name.into(),
+        "--working-dir".into(),
+        std::env::current_dir()?.into_os_string(),
+        "--".into(),
+    ];
+    launch_arguments.extend(command.into_iter().map(OsString::from));
+
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE)
+        .context("Failed to connect to the service manager. Installing a service needs an administrator command prompt")?;
+    let info = ServiceInfo {
+        name: name.into(),
+        display_name: format!("mwdh ({})", name).into(),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe()?,
+        launch_arguments,
+        dependencies: Vec::new(),
+        account_name: None, // LocalSystem
+        account_password: None,
+    };
+    let service = manager
+        .create_service(&info, ServiceAccess::CHANGE_CONFIG | ServiceAccess::START)
+        .with_context(|| format!("Failed to create the service {}", name))?;
+    service.set_description("Hosts a Minecraft world download")?;
+
+    let restart = ServiceAction {
+        action_type: ServiceActionType::Restart,
+        delay: RESTART_DELAY,
+    };
+    service.update_failure_actions(ServiceFailureActions {
+        reset_period: ServiceFailureResetPeriod::After(Duration::from_secs(24 * 60 * 60)),
+        reboot_msg: None,
+        command: None,
+        actions: Some(vec![restart.clone(), restart.clone(), restart]),
+    })?;
+    // also restart when mwdh exits with an error, not only when it crashes
+    service.set_failure_actions_on_non_crash_failures(true)?;
+
+    service.start(&[] as &[&OsStr]).with_context(|| format!("Failed to start the service {}", name))?;
+    info!("Installed and started the service {}. It starts with Windows, its log is in the Event Viewer under Windows Logs > Application", name);
+    Ok(())
+}
+
+fn uninstall(name: &str) -> anyhow::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .context("Failed to connect to the service manager. Uninstalling a service needs an administrator command prompt")?;
+    let service = manager
+        .open_service(name, ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE)
+        .with_context(|| format!("Failed to open the service {}", name))?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+    }
+    service.delete()?;
+    info!("Uninstalled the service {}", name);
+    Ok(())
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn service_main(_arguments: Vec<OsString>) {
+    let (name, command) = SERVICE.get().expect("set before starting the dispatcher");
+    if let Err(err) = run_service(name, command) {
+        error!("{}", err);
+    }
+}
+
+fn run_service(name: &str, command: &[String]) -> BoxResult {
+    let stop = CancellationToken::new();
+    let handler_stop = stop.clone();
+    let status = service_control_handler::register(name, move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            handler_stop.cancel();
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    })?;
+    let set_state = |state: ServiceState, exit_code: u32| {
+        status.set_service_status(ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted: if state == ServiceState::Running {
+                ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN
+            } else {
+                ServiceControlAccept::empty()
+            },
+            exit_code: ServiceExitCode::ServiceSpecific(exit_code),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        })
+    };
+
+    let result = parse_service_command(command).map_err(Into::into).and_then(|mut options| {
+        // the service manager stops the server and the archiving through their tokens
+        match options {
+            MwdhOptions::Server(ref mut server) => server.cancel = stop.clone(),
+            MwdhOptions::Both { ref mut server, ref mut archive, .. } => {
+                server.cancel = stop.clone();
+                archive.cancel = stop.clone();
+            }
+            _ => {}
+        }
+        set_state(ServiceState::Running, 0)?;
+        crate::run(options)
+    });
+
+    // a non-zero exit code has the service manager restart the service
+    match result {
+        Err(err) if !stop.is_cancelled() => {
+            set_state(ServiceState::Stopped, 1)?;
+            Err(err)
+        }
+        _ => {
+            set_state(ServiceState::Stopped, 0)?;
+            Ok(())
+        }
+    }
+}
+
+/// Writes the log messages to the Windows event log, where the messages of services are expected.
+struct EventLog(HANDLE);
+
+// event source handles can be used from any thread
+unsafe impl Send for EventLog {}
+unsafe impl Sync for EventLog {}
+
+impl EventLog {
+    fn register(source: &str) -> io::Result<EventLog> {
+        let source = wide(source);
+        let handle = unsafe { RegisterEventSourceW(std::ptr::null(), source.as_ptr()) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(EventLog(handle))
+    }
+}
+
+impl<'a> MakeWriter<'a> for EventLog {
+    type Writer = EventLogEntry<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        EventLogEntry {
+            log: self,
+            kind: EVENTLOG_INFORMATION_TYPE,
+            message: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        let kind = match *meta.level() {
+            Level::ERROR => EVENTLOG_ERROR_TYPE,
+            Level::WARN => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+        EventLogEntry {
+            log: self,
+            kind,
+            message: Vec::new(),
+        }
+    }
+}
+
+/// A log message, reported to the event log once it's formatted and dropped.
+struct EventLogEntry<'a> {
+    log: &'a EventLog,
+    kind: REPORT_EVENT_TYPE,
+    message: Vec<u8>,
+}
+
+impl Write for EventLogEntry<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.message.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for EventLogEntry<'_> {
+    fn drop(&mut self) {
+        let message = String::from_utf8_lossy(&self.message);
+        let message = message.trim_end();
+        if message.is_empty() {
+            return;
+        }
+        let message = wide(message);
+        let strings = [message.as_ptr()];
+        unsafe {
+            ReportEventW(
+                self.log.0,
+                self.kind,
+                0,
+                0,
+                std::ptr::null_mut(),
+                1,
+                0,
+                strings.as_ptr(),
+                std::ptr::null(),
+            );
+        }
+    }
+}
+
+/// A nul terminated UTF-16 string for the Windows API.
+fn wide(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain(Some(0)).collect()
+}
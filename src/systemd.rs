@@ -0,0 +1,61 @@
+//! Working together with systemd: listening on the socket of a socket unit and telling systemd when mwdh is
+//! ready and what it is doing. All of it does nothing when mwdh wasn't started by systemd.
+
+#[cfg(unix)]
+use sd_notify::NotifyState;
+#[cfg(unix)]
+use tracing::debug;
+
+/// Sets the status `systemctl status` shows, like what is being compressed.
+pub fn notify_status(status: &str) {
+    #[cfg(unix)]
+    notify(&[NotifyState::Status(status)]);
+    #[cfg(not(unix))]
+    let _ = status;
+}
+
+/// Tells a `Type=notify` service that mwdh is up, along with its status.
+pub fn notify_ready(status: &str) {
+    #[cfg(unix)]
+    notify(&[NotifyState::Ready, NotifyState::Status(status)]);
+    #[cfg(not(unix))]
+    let _ = status;
+}
+
+/// Tells systemd that mwdh is shutting down on its own, so it isn't seen as a crash.
+pub fn notify_stopping() {
+    #[cfg(unix)]
+    notify(&[NotifyState::Stopping]);
+}
+
+#[cfg(unix)]
+fn notify(state: &[NotifyState]) {
+    // NOTIFY_SOCKET is kept for the notifications that follow
+    if let Err(err) = sd_notify::notify(false, state) {
+        debug!("Failed to notify systemd: {}", err);
+    }
+}
+
+/// The listening socket passed by a systemd socket unit (`LISTEN_FDS`), to serve on instead of binding one.
+/// Can only be taken once.
+#[cfg(feature = "server")]
+pub(crate) fn take_listener() -> std::io::Result<Option<std::net::TcpListener>> {
+    #[cfg(unix)]
+    {
+        use std::os::fd::FromRawFd;
+
+        let mut fds = sd_notify::listen_fds()?;
+        let Some(fd) = fds.next() else {
+            return Ok(None);
+        };
+        if fds.next().is_some() {
+            tracing::warn!("The socket unit passed more than one socket, only serving on the first one");
+        }
+        // systemd hands the socket over to this process, nothing else owns it
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        listener.set_nonblocking(true)?;
+        Ok(Some(listener))
+    }
+    #[cfg(not(unix))]
+    Ok(None)
+}
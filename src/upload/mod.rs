@@ -0,0 +1,134 @@
+pub mod s3;
+pub mod sftp;
+pub mod webdav;
+
+use std::{fmt::Display, path::Path, str::FromStr};
+
+use anyhow::{Context, Result, anyhow};
+use tracing::info;
+use url::Url;
+
+use crate::upload::{sftp::SftpTarget, webdav::WebDavTarget};
+
+/// Where to push a finished archive to, parsed from an URL like `s3://bucket/key`, `sftp://user@host/path` or `davs://user@host/path`.
+#[derive(Debug, Clone)]
+pub enum UploadTarget {
+    S3 { bucket: String, key: String },
+    Sftp(SftpTarget),
+    WebDav(WebDavTarget),
+}
+
+impl FromStr for UploadTarget {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = s
+            .split_once("://")
+            .ok_or_else(|| anyhow!("Upload target has to be an URL like s3://bucket/key"))?;
+        match scheme {
+            "s3" => {
+                let (bucket, key) = rest.split_once('/').unwrap_or((rest, ""));
+                if bucket.is_empty() {
+                    return Err(anyhow!("Missing bucket in {}", s));
+                }
+                Ok(UploadTarget::S3 {
+                    bucket: bucket.to_string(),
+                    key: key.to_string(),
+                })
+            }
+            "sftp" | "scp" => {
+                let url = Url::parse(s).context("Invalid SFTP URL")?;
+                let host = url
+                    .host_str()
+                    .ok_or_else(|| anyhow!("Missing host in {}", s))?
+                    .to_string();
+                let user = match url.username() {
+                    "" => std::env::var("USER").context("Missing user in SFTP URL")?,
+                    user => percent_encoding::percent_decode_str(user).decode_utf8()?.to_string(),
+                };
+                // passwords in the URL end up in the process list, so also allow passing it via the environment
+                let password = match url.password() {
+                    Some(password) => Some(percent_encoding::percent_decode_str(password).decode_utf8()?.to_string()),
+                    None => std::env::var("MWDH_UPLOAD_PASSWORD").ok(),
+                };
+                let remote_path = percent_encoding::percent_decode_str(url.path()).decode_utf8()?.to_string();
+                Ok(UploadTarget::Sftp(SftpTarget {
+                    user,
+                    password,
+                    host,
+                    port: url.port().unwrap_or(22),
+                    remote_path: if remote_path.is_empty() { "/".to_string() } else { remote_path },
+                    key: None,
+                }))
+            }
+            "dav" | "davs" | "webdav" | "webdavs" => {
+                let http_scheme = if scheme.ends_with('s') { "https" } else { "http" };
+                let mut url = Url::parse(&format!("{}://{}", http_scheme, rest)).context("Invalid WebDAV URL")?;
+                let user = match url.username() {
+                    "" => None,
+                    user => Some(percent_encoding::percent_decode_str(user).decode_utf8()?.to_string()),
+                };
+                let password = match url.password() {
+                    Some(password) => Some(percent_encoding::percent_decode_str(password).decode_utf8()?.to_string()),
+                    None => std::env::var("MWDH_UPLOAD_PASSWORD").ok(),
+                };
+                // credentials are sent via basic auth, not as part of the URL
+                url.set_username("").ok();
+                url.set_password(None).ok();
+                Ok(UploadTarget::WebDav(WebDavTarget {
+                    url,
+                    user,
+                    password,
+                    chunk_size: webdav::DEFAULT_CHUNK_SIZE,
+                }))
+            }
+            _ => Err(anyhow!("Unsupported upload target scheme: {}://", scheme)),
+        }
+    }
+}
+
+impl Display for UploadTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UploadTarget::S3 { bucket, key } => write!(f, "s3://{}/{}", bucket, key),
+            UploadTarget::Sftp(target) => write!(
+                f,
+                "sftp://{}@{}:{}{}",
+                target.user, target.host, target.port, target.remote_path
+            ),
+            UploadTarget::WebDav(target) => write!(f, "{}", target.url),
+        }
+    }
+}
+
+/// Uploads the archive at `archive_path` to `target`. Targets ending in `/` (or without a key/path) get the archive's file name appended.
+pub async fn upload(archive_path: &Path, target: &UploadTarget) -> Result<()> {
+    let file_name = archive_path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid archive path: {}", archive_path.display()))?
+        .to_string_lossy();
+    info!("Uploading {} to {}", archive_path.display(), target);
+    match target {
+        UploadTarget::S3 { bucket, key } => {
+            let key = if key.is_empty() || key.ends_with('/') {
+                format!("{}{}", key, file_name)
+            } else {
+                key.clone()
+            };
+            s3::upload_multipart(archive_path, bucket, &key).await
+        }
+        UploadTarget::Sftp(target) => {
+            let mut target = target.clone();
+            if target.remote_path.ends_with('/') {
+                target.remote_path.push_str(&file_name);
+            }
+            sftp::upload(archive_path, &target).await
+        }
+        UploadTarget::WebDav(target) => {
+            let mut target = target.clone();
+            if target.url.path().ends_with('/') {
+                target.url = target.url.join(&file_name)?;
+            }
+            webdav::upload(archive_path, &target).await
+        }
+    }
+}
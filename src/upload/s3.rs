@@ -0,0 +1,151 @@
+use std::{path::Path, time::Duration};
+
+use anyhow::{Context, Result, anyhow};
+use tracing::info;
+use aws_sdk_s3::{
+    Client,
+    primitives::ByteStream,
+    types::{CompletedMultipartUpload, CompletedPart},
+};
+use bytes::Bytes;
+use crate::archive::progress::{TransferProgress, transfer_bar};
+use tokio::io::AsyncReadExt;
+
+/// S3 wants parts of at least 5 MiB (except the last one) and at most 10000 parts per upload.
+const MIN_PART_SIZE: u64 = 8 * 1024 * 1024;
+const MAX_PARTS: u64 = 10_000;
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Streams the file at `path` to `s3://bucket/key` part by part using a multipart upload.
+/// Credentials and region are resolved the standard AWS way (environment, profile, instance metadata, ...).
+/// Failed parts are retried with exponential backoff; if the upload can't be completed it gets aborted so no orphaned parts are left behind.
+pub async fn upload_multipart(path: &Path, bucket: &str, key: &str) -> Result<()> {
+    let config = aws_config::load_from_env().await;
+    let client = Client::new(&config);
+
+    let file_size = tokio::fs::metadata(path)
+        .await
+        .with_context(|| format!("Failed to stat: {}", path.display()))?
+        .len();
+    let part_size = MIN_PART_SIZE.max(file_size.div_ceil(MAX_PARTS));
+
+    let upload = client
+        .create_multipart_upload()
+        .bucket(bucket)
+        .key(key)
+        .send()
+        .await
+        .context("Failed to start multipart upload")?;
+    let upload_id = upload
+        .upload_id()
+        .ok_or_else(|| anyhow!("S3 didn't return an upload id"))?;
+
+    let progress_bar = transfer_bar(file_size, "Uploading");
+
+    let parts = upload_parts(&client, path, bucket, key, upload_id, part_size, &progress_bar).await;
+    let parts = match parts {
+        Ok(parts) => parts,
+        Err(err) => {
+            progress_bar.abandon_with_message("Upload failed");
+            client
+                .abort_multipart_upload()
+                .bucket(bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .send()
+                .await
+                .ok();
+            return Err(err);
+        }
+    };
+
+    client
+        .complete_multipart_upload()
+        .bucket(bucket)
+        .key(key)
+        .upload_id(upload_id)
+        .multipart_upload(
+            CompletedMultipartUpload::builder()
+                .set_parts(Some(parts))
+                .build(),
+        )
+        .send()
+        .await
+        .context("Failed to complete multipart upload")?;
+
+    progress_bar.finish_with_message("Upload complete");
+    info!("Uploaded to s3://{}/{}", bucket, key);
+    Ok(())
+}
+
+async fn upload_parts(
+    client: &Client,
+    path: &Path,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+    part_size: u64,
+    progress_bar: &TransferProgress,
+) -> Result<Vec<CompletedPart>> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("Failed to open: {}", path.display()))?;
+    let mut parts = Vec::new();
+    let mut part_number = 1;
+
+    loop {
+        let mut buffer = Vec::with_capacity(part_size as usize);
+        (&mut file).take(part_size).read_to_end(&mut buffer).await?;
+        if buffer.is_empty() && part_number > 1 {
+            break;
+        }
+        let part_len = buffer.len() as u64;
+        let body = Bytes::from(buffer);
+
+        let mut attempt = 0;
+        let e_tag = loop {
+            attempt += 1;
+            let result = client
+                .upload_part()
+                .bucket(bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(ByteStream::from(body.clone()))
+                .send()
+                .await;
+            match result {
+                Ok(output) => break output.e_tag().map(str::to_string),
+                Err(err) if attempt < MAX_ATTEMPTS => {
+                    let backoff = Duration::from_secs(1 << attempt);
+                    progress_bar.println(format!(
+                        "Uploading part {} failed ({}), retrying in {}s",
+                        part_number,
+                        err,
+                        backoff.as_secs()
+                    ));
+                    tokio::time::sleep(backoff).await;
+                }
+                Err(err) => {
+                    return Err(anyhow::Error::new(err)
+                        .context(format!("Failed to upload part {}", part_number)));
+                }
+            }
+        };
+
+        parts.push(
+            CompletedPart::builder()
+                .set_e_tag(e_tag)
+                .part_number(part_number)
+                .build(),
+        );
+        progress_bar.inc(part_len);
+
+        if part_len < part_size {
+            break;
+        }
+        part_number += 1;
+    }
+
+    Ok(parts)
+}
@@ -0,0 +1,148 @@
+use std::{
+    io::{Read, Seek, SeekFrom, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, anyhow};
+use tracing::info;
+use ssh2::{CheckResult, KnownHostFileKind, OpenFlags, OpenType, Session};
+
+use crate::archive::progress::transfer_bar;
+
+#[derive(Debug, Clone)]
+pub struct SftpTarget {
+    pub user: String,
+    pub password: Option<String>,
+    pub host: String,
+    pub port: u16,
+    pub remote_path: String,
+    /// Private key to authenticate with. Falls back to the ssh agent and the default keys in ~/.ssh.
+    pub key: Option<PathBuf>,
+}
+
+/// Uploads `path` to the SFTP target. If a smaller file already exists at the remote path,
+/// it's treated as a previously interrupted upload and continued from where it stopped.
+pub async fn upload(path: &Path, target: &SftpTarget) -> Result<()> {
+    let path = path.to_path_buf();
+    let target = target.clone();
+    tokio::task::spawn_blocking(move || upload_blocking(&path, &target)).await?
+}
+
+fn upload_blocking(path: &Path, target: &SftpTarget) -> Result<()> {
+    let tcp = TcpStream::connect((target.host.as_str(), target.port))
+        .with_context(|| format!("Failed to connect to {}:{}", target.host, target.port))?;
+    let mut session = Session::new()?;
+    session.set_tcp_stream(tcp);
+    session.handshake().context("SSH handshake failed")?;
+    verify_host_key(&session, &target.host, target.port)?;
+    authenticate(&session, target)?;
+
+    let sftp = session.sftp().context("Failed to start SFTP subsystem")?;
+    let remote_path = Path::new(&target.remote_path);
+
+    let mut local_file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to open: {}", path.display()))?;
+    let local_size = local_file.metadata()?.len();
+
+    let already_uploaded = match sftp.stat(remote_path) {
+        Ok(stat) => stat.size.unwrap_or(0),
+        Err(_) => 0,
+    };
+    let resume_from = if already_uploaded > 0 && already_uploaded < local_size {
+        info!(
+            "Resuming upload at {}",
+            crate::format_bytes(already_uploaded)
+        );
+        already_uploaded
+    } else {
+        0
+    };
+
+    let mut flags = OpenFlags::WRITE | OpenFlags::CREATE;
+    if resume_from == 0 {
+        flags |= OpenFlags::TRUNCATE;
+    }
+    let mut remote_file = sftp
+        .open_mode(remote_path, flags, 0o644, OpenType::File)
+        .with_context(|| format!("Failed to open remote file {}", target.remote_path))?;
+    remote_file.seek(SeekFrom::Start(resume_from))?;
+    local_file.seek(SeekFrom::Start(resume_from))?;
+
+    let progress_bar = transfer_bar(local_size, "Uploading");
+    progress_bar.set_position(resume_from);
+
+    let mut buffer = vec![0u8; 256 * 1024];
+    loop {
+        let read = local_file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        remote_file
+            .write_all(&buffer[..read])
+            .context("Failed to write to remote file")?;
+        progress_bar.inc(read as u64);
+    }
+    remote_file.fsync().ok(); // not every server supports the fsync extension
+    drop(remote_file);
+
+    progress_bar.finish_with_message("Upload complete");
+    info!(
+        "Uploaded to sftp://{}@{}:{}{}",
+        target.user, target.host, target.port, target.remote_path
+    );
+    Ok(())
+}
+
+fn verify_host_key(session: &Session, host: &str, port: u16) -> Result<()> {
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| anyhow!("Server didn't send a host key"))?;
+    let mut known_hosts = session.known_hosts()?;
+    if let Some(home) = std::env::home_dir() {
+        known_hosts
+            .read_file(&home.join(".ssh/known_hosts"), KnownHostFileKind::OpenSSH)
+            .ok();
+    }
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(anyhow!(
+            "Host key of {} does NOT match the one in ~/.ssh/known_hosts. Refusing to upload",
+            host
+        )),
+        CheckResult::NotFound | CheckResult::Failure => Err(anyhow!(
+            "{} is not in ~/.ssh/known_hosts. Connect once with `ssh` to verify and add its host key",
+            host
+        )),
+    }
+}
+
+fn authenticate(session: &Session, target: &SftpTarget) -> Result<()> {
+    if let Some(ref password) = target.password {
+        session
+            .userauth_password(&target.user, password)
+            .context("Password authentication failed")?;
+        return Ok(());
+    }
+    if let Some(ref key) = target.key {
+        session
+            .userauth_pubkey_file(&target.user, None, key, None)
+            .with_context(|| format!("Authentication with key {} failed", key.display()))?;
+        return Ok(());
+    }
+    if session.userauth_agent(&target.user).is_ok() {
+        return Ok(());
+    }
+    if let Some(home) = std::env::home_dir() {
+        for key_name in ["id_ed25519", "id_ecdsa", "id_rsa"] {
+            let key = home.join(".ssh").join(key_name);
+            if key.exists() && session.userauth_pubkey_file(&target.user, None, &key, None).is_ok() {
+                return Ok(());
+            }
+        }
+    }
+    Err(anyhow!(
+        "Could not authenticate as {}. Pass a password in the URL, a key with --upload-key or add a key to your ssh agent",
+        target.user
+    ))
+}
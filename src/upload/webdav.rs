@@ -0,0 +1,157 @@
+use std::path::Path;
+
+use anyhow::{Context, Result, anyhow};
+use tracing::info;
+use crate::archive::progress::{TransferProgress, transfer_bar};
+use reqwest::{Body, Client, Method, Response, header::HeaderValue};
+use tokio::io::AsyncReadExt;
+use tokio_util::io::ReaderStream;
+use url::Url;
+
+/// Files larger than this are uploaded in chunks when the server supports it (Nextcloud), since a lot of
+/// setups limit the size of a single request.
+pub const DEFAULT_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct WebDavTarget {
+    /// Full http(s) URL of the file to create
+    pub url: Url,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub chunk_size: u64,
+}
+
+pub async fn upload(path: &Path, target: &WebDavTarget) -> Result<()> {
+    let client = Client::new();
+    let file_size = tokio::fs::metadata(path)
+        .await
+        .with_context(|| format!("Failed to stat: {}", path.display()))?
+        .len();
+
+    let progress_bar = transfer_bar(file_size, "Uploading");
+
+    match nextcloud_uploads_url(&target.url) {
+        Some(uploads_url) if file_size > target.chunk_size => {
+            upload_chunked(&client, path, target, uploads_url, file_size, &progress_bar).await?
+        }
+        _ => upload_single(&client, path, target, &progress_bar).await?,
+    }
+
+    progress_bar.finish_with_message("Upload complete");
+    info!("Uploaded to {}", target.url);
+    Ok(())
+}
+
+/// Nextcloud/ownCloud expose files at `/remote.php/dav/files/<user>/...` and accept chunked uploads at `/remote.php/dav/uploads/<user>/`.
+fn nextcloud_uploads_url(file_url: &Url) -> Option<Url> {
+    let path = file_url.path();
+    let (prefix, rest) = path.split_once("/remote.php/dav/files/")?;
+    let (user, _) = rest.split_once('/')?;
+    let mut uploads_url = file_url.clone();
+    uploads_url.set_path(&format!("{}/remote.php/dav/uploads/{}/", prefix, user));
+    uploads_url.set_query(None);
+    Some(uploads_url)
+}
+
+fn request(client: &Client, method: Method, url: Url, target: &WebDavTarget) -> reqwest::RequestBuilder {
+    let request = client.request(method, url);
+    match target.user {
+        Some(ref user) => request.basic_auth(user, target.password.as_ref()),
+        None => request,
+    }
+}
+
+fn check(response: Response, what: &str) -> Result<Response> {
+    if response.status().is_success() {
+        Ok(response)
+    } else {
+        Err(anyhow!("{} failed: HTTP {}", what, response.status()))
+    }
+}
+
+async fn upload_single(
+    client: &Client,
+    path: &Path,
+    target: &WebDavTarget,
+    progress_bar: &TransferProgress,
+) -> Result<()> {
+    let file = tokio::fs::File::open(path).await?;
+    let file_size = file.metadata().await?.len();
+    let progress_bar = progress_bar.clone();
+    let stream = futures_util::StreamExt::inspect(ReaderStream::new(file), move |chunk| {
+        if let Ok(chunk) = chunk {
+            progress_bar.inc(chunk.len() as u64);
+        }
+    });
+    let response = request(client, Method::PUT, target.url.clone(), target)
+        .header(reqwest::header::CONTENT_LENGTH, file_size)
+        .body(Body::wrap_stream(stream))
+        .send()
+        .await
+        .context("Upload request failed")?;
+    check(response, "Upload")?;
+    Ok(())
+}
+
+/// Nextcloud's chunked upload (v2): create an upload directory, PUT numbered chunks into it, then MOVE the assembled `.file` to the destination.
+async fn upload_chunked(
+    client: &Client,
+    path: &Path,
+    target: &WebDavTarget,
+    uploads_url: Url,
+    file_size: u64,
+    progress_bar: &TransferProgress,
+) -> Result<()> {
+    let transfer_id = format!("mwdh-{}", rand::random::<u64>());
+    let upload_dir = uploads_url.join(&format!("{}/", transfer_id))?;
+    let destination = HeaderValue::from_str(target.url.as_str())?;
+
+    let mkcol = Method::from_bytes(b"MKCOL").unwrap();
+    let response = request(client, mkcol, upload_dir.clone(), target)
+        .header("Destination", destination.clone())
+        .send()
+        .await?;
+    check(response, "Creating the upload directory")?;
+
+    let result = async {
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut chunk_number = 1;
+        loop {
+            let mut buffer = Vec::with_capacity(target.chunk_size as usize);
+            (&mut file).take(target.chunk_size).read_to_end(&mut buffer).await?;
+            if buffer.is_empty() {
+                break;
+            }
+            let chunk_len = buffer.len() as u64;
+            let response = request(client, Method::PUT, upload_dir.join(&format!("{:05}", chunk_number))?, target)
+                .header("Destination", destination.clone())
+                .header("OC-Total-Length", file_size)
+                .body(buffer)
+                .send()
+                .await?;
+            check(response, &format!("Uploading chunk {}", chunk_number))?;
+            progress_bar.inc(chunk_len);
+            chunk_number += 1;
+        }
+
+        let response = request(
+            client,
+            Method::from_bytes(b"MOVE").unwrap(),
+            upload_dir.join(".file")?,
+            target,
+        )
+        .header("Destination", destination.clone())
+        .header("OC-Total-Length", file_size)
+        .send()
+        .await?;
+        check(response, "Assembling the chunks")?;
+        anyhow::Ok(())
+    }
+    .await;
+
+    if result.is_err() {
+        // clean up the partial upload, the server would otherwise keep the chunks around for a while
+        request(client, Method::DELETE, upload_dir, target).send().await.ok();
+    }
+    result
+}
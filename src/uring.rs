@@ -0,0 +1,198 @@
+//! Reading files through io_uring on Linux (the `io-uring` feature). A few reads are kept in flight ahead of the
+//! reader, so the disk is busy while the last chunk is being compressed or sent. Everything falls back to plain
+//! reads when the kernel doesn't allow io_uring, like in containers with `io_uring_disabled` set.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{self, Read},
+    os::fd::AsRawFd,
+    sync::OnceLock,
+};
+
+use io_uring::{IoUring, opcode, types};
+use tracing::debug;
+
+/// How many chunks are read ahead
+const QUEUE_DEPTH: u32 = 4;
+
+/// Whether io_uring can be used, checked once.
+pub fn available() -> bool {
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+    *AVAILABLE.get_or_init(|| match IoUring::new(1) {
+        Ok(_) => true,
+        Err(err) => {
+            debug!("io_uring isn't available, reading files the usual way: {}", err);
+            false
+        }
+    })
+}
+
+/// Reads the first `len` bytes of a file, in chunks of `chunk_size`. Stops early if the file shrank.
+pub struct UringReader {
+    file: File,
+    ring: IoUring,
+    len: u64,
+    chunk_size: usize,
+    next_offset: u64,
+    next_index: u64,
+    /// Chunks submitted in the order they're read, by index. The kernel writes into their buffers.
+    in_flight: VecDeque<(u64, Vec<u8>)>,
+    completed: HashMap<u64, i32>,
+    eof: bool,
+    current: Vec<u8>,
+    pos: usize,
+}
+
+impl UringReader {
+    pub fn new(file: File, len: u64, chunk_size: usize) -> io::Result<Self> {
+        Ok(UringReader {
+            file,
+            ring: IoUring::new(QUEUE_DEPTH)?,
+            len,
+            chunk_size,
+            next_offset: 0,
+            next_index: 0,
+            in_flight: VecDeque::new(),
+            completed: HashMap::new(),
+            eof: false,
+            current: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// The next chunk of the file, `None` at its end.
+    pub fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.eof {
+            return Ok(None);
+        }
+        self.submit_reads()?;
+        let Some(&(index, _)) = self.in_flight.front() else {
+            self.eof = true;
+            return Ok(None);
+        };
+        while !self.completed.contains_key(&index) {
+            self.wait_for_completions(1)?;
+        }
+        let (_, mut chunk) = self.in_flight.pop_front().expect("checked above");
+        let result = self.completed.remove(&index).expect("checked above");
+        if result < 0 {
+            self.eof = true;
+            return Err(io::Error::from_raw_os_error(-result));
+        }
+        let read = result as usize;
+        if read < chunk.len() {
+            // the file got shorter, the chunks behind this one have nothing to read
+            self.eof = true;
+        }
+        chunk.truncate(read);
+        if chunk.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(chunk))
+    }
+
+    /// Keeps up to [`QUEUE_DEPTH`] reads in flight.
+    fn submit_reads(&mut self) -> io::Result<()> {
+        let mut submitted = false;
+        while self.in_flight.len() < QUEUE_DEPTH as usize && self.next_offset < self.len {
+            let len = (self.len - self.next_offset).min(self.chunk_size as u64) as usize;
+            let mut buf = vec![0u8; len];
+            let entry = opcode::Read::new(types::Fd(self.file.as_raw_fd()), buf.as_mut_ptr(), len as u32)
+                .offset(self.next_offset)
+                .build()
+                .user_data(self.next_index);
+            // the buffer stays in `in_flight` until the read completed, even when dropped
+            unsafe { self.ring.submission().push(&entry) }.map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+            self.in_flight.push_back((self.next_index, buf));
+            self.next_offset += len as u64;
+            self.next_index += 1;
+            submitted = true;
+        }
+        if submitted {
+            self.ring.submit()?;
+        }
+        Ok(())
+    }
+
+    fn wait_for_completions(&mut self, want: usize) -> io::Result<()> {
+        loop {
+            match self.ring.submit_and_wait(want) {
+                Ok(_) => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        for entry in self.ring.completion() {
+            self.completed.insert(entry.user_data(), entry.result());
+        }
+        Ok(())
+    }
+}
+
+impl Read for UringReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.current.len() {
+            match self.next_chunk()? {
+                Some(chunk) => {
+                    self.current = chunk;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let read = buf.len().min(self.current.len() - self.pos);
+        buf[..read].copy_from_slice(&self.current[self.pos..self.pos + read]);
+        self.pos += read;
+        Ok(read)
+    }
+}
+
+impl Drop for UringReader {
+    fn drop(&mut self) {
+        // the kernel may still write into the buffers of reads that are in flight
+        while self.in_flight.iter().any(|(index, _)| !self.completed.contains_key(index)) {
+            if self.wait_for_completions(1).is_err() {
+                // can't tell when the buffers are safe to free anymore
+                std::mem::forget(std::mem::take(&mut self.in_flight));
+                return;
+            }
+        }
+    }
+}
+
+/// Streams `len` bytes of the file in chunks from a blocking thread, for a download.
+#[cfg(feature = "server")]
+pub fn stream_file(
+    file: File,
+    len: u64,
+    chunk_size: usize,
+) -> impl futures_util::Stream<Item = io::Result<bytes::Bytes>> + Send + Sync + 'static {
+    let (tx, mut rx) = tokio::sync::mpsc::channel(QUEUE_DEPTH as usize);
+    tokio::task::spawn_blocking(move || {
+        let mut reader = match UringReader::new(file, len, chunk_size) {
+            Ok(reader) => reader,
+            Err(err) => {
+                tx.blocking_send(Err(err)).ok();
+                return;
+            }
+        };
+        loop {
+            let chunk = match reader.next_chunk() {
+                Ok(Some(chunk)) => Ok(bytes::Bytes::from(chunk)),
+                Ok(None) => break,
+                Err(err) => Err(err),
+            };
+            let failed = chunk.is_err();
+            // fails once the client is gone
+            if tx.blocking_send(chunk).is_err() || failed {
+                break;
+            }
+        }
+    });
+    futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx))
+}
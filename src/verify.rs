@@ -0,0 +1,159 @@
+use std::{collections::HashMap, io::Read, path::{Path, PathBuf}};
+
+use anyhow::{Context, Result, anyhow};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::{
+    archive::entries, format_bytes, logging,
+    manifest::{MANIFEST_NAME, Manifest},
+};
+
+#[derive(Clone)]
+pub struct ListOptions {
+    /// The .zip or .tar.zst archive to list
+    pub archive: PathBuf,
+}
+
+#[derive(Clone)]
+pub struct VerifyOptions {
+    /// The .zip or .tar.zst archive to check against its manifest
+    pub archive: PathBuf,
+}
+
+/// Prints the manifest of an archive created with `--manifest` and the files it lists. Archives without one are
+/// read through to list their entries.
+pub fn list(options: &ListOptions) -> Result<()> {
+    let mut manifest = None;
+    let mut entries = Vec::new();
+    entries::for_each_entry(&options.archive, |entry, contents| {
+        if entry.path == MANIFEST_NAME && entries.is_empty() {
+            manifest = Some(read_manifest(contents)?);
+            // everything else is in the manifest, the rest of the archive doesn't have to be decompressed
+            return Ok(false);
+        }
+        entries.push((entry.path, entry.size));
+        Ok(true)
+    })?;
+
+    match manifest {
+        Some(manifest) => {
+            println!("Created by:    mwdh {}", manifest.mwdh_version);
+            println!("Created:       {}", manifest.created);
+            println!("World name:    {}", manifest.world_name);
+            println!("Version:       {}", manifest.minecraft_version.as_deref().unwrap_or("unknown"));
+            println!("Dimensions:    {}", manifest.dimensions.join(", "));
+            if !manifest.region_issues.is_empty() {
+                println!("Region issues: {}", manifest.region_issues.len());
+                for issue in &manifest.region_issues {
+                    println!("  {}", issue);
+                }
+            }
+            println!();
+            entries = manifest.files.into_iter().map(|entry| (entry.path, entry.size)).collect();
+        }
+        None => info!("{} has no manifest, listing its entries", options.archive.display()),
+    }
+    for (path, size) in &entries {
+        println!("{:>12}  {}", format_bytes(*size), path);
+    }
+    let total = entries.iter().map(|(_, size)| size).sum();
+    println!();
+    println!("{} files, {}", entries.len(), format_bytes(total));
+    Ok(())
+}
+
+/// Hashes every file in the archive and compares it to the manifest. Fails if a file is different, missing or
+/// isn't in the manifest.
+pub fn verify(options: &VerifyOptions) -> Result<()> {
+    let mut manifest: Option<HashMap<String, (u64, String)>> = None;
+    let mut changed = 0;
+    let mut unexpected = 0;
+    let mut checked = 0;
+    entries::for_each_entry(&options.archive, |entry, contents| {
+        let Some(ref mut expected) = manifest else {
+            if entry.path != MANIFEST_NAME {
+                return Err(anyhow!(
+                    "{} has no manifest to verify it against, it was created without --manifest",
+                    options.archive.display()
+                ));
+            }
+            let parsed = read_manifest(contents)?;
+            info!(
+                "Verifying {} files of {} created by mwdh {} on {}",
+                parsed.files.len(),
+                parsed.world_name,
+                parsed.mwdh_version,
+                parsed.created
+            );
+            manifest = Some(parsed.files.into_iter().map(|entry| (entry.path, (entry.size, entry.sha256))).collect());
+            return Ok(true);
+        };
+
+        let mut hasher = Sha256::new();
+        std::io::copy(contents, &mut hasher).with_context(|| format!("Failed to read {} from the archive", entry.path))?;
+        let sha256 = format!("{:x}", hasher.finalize());
+        match expected.remove(&entry.path) {
+            Some((expected_size, expected_sha256)) if expected_size == entry.size && expected_sha256 == sha256 => checked += 1,
+            Some(_) => {
+                warn!("{} is different from when the archive was created", entry.path);
+                changed += 1;
+            }
+            None => {
+                warn!("{} isn't in the manifest", entry.path);
+                unexpected += 1;
+            }
+        }
+        Ok(true)
+    })?;
+
+    let Some(missing) = manifest else {
+        return Err(anyhow!("{} is empty", options.archive.display()));
+    };
+    let mut missing: Vec<_> = missing.into_keys().collect();
+    missing.sort();
+    for path in &missing {
+        warn!("{} is missing from the archive", path);
+    }
+    if changed > 0 || unexpected > 0 || !missing.is_empty() {
+        return Err(anyhow!(
+            "{} doesn't match its manifest: {} files changed, {} missing, {} not in the manifest",
+            options.archive.display(),
+            changed,
+            missing.len(),
+            unexpected
+        ));
+    }
+    info!(target: logging::SUMMARY, "All {} files of {} match the manifest", checked, options.archive.display());
+    Ok(())
+}
+
+/// Whether the archive was created with `--manifest`, which puts the manifest first.
+pub fn has_manifest(archive: &Path) -> Result<bool> {
+    let mut found = false;
+    entries::for_each_entry(archive, |entry, _| {
+        found = entry.path == MANIFEST_NAME;
+        Ok(false)
+    })?;
+    Ok(found)
+}
+
+/// The manifest JSON of an archive created with `--manifest`, `None` for other archives.
+pub fn manifest_json(archive: &Path) -> Result<Option<Vec<u8>>> {
+    let mut manifest = None;
+    entries::for_each_entry(archive, |entry, contents| {
+        if entry.path == MANIFEST_NAME {
+            let mut bytes = Vec::new();
+            contents.read_to_end(&mut bytes)?;
+            manifest = Some(bytes);
+        }
+        Ok(false)
+    })?;
+    Ok(manifest)
+}
+
+fn read_manifest(contents: &mut dyn Read) -> Result<Manifest> {
+    let mut bytes = Vec::new();
+    contents.read_to_end(&mut bytes)?;
+    Manifest::from_json(&bytes)
+}